@@ -0,0 +1,25 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    // Our memory.x keeps the program out of the pages at the end of flash that hold data. The
+    // linker looks here before it looks at f3's, which has all of flash.
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy("memory.x", out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+
+    // defmt keeps its format strings in a section that its linker script sets up
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg=-Tdefmt.x");
+    }
+
+    // Embassy's vector table comes from stm32-metapac, which has its own names for some of the
+    // interrupts
+    if env::var_os("CARGO_FEATURE_EMBASSY").is_some() {
+        fs::copy("embassy.x", out.join("embassy.x")).unwrap();
+        println!("cargo:rustc-link-arg-bin=embassy=-Tembassy.x");
+        println!("cargo:rerun-if-changed=embassy.x");
+    }
+}
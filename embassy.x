@@ -0,0 +1,28 @@
+/* The default handlers of the interrupts that stm32-metapac names differently from stm32f30x.
+   The linker finds f3's device.x first, which only has stm32f30x's names, so the Embassy
+   build adds these on top. See build.rs. */
+PROVIDE(COMP1_2_3 = DefaultHandler);
+PROVIDE(COMP4_5_6 = DefaultHandler);
+PROVIDE(DMA1_CHANNEL1 = DefaultHandler);
+PROVIDE(DMA1_CHANNEL2 = DefaultHandler);
+PROVIDE(DMA1_CHANNEL3 = DefaultHandler);
+PROVIDE(DMA1_CHANNEL4 = DefaultHandler);
+PROVIDE(DMA1_CHANNEL5 = DefaultHandler);
+PROVIDE(DMA1_CHANNEL6 = DefaultHandler);
+PROVIDE(DMA1_CHANNEL7 = DefaultHandler);
+PROVIDE(DMA2_CHANNEL1 = DefaultHandler);
+PROVIDE(DMA2_CHANNEL2 = DefaultHandler);
+PROVIDE(DMA2_CHANNEL3 = DefaultHandler);
+PROVIDE(DMA2_CHANNEL4 = DefaultHandler);
+PROVIDE(DMA2_CHANNEL5 = DefaultHandler);
+PROVIDE(I2C1_EV = DefaultHandler);
+PROVIDE(I2C2_EV = DefaultHandler);
+PROVIDE(RTC_ALARM = DefaultHandler);
+PROVIDE(TIM6_DAC = DefaultHandler);
+PROVIDE(UART4 = DefaultHandler);
+PROVIDE(UART5 = DefaultHandler);
+PROVIDE(USART1 = DefaultHandler);
+PROVIDE(USART2 = DefaultHandler);
+PROVIDE(USART3 = DefaultHandler);
+PROVIDE(USBWAKEUP = DefaultHandler);
+PROVIDE(USBWAKEUP_RMP = DefaultHandler);
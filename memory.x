@@ -0,0 +1,10 @@
+/* Linker script for the STM32F303VCT6, like f3's, but with FLASH ending where the data starts */
+MEMORY
+{
+  CCRAM : ORIGIN = 0x10000000, LENGTH = 8K
+  /* 0x0803D000 on is for the heading log and the settings, see `storage::DATA_ADDRESS` */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 244K
+  RAM : ORIGIN = 0x20000000, LENGTH = 40K
+}
+
+_stack_start = ORIGIN(CCRAM) + LENGTH(CCRAM);
@@ -0,0 +1,181 @@
+//! The messages that the compass sends over its serial port, and how they're framed
+//!
+//! This crate is shared by the firmware and by host tools, so that both sides agree on the format
+//! without anyone parsing bytes by hand.
+//!
+//! A message is serialized with postcard and followed by a CRC-16 of the serialized bytes. The
+//! whole thing is COBS-encoded and terminated with a zero byte, so a receiver that loses bytes can
+//! find the start of the next frame by waiting for a zero, and the CRC tells it to drop the damaged
+//! one.
+
+#![no_std]
+
+use serde::{Deserialize, Serialize};
+
+/// Big enough for any encoded `Message`, including the terminating zero
+// Config is the largest message: tag (1) + 17 f32s (68) + the Option tag (1), plus the CRC (2),
+// the COBS overhead (1) and the zero
+pub const MAX_FRAME_SIZE: usize = 74;
+
+const CRC_SIZE: usize = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer is too small for the frame
+    BufferFull,
+    /// The frame isn't valid COBS
+    Cobs,
+    /// The CRC doesn't match, so the frame was damaged
+    Crc,
+    /// The CRC matches, but the bytes aren't a `Message`
+    Postcard(postcard::Error),
+}
+
+/// One magnetometer sample
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Telemetry {
+    pub timestamp_ms: u32,
+    /// Magnetometer reading before calibration
+    pub raw: (i16, i16, i16),
+    /// Magnetometer reading after calibration
+    pub calibrated: (i16, i16, i16),
+    /// Bearing in degrees clockwise from north, if we know it yet
+    pub heading: Option<f32>,
+    /// How fast the bearing is changing in degrees per second, positive clockwise, if we know it yet
+    pub rate_of_turn: Option<f32>,
+    /// Orientation of the Earth frame relative to the board from the AHRS filter, as a unit
+    /// quaternion (w, x, y, z), for 3D visualizers
+    pub orientation: (f32, f32, f32, f32),
+    /// Whether the field doesn't look like the Earth's, e.g. because of nearby steel or magnets, so
+    /// that the heading is probably wrong
+    pub suspect: bool,
+}
+
+/// The settings that the compass keeps in flash
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Hard-iron offset, subtracted from the raw reading
+    pub offset: (f32, f32, f32),
+    /// Soft-iron correction, applied after subtracting the offset
+    pub matrix: [[f32; 3]; 3],
+    /// Magnetic declination in degrees, positive east
+    pub declination_deg: f32,
+    /// The temperature at which `offset` was measured, if known
+    pub drift_reference_c: Option<f32>,
+    /// How much the offset changes per degree Celsius
+    pub drift_offset_per_c: (f32, f32, f32),
+}
+
+/// The off-course alarm went off or stopped
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Alarm {
+    pub timestamp_ms: u32,
+    /// Whether the alarm is going off now
+    pub raised: bool,
+    /// How far the bearing is off the course in degrees, positive clockwise
+    pub deviation_deg: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Message {
+    Telemetry(Telemetry),
+    Config(Config),
+    Alarm(Alarm),
+}
+
+/// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xffff
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            let mask = (crc >> 15).wrapping_neg();
+            crc = (crc << 1) ^ (0x1021 & mask);
+        }
+    }
+    crc
+}
+
+/// Consistent Overhead Byte Stuffing: replace every zero with the distance to the next one, so
+/// that zero can mark the end of a frame. Returns the number of bytes written to `output`, which
+/// doesn't include the terminating zero.
+fn cobs_encode(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+    let mut push = |index: usize, byte: u8| match output.get_mut(index) {
+        Some(slot) => {
+            *slot = byte;
+            Ok(())
+        }
+        None => Err(Error::BufferFull),
+    };
+
+    // Where the distance to the next zero goes once we know it
+    let mut code_index = 0;
+    let mut code = 1u8;
+    let mut len = 1;
+    for &byte in input {
+        if byte != 0 {
+            push(len, byte)?;
+            len += 1;
+            code += 1;
+        }
+        // A block can be at most 254 bytes long
+        if byte == 0 || code == 0xff {
+            push(code_index, code)?;
+            code_index = len;
+            len += 1;
+            code = 1;
+        }
+    }
+    push(code_index, code)?;
+    Ok(len)
+}
+
+/// Undo `cobs_encode` in place. `frame` must not include the terminating zero. Returns the length
+/// of the decoded data.
+fn cobs_decode(frame: &mut [u8]) -> Result<usize, Error> {
+    let mut read = 0;
+    let mut len = 0;
+    while read < frame.len() {
+        let code = usize::from(frame[read]);
+        if code == 0 || read + code > frame.len() {
+            return Err(Error::Cobs);
+        }
+        frame.copy_within(read + 1..read + code, len);
+        len += code - 1;
+        read += code;
+        // Every block except a full one and the last one stands for a zero
+        if code != 0xff && read < frame.len() {
+            frame[len] = 0;
+            len += 1;
+        }
+    }
+    Ok(len)
+}
+
+/// Encode `message` into `frame`, including the terminating zero. Returns the length of the frame.
+pub fn encode(message: &Message, frame: &mut [u8]) -> Result<usize, Error> {
+    let mut buffer = [0u8; MAX_FRAME_SIZE];
+    let len = postcard::to_slice(message, &mut buffer[..MAX_FRAME_SIZE - CRC_SIZE])
+        .map_err(Error::Postcard)?
+        .len();
+    let crc = crc16(&buffer[..len]);
+    buffer[len..len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+    let len = cobs_encode(&buffer[..len + CRC_SIZE], frame)?;
+    *frame.get_mut(len).ok_or(Error::BufferFull)? = 0;
+    Ok(len + 1)
+}
+
+/// Decode a frame that was received up to, but not including, the terminating zero. This
+/// overwrites `frame`.
+pub fn decode(frame: &mut [u8]) -> Result<Message, Error> {
+    let len = cobs_decode(frame)?;
+    if len < CRC_SIZE {
+        return Err(Error::Crc);
+    }
+    let (payload, crc) = frame[..len].split_at(len - CRC_SIZE);
+    if u16::from_le_bytes([crc[0], crc[1]]) != crc16(payload) {
+        return Err(Error::Crc);
+    }
+    postcard::from_bytes(payload).map_err(Error::Postcard)
+}
@@ -0,0 +1,170 @@
+//! Driver for the accelerometer half of the LSM303DLHC
+
+use crate::drdy::DataReady;
+use embedded_hal_async::i2c::I2c;
+use futures::{stream, Stream};
+
+// Slave address
+const ACCELEROMETER: u8 = 0b001_1001;
+
+// Control registers
+const CTRL_REG1_A: u8 = 0x20;
+const CTRL_REG3_A: u8 = 0x22;
+const CTRL_REG4_A: u8 = 0x23;
+const CTRL_REG6_A: u8 = 0x25;
+
+// Click detection registers
+const CLICK_CFG_A: u8 = 0x38;
+const CLICK_SRC_A: u8 = 0x39;
+const CLICK_THS_A: u8 = 0x3a;
+const TIME_LIMIT_A: u8 = 0x3b;
+const TIME_LATENCY_A: u8 = 0x3c;
+const TIME_WINDOW_A: u8 = 0x3d;
+
+// CLICK_SRC_A bits: an interrupt is active, and whether it's a double or a single click
+const CLICK_IA: u8 = 1 << 6;
+const DCLICK: u8 = 1 << 5;
+const SCLICK: u8 = 1 << 4;
+
+// STATUS_REG_A has the data-ready bit, followed by the acceleration data
+const STATUS_REG_A: u8 = 0x27;
+const ZYXDA: u8 = 1 << 3;
+
+// Setting the MSB of the register address makes the accelerometer auto-increment it, which we need
+// in order to read all the axes in one transaction
+const AUTO_INCREMENT: u8 = 0x80;
+
+// ODR = 100 Hz, normal power mode, X, Y and Z enabled
+const CTRL_REG1_A_VALUE: u8 = 0b0101_0111;
+
+// BDU = 1 so that we never see the high and low bytes of different samples
+// FS = ±2 g
+// HR = 1 for 12-bit resolution
+const CTRL_REG4_A_VALUE: u8 = 0b1000_1000;
+
+// In high resolution mode the data is 12 bits, left-justified in 16 bits, and at ±2 g each LSB is
+// 1 milli-g
+const SHIFT: u32 = 4;
+
+// I1_DRDY1: signal new data on INT1
+const CTRL_REG3_A_VALUE: u8 = 0b0001_0000;
+
+// I2_CLICKen: signal clicks on INT2
+const CTRL_REG6_A_VALUE: u8 = 0b1000_0000;
+
+// Single and double clicks along Z, which is what tapping the top of the board makes
+const CLICK_CFG_A_VALUE: u8 = 0b0011_0000;
+
+// At ±2 g, 1 LSB is 16 mg, so this is about 0.5 g
+const CLICK_THRESHOLD: u8 = 32;
+
+// Timing in units of the 10 ms sample period: a click is over within 100 ms, and the second click
+// of a double click comes at least 200 ms after the first one, but within the 400 ms after that
+const CLICK_TIME_LIMIT: u8 = 10;
+const CLICK_LATENCY: u8 = 20;
+const CLICK_WINDOW: u8 = 40;
+
+/// Time between samples at the configured data rate of 100 Hz, in seconds
+pub const SAMPLE_PERIOD_S: f32 = 1.0 / 100.0;
+
+/// What `get_click` found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Tap {
+    Single,
+    Double,
+}
+
+pub struct Accelerometer<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Accelerometer<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Accelerometer { i2c }
+    }
+
+    /// Configure the data rate, range and resolution, and signal new data on INT1. This needs to be
+    /// called once before `get_accel`.
+    pub async fn init(&mut self) -> Result<(), I2C::Error> {
+        self.i2c
+            .write(ACCELEROMETER, &[CTRL_REG1_A, CTRL_REG1_A_VALUE])
+            .await?;
+        self.i2c
+            .write(ACCELEROMETER, &[CTRL_REG4_A, CTRL_REG4_A_VALUE])
+            .await?;
+        self.i2c
+            .write(ACCELEROMETER, &[CTRL_REG3_A, CTRL_REG3_A_VALUE])
+            .await
+    }
+
+    /// Detect taps on the top of the board and signal them on INT2
+    pub async fn enable_clicks(&mut self) -> Result<(), I2C::Error> {
+        for &(register, value) in &[
+            (CLICK_THS_A, CLICK_THRESHOLD),
+            (TIME_LIMIT_A, CLICK_TIME_LIMIT),
+            (TIME_LATENCY_A, CLICK_LATENCY),
+            (TIME_WINDOW_A, CLICK_WINDOW),
+            (CLICK_CFG_A, CLICK_CFG_A_VALUE),
+            (CTRL_REG6_A, CTRL_REG6_A_VALUE),
+        ] {
+            self.i2c.write(ACCELEROMETER, &[register, value]).await?;
+        }
+        Ok(())
+    }
+
+    /// Find out which click INT2 is signaling, if it's still signaling one
+    pub async fn get_click(&mut self) -> Result<Option<Tap>, I2C::Error> {
+        let mut source = [0u8];
+        self.i2c
+            .write_read(ACCELEROMETER, &[CLICK_SRC_A], &mut source)
+            .await?;
+        let source = source[0];
+
+        Ok(if source & CLICK_IA == 0 {
+            None
+        } else if source & DCLICK != 0 {
+            Some(Tap::Double)
+        } else if source & SCLICK != 0 {
+            Some(Tap::Single)
+        } else {
+            None
+        })
+    }
+
+    /// Wait for a sample that we haven't read yet, then return the acceleration as (x, y, z) in
+    /// milli-g. Consecutive results are `SAMPLE_PERIOD_S` apart.
+    pub async fn get_accel(&mut self) -> Result<(i16, i16, i16), I2C::Error> {
+        loop {
+            // Read the status register and the data in one go; the data only counts if the status
+            // register says that it's new
+            let mut buffer = [0u8; 7];
+            self.i2c
+                .write_read(ACCELEROMETER, &[STATUS_REG_A | AUTO_INCREMENT], &mut buffer)
+                .await?;
+            if buffer[0] & ZYXDA == 0 {
+                continue;
+            }
+
+            // Unlike the magnetometer, the accelerometer sends the low byte first
+            let x = i16::from_le_bytes([buffer[1], buffer[2]]) >> SHIFT;
+            let y = i16::from_le_bytes([buffer[3], buffer[4]]) >> SHIFT;
+            let z = i16::from_le_bytes([buffer[5], buffer[6]]) >> SHIFT;
+
+            return Ok((x, y, z));
+        }
+    }
+}
+
+/// Accelerometer readings, as fast as the accelerometer makes them. `drdy` must be the
+/// accelerometer's INT1 line.
+pub fn get_accel_forever<I2C: I2c>(
+    accel: Accelerometer<I2C>,
+    drdy: DataReady,
+) -> impl Stream<Item = Result<(i16, i16, i16), I2C::Error>> {
+    stream::unfold((accel, drdy), |(mut accel, drdy)| async move {
+        drdy.wait_for_drdy().await;
+        let result = accel.get_accel().await;
+        Some((result, (accel, drdy)))
+    })
+}
@@ -0,0 +1,98 @@
+//! Reading noise from ADC1
+//!
+//! The ADC isn't used to measure anything. Its conversions have a few LSBs of noise, from thermal
+//! noise in the sampling capacitor and from whatever a floating pin picks up, and none of it has
+//! anything to do with the magnetic field. That makes it a source of entropy that still works in a
+//! magnetically quiet place, where the magnetometer's readings barely change.
+//!
+//! We read PA1, which isn't connected to anything on the Discovery board, and two internal
+//! channels: the temperature sensor and the internal reference voltage. Every channel uses the
+//! shortest sample time, which makes the readings inaccurate but keeps their noise.
+
+use crate::delay::{Delay, Tim7};
+use f3::hal::stm32f30x::{adc1, adc1_2, gpioa, rcc, ADC1, ADC1_2, GPIOA, RCC};
+
+// The floating pin, PA1, is ADC1_IN2
+const PIN: u32 = 1;
+
+// The ADC's clock is HCLK / 1 = 72 MHz, synchronous with the AHB, which is as fast as the ADC goes
+const CKMODE_HCLK: u8 = 0b01;
+
+// The voltage regulator takes up to 10 µs to start up
+const REGULATOR_STARTUP_US: u16 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Channel {
+    /// PA1
+    FloatingPin = 2,
+    Temperature = 16,
+    ReferenceVoltage = 18,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [
+        Channel::FloatingPin,
+        Channel::Temperature,
+        Channel::ReferenceVoltage,
+    ];
+}
+
+pub struct Adc {
+    adc1: &'static adc1::RegisterBlock,
+}
+
+impl Adc {
+    /// Power on and calibrate ADC1, and connect PA1 and the internal channels to it
+    pub async fn new(delay: &Delay<Tim7>) -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let gpioa: &'static gpioa::RegisterBlock = unsafe { &*GPIOA::ptr() };
+        let common: &'static adc1_2::RegisterBlock = unsafe { &*ADC1_2::ptr() };
+        let adc1: &'static adc1::RegisterBlock = unsafe { &*ADC1::ptr() };
+
+        rcc.ahbenr
+            .modify(|_, w| w.iopaen().set_bit().adc12en().set_bit());
+
+        // Analog mode, which also disconnects the pin's digital input
+        gpioa
+            .moder
+            .modify(|r, w| unsafe { w.bits(r.bits() | (0b11 << (2 * PIN))) });
+
+        common.ccr.modify(|_, w| unsafe {
+            w.ckmode()
+                .bits(CKMODE_HCLK)
+                .tsen()
+                .set_bit()
+                .vrefen()
+                .set_bit()
+        });
+
+        // The regulator has to leave deep power down before it can be enabled
+        adc1.cr.modify(|_, w| w.deeppwd().clear_bit());
+        adc1.cr.modify(|_, w| w.advregen().set_bit());
+        delay.delay_us(REGULATOR_STARTUP_US).await;
+
+        // Calibrate for single-ended inputs, which clears ADCAL once it's done
+        adc1.cr
+            .modify(|_, w| w.adcaldif().clear_bit().adcal().set_bit());
+        while adc1.cr.read().adcal().bit_is_set() {}
+
+        adc1.cr.modify(|_, w| w.aden().set_bit());
+        while adc1.isr.read().adrdy().bit_is_clear() {}
+
+        Adc { adc1 }
+    }
+
+    /// Convert `channel` once, and return the 12-bit result
+    pub fn read(&mut self, channel: Channel) -> u16 {
+        // A sequence of one conversion. The sample times are left at their reset value, the
+        // shortest.
+        self.adc1
+            .sqr1
+            .write(|w| unsafe { w.bits((channel as u32) << 6) });
+        self.adc1.cr.modify(|_, w| w.adstart().set_bit());
+        while self.adc1.isr.read().eoc().bit_is_clear() {}
+        // Reading the data clears EOC
+        self.adc1.dr.read().regular_data().bits()
+    }
+}
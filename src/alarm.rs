@@ -0,0 +1,187 @@
+//! Off-course alarm
+//!
+//! The user sets a course, a tolerance and a delay. Once the heading has been further off the
+//! course than the tolerance for the whole delay, the alarm goes off, and it stays on until the
+//! heading is back within the tolerance. The delay keeps waves, gusts and the odd noisy reading from
+//! setting it off.
+
+use crate::fusion::wrap_degrees;
+
+/// When the alarm goes off
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AlarmSettings {
+    /// The course to hold, in degrees clockwise from north
+    pub course: f32,
+    /// How far off the course still counts as on course, in degrees
+    pub tolerance_deg: f32,
+    /// How long the heading has to stay off course before the alarm goes off, in seconds
+    pub delay_s: f32,
+}
+
+/// What the alarm does when it goes off
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AlarmAction {
+    /// Flash the LEDs
+    Flash,
+    /// Sound the buzzer
+    Buzzer,
+    /// Send an event to the host
+    Event,
+}
+
+/// Which of the `AlarmAction`s are enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AlarmActions {
+    pub flash: bool,
+    pub buzzer: bool,
+    pub event: bool,
+}
+
+impl AlarmActions {
+    /// Everything enabled
+    pub fn new() -> Self {
+        AlarmActions {
+            flash: true,
+            buzzer: true,
+            event: true,
+        }
+    }
+
+    pub fn set(&mut self, action: AlarmAction, enabled: bool) {
+        match action {
+            AlarmAction::Flash => self.flash = enabled,
+            AlarmAction::Buzzer => self.buzzer = enabled,
+            AlarmAction::Event => self.event = enabled,
+        }
+    }
+}
+
+impl Default for AlarmActions {
+    fn default() -> Self {
+        AlarmActions::new()
+    }
+}
+
+/// A change in whether the alarm is going off
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AlarmEvent {
+    /// The heading has been off course for too long. `deviation_deg` is positive when the heading
+    /// is clockwise of the course.
+    Raised { deviation_deg: f32 },
+    /// The heading is back on course
+    Cleared,
+}
+
+pub struct OffCourseAlarm {
+    settings: Option<AlarmSettings>,
+    // How long the heading has been off course, in seconds
+    off_course_s: f32,
+    raised: bool,
+}
+
+impl OffCourseAlarm {
+    /// An alarm without a course, which never goes off
+    pub fn new() -> Self {
+        OffCourseAlarm {
+            settings: None,
+            off_course_s: 0.0,
+            raised: false,
+        }
+    }
+
+    pub fn settings(&self) -> Option<AlarmSettings> {
+        self.settings
+    }
+
+    /// Replace the settings, or turn the alarm off with `None`. Either way, the alarm starts over,
+    /// so if it was going off it stops without a `Cleared` event.
+    pub fn set(&mut self, settings: Option<AlarmSettings>) {
+        self.settings = settings;
+        self.off_course_s = 0.0;
+        self.raised = false;
+    }
+
+    /// Whether the alarm is going off
+    pub fn is_raised(&self) -> bool {
+        self.raised
+    }
+
+    /// Add a bearing that came `dt_s` seconds after the previous one, and return whether that
+    /// raised or cleared the alarm
+    pub fn update(&mut self, bearing: f32, dt_s: f32) -> Option<AlarmEvent> {
+        let settings = self.settings?;
+        let deviation_deg = wrap_degrees(bearing - settings.course);
+        if deviation_deg.abs() <= settings.tolerance_deg {
+            self.off_course_s = 0.0;
+            if self.raised {
+                self.raised = false;
+                return Some(AlarmEvent::Cleared);
+            }
+            return None;
+        }
+
+        self.off_course_s += dt_s;
+        if !self.raised && self.off_course_s >= settings.delay_s {
+            self.raised = true;
+            return Some(AlarmEvent::Raised { deviation_deg });
+        }
+        None
+    }
+}
+
+impl Default for OffCourseAlarm {
+    fn default() -> Self {
+        OffCourseAlarm::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alarm() -> OffCourseAlarm {
+        let mut alarm = OffCourseAlarm::new();
+        alarm.set(Some(AlarmSettings {
+            course: 350.0,
+            tolerance_deg: 10.0,
+            delay_s: 1.0,
+        }));
+        alarm
+    }
+
+    #[test]
+    fn goes_off_after_the_delay() {
+        let mut alarm = alarm();
+        // Within the tolerance, across north
+        assert_eq!(alarm.update(0.0, 0.5), None);
+        for _ in 0..3 {
+            assert_eq!(alarm.update(5.0, 0.25), None);
+        }
+        assert_eq!(
+            alarm.update(5.0, 0.25),
+            Some(AlarmEvent::Raised {
+                deviation_deg: 15.0
+            })
+        );
+        assert!(alarm.is_raised());
+        assert_eq!(alarm.update(5.0, 0.25), None);
+        assert_eq!(alarm.update(345.0, 0.25), Some(AlarmEvent::Cleared));
+        assert!(!alarm.is_raised());
+    }
+
+    #[test]
+    fn brief_excursions_are_forgiven() {
+        let mut alarm = alarm();
+        for _ in 0..10 {
+            assert_eq!(alarm.update(320.0, 0.5), None);
+            assert_eq!(alarm.update(350.0, 0.1), None);
+        }
+
+        alarm.set(None);
+        assert_eq!(alarm.update(170.0, 10.0), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Magnetic anomaly detection, which turns the compass into a crude metal detector
+//!
+//! We follow the strength of the field with a slow low-pass filter. That baseline absorbs the
+//! earth's field and anything that stays put, so only something that changes the field quickly,
+//! like a piece of iron that's moved close to the board, makes the strength deviate from it.
+
+use crate::trig;
+
+pub struct AnomalyDetector {
+    /// How long it takes, in seconds, for the baseline to absorb most of a change
+    time_constant_s: f32,
+    baseline: Option<f32>,
+}
+
+impl AnomalyDetector {
+    pub fn new(time_constant_s: f32) -> Self {
+        AnomalyDetector {
+            time_constant_s,
+            baseline: None,
+        }
+    }
+
+    /// Forget the baseline, so that the next reading becomes the new one
+    pub fn reset(&mut self) {
+        self.baseline = None;
+    }
+
+    /// Add a calibrated reading that came `dt_s` seconds after the previous one, and return how far
+    /// its strength deviates from the baseline, as a fraction of the baseline
+    pub fn update(&mut self, mag: (i16, i16, i16), dt_s: f32) -> f32 {
+        let (x, y, z) = (f32::from(mag.0), f32::from(mag.1), f32::from(mag.2));
+        let strength = trig::sqrt(x * x + y * y + z * z);
+
+        let baseline = match self.baseline {
+            None => strength,
+            Some(baseline) => {
+                let gain = dt_s / (self.time_constant_s + dt_s);
+                baseline + gain * (strength - baseline)
+            }
+        };
+        self.baseline = Some(baseline);
+
+        if baseline < f32::EPSILON {
+            0.0
+        } else {
+            (strength - baseline).abs() / baseline
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::E;
+
+    const TIME_CONSTANT_S: f32 = 5.0;
+    const DT_S: f32 = 0.1;
+
+    /// Hold the field at `mag` for `duration_s`, and return the last deviation
+    fn hold(detector: &mut AnomalyDetector, mag: (i16, i16, i16), duration_s: f32) -> f32 {
+        let mut deviation = 0.0;
+        for _ in 0..(duration_s / DT_S) as usize {
+            deviation = detector.update(mag, DT_S);
+        }
+        deviation
+    }
+
+    #[test]
+    fn a_steady_field_is_no_anomaly() {
+        let mut detector = AnomalyDetector::new(TIME_CONSTANT_S);
+        assert_eq!(hold(&mut detector, (300, 400, 0), 10.0), 0.0);
+    }
+
+    #[test]
+    fn the_deviation_decays_with_the_time_constant() {
+        let mut detector = AnomalyDetector::new(TIME_CONSTANT_S);
+        hold(&mut detector, (300, 400, 0), 10.0);
+
+        // After one time constant, the baseline has absorbed all but 1/e of the step
+        let deviation = hold(&mut detector, (600, 800, 0), TIME_CONSTANT_S);
+        let left = 500.0 / E;
+        let expected = left / (1000.0 - left);
+        assert!((deviation - expected).abs() < 0.01, "{}", deviation);
+
+        // And after many, the baseline is the new strength
+        let deviation = hold(&mut detector, (600, 800, 0), 10.0 * TIME_CONSTANT_S);
+        assert!(deviation < 0.001, "{}", deviation);
+    }
+}
@@ -0,0 +1,185 @@
+//! The heading on Embassy
+//!
+//! This reads the magnetometer and points an LED north like the main firmware, but with Embassy's
+//! executor, timers and drivers for the peripherals instead of our own. The sensor, the LEDs and
+//! the logger are each an Embassy task. The sensor task publishes the heading in a `LatestValue`,
+//! and the other two pick it up on their own schedules.
+//!
+//! What carries over from the main firmware is everything that only needs `embedded-hal-async`
+//! or no peripherals at all: the LSM303DLHC driver, the filters, the heading math and the logger.
+//!
+//! ```text
+//! cargo run --bin embassy --features embassy
+//! ```
+
+#![no_main]
+#![no_std]
+// The logger is shared with the main firmware, which uses more of it
+#![allow(dead_code, unused_macros)]
+
+use compass::filters::low_pass::LowPass;
+use compass::filters::median::Median;
+use compass::heading::{angle_to_bearing, angle_to_direction, mag_to_angle};
+use compass::latest::LatestValue;
+use compass::lsm303dlhc::{DataRate, Gain, Lsm303dlhc, Lsm303dlhcConfig, Mode};
+use compass::magnetometer::Magnetometer;
+use compass::smoothing::HeadingSmoother;
+use core::panic::PanicInfo;
+use cortex_m::peripheral::ITM;
+use embassy_executor::Spawner;
+use embassy_stm32::gpio::{AnyPin, Level, Output, Speed};
+use embassy_stm32::i2c::{self, I2c, Master};
+use embassy_stm32::mode::Async;
+use embassy_stm32::rcc::{self, APBPrescaler, Hse, HseMode, Pll, PllMul, PllPreDiv, PllSource};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::{bind_interrupts, dma, peripherals, Peri};
+use embassy_time::{Duration, Ticker, Timer};
+use logger::Logger;
+
+#[path = "../logger.rs"]
+#[macro_use]
+mod logger;
+
+/// What `logger` stamps the messages with
+mod clock {
+    pub fn now() -> embassy_time::Instant {
+        embassy_time::Instant::now()
+    }
+}
+
+// The same filters as the simulator, which also reads the magnetometer at its data rate
+const MAG_DATA_RATE: DataRate = DataRate::Hz15;
+const MEDIAN_WINDOW: usize = 5;
+const MAG_CUTOFF_HZ: f32 = 1.0;
+const SMOOTHING_WINDOW: usize = 5;
+
+// How often the LEDs look for a new heading, and how often it's logged
+const DISPLAY_PERIOD_MS: u64 = 50;
+const LOG_PERIOD_MS: u64 = 1_000;
+
+/// The smoothed heading as an angle from `mag_to_angle`, or `None` until there is one
+static HEADING: LatestValue<Option<f32>> = LatestValue::new(None);
+
+bind_interrupts!(struct Irqs {
+    I2C1_EV => i2c::EventInterruptHandler<peripherals::I2C1>;
+    I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
+    DMA1_CHANNEL6 => dma::InterruptHandler<peripherals::DMA1_CH6>;
+    DMA1_CHANNEL7 => dma::InterruptHandler<peripherals::DMA1_CH7>;
+});
+
+/// The same clock tree as `clocks`: HSE from the ST-LINK, multiplied by 9
+fn config() -> embassy_stm32::Config {
+    let mut config = embassy_stm32::Config::default();
+    config.rcc.hse = Some(Hse {
+        freq: Hertz(8_000_000),
+        mode: HseMode::Bypass,
+    });
+    config.rcc.pll = Some(Pll {
+        src: PllSource::HSE,
+        prediv: PllPreDiv::DIV1,
+        mul: PllMul::MUL9,
+    });
+    config.rcc.sys = rcc::Sysclk::PLL1_P;
+    config.rcc.apb1_pre = APBPrescaler::DIV2;
+    config
+}
+
+/// Read the magnetometer at its data rate, and publish the smoothed heading
+#[embassy_executor::task]
+async fn sensor(i2c: I2c<'static, Async, Master>) {
+    let mut mag = Lsm303dlhc::new(i2c);
+    let config = Lsm303dlhcConfig::new()
+        .data_rate(MAG_DATA_RATE)
+        .gain(Gain::Gauss1_3)
+        .mode(Mode::Continuous);
+    mag.configure(config)
+        .await
+        .expect("Couldn't configure the magnetometer");
+
+    let mut median = Median::<MEDIAN_WINDOW>::new();
+    let mut low_pass = LowPass::new(MAG_CUTOFF_HZ, MAG_DATA_RATE.period_s());
+    let mut smoother = HeadingSmoother::<SMOOTHING_WINDOW>::new();
+    let period_ms = (MAG_DATA_RATE.period_s() * 1000.0) as u64;
+    let mut ticker = Ticker::every(Duration::from_millis(period_ms));
+    loop {
+        ticker.next().await;
+        // A failed read shows up in the log as a heading that stopped changing
+        if let Ok(sample) = mag.read().await {
+            let (x, y, z) = low_pass.update(median.update(sample.raw));
+            let mag = (f32::from(x), f32::from(y), f32::from(z));
+            smoother.add(mag_to_angle(mag, 0.0));
+            HEADING.set(smoother.heading());
+        }
+    }
+}
+
+/// Light the LED that points north. `leds` are in the same order as `Direction`.
+#[embassy_executor::task]
+async fn display(mut leds: [Output<'static>; 8]) {
+    let mut heading = HEADING.subscribe();
+    loop {
+        Timer::after_millis(DISPLAY_PERIOD_MS).await;
+        if let Some(Some(angle)) = heading.changed() {
+            let north = angle_to_direction((angle + 360.0) % 360.0) as usize;
+            for (i, led) in leds.iter_mut().enumerate() {
+                led.set_level(if i == north { Level::High } else { Level::Low });
+            }
+        }
+    }
+}
+
+/// Log the heading once a second
+#[embassy_executor::task]
+async fn log(mut logger: Logger) {
+    loop {
+        Timer::after_millis(LOG_PERIOD_MS).await;
+        match HEADING.get() {
+            Some(angle) => info!(logger, "Heading: {:?}", angle_to_bearing(angle)),
+            None => warn!(logger, "No heading yet"),
+        }
+        logger.drain();
+    }
+}
+
+/// Stop with the message on ITM. Unlike `fault`, this leaves the LEDs alone, since they're Embassy's.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    let itm = unsafe { &mut *(ITM::PTR as *mut <ITM as core::ops::Deref>::Target) };
+    cortex_m::itm::write_fmt(&mut itm.stim[0], format_args!("{}\n", info));
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = embassy_stm32::init(config());
+    let itm = cortex_m::Peripherals::take().unwrap().ITM;
+
+    // SCL and SDA are PB6 and PB7, and the DMA channels are fixed for I2C1
+    let i2c = I2c::new(
+        p.I2C1,
+        p.PB6,
+        p.PB7,
+        p.DMA1_CH6,
+        p.DMA1_CH7,
+        Irqs,
+        i2c::Config::default(),
+    );
+    let led = |pin: Peri<'static, AnyPin>| Output::new(pin, Level::Low, Speed::Low);
+    let leds = [
+        led(p.PE9.into()),
+        led(p.PE10.into()),
+        led(p.PE11.into()),
+        led(p.PE12.into()),
+        led(p.PE13.into()),
+        led(p.PE14.into()),
+        led(p.PE15.into()),
+        led(p.PE8.into()),
+    ];
+
+    spawner.spawn(sensor(i2c).unwrap());
+    spawner.spawn(display(leds).unwrap());
+    spawner.spawn(log(Logger::new(itm)).unwrap());
+}
@@ -0,0 +1,182 @@
+//! The heading on RTIC
+//!
+//! This is the part of the firmware that reads the magnetometer and points an LED north, scheduled
+//! by RTIC instead of the firmware's own executor. The interrupts that the drivers wait on are
+//! hardware tasks, which run the same handlers as the main firmware binds in `interrupts`, and the
+//! async pipeline runs in `idle`, which sleeps whenever none of them has woken it. Everything else,
+//! from the board setup and the I2C driver to the clock and the filters, is shared with the main
+//! firmware.
+//!
+//! ```text
+//! cargo run --bin rtic --features rtic
+//! ```
+
+#![no_main]
+#![no_std]
+// The modules are shared with the main firmware, which uses more of them
+#![allow(dead_code)]
+// RTIC 0.5's generated code is older than these lints
+#![allow(non_local_definitions, static_mut_refs, unexpected_cfgs)]
+
+// RTIC 0.5 can't leave hardware tasks out with `cfg`, so this only binds the F3 Discovery's I2C1
+#[cfg(feature = "nucleo-f303ze")]
+compile_error!("The RTIC build only runs on the F3 Discovery");
+
+use board::{Board, Leds};
+use compass::filters::low_pass::LowPass;
+use compass::filters::median::Median;
+use compass::heading::{angle_to_bearing, angle_to_direction, mag_to_angle, Direction};
+use compass::i2c_timing::BusSpeed;
+use compass::lsm303dlhc::{DataRate, Gain, Lsm303dlhc, Lsm303dlhcConfig, Mode};
+use compass::magnetometer::Magnetometer;
+use compass::smoothing::HeadingSmoother;
+use delay::Delay;
+use i2c::{I2c, I2cBus, I2cError};
+use logger::Logger;
+
+#[path = "../black_box.rs"]
+mod black_box;
+#[path = "../board/mod.rs"]
+mod board;
+#[path = "../bus_recovery.rs"]
+mod bus_recovery;
+#[path = "../clock.rs"]
+mod clock;
+#[path = "../clocks.rs"]
+mod clocks;
+#[path = "../delay.rs"]
+mod delay;
+#[path = "../executor.rs"]
+mod executor;
+#[path = "../fault.rs"]
+mod fault;
+#[path = "../i2c.rs"]
+mod i2c;
+#[path = "../logger.rs"]
+#[macro_use]
+mod logger;
+#[path = "../power.rs"]
+mod power;
+#[path = "../wakers.rs"]
+mod wakers;
+#[path = "../watchdog.rs"]
+mod watchdog;
+
+/// What RTIC expects of a device crate. stm32f30x is older than the `interrupt` enum that RTIC
+/// checks the interrupts of hardware tasks against, but its `Interrupt` is the same thing.
+mod device {
+    pub use f3::hal::stm32f30x::Interrupt as interrupt;
+    pub use f3::hal::stm32f30x::{Peripherals, NVIC_PRIO_BITS};
+}
+
+const I2C_SPEED: BusSpeed = BusSpeed::Fast;
+
+// The same filters as the simulator, which also reads the magnetometer at its data rate
+const MAG_DATA_RATE: DataRate = DataRate::Hz15;
+const MEDIAN_WINDOW: usize = 5;
+const MAG_CUTOFF_HZ: f32 = 1.0;
+const SMOOTHING_WINDOW: usize = 5;
+
+/// Light only the LED in `direction`
+fn point(_leds: &mut Leds, direction: Direction) {
+    // The lower half of BSRR sets pins and the upper half resets them
+    let mut bsrr = 0;
+    for (i, pin) in board::LEDS.iter().enumerate() {
+        let Some(pin) = pin else {
+            continue;
+        };
+        if i == direction as usize {
+            bsrr |= 1 << pin;
+        } else {
+            bsrr |= 1 << (pin + 16);
+        }
+    }
+    board::led_port().bsrr.write(|w| unsafe { w.bits(bsrr) });
+}
+
+/// Read the magnetometer at its data rate, and point the LEDs at the smoothed heading
+async fn show_heading_forever(
+    mut mag: impl Magnetometer<Error = I2cError>,
+    logger: &mut Logger,
+    leds: &mut Leds,
+) -> ! {
+    let period_ms = (MAG_DATA_RATE.period_s() * 1000.0) as u32;
+    let mut median = Median::<MEDIAN_WINDOW>::new();
+    let mut low_pass = LowPass::new(MAG_CUTOFF_HZ, MAG_DATA_RATE.period_s());
+    let mut smoother = HeadingSmoother::<SMOOTHING_WINDOW>::new();
+    loop {
+        clock::sleep(period_ms).await;
+        match mag.read().await {
+            Ok(sample) => {
+                let (x, y, z) = low_pass.update(median.update(sample.raw));
+                let mag = (f32::from(x), f32::from(y), f32::from(z));
+                smoother.add(mag_to_angle(mag, 0.0));
+            }
+            Err(error) => warn!(logger, "Couldn't read the magnetometer: {:?}", error),
+        }
+        if let Some(heading) = smoother.heading() {
+            point(leds, angle_to_direction((heading + 360.0) % 360.0));
+            debug!(logger, "Heading: {:?}", angle_to_bearing(heading));
+        }
+        logger.drain();
+    }
+}
+
+#[rtic::app(device = crate::device, peripherals = true)]
+const APP: () = {
+    struct Resources {
+        logger: Logger,
+        leds: Leds,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> init::LateResources {
+        let Board { leds, itm, .. } = board::set_up(cx.core.SYST, cx.core.ITM);
+        power::init();
+        clock::init();
+
+        init::LateResources {
+            logger: Logger::new(itm),
+            leds,
+        }
+    }
+
+    #[idle(resources = [logger, leds])]
+    fn idle(cx: idle::Context) -> ! {
+        let logger = cx.resources.logger;
+        let i2c1 = I2cBus::new(I2c::new(board::i2c(), Delay::micros(), I2C_SPEED));
+        let mut mag = Lsm303dlhc::new(i2c1.device());
+        let config = Lsm303dlhcConfig::new()
+            .data_rate(MAG_DATA_RATE)
+            .gain(Gain::Gauss1_3)
+            .mode(Mode::Continuous);
+        executor::block_on(mag.configure(config)).expect("Couldn't configure the magnetometer");
+        match executor::block_on(mag.self_test()) {
+            Ok(()) => info!(logger, "Magnetometer self-test passed"),
+            Err(error) => error!(logger, "Magnetometer self-test failed: {:?}", error),
+        }
+        fault::running();
+        executor::block_on(show_heading_forever(mag, logger, cx.resources.leds))
+    }
+
+    #[task(binds = I2C1_EV_EXTI23)]
+    fn i2c_ev(_cx: i2c_ev::Context) {
+        wakers::i2c_ev();
+    }
+
+    #[task(binds = I2C1_ER)]
+    fn i2c_er(_cx: i2c_er::Context) {
+        wakers::i2c_er();
+    }
+
+    #[task(binds = TIM2)]
+    fn tim2(_cx: tim2::Context) {
+        clock::tim2();
+    }
+
+    // The microsecond delays of the I2C driver
+    #[task(binds = TIM7)]
+    fn tim7(_cx: tim7::Context) {
+        wakers::tim7();
+    }
+};
@@ -0,0 +1,34 @@
+//! The flight recorder, where the panic handler can find it
+//!
+//! The main loop records every magnetometer sample here, see `compass::flight_recorder`. The panic
+//! handler dumps the records along with the panic message, and the shell's `flight` command dumps
+//! them to the log without stopping anything.
+
+use compass::flight_recorder::{FlightRecorder, Record};
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+
+// At the magnetometer's output rate of 15 Hz, this is about four seconds
+const RECORDS: usize = 64;
+
+static RECORDER: Mutex<RefCell<FlightRecorder<RECORDS>>> =
+    Mutex::new(RefCell::new(FlightRecorder::new()));
+
+pub fn record(record: Record) {
+    interrupt::free(|cs| RECORDER.borrow(cs).borrow_mut().push(record));
+}
+
+/// Call `f` with every record, oldest first, and return how many there were. The records stay
+/// where they are.
+///
+/// This is also called by the panic handler, so if the panic happened halfway through `record`,
+/// there's nothing to dump.
+pub fn dump(mut f: impl FnMut(&Record)) -> usize {
+    interrupt::free(|cs| match RECORDER.borrow(cs).try_borrow() {
+        Ok(recorder) => {
+            recorder.iter().for_each(&mut f);
+            recorder.len()
+        }
+        Err(_) => 0,
+    })
+}
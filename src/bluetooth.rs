@@ -0,0 +1,131 @@
+//! AT commands for Bluetooth serial modules
+//!
+//! An HC-05 (Bluetooth Classic, SPP) or HM-10 (Bluetooth LE) in place of the USB serial cable on
+//! USART1 makes the heading and the shell available to a terminal app on a phone. Once a phone is
+//! connected, both modules pass bytes through untouched, at 9600 baud out of the box. Before that,
+//! they take AT commands, which we use to give them a name and a PIN:
+//!
+//! - The HC-05 only takes them in its command mode, which it enters if its KEY pin is high when it
+//!   powers up. It then runs at 38400 baud, terminates commands and replies with CRLF, and stays in
+//!   command mode until it's powered up again with KEY low.
+//! - The HM-10 takes them whenever nothing is connected, at its usual baud rate. Commands and
+//!   replies aren't terminated at all, so a reply is over when the module goes quiet.
+//!
+//! Replies to successful commands start with `OK` on both. Neither uses its new name and PIN until
+//! it restarts, so setting them up ends with a reset.
+
+use core::fmt::{self, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Module {
+    Hc05,
+    Hm10,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AtCommand<'a> {
+    /// Does nothing, but tells us whether the module takes commands
+    Ping,
+    /// The name that phones see when they scan
+    Name(&'a str),
+    /// The PIN to pair with, 4 digits on the HC-05 and 6 on the HM-10
+    Pin(u32),
+    /// Make the HM-10 ask for its PIN when pairing, which it doesn't by default. The HC-05 always
+    /// asks, so this is a ping for it.
+    RequirePin,
+    /// Restart the module, which replies before it does
+    Reset,
+}
+
+impl Module {
+    /// The baud rate while a phone is connected, as the modules come
+    pub const fn data_baud(self) -> u32 {
+        9_600
+    }
+
+    /// The baud rate that the module takes AT commands at
+    pub const fn command_baud(self) -> u32 {
+        match self {
+            Module::Hc05 => 38_400,
+            Module::Hm10 => 9_600,
+        }
+    }
+
+    /// The commands that give the module `name` and `pin`, starting with a ping
+    pub fn setup(self, name: &str, pin: u32) -> [AtCommand<'_>; 5] {
+        [
+            AtCommand::Ping,
+            AtCommand::Name(name),
+            AtCommand::Pin(pin),
+            AtCommand::RequirePin,
+            AtCommand::Reset,
+        ]
+    }
+
+    /// Write `command` the way the module expects it, including the line ending if it takes one
+    pub fn write_command(self, command: AtCommand, out: &mut impl Write) -> fmt::Result {
+        match (self, command) {
+            (_, AtCommand::Ping) | (Module::Hc05, AtCommand::RequirePin) => out.write_str("AT")?,
+            (Module::Hc05, AtCommand::Name(name)) => write!(out, "AT+NAME={}", name)?,
+            (Module::Hc05, AtCommand::Pin(pin)) => write!(out, "AT+PSWD={:04}", pin)?,
+            (Module::Hm10, AtCommand::Name(name)) => write!(out, "AT+NAME{}", name)?,
+            (Module::Hm10, AtCommand::Pin(pin)) => write!(out, "AT+PASS{:06}", pin)?,
+            (Module::Hm10, AtCommand::RequirePin) => out.write_str("AT+TYPE2")?,
+            (_, AtCommand::Reset) => out.write_str("AT+RESET")?,
+        }
+        match self {
+            Module::Hc05 => out.write_str("\r\n"),
+            Module::Hm10 => Ok(()),
+        }
+    }
+
+    /// Whether `reply` means that the command worked. Surrounding whitespace doesn't matter.
+    pub fn is_ok(self, reply: &str) -> bool {
+        reply.trim().starts_with("OK")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::String;
+
+    fn commands(module: Module) -> [String<32>; 5] {
+        module.setup("Compass", 1234).map(|command| {
+            let mut text = String::new();
+            module.write_command(command, &mut text).unwrap();
+            text
+        })
+    }
+
+    #[test]
+    fn writes_each_modules_dialect() {
+        assert_eq!(
+            commands(Module::Hc05),
+            [
+                "AT\r\n",
+                "AT+NAME=Compass\r\n",
+                "AT+PSWD=1234\r\n",
+                "AT\r\n",
+                "AT+RESET\r\n"
+            ]
+        );
+        assert_eq!(
+            commands(Module::Hm10),
+            [
+                "AT",
+                "AT+NAMECompass",
+                "AT+PASS001234",
+                "AT+TYPE2",
+                "AT+RESET"
+            ]
+        );
+
+        assert!(Module::Hc05.is_ok("OK\r\n"));
+        assert!(Module::Hm10.is_ok("OK+Set:Compass"));
+        assert!(!Module::Hc05.is_ok("ERROR:(0)\r\n"));
+        assert!(!Module::Hm10.is_ok(""));
+    }
+}
@@ -0,0 +1,94 @@
+//! Setting up a Bluetooth serial module on USART1
+//!
+//! With the `hc05` or `hm10` feature, the module's RX and TX go to USART1's TX (PA9) and RX (PA10)
+//! instead of a USB serial cable, and USART1 runs at the module's data rate. At startup, `setup`
+//! names the module and sets its PIN with the AT commands from `compass::bluetooth`, so that it
+//! shows up as "Compass" on a phone. If the module doesn't answer, e.g. because an HC-05 isn't in
+//! command mode, it's left as it is, and once a phone connects the heading and the shell work the
+//! same as over a cable. At 9600 baud there's only room for about 25 telemetry frames a second,
+//! see `telemetry`, so `rate` has to stay well below that.
+
+use crate::clock::{with_timeout, Timeout};
+use crate::uart::{Line, UartError, Usart1};
+use compass::bluetooth::Module;
+use core::str;
+
+#[cfg(all(feature = "hc05", feature = "hm10"))]
+compile_error!("The hc05 and hm10 features can't be enabled together");
+
+#[cfg(feature = "hc05")]
+pub const MODULE: Module = Module::Hc05;
+#[cfg(all(feature = "hm10", not(feature = "hc05")))]
+pub const MODULE: Module = Module::Hm10;
+
+// What phones see when they scan, and the PIN to pair with
+const NAME: &str = "Compass";
+const PIN: u32 = 1234;
+
+// How long the module has to start replying, and how long it can pause within a reply. The HM-10
+// doesn't end its replies, so a pause is the only way to tell that one is over.
+const REPLY_TIMEOUT_MS: u32 = 500;
+const REPLY_GAP_MS: u32 = 50;
+
+// Long enough for any reply
+const MAX_REPLY: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SetupError {
+    /// The module didn't answer, which is expected if it isn't taking commands
+    NoReply,
+    /// The module answered with something other than OK
+    Rejected,
+    Read(UartError),
+}
+
+/// Read a reply, which is over at the end of a line or when the module pauses. Whatever doesn't
+/// fit into `reply` is dropped.
+async fn read_reply(uart: &Usart1, reply: &mut [u8; MAX_REPLY]) -> Result<usize, SetupError> {
+    let mut len = 0;
+    let mut timeout_ms = REPLY_TIMEOUT_MS;
+    loop {
+        match with_timeout(uart.read_byte(), timeout_ms).await {
+            Err(Timeout) if len == 0 => return Err(SetupError::NoReply),
+            Err(Timeout) => return Ok(len),
+            Ok(Err(error)) => return Err(SetupError::Read(error)),
+            Ok(Ok(byte)) => {
+                if let Some(slot) = reply.get_mut(len) {
+                    *slot = byte;
+                    len += 1;
+                }
+                if byte == b'\n' {
+                    return Ok(len);
+                }
+                timeout_ms = REPLY_GAP_MS;
+            }
+        }
+    }
+}
+
+async fn send_setup(uart: &Usart1) -> Result<(), SetupError> {
+    for &command in MODULE.setup(NAME, PIN).iter() {
+        let mut line = Line::new();
+        // Every command fits into a line
+        MODULE.write_command(command, &mut line).unwrap();
+        uart.write_all(line.as_bytes()).await;
+
+        let mut reply = [0; MAX_REPLY];
+        let len = read_reply(uart, &mut reply).await?;
+        match str::from_utf8(&reply[..len]) {
+            Ok(reply) if MODULE.is_ok(reply) => {}
+            _ => return Err(SetupError::Rejected),
+        }
+    }
+    Ok(())
+}
+
+/// Give the module our name and PIN, then go back to its data rate. Stops at the first command that
+/// fails.
+pub async fn setup(uart: &Usart1) -> Result<(), SetupError> {
+    uart.set_baud(MODULE.command_baud());
+    let result = send_setup(uart).await;
+    uart.set_baud(MODULE.data_baud());
+    result
+}
@@ -0,0 +1,67 @@
+//! The STM32F3DISCOVERY
+//!
+//! Everything is on the board, with the LSM303DLHC on I2C1 (PB6 and PB7) and a ring of eight LEDs on
+//! PE8 to PE15.
+
+use super::as_port;
+use f3::hal::stm32f30x::{gpioc, i2c1, rcc, Interrupt, GPIOB, GPIOE, I2C1};
+
+/// The GPIO pin of the LED in each direction, in the same order as `Leds`
+pub const LEDS: [Option<u32>; 8] = [
+    Some(9),
+    Some(10),
+    Some(11),
+    Some(12),
+    Some(13),
+    Some(14),
+    Some(15),
+    Some(8),
+];
+
+/// The LEDs that blink after a fault: East and West
+pub const FAULT_LEDS: &[u32] = &[11, 15];
+
+pub fn led_port() -> &'static gpioc::RegisterBlock {
+    unsafe { &*GPIOE::ptr() }
+}
+
+pub fn enable_led_port(rcc: &rcc::RegisterBlock) {
+    rcc.ahbenr.modify(|_, w| w.iopeen().set_bit());
+}
+
+// SCL and SDA of I2C1 are PB6 and PB7
+pub const SCL: u32 = 6;
+pub const SDA: u32 = 7;
+
+// I2C1 is alternate function 4 of PB6 and PB7
+pub const I2C_AF: u32 = 4;
+
+/// The event and error interrupts of the I2C peripheral
+pub const I2C_EV_INTERRUPT: Interrupt = Interrupt::I2C1_EV_EXTI23;
+pub const I2C_ER_INTERRUPT: Interrupt = Interrupt::I2C1_ER;
+
+pub fn i2c_port() -> &'static gpioc::RegisterBlock {
+    as_port(GPIOB::ptr())
+}
+
+pub fn i2c() -> &'static i2c1::RegisterBlock {
+    unsafe { &*I2C1::ptr() }
+}
+
+/// Power on the pins' port, and the peripheral running from HSI, see `clocks`
+pub fn enable_i2c(rcc: &rcc::RegisterBlock) {
+    rcc.ahbenr.modify(|_, w| w.iopben().set_bit());
+    rcc.cfgr3.modify(|_, w| w.i2c1sw().clear_bit());
+    rcc.apb1enr.modify(|_, w| w.i2c1en().set_bit());
+}
+
+/// Put the peripheral's registers back to their reset values
+pub fn reset_i2c(rcc: &rcc::RegisterBlock) {
+    rcc.apb1rstr.modify(|_, w| w.i2c1rst().set_bit());
+    rcc.apb1rstr.modify(|_, w| w.i2c1rst().clear_bit());
+}
+
+/// Whether the peripheral runs from SYSCLK rather than HSI
+pub fn i2c_on_sysclk(rcc: &rcc::RegisterBlock) -> bool {
+    rcc.cfgr3.read().i2c1sw().bit_is_set()
+}
@@ -0,0 +1,125 @@
+//! Bringing up the board
+//!
+//! This takes the peripherals and sets up everything that the drivers expect to find when they
+//! start: the clocks, the LEDs, and the I2C peripheral on the pins that the LSM303DLHC is connected
+//! to. It doesn't talk to the LSM303DLHC itself. The drivers write every one of its configuration
+//! registers from their configs, `Lsm303dlhcConfig` for the magnetometer and the constants in
+//! `accel` for the accelerometer, so nothing depends on what a previous program left in them.
+//!
+//! The I2C peripheral is left disabled, because its timing can only be written while it is, and
+//! `I2c::new` sets that up.
+//!
+//! Where the LEDs are and which I2C peripheral the sensors are on depend on the board, so they're
+//! in a module for each board, selected with a Cargo feature:
+//!
+//! - `f3_discovery`, the default
+//! - `nucleo_f303ze`, with the `nucleo-f303ze` feature
+//!
+//! Both have the same constants and functions, which the rest of the firmware uses instead of
+//! naming those pins and peripherals itself.
+
+use crate::clocks;
+use cortex_m::peripheral::{ITM, SYST};
+use f3::hal::stm32f30x::{self, gpioc, i2c1, rcc, RCC};
+
+#[cfg(not(feature = "nucleo-f303ze"))]
+mod f3_discovery;
+#[cfg(not(feature = "nucleo-f303ze"))]
+pub use f3_discovery::*;
+
+#[cfg(feature = "nucleo-f303ze")]
+mod nucleo_f303ze;
+#[cfg(feature = "nucleo-f303ze")]
+pub use nucleo_f303ze::*;
+
+/// The LEDs, as push-pull outputs. Whoever owns this is the only one who drives them.
+pub struct Leds {
+    _private: (),
+}
+
+pub struct Board {
+    pub leds: Leds,
+    pub i2c: &'static i2c1::RegisterBlock,
+    pub syst: SYST,
+    pub itm: ITM,
+}
+
+/// Take the peripherals and set up the board. This can only be called once.
+pub fn init() -> Board {
+    let cp = cortex_m::Peripherals::take().unwrap();
+    // The drivers get at the device peripherals through their pointers, but taking them still
+    // makes sure that this only runs once
+    let _dp = stm32f30x::Peripherals::take().unwrap();
+
+    set_up(cp.SYST, cp.ITM)
+}
+
+/// Set up the board once something else has taken the peripherals, like RTIC's `init`
+pub fn set_up(syst: SYST, itm: ITM) -> Board {
+    clocks::init();
+
+    Board {
+        leds: init_leds(),
+        i2c: init_i2c(),
+        syst,
+        itm,
+    }
+}
+
+/// GPIOA and GPIOB have their own register block types, because their reset values are different,
+/// but they have the same registers as the other ports
+fn as_port<T>(port: *const T) -> &'static gpioc::RegisterBlock {
+    unsafe { &*(port as *const gpioc::RegisterBlock) }
+}
+
+/// Connect `pin` to alternate function `af`
+fn set_alternate_function(port: &gpioc::RegisterBlock, pin: u32, af: u32) {
+    if pin < 8 {
+        port.afrl.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b1111 << (4 * pin))) | (af << (4 * pin)))
+        });
+    } else {
+        port.afrh.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b1111 << (4 * (pin - 8)))) | (af << (4 * (pin - 8))))
+        });
+    }
+    port.moder
+        .modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << (2 * pin))) | (0b10 << (2 * pin))) });
+}
+
+/// Switch every LED off and make it an output
+fn init_leds() -> Leds {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let port = led_port();
+
+    enable_led_port(rcc);
+    for pin in LEDS.iter().flatten() {
+        port.bsrr.write(|w| unsafe { w.bits(1 << (pin + 16)) });
+        port.moder.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << (2 * pin))) | (0b01 << (2 * pin)))
+        });
+    }
+
+    Leds { _private: () }
+}
+
+/// Connect the I2C peripheral to its pins, and power it on with its registers at their reset
+/// values
+fn init_i2c() -> &'static i2c1::RegisterBlock {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let port = i2c_port();
+
+    enable_i2c(rcc);
+
+    // Open drain, since the bus is pulled up by the board, and high speed for fast mode
+    port.otyper
+        .modify(|r, w| unsafe { w.bits(r.bits() | (1 << SCL) | (1 << SDA)) });
+    port.ospeedr
+        .modify(|r, w| unsafe { w.bits(r.bits() | (0b11 << (2 * SCL)) | (0b11 << (2 * SDA))) });
+    set_alternate_function(port, SCL, I2C_AF);
+    set_alternate_function(port, SDA, I2C_AF);
+
+    reset_i2c(rcc);
+
+    i2c()
+}
@@ -0,0 +1,66 @@
+//! The NUCLEO-F303ZE
+//!
+//! The sensors are on breakouts, wired to the same pins as on the F3 Discovery, except for the I2C
+//! bus. The board's blue LED LD2 is on PB7, which is I2C1's SDA, so the LSM303DLHC is on I2C2
+//! instead, with SCL on PA9 and SDA on PA10.
+//!
+//! There's no ring of LEDs, so the green LED LD1 (PB0) stands in for the North LED and LD2 for the
+//! South one: the green one lights up when the board points north, and the blue one when it points
+//! south. The red LED LD3 is on PB14, which is SPI2's MISO for the SD card, so it only comes on
+//! after a fault.
+//!
+//! ST-LINK feeds the MCU the same 8 MHz clock as on the F3 Discovery, so `clocks` doesn't change.
+
+use super::as_port;
+use f3::hal::stm32f30x::{gpioc, i2c1, rcc, Interrupt, GPIOA, GPIOB, I2C2};
+
+/// The GPIO pin of the LED in each direction, in the same order as `Leds`
+pub const LEDS: [Option<u32>; 8] = [Some(0), None, None, None, Some(7), None, None, None];
+
+/// The LEDs that blink after a fault: LD3
+pub const FAULT_LEDS: &[u32] = &[14];
+
+pub fn led_port() -> &'static gpioc::RegisterBlock {
+    as_port(GPIOB::ptr())
+}
+
+pub fn enable_led_port(rcc: &rcc::RegisterBlock) {
+    rcc.ahbenr.modify(|_, w| w.iopben().set_bit());
+}
+
+// SCL and SDA of I2C2 are PA9 and PA10
+pub const SCL: u32 = 9;
+pub const SDA: u32 = 10;
+
+// I2C2 is alternate function 4 of PA9 and PA10
+pub const I2C_AF: u32 = 4;
+
+/// The event and error interrupts of the I2C peripheral
+pub const I2C_EV_INTERRUPT: Interrupt = Interrupt::I2C2_EV_EXTI24;
+pub const I2C_ER_INTERRUPT: Interrupt = Interrupt::I2C2_ER;
+
+pub fn i2c_port() -> &'static gpioc::RegisterBlock {
+    as_port(GPIOA::ptr())
+}
+
+pub fn i2c() -> &'static i2c1::RegisterBlock {
+    unsafe { &*I2C2::ptr() }
+}
+
+/// Power on the pins' port, and the peripheral running from HSI, see `clocks`
+pub fn enable_i2c(rcc: &rcc::RegisterBlock) {
+    rcc.ahbenr.modify(|_, w| w.iopaen().set_bit());
+    rcc.cfgr3.modify(|_, w| w.i2c2sw().clear_bit());
+    rcc.apb1enr.modify(|_, w| w.i2c2en().set_bit());
+}
+
+/// Put the peripheral's registers back to their reset values
+pub fn reset_i2c(rcc: &rcc::RegisterBlock) {
+    rcc.apb1rstr.modify(|_, w| w.i2c2rst().set_bit());
+    rcc.apb1rstr.modify(|_, w| w.i2c2rst().clear_bit());
+}
+
+/// Whether the peripheral runs from SYSCLK rather than HSI
+pub fn i2c_on_sysclk(rcc: &rcc::RegisterBlock) -> bool {
+    rcc.cfgr3.read().i2c2sw().bit_is_set()
+}
@@ -0,0 +1,89 @@
+//! I2C bus recovery
+//!
+//! If the MCU resets in the middle of a read, the LSM303 may still be waiting to clock out the
+//! rest of a byte and will hold SDA low forever. The I2C peripheral can't do anything about that,
+//! so we temporarily take over the pins as GPIOs, clock SCL until the slave lets go of SDA, send a
+//! STOP condition by hand and then give the pins back to a freshly reset peripheral. The clock is
+//! timed with TIM7, so other tasks keep running while we wait between edges. The pins are whichever
+//! ones `board` puts the bus on.
+
+use crate::board::{self, SCL, SDA};
+use crate::delay::{Delay, Tim7};
+use f3::hal::stm32f30x::{i2c1, rcc, RCC};
+
+// A slave that is stuck mid-byte releases SDA after at most 9 clock pulses
+const MAX_PULSES: usize = 9;
+
+// Half of an SCL period at 100 KHz, in µs
+const HALF_PERIOD_US: u16 = 5;
+
+fn sda_is_high() -> bool {
+    board::i2c_port().idr.read().bits() & (1 << SDA) != 0
+}
+
+async fn set_pin(pin: u32, high: bool, delay: &Delay<Tim7>) {
+    if high {
+        board::i2c_port()
+            .bsrr
+            .write(|w| unsafe { w.bits(1 << pin) });
+    } else {
+        board::i2c_port()
+            .bsrr
+            .write(|w| unsafe { w.bits(1 << (pin + 16)) });
+    }
+    delay.delay_us(HALF_PERIOD_US).await;
+}
+
+/// Returns true if a slave is holding SDA low while the peripheral thinks that the bus is idle. The input
+/// data register reflects the pin level even while the pin is in alternate function mode.
+pub fn bus_is_stuck(i2c1: &'static i2c1::RegisterBlock) -> bool {
+    i2c1.isr.read().busy().bit_is_clear() && !sda_is_high()
+}
+
+/// Free the bus and re-initialize the peripheral with its previous timing configuration
+pub async fn recover(i2c1: &'static i2c1::RegisterBlock, delay: &Delay<Tim7>) {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let port = board::i2c_port();
+
+    let timingr = i2c1.timingr.read().bits();
+    let moder = port.moder.read().bits();
+    let otyper = port.otyper.read().bits();
+
+    i2c1.cr1.modify(|_, w| w.pe().clear_bit());
+
+    // Release both lines before switching them to open-drain outputs so that we don't glitch
+    set_pin(SCL, true, delay).await;
+    set_pin(SDA, true, delay).await;
+    port.otyper
+        .modify(|r, w| unsafe { w.bits(r.bits() | (1 << SCL) | (1 << SDA)) });
+    port.moder.modify(|r, w| unsafe {
+        let mask = (0b11 << (2 * SCL)) | (0b11 << (2 * SDA));
+        let output = (0b01 << (2 * SCL)) | (0b01 << (2 * SDA));
+        w.bits((r.bits() & !mask) | output)
+    });
+
+    // Clock SCL until the slave has finished shifting out its byte and releases SDA
+    for _ in 0..MAX_PULSES {
+        if sda_is_high() {
+            break;
+        }
+        set_pin(SCL, false, delay).await;
+        set_pin(SCL, true, delay).await;
+    }
+
+    // STOP: SDA goes from low to high while SCL is high
+    set_pin(SCL, false, delay).await;
+    set_pin(SDA, false, delay).await;
+    set_pin(SCL, true, delay).await;
+    set_pin(SDA, true, delay).await;
+
+    // Give the pins back to the peripheral
+    port.otyper.write(|w| unsafe { w.bits(otyper) });
+    port.moder.write(|w| unsafe { w.bits(moder) });
+
+    // Reset the peripheral to clear any state left over from the interrupted transaction
+    board::reset_i2c(rcc);
+
+    i2c1.timingr.write(|w| unsafe { w.bits(timingr) });
+    i2c1.cr1.write(|w| w.pe().set_bit());
+}
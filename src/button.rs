@@ -0,0 +1,122 @@
+//! The blue user button on PA0
+//!
+//! EXTI0 latches both edges of the button, so we don't have to poll it, and it wakes the MCU from
+//! STOP mode. The contacts bounce for a few ms after every press and release, so after an edge we
+//! wait for them to settle and only believe the level that's left. An edge that leaves the level
+//! where it was is a bounce.
+//!
+//! Whether a press was long is only known once it's over, so a release reports how long the button
+//! was held down instead of a long press announcing itself while the button is still down. That way
+//! the consumer can tell long and very long presses apart without one turning into the other.
+
+use crate::clock::{self, Instant};
+use crate::power::Awake;
+use crate::wakers;
+use f3::hal::stm32f30x::{exti, gpioa, rcc, syscfg, EXTI, GPIOA, RCC, SYSCFG};
+use futures::{stream, Stream};
+
+// The button is on PA0 and reads high while pressed
+const PIN: u32 = 0;
+
+// EXTICR1 value that routes EXTI0 to port A
+const PORT_A: u8 = 0b0000;
+
+// How long the contacts take to settle after an edge
+const DEBOUNCE_MS: u32 = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ButtonEvent {
+    /// The button went down
+    Pressed,
+    /// The button came back up before it counted as a long press
+    Released,
+    /// The button came back up after a long press, which lasted `held_ms`
+    LongPress { held_ms: u32 },
+}
+
+pub struct UserButton {
+    gpioa: &'static gpioa::RegisterBlock,
+    exti: &'static exti::RegisterBlock,
+}
+
+impl UserButton {
+    /// Configure PA0 as an input and EXTI0 to latch both of its edges. The interrupt itself is
+    /// only enabled while waiting.
+    pub fn new() -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let syscfg: &'static syscfg::RegisterBlock = unsafe { &*SYSCFG::ptr() };
+        let exti: &'static exti::RegisterBlock = unsafe { &*EXTI::ptr() };
+        let gpioa: &'static gpioa::RegisterBlock = unsafe { &*GPIOA::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopaen().set_bit());
+        rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        // Input mode; the board has an external pull-down
+        gpioa
+            .moder
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << (2 * PIN))) });
+
+        syscfg
+            .exticr1
+            .modify(|_, w| unsafe { w.exti0().bits(PORT_A) });
+        exti.rtsr1.modify(|_, w| w.tr0().set_bit());
+        exti.ftsr1.modify(|_, w| w.tr0().set_bit());
+        exti.pr1.write(|w| w.pr0().set_bit());
+
+        UserButton { gpioa, exti }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.gpioa.idr.read().bits() & (1 << PIN) != 0
+    }
+
+    /// Wait for an edge in either direction, including one that happened since the previous call
+    async fn wait_for_edge(&self) {
+        let exti = self.exti;
+        wakers::wait_for(
+            &wakers::EXTI0_EV,
+            || exti.pr1.read().pr0().bit_is_set(),
+            || exti.imr1.modify(|_, w| w.mr0().set_bit()),
+        )
+        .await;
+        exti.pr1.write(|w| w.pr0().set_bit());
+    }
+}
+
+/// Debounced presses and releases, as they happen. A release after the button was held for at
+/// least `long_press_ms` is a `LongPress`.
+pub fn events_forever(button: UserButton, long_press_ms: u32) -> impl Stream<Item = ButtonEvent> {
+    // The state is when the button went down and, since STOP mode stops the clock, something to
+    // keep it running until the button comes back up
+    let state: Option<(Instant, Awake)> = None;
+    stream::unfold((button, state), move |(button, mut pressed)| async move {
+        loop {
+            button.wait_for_edge().await;
+            let at = clock::now();
+            clock::sleep(DEBOUNCE_MS).await;
+            // An edge while we slept latches again, so we'll look at the level once more if it
+            // changed after this
+            let event = match (button.is_pressed(), pressed.take()) {
+                (true, None) => {
+                    pressed = Some((at, Awake::new()));
+                    ButtonEvent::Pressed
+                }
+                (false, Some((down_at, _awake))) => {
+                    let held_ms = (at.as_millis() - down_at.as_millis()) as u32;
+                    if held_ms >= long_press_ms {
+                        ButtonEvent::LongPress { held_ms }
+                    } else {
+                        ButtonEvent::Released
+                    }
+                }
+                // A bounce, which left the button where it was
+                (_, state) => {
+                    pressed = state;
+                    continue;
+                }
+            };
+            return Some((event, (button, pressed)));
+        }
+    })
+}
@@ -0,0 +1,137 @@
+//! Beeping a passive piezo buzzer to guide the user toward a target heading
+//!
+//! The buzzer is on PC6, which is TIM3 channel 1. TIM3 generates a 2 kHz square wave in hardware,
+//! and its update interrupt switches the wave on and off to make beeps. The closer the heading is to
+//! the target, the faster it beeps, and once it's on target the tone is continuous. The off-course
+//! alarm beeps faster than any of that.
+
+use crate::clocks;
+use crate::power::Awake;
+use core::sync::atomic::{AtomicU16, Ordering};
+use cortex_m::peripheral::NVIC;
+use f3::hal::stm32f30x::{gpioc, interrupt, rcc, tim2, Interrupt, GPIOC, RCC, TIM3};
+
+// The buzzer pin, in alternate function 2
+const PIN: u32 = 6;
+
+// A 2 kHz tone, which is loud on most piezo buzzers
+// APB1_TIMER_CLOCK = 72 MHz
+// ARR = 35999
+// 72 MHz / (35999 + 1) = 2 kHz
+const TONE_HZ: u32 = 2_000;
+const ARR: u32 = clocks::APB1_TIMER_HZ / TONE_HZ - 1;
+
+// A 50% duty cycle is the loudest
+const CCR_ON: u32 = clocks::APB1_TIMER_HZ / TONE_HZ / 2;
+
+// PWM mode 1 with preload on channel 1
+const OC1_PWM: u32 = 0b110 << 4 | 1 << 3;
+
+// How long each beep lasts
+const BEEP_MS: u16 = 60;
+
+// How often to beep when the heading is right on the target and when it's as far away as it gets
+const MIN_PERIOD_MS: f32 = 150.0;
+const MAX_PERIOD_MS: f32 = 1_500.0;
+
+// Closer to the target than this is on target
+const ON_TARGET_DEG: f32 = 2.0;
+
+// How often to beep while the alarm is going off
+const ALARM_PERIOD_MS: u16 = 2 * BEEP_MS;
+
+// Time from the start of one beep to the start of the next one, in ms. 0 is silent, and a period
+// that isn't longer than a beep is a continuous tone. Written by `Buzzer::play`, read by the
+// interrupt handler.
+static PERIOD_MS: AtomicU16 = AtomicU16::new(0);
+
+pub struct Buzzer {
+    tim3: &'static tim2::RegisterBlock,
+    // TIM3 stops in STOP mode, so the MCU stays awake while there's something to play
+    awake: Option<Awake>,
+}
+
+impl Buzzer {
+    /// Set up TIM3 and the pin, silent until the first call to `guide`
+    pub fn new() -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let gpioc: &'static gpioc::RegisterBlock = unsafe { &*GPIOC::ptr() };
+        let tim3: &'static tim2::RegisterBlock = unsafe { &*TIM3::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopcen().set_bit());
+        rcc.apb1enr.modify(|_, w| w.tim3en().set_bit());
+
+        gpioc.moder.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << (2 * PIN))) | (0b10 << (2 * PIN)))
+        });
+        gpioc.afrl.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b1111 << (4 * PIN))) | (2 << (4 * PIN)))
+        });
+
+        tim3.psc.write(|w| unsafe { w.bits(0) });
+        tim3.arr.write(|w| unsafe { w.bits(ARR) });
+        tim3.ccr1.write(|w| unsafe { w.bits(0) });
+        tim3.ccmr1_output.write(|w| unsafe { w.bits(OC1_PWM) });
+        tim3.ccer.write(|w| w.cc1e().set_bit());
+        // ARPE so that changes only take effect at the end of a period, which avoids clicks
+        tim3.cr1.write(|w| w.arpe().set_bit().cen().set_bit());
+
+        unsafe { NVIC::unmask(Interrupt::TIM3) };
+
+        Buzzer { tim3, awake: None }
+    }
+
+    /// Beep according to how far the heading is from the target, in degrees, or be quiet if
+    /// there's no target
+    pub fn guide(&mut self, error_deg: Option<f32>) {
+        let period_ms = match error_deg {
+            None => 0,
+            Some(error) if error.abs() < ON_TARGET_DEG => BEEP_MS,
+            Some(error) => {
+                let closeness = error.abs().min(180.0) / 180.0;
+                (MIN_PERIOD_MS + closeness * (MAX_PERIOD_MS - MIN_PERIOD_MS)) as u16
+            }
+        };
+        self.play(period_ms);
+    }
+
+    /// Beep as fast as possible, until the next call to `guide`
+    pub fn alarm(&mut self) {
+        self.play(ALARM_PERIOD_MS);
+    }
+
+    /// Beep every `period_ms`, see `PERIOD_MS`
+    fn play(&mut self, period_ms: u16) {
+        PERIOD_MS.store(period_ms, Ordering::Relaxed);
+
+        // Only interrupt while there's something to play
+        if period_ms == 0 {
+            self.tim3.dier.modify(|_, w| w.uie().clear_bit());
+            self.tim3.ccr1.write(|w| unsafe { w.bits(0) });
+            self.awake = None;
+        } else {
+            self.tim3.dier.modify(|_, w| w.uie().set_bit());
+            self.awake.get_or_insert_with(Awake::new);
+        }
+    }
+}
+
+/// `phase` counts the periods of the tone since the start of the current beep
+fn tim3(phase: &mut u32) {
+    let tim3 = unsafe { &*TIM3::ptr() };
+    tim3.sr.modify(|_, w| w.uif().clear_bit());
+
+    let period_ms = u32::from(PERIOD_MS.load(Ordering::Relaxed));
+    let period = period_ms * TONE_HZ / 1000;
+    let beep = u32::from(BEEP_MS) * TONE_HZ / 1000;
+
+    *phase += 1;
+    if *phase >= period {
+        *phase = 0;
+    }
+    let on = period != 0 && (period <= beep || *phase < beep);
+    let ccr1 = if on { CCR_ON } else { 0 };
+    tim3.ccr1.write(|w| unsafe { w.bits(ccr1) });
+}
+
+interrupt!(TIM3, tim3, state: u32 = 0);
@@ -0,0 +1,767 @@
+//! Hard-iron and soft-iron calibration
+//!
+//! As the board rotates through every orientation, an ideal magnetometer traces out a sphere
+//! centered on the origin. Magnets and magnetized metal near the sensor (hard iron) add a constant
+//! offset to every reading, which moves the center of the sphere. Unmagnetized metal such as an
+//! enclosure (soft iron) bends the field, which squashes the sphere into an ellipsoid.
+//!
+//! The simplest fix is to record the smallest and largest reading on each axis and use the center
+//! of that box as the offset. Better is to fit an ellipsoid to the readings by least squares, which
+//! gives both the offset and a matrix that turns the ellipsoid back into a sphere.
+//!
+//! Either way, the fit is only as good as the readings. `Coverage` tracks how much of the sphere
+//! they cover, so that the user knows when they've rotated the board enough.
+
+use crate::fixed::Q16;
+use crate::trig;
+
+// Readings are divided by this before fitting so that the fourth powers in the normal equations
+// stay in a range where we don't lose precision
+const FIT_SCALE: f64 = 1000.0;
+
+// The fit has 9 parameters; demand plenty more samples than that before trusting it
+const MIN_FIT_SAMPLES: usize = 100;
+
+// Two calibrations must be at least this far apart in temperature, in °C, before we estimate the
+// offset's temperature drift from them
+const MIN_DRIFT_SPAN_C: f32 = 5.0;
+
+// `Coverage` divides the sphere into bands of equal height, which also have equal areas, and each
+// band into sectors of equal longitude
+const COVERAGE_BANDS: u32 = 4;
+const COVERAGE_SECTORS: u32 = 8;
+
+// Number of sweeps of the Jacobi eigenvalue algorithm. It converges quadratically, and 3x3
+// matrices are done after a handful of sweeps.
+const JACOBI_SWEEPS: usize = 10;
+
+/// Per-axis offsets that get subtracted from every magnetometer reading. Convert to a
+/// `MagCalibration` in order to apply them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HardIron {
+    pub offset: (i16, i16, i16),
+}
+
+/// Collects the range of raw readings during calibration
+pub struct HardIronCollector {
+    min: (i16, i16, i16),
+    max: (i16, i16, i16),
+    samples: usize,
+}
+
+fn midpoint(min: i16, max: i16) -> i16 {
+    ((i32::from(min) + i32::from(max)) / 2) as i16
+}
+
+impl HardIronCollector {
+    pub fn new() -> Self {
+        HardIronCollector {
+            min: (i16::MAX, i16::MAX, i16::MAX),
+            max: (i16::MIN, i16::MIN, i16::MIN),
+            samples: 0,
+        }
+    }
+
+    /// Record a raw, uncalibrated reading
+    pub fn add(&mut self, mag: (i16, i16, i16)) {
+        self.min = (
+            self.min.0.min(mag.0),
+            self.min.1.min(mag.1),
+            self.min.2.min(mag.2),
+        );
+        self.max = (
+            self.max.0.max(mag.0),
+            self.max.1.max(mag.1),
+            self.max.2.max(mag.2),
+        );
+        self.samples += 1;
+    }
+
+    /// Compute the offsets, or `None` if we haven't seen any readings
+    pub fn finish(&self) -> Option<HardIron> {
+        if self.samples == 0 {
+            return None;
+        }
+
+        Some(HardIron {
+            offset: (
+                midpoint(self.min.0, self.max.0),
+                midpoint(self.min.1, self.max.1),
+                midpoint(self.min.2, self.max.2),
+            ),
+        })
+    }
+}
+
+impl Default for HardIronCollector {
+    fn default() -> Self {
+        HardIronCollector::new()
+    }
+}
+
+/// Tracks which parts of the sphere the readings cover
+pub struct Coverage {
+    // One bit for each part, band by band
+    visited: u32,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage { visited: 0 }
+    }
+
+    /// Record a raw reading. `center` is the best guess so far at the center of the sphere, which
+    /// doesn't have to be exact, since the parts are big. Until the readings span most of the
+    /// sphere, the guess can be far enough off to put readings into the wrong part, so it takes
+    /// turning the board around more than once to cover everything.
+    pub fn add(&mut self, mag: (i16, i16, i16), center: (i16, i16, i16)) {
+        let x = f32::from(mag.0) - f32::from(center.0);
+        let y = f32::from(mag.1) - f32::from(center.1);
+        let z = f32::from(mag.2) - f32::from(center.2);
+        let radius = trig::sqrt(x * x + y * y + z * z);
+        if radius < f32::EPSILON {
+            return;
+        }
+
+        let band = ((z / radius + 1.0) / 2.0 * COVERAGE_BANDS as f32) as u32;
+        let longitude = trig::atan2(y, x).to_degrees() + 180.0;
+        let sector = (longitude / 360.0 * COVERAGE_SECTORS as f32) as u32;
+        let part =
+            band.min(COVERAGE_BANDS - 1) * COVERAGE_SECTORS + sector.min(COVERAGE_SECTORS - 1);
+        self.visited |= 1 << part;
+    }
+
+    /// The fraction of the sphere that the readings cover, from 0 to 1
+    pub fn fraction(&self) -> f32 {
+        self.visited.count_ones() as f32 / (COVERAGE_BANDS * COVERAGE_SECTORS) as f32
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+/// A full calibration: readings are corrected by subtracting `offset` and then multiplying by
+/// `matrix`. A hard-iron-only calibration has the identity matrix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MagCalibration {
+    pub offset: (f32, f32, f32),
+    pub matrix: [[f32; 3]; 3],
+}
+
+impl Default for MagCalibration {
+    fn default() -> Self {
+        MagCalibration {
+            offset: (0.0, 0.0, 0.0),
+            matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+}
+
+impl From<HardIron> for MagCalibration {
+    fn from(hard_iron: HardIron) -> Self {
+        let (x, y, z) = hard_iron.offset;
+        MagCalibration {
+            offset: (f32::from(x), f32::from(y), f32::from(z)),
+            ..MagCalibration::default()
+        }
+    }
+}
+
+fn saturate(value: f32) -> i16 {
+    if value >= f32::from(i16::MAX) {
+        i16::MAX
+    } else if value <= f32::from(i16::MIN) {
+        i16::MIN
+    } else if value >= 0.0 {
+        (value + 0.5) as i16
+    } else {
+        (value - 0.5) as i16
+    }
+}
+
+impl MagCalibration {
+    pub fn apply(&self, mag: (i16, i16, i16)) -> (i16, i16, i16) {
+        let v = [
+            f32::from(mag.0) - self.offset.0,
+            f32::from(mag.1) - self.offset.1,
+            f32::from(mag.2) - self.offset.2,
+        ];
+        let m = &self.matrix;
+        let row = |i: usize| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2];
+        (saturate(row(0)), saturate(row(1)), saturate(row(2)))
+    }
+}
+
+/// Linear model of how the hard-iron offset drifts as the magnetometer warms up:
+/// `offset(T) = offset + offset_per_c * (T - reference_c)`
+///
+/// We learn the drift from two calibrations at different temperatures, so it takes one calibration
+/// while the board is cold and another while it's warm.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TemperatureDrift {
+    /// Temperature of the current calibration, in °C as reported by the magnetometer. `None` if we
+    /// don't know it, in which case there's no compensation.
+    pub reference_c: Option<f32>,
+    /// How much the offset moves per °C
+    pub offset_per_c: (f32, f32, f32),
+}
+
+impl TemperatureDrift {
+    /// Update the model after replacing the calibration `old` with `new`, which was done at
+    /// `temperature_c`. If `old` was done at a different enough temperature, the difference between
+    /// the two offsets gives us the drift. Either way, `new` becomes the reference.
+    pub fn recalibrated(
+        &self,
+        old: &MagCalibration,
+        new: &MagCalibration,
+        temperature_c: f32,
+    ) -> TemperatureDrift {
+        let offset_per_c = match self.reference_c {
+            Some(reference_c) if (temperature_c - reference_c).abs() >= MIN_DRIFT_SPAN_C => {
+                let span = temperature_c - reference_c;
+                (
+                    (new.offset.0 - old.offset.0) / span,
+                    (new.offset.1 - old.offset.1) / span,
+                    (new.offset.2 - old.offset.2) / span,
+                )
+            }
+            _ => self.offset_per_c,
+        };
+        TemperatureDrift {
+            reference_c: Some(temperature_c),
+            offset_per_c,
+        }
+    }
+}
+
+impl MagCalibration {
+    /// This calibration with its offset moved to where `drift` predicts it at `temperature_c`
+    pub fn at_temperature(&self, drift: &TemperatureDrift, temperature_c: f32) -> MagCalibration {
+        match drift.reference_c {
+            None => *self,
+            Some(reference_c) => {
+                let delta = temperature_c - reference_c;
+                let (dx, dy, dz) = drift.offset_per_c;
+                MagCalibration {
+                    offset: (
+                        self.offset.0 + dx * delta,
+                        self.offset.1 + dy * delta,
+                        self.offset.2 + dz * delta,
+                    ),
+                    ..*self
+                }
+            }
+        }
+    }
+}
+
+/// The per-axis part of a calibration, in integer math: readings are corrected by subtracting
+/// `offset` and then multiplying each axis by its `scale`. This is what a magnetometer with offset
+/// and gain registers would do by itself, so it's applied as the samples come out of the driver,
+/// see `magnetometer::Corrected`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AxisCorrection {
+    pub offset: (i16, i16, i16),
+    pub scale: (Q16, Q16, Q16),
+}
+
+impl AxisCorrection {
+    /// Leaves readings as they are
+    pub const IDENTITY: AxisCorrection = AxisCorrection {
+        offset: (0, 0, 0),
+        scale: (Q16::ONE, Q16::ONE, Q16::ONE),
+    };
+
+    pub fn apply(&self, mag: (i16, i16, i16)) -> (i16, i16, i16) {
+        let axis = |value: i16, offset: i16, scale: Q16| {
+            ((Q16::from_int(value) - Q16::from_int(offset)) * scale).round()
+        };
+        (
+            axis(mag.0, self.offset.0, self.scale.0),
+            axis(mag.1, self.offset.1, self.scale.1),
+            axis(mag.2, self.offset.2, self.scale.2),
+        )
+    }
+}
+
+impl Default for AxisCorrection {
+    fn default() -> Self {
+        AxisCorrection::IDENTITY
+    }
+}
+
+// A scale this close to 0 can't be undone by `MagCalibration::after`, so `axes` leaves the axis
+// unscaled instead
+const MIN_AXIS_SCALE: f32 = 0.01;
+
+impl MagCalibration {
+    /// The per-axis part of this calibration: its offset, rounded, and the diagonal of its matrix
+    pub fn axes(&self) -> AxisCorrection {
+        let scale = |value: f32| {
+            if value.abs() < MIN_AXIS_SCALE {
+                Q16::ONE
+            } else {
+                Q16::from_f32(value)
+            }
+        };
+        AxisCorrection {
+            offset: (
+                saturate(self.offset.0),
+                saturate(self.offset.1),
+                saturate(self.offset.2),
+            ),
+            scale: (
+                scale(self.matrix[0][0]),
+                scale(self.matrix[1][1]),
+                scale(self.matrix[2][2]),
+            ),
+        }
+    }
+
+    /// What's left of this calibration for readings that `axes` has already corrected, so that
+    /// applying the two in turn comes out the same as applying this to the raw readings, give or
+    /// take the rounding. None of the scales of `axes` may be 0.
+    pub fn after(&self, axes: &AxisCorrection) -> MagCalibration {
+        // With D the scales, the corrected reading is D (raw - rounded offset), so the calibrated
+        // one is M (raw - offset) = M D⁻¹ (corrected - D (offset - rounded offset))
+        let scale = [
+            axes.scale.0.to_f32(),
+            axes.scale.1.to_f32(),
+            axes.scale.2.to_f32(),
+        ];
+        let offset = [self.offset.0, self.offset.1, self.offset.2];
+        let rounded = [axes.offset.0, axes.offset.1, axes.offset.2];
+        let remainder = |i: usize| scale[i] * (offset[i] - f32::from(rounded[i]));
+        let mut matrix = self.matrix;
+        for row in matrix.iter_mut() {
+            for (element, scale) in row.iter_mut().zip(scale.iter()) {
+                *element /= scale;
+            }
+        }
+        MagCalibration {
+            offset: (remainder(0), remainder(1), remainder(2)),
+            matrix,
+        }
+    }
+}
+
+/// Solve `a * x = b` by Gaussian elimination with partial pivoting. Returns `None` if `a` is
+/// (nearly) singular.
+fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for column in 0..N {
+        let pivot = (column..N)
+            .max_by(|&i, &j| a[i][column].abs().partial_cmp(&a[j][column].abs()).unwrap())?;
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+
+        let (upper, lower) = a.split_at_mut(column + 1);
+        let pivot_row = &upper[column];
+        for (offset, row) in lower.iter_mut().enumerate() {
+            let factor = row[column] / pivot_row[column];
+            for (target, source) in row[column..].iter_mut().zip(&pivot_row[column..]) {
+                *target -= factor * source;
+            }
+            b[column + 1 + offset] -= factor * b[column];
+        }
+    }
+
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let sum: f64 = (row + 1..N).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Eigen-decompose a symmetric 3x3 matrix with the Jacobi eigenvalue algorithm. Returns the
+/// eigenvalues and a matrix whose columns are the corresponding eigenvectors.
+fn eigen_symmetric(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    for _ in 0..JACOBI_SWEEPS {
+        for &(p, q) in &[(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-15 {
+                continue;
+            }
+
+            // Rotate by the angle that zeroes a[p][q]
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + trig::sqrt_f64(theta * theta + 1.0));
+            let c = 1.0 / trig::sqrt_f64(t * t + 1.0);
+            let s = t * c;
+
+            for row in &mut a {
+                let akp = row[p];
+                let akq = row[q];
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (row_p, row_q) = (a[p], a[q]);
+            for (k, (apk, aqk)) in row_p.iter().zip(&row_q).enumerate() {
+                a[p][k] = c * apk - s * aqk;
+                a[q][k] = s * apk + c * aqk;
+            }
+            for row in &mut v {
+                let vkp = row[p];
+                let vkq = row[q];
+                row[p] = c * vkp - s * vkq;
+                row[q] = s * vkp + c * vkq;
+            }
+        }
+    }
+
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+/// Fits an ellipsoid to raw readings by least squares
+///
+/// The general ellipsoid is
+/// `A x² + B y² + C z² + 2D xy + 2E xz + 2F yz + 2G x + 2H y + 2I z = 1`. Instead of storing the
+/// readings, we accumulate the normal equations of the least squares problem as they come in, so
+/// this takes constant memory no matter how long the user spends rotating the board.
+pub struct EllipsoidCollector {
+    normal: [[f64; 9]; 9],
+    rhs: [f64; 9],
+    samples: usize,
+}
+
+impl EllipsoidCollector {
+    pub fn new() -> Self {
+        EllipsoidCollector {
+            normal: [[0.0; 9]; 9],
+            rhs: [0.0; 9],
+            samples: 0,
+        }
+    }
+
+    /// Record a raw, uncalibrated reading
+    pub fn add(&mut self, mag: (i16, i16, i16)) {
+        let x = f64::from(mag.0) / FIT_SCALE;
+        let y = f64::from(mag.1) / FIT_SCALE;
+        let z = f64::from(mag.2) / FIT_SCALE;
+        let row = [
+            x * x,
+            y * y,
+            z * z,
+            2.0 * x * y,
+            2.0 * x * z,
+            2.0 * y * z,
+            2.0 * x,
+            2.0 * y,
+            2.0 * z,
+        ];
+
+        for i in 0..9 {
+            for j in 0..9 {
+                self.normal[i][j] += row[i] * row[j];
+            }
+            self.rhs[i] += row[i];
+        }
+        self.samples += 1;
+    }
+
+    /// Fit the ellipsoid and compute the calibration that maps it onto a sphere with the same mean
+    /// radius. Returns `None` if there aren't enough readings or they don't describe an ellipsoid,
+    /// e.g. because the board was only rotated around one axis.
+    pub fn finish(&self) -> Option<MagCalibration> {
+        if self.samples < MIN_FIT_SAMPLES {
+            return None;
+        }
+
+        let p = solve(self.normal, self.rhs)?;
+        let quadratic = [[p[0], p[3], p[4]], [p[3], p[1], p[5]], [p[4], p[5], p[2]]];
+        let linear = [p[6], p[7], p[8]];
+
+        // The center is where the gradient vanishes: quadratic * center = -linear
+        let center = solve(quadratic, [-linear[0], -linear[1], -linear[2]])?;
+
+        // Moving the origin to the center turns the equation into
+        // (v - center)ᵀ quadratic (v - center) = 1 + centerᵀ quadratic center
+        let mut k = 1.0;
+        for i in 0..3 {
+            for j in 0..3 {
+                k += center[i] * quadratic[i][j] * center[j];
+            }
+        }
+
+        let (values, vectors) = eigen_symmetric(quadratic);
+        let mut radii = [0.0; 3];
+        for i in 0..3 {
+            let value = values[i] / k;
+            if value <= 0.0 {
+                // Not an ellipsoid
+                return None;
+            }
+            radii[i] = 1.0 / trig::sqrt_f64(value);
+        }
+        let mean_radius = (radii[0] + radii[1] + radii[2]) / 3.0;
+
+        // Stretch each principal axis to the mean radius: matrix = V diag(mean / r) Vᵀ
+        let mut matrix = [[0.0f32; 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                let sum: f64 = (0..3)
+                    .map(|axis| vectors[i][axis] * (mean_radius / radii[axis]) * vectors[j][axis])
+                    .sum();
+                matrix[i][j] = sum as f32;
+            }
+        }
+
+        Some(MagCalibration {
+            offset: (
+                (center[0] * FIT_SCALE) as f32,
+                (center[1] * FIT_SCALE) as f32,
+                (center[2] * FIT_SCALE) as f32,
+            ),
+            matrix,
+        })
+    }
+}
+
+impl Default for EllipsoidCollector {
+    fn default() -> Self {
+        EllipsoidCollector::new()
+    }
+}
+
+/// Runs both calibrations on the same readings, preferring the ellipsoid fit
+pub struct Calibrator {
+    hard_iron: HardIronCollector,
+    ellipsoid: EllipsoidCollector,
+    coverage: Coverage,
+}
+
+impl Calibrator {
+    pub fn new() -> Self {
+        Calibrator {
+            hard_iron: HardIronCollector::new(),
+            ellipsoid: EllipsoidCollector::new(),
+            coverage: Coverage::new(),
+        }
+    }
+
+    /// Record a raw, uncalibrated reading
+    pub fn add(&mut self, mag: (i16, i16, i16)) {
+        self.hard_iron.add(mag);
+        self.ellipsoid.add(mag);
+        if let Some(hard_iron) = self.hard_iron.finish() {
+            self.coverage.add(mag, hard_iron.offset);
+        }
+    }
+
+    /// The fraction of the sphere that the readings so far cover, from 0 to 1
+    pub fn coverage(&self) -> f32 {
+        self.coverage.fraction()
+    }
+
+    /// Use the ellipsoid fit if it worked, and otherwise fall back to hard-iron offsets
+    pub fn finish(&self) -> Option<MagCalibration> {
+        self.ellipsoid
+            .finish()
+            .or_else(|| self.hard_iron.finish().map(MagCalibration::from))
+    }
+}
+
+impl Default for Calibrator {
+    fn default() -> Self {
+        Calibrator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Readings all over an ellipsoid with the given center and radii along the axes
+    fn ellipsoid(
+        center: (f64, f64, f64),
+        radii: (f64, f64, f64),
+    ) -> impl Iterator<Item = (i16, i16, i16)> {
+        (0..20).flat_map(move |i| {
+            (0..20).map(move |j| {
+                let (sin_lat, cos_lat) =
+                    libm::sincos((f64::from(i) + 0.5) / 20.0 * core::f64::consts::PI);
+                let (sin_lon, cos_lon) =
+                    libm::sincos(f64::from(j) / 20.0 * 2.0 * core::f64::consts::PI);
+                (
+                    libm::round(center.0 + radii.0 * sin_lat * cos_lon) as i16,
+                    libm::round(center.1 + radii.1 * sin_lat * sin_lon) as i16,
+                    libm::round(center.2 + radii.2 * cos_lat) as i16,
+                )
+            })
+        })
+    }
+
+    fn norm(v: (i16, i16, i16)) -> f32 {
+        let (x, y, z) = (f32::from(v.0), f32::from(v.1), f32::from(v.2));
+        trig::sqrt(x * x + y * y + z * z)
+    }
+
+    #[test]
+    fn hard_iron_offset_is_center_of_range() {
+        let mut collector = HardIronCollector::new();
+        collector.add((-100, 50, 0));
+        collector.add((300, -150, 40));
+        assert_eq!(
+            collector.finish(),
+            Some(HardIron {
+                offset: (100, -50, 20)
+            })
+        );
+    }
+
+    #[test]
+    fn hard_iron_needs_readings() {
+        assert_eq!(HardIronCollector::new().finish(), None);
+    }
+
+    #[test]
+    fn applying_subtracts_offset() {
+        let calibration = MagCalibration::from(HardIron {
+            offset: (10, -20, 30),
+        });
+        assert_eq!(calibration.apply((10, -20, 30)), (0, 0, 0));
+        assert_eq!(calibration.apply((110, 0, 0)), (100, 20, -30));
+    }
+
+    #[test]
+    fn ellipsoid_fit_finds_center() {
+        let mut collector = EllipsoidCollector::new();
+        for reading in ellipsoid((120.0, -80.0, 40.0), (450.0, 350.0, 300.0)) {
+            collector.add(reading);
+        }
+        let calibration = collector.finish().unwrap();
+        let (x, y, z) = calibration.offset;
+        assert!((x - 120.0).abs() < 2.0 && (y + 80.0).abs() < 2.0 && (z - 40.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn ellipsoid_fit_makes_a_sphere() {
+        let mut collector = EllipsoidCollector::new();
+        let readings = || ellipsoid((120.0, -80.0, 40.0), (450.0, 350.0, 300.0));
+        for reading in readings() {
+            collector.add(reading);
+        }
+        let calibration = collector.finish().unwrap();
+
+        let (min, max) = readings()
+            .map(|reading| norm(calibration.apply(reading)))
+            .fold((f32::MAX, f32::MIN), |(min, max), r| {
+                (min.min(r), max.max(r))
+            });
+        assert!(max / min < 1.02, "radius varies from {} to {}", min, max);
+    }
+
+    #[test]
+    fn ellipsoid_fit_needs_enough_readings() {
+        let mut collector = EllipsoidCollector::new();
+        for reading in ellipsoid((0.0, 0.0, 0.0), (400.0, 400.0, 400.0)).take(MIN_FIT_SAMPLES - 1) {
+            collector.add(reading);
+        }
+        assert_eq!(collector.finish(), None);
+    }
+
+    #[test]
+    fn coverage_needs_rotation_about_every_axis() {
+        // The first time around, the center is still moving, so it takes a second time
+        let mut calibrator = Calibrator::new();
+        for _ in 0..2 {
+            for reading in ellipsoid((120.0, -80.0, 40.0), (450.0, 350.0, 300.0)) {
+                calibrator.add(reading);
+            }
+        }
+        assert_eq!(calibrator.coverage(), 1.0);
+
+        // Turning the board flat on a table only covers the band around the equator
+        let mut coverage = Coverage::new();
+        for reading in ellipsoid((0.0, 0.0, 0.0), (400.0, 400.0, 400.0))
+            .skip(200)
+            .take(20)
+        {
+            coverage.add(reading, (0, 0, 0));
+        }
+        assert_eq!(coverage.fraction(), 0.25);
+    }
+
+    #[test]
+    fn calibrator_falls_back_to_hard_iron() {
+        // Rotating around only one axis doesn't describe an ellipsoid
+        let mut calibrator = Calibrator::new();
+        for reading in ellipsoid((100.0, 0.0, 0.0), (400.0, 400.0, 0.0)) {
+            calibrator.add(reading);
+        }
+        let calibration = calibrator.finish().unwrap();
+        assert_eq!(calibration.matrix, MagCalibration::default().matrix);
+        assert_eq!(calibration.offset, (100.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn axis_corrections_round_to_the_nearest_integer() {
+        let axes = AxisCorrection {
+            offset: (100, -50, 0),
+            scale: (Q16::from_f32(0.5), Q16::ONE, Q16::from_f32(2.0)),
+        };
+        assert_eq!(axes.apply((103, -60, 7)), (2, -10, 14));
+        assert_eq!(axes.apply((-32768, 32767, 20000)), (-16384, 32767, 32767));
+        assert_eq!(AxisCorrection::IDENTITY.apply((1, -2, 3)), (1, -2, 3));
+    }
+
+    #[test]
+    fn axes_and_the_rest_add_up_to_the_calibration() {
+        let calibration = MagCalibration {
+            offset: (120.4, -35.7, 10.0),
+            matrix: [[1.2, 0.1, 0.0], [0.1, 0.9, -0.05], [0.0, -0.05, 1.1]],
+        };
+        let axes = calibration.axes();
+        assert_eq!(axes.offset, (120, -36, 10));
+        let rest = calibration.after(&axes);
+        for &reading in &[(500, 200, -300), (-400, 0, 450), (120, -36, 10)] {
+            let direct = calibration.apply(reading);
+            let split = rest.apply(axes.apply(reading));
+            assert!((direct.0 - split.0).abs() <= 1);
+            assert!((direct.1 - split.1).abs() <= 1);
+            assert!((direct.2 - split.2).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn drift_is_learned_from_two_temperatures() {
+        let cold = MagCalibration::from(HardIron {
+            offset: (100, 0, 0),
+        });
+        let warm = MagCalibration::from(HardIron {
+            offset: (120, -10, 0),
+        });
+        let drift = TemperatureDrift::default()
+            .recalibrated(&MagCalibration::default(), &cold, 10.0)
+            .recalibrated(&cold, &warm, 30.0);
+        assert_eq!(drift.reference_c, Some(30.0));
+        assert_eq!(drift.offset_per_c, (1.0, -0.5, 0.0));
+
+        let hot = warm.at_temperature(&drift, 40.0);
+        assert_eq!(hot.offset, (130.0, -15.0, 0.0));
+    }
+
+    #[test]
+    fn drift_ignores_close_temperatures() {
+        let first = MagCalibration::from(HardIron {
+            offset: (100, 0, 0),
+        });
+        let second = MagCalibration::from(HardIron {
+            offset: (120, 0, 0),
+        });
+        let drift = TemperatureDrift::default()
+            .recalibrated(&MagCalibration::default(), &first, 20.0)
+            .recalibrated(&first, &second, 20.5);
+        assert_eq!(drift.offset_per_c, (0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,175 @@
+//! A text format for raw sensor samples, for recording them on the board and replaying them later
+//!
+//! Each sample is one line: a tag, then its values separated by spaces, e.g. `mag -120 35 -440`.
+//! Ticks of the main loop are recorded too, so that a replay sees the samples and the ticks in the
+//! same order as the board did. The format is plain text so that a recording can be captured with
+//! any serial terminal and edited by hand.
+
+use core::fmt::{self, Write};
+use core::str::{FromStr, SplitWhitespace};
+
+/// One line of a recording
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sample {
+    /// Raw magnetometer reading, before calibration
+    Mag((i16, i16, i16)),
+    /// Raw accelerometer reading
+    Accel((i16, i16, i16)),
+    /// Angular rate in degrees per second
+    Gyro((f32, f32, f32)),
+    /// Magnetometer temperature in °C
+    Temperature(f32),
+    /// The main loop ran the sensor fusion
+    Tick,
+}
+
+/// Why a line that looks like a sample couldn't be parsed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// There are fewer values than the tag needs
+    MissingValue,
+    /// A value isn't a number, or is out of range
+    BadValue,
+    /// There are more values than the tag needs
+    TrailingValue,
+}
+
+fn value<T: FromStr>(words: &mut SplitWhitespace) -> Result<T, ParseError> {
+    words
+        .next()
+        .ok_or(ParseError::MissingValue)?
+        .parse()
+        .map_err(|_| ParseError::BadValue)
+}
+
+fn triple<T: FromStr>(words: &mut SplitWhitespace) -> Result<(T, T, T), ParseError> {
+    Ok((value(words)?, value(words)?, value(words)?))
+}
+
+impl Sample {
+    /// Write the sample as one line, terminated by CR LF like everything else on the serial port
+    pub fn write<W: Write>(&self, out: &mut W) -> fmt::Result {
+        match *self {
+            Sample::Mag((x, y, z)) => write!(out, "mag {} {} {}\r\n", x, y, z),
+            Sample::Accel((x, y, z)) => write!(out, "accel {} {} {}\r\n", x, y, z),
+            // Without a precision, floats are written with as many digits as it takes to read back
+            // exactly the same value
+            Sample::Gyro((x, y, z)) => write!(out, "gyro {} {} {}\r\n", x, y, z),
+            Sample::Temperature(celsius) => write!(out, "temp {}\r\n", celsius),
+            Sample::Tick => write!(out, "tick\r\n"),
+        }
+    }
+
+    /// Parse a line written by `write`. Returns `None` for anything that isn't a sample, like an
+    /// empty line or a reply to a shell command.
+    pub fn parse(line: &str) -> Option<Result<Sample, ParseError>> {
+        let mut words = line.split_whitespace();
+        let sample = match words.next()? {
+            "mag" => triple(&mut words).map(Sample::Mag),
+            "accel" => triple(&mut words).map(Sample::Accel),
+            "gyro" => triple(&mut words).map(Sample::Gyro),
+            "temp" => value(&mut words).map(Sample::Temperature),
+            "tick" => Ok(Sample::Tick),
+            _ => return None,
+        };
+        Some(match (sample, words.next()) {
+            (Ok(_), Some(_)) => Err(ParseError::TrailingValue),
+            (sample, _) => sample,
+        })
+    }
+}
+
+/// The samples in a recording, in order. Errors come with the line number, counting from 1.
+pub fn replay(recording: &str) -> impl Iterator<Item = Result<Sample, (usize, ParseError)>> + '_ {
+    recording.lines().enumerate().filter_map(|(index, line)| {
+        Sample::parse(line).map(|sample| sample.map_err(|error| (index + 1, error)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Just enough of a `Line` to format into on the host
+    struct Text {
+        buffer: [u8; 64],
+        len: usize,
+    }
+
+    impl Text {
+        fn new() -> Self {
+            Text {
+                buffer: [0; 64],
+                len: 0,
+            }
+        }
+
+        fn as_str(&self) -> &str {
+            core::str::from_utf8(&self.buffer[..self.len]).unwrap()
+        }
+    }
+
+    impl Write for Text {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.buffer
+                .get_mut(self.len..end)
+                .ok_or(fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn samples_survive_a_round_trip() {
+        let samples = [
+            Sample::Mag((-120, 35, -440)),
+            Sample::Accel((i16::MIN, 0, i16::MAX)),
+            Sample::Gyro((0.1, -12.345678, 1e-7)),
+            Sample::Temperature(23.875),
+            Sample::Tick,
+        ];
+        for &sample in samples.iter() {
+            let mut text = Text::new();
+            sample.write(&mut text).unwrap();
+            assert!(text.as_str().ends_with("\r\n"));
+            assert_eq!(Sample::parse(text.as_str()), Some(Ok(sample)));
+        }
+    }
+
+    #[test]
+    fn other_lines_are_skipped() {
+        assert_eq!(Sample::parse(""), None);
+        assert_eq!(Sample::parse("ok\r\n"), None);
+        assert_eq!(Sample::parse("$HCHDM,123.4,M*2A"), None);
+    }
+
+    #[test]
+    fn bad_samples_are_errors() {
+        assert_eq!(
+            Sample::parse("mag 1 2"),
+            Some(Err(ParseError::MissingValue))
+        );
+        assert_eq!(Sample::parse("mag 1 2 x"), Some(Err(ParseError::BadValue)));
+        assert_eq!(
+            Sample::parse("mag 1 2 40000"),
+            Some(Err(ParseError::BadValue))
+        );
+        assert_eq!(
+            Sample::parse("tick 1"),
+            Some(Err(ParseError::TrailingValue))
+        );
+    }
+
+    #[test]
+    fn replay_reports_line_numbers() {
+        let recording = "mag 1 2 3\r\nok\r\ntick\r\ntemp warm\r\n";
+        let mut samples = replay(recording);
+        assert_eq!(samples.next(), Some(Ok(Sample::Mag((1, 2, 3)))));
+        assert_eq!(samples.next(), Some(Ok(Sample::Tick)));
+        assert_eq!(samples.next(), Some(Err((4, ParseError::BadValue))));
+        assert_eq!(samples.next(), None);
+    }
+}
@@ -0,0 +1,173 @@
+//! Bounded channels between tasks
+//!
+//! A `Channel` is a `heapless` single-producer single-consumer queue with a waker on each end, so
+//! that a task can wait for a value to arrive, or for room to send one. Nothing is allocated: the
+//! channel lives wherever it's declared, and `split` hands out a `Sender` and a `Receiver` that
+//! borrow it.
+//!
+//! What happens when the queue is full is up to the sender, for every value:
+//!
+//! - `send` waits until the receiver has made room, which slows the sender down to the receiver's
+//!   pace.
+//! - `try_send` gives the value back, so the sender can drop it and carry on.
+//!
+//! Like the `heapless` queue, a channel of `N` holds at most `N - 1` values.
+
+use core::cell::RefCell;
+use core::task::Poll;
+use futures::future::poll_fn;
+use futures::task::AtomicWaker;
+use heapless::spsc::{Consumer, Producer, Queue};
+
+pub struct Channel<T, const N: usize> {
+    queue: Queue<T, N>,
+    // Woken when a value has been sent
+    sent: AtomicWaker,
+    // Woken when a value has been received
+    received: AtomicWaker,
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        Channel {
+            queue: Queue::new(),
+            sent: AtomicWaker::new(),
+            received: AtomicWaker::new(),
+        }
+    }
+
+    /// The two ends of the channel
+    pub fn split(&mut self) -> (Sender<'_, T, N>, Receiver<'_, T, N>) {
+        let (producer, consumer) = self.queue.split();
+        let sender = Sender {
+            producer: RefCell::new(producer),
+            sent: &self.sent,
+            received: &self.received,
+        };
+        let receiver = Receiver {
+            consumer: RefCell::new(consumer),
+            sent: &self.sent,
+            received: &self.received,
+        };
+        (sender, receiver)
+    }
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Channel::new()
+    }
+}
+
+/// The sending end of a `Channel`
+pub struct Sender<'a, T, const N: usize> {
+    producer: RefCell<Producer<'a, T, N>>,
+    sent: &'a AtomicWaker,
+    received: &'a AtomicWaker,
+}
+
+impl<'a, T, const N: usize> Sender<'a, T, N> {
+    /// Send `value` if there's room, or give it back if the channel is full
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        self.producer.borrow_mut().enqueue(value)?;
+        self.sent.wake();
+        Ok(())
+    }
+
+    /// Send `value`, waiting for room if the channel is full
+    pub async fn send(&self, value: T) {
+        let mut value = Some(value);
+        poll_fn(move |cx| {
+            let mut pending = value.take().expect("polled after completion");
+            for _ in 0..2 {
+                pending = match self.try_send(pending) {
+                    Ok(()) => return Poll::Ready(()),
+                    Err(pending) => pending,
+                };
+                // The receiver may make room between the first attempt and registering
+                self.received.register(cx.waker());
+            }
+            value = Some(pending);
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// The receiving end of a `Channel`
+pub struct Receiver<'a, T, const N: usize> {
+    consumer: RefCell<Consumer<'a, T, N>>,
+    sent: &'a AtomicWaker,
+    received: &'a AtomicWaker,
+}
+
+impl<'a, T, const N: usize> Receiver<'a, T, N> {
+    /// The oldest value in the channel, if there is one
+    pub fn try_recv(&self) -> Option<T> {
+        let value = self.consumer.borrow_mut().dequeue()?;
+        self.received.wake();
+        Some(value)
+    }
+
+    /// Wait for the oldest value in the channel
+    pub async fn recv(&self) -> T {
+        poll_fn(move |cx| {
+            if let Some(value) = self.try_recv() {
+                return Poll::Ready(value);
+            }
+            // The sender may send something between the first attempt and registering
+            self.sent.register(cx.waker());
+            match self.try_recv() {
+                Some(value) => Poll::Ready(value),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+    use core::task::Context;
+    use futures::pin_mut;
+    use futures::task::noop_waker;
+
+    fn poll<F: Future>(future: F) -> Poll<F::Output> {
+        pin_mut!(future);
+        let waker = noop_waker();
+        future.poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn values_come_out_in_order() {
+        let mut channel = Channel::<u32, 4>::new();
+        let (sender, receiver) = channel.split();
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(poll(sender.send(2)), Poll::Ready(()));
+        assert_eq!(poll(receiver.recv()), Poll::Ready(1));
+        assert_eq!(receiver.try_recv(), Some(2));
+        assert_eq!(poll(receiver.recv()), Poll::Pending);
+    }
+
+    #[test]
+    fn full_channels_push_back() {
+        let mut channel = Channel::<u32, 3>::new();
+        let (sender, receiver) = channel.split();
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(sender.try_send(2), Ok(()));
+        assert_eq!(sender.try_send(3), Err(3));
+        {
+            let send = sender.send(3);
+            pin_mut!(send);
+            let waker = noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            assert_eq!(send.as_mut().poll(&mut cx), Poll::Pending);
+            assert_eq!(receiver.try_recv(), Some(1));
+            assert_eq!(send.poll(&mut cx), Poll::Ready(()));
+        }
+        assert_eq!(receiver.try_recv(), Some(2));
+        assert_eq!(receiver.try_recv(), Some(3));
+    }
+}
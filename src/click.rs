@@ -0,0 +1,81 @@
+//! Taps on the board, detected by the accelerometer
+//!
+//! The LSM303DLHC can recognize single and double clicks by itself and signal them on its INT2
+//! pin, which is wired to PE5 on the F3 Discovery. INT2 only goes high briefly, so unlike DRDY we
+//! wait for the edge that EXTI5 latched rather than for the level. After each edge we ask the
+//! accelerometer what it was.
+//!
+//! A double tap starts with a single one, so every double tap is also reported as a single tap.
+
+use crate::accel::{Accelerometer, Tap};
+use crate::wakers;
+use embedded_hal_async::i2c::I2c;
+use f3::hal::stm32f30x::{exti, gpioc, rcc, syscfg, EXTI, GPIOE, RCC, SYSCFG};
+use futures::{stream, Stream};
+
+// INT2 is on PE5
+const PIN: u32 = 5;
+
+// EXTICR2 value that routes EXTI5 to port E
+const PORT_E: u8 = 0b0100;
+
+pub struct ClickLine {
+    exti: &'static exti::RegisterBlock,
+}
+
+impl ClickLine {
+    /// Configure PE5 as an input and EXTI5 to latch its rising edges. The interrupt itself is only
+    /// enabled while waiting.
+    pub fn new() -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let syscfg: &'static syscfg::RegisterBlock = unsafe { &*SYSCFG::ptr() };
+        let exti: &'static exti::RegisterBlock = unsafe { &*EXTI::ptr() };
+        let gpioe: &'static gpioc::RegisterBlock = unsafe { &*GPIOE::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopeen().set_bit());
+        rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        gpioe
+            .moder
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << (2 * PIN))) });
+
+        syscfg
+            .exticr2
+            .modify(|_, w| unsafe { w.exti5().bits(PORT_E) });
+        exti.rtsr1.modify(|_, w| w.tr5().set_bit());
+        exti.pr1.write(|w| w.pr5().set_bit());
+
+        ClickLine { exti }
+    }
+
+    /// Wait for a rising edge on INT2, including one that happened since the previous call
+    async fn wait_for_edge(&self) {
+        let exti = self.exti;
+        wakers::wait_for(
+            &wakers::EXTI5_EV,
+            || exti.pr1.read().pr5().bit_is_set(),
+            || exti.imr1.modify(|_, w| w.mr5().set_bit()),
+        )
+        .await;
+        exti.pr1.write(|w| w.pr5().set_bit());
+    }
+}
+
+/// Taps, as they happen. `accel` must already have click detection enabled.
+pub fn get_taps_forever<I2C: I2c>(
+    accel: Accelerometer<I2C>,
+    line: ClickLine,
+) -> impl Stream<Item = Result<Tap, I2C::Error>> {
+    stream::unfold((accel, line), |(mut accel, line)| async move {
+        let result = loop {
+            line.wait_for_edge().await;
+            match accel.get_click().await {
+                Ok(Some(tap)) => break Ok(tap),
+                // The click was over before we got around to asking
+                Ok(None) => {}
+                Err(error) => break Err(error),
+            }
+        };
+        Some((result, (accel, line)))
+    })
+}
@@ -0,0 +1,216 @@
+//! A monotonic clock in microseconds, from TIM2
+//!
+//! TIM2 is the only 32-bit timer, so it counts microseconds for over an hour before it wraps. Its
+//! update interrupt counts the wraps, which extends it to 64 bits, enough to never wrap at all.
+//!
+//! Any number of tasks can `sleep` at the same time on the one timer: the deadlines go into a
+//! `TimerQueue`, and compare channel 1 interrupts at the earliest of them. CCR1 only matches the low
+//! 32 bits of a deadline, so a deadline more than a wrap away may fire early, once per wrap, which
+//! just re-arms the channel.
+//!
+//! TIM2 stops in STOP mode, so in low-power builds the clock only counts the time that the MCU is
+//! awake, and a `Sleep` keeps the MCU awake until it's over.
+
+use crate::clocks;
+use crate::power::Awake;
+use compass::timer_queue::TimerQueue;
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+use cortex_m::interrupt::{CriticalSection, Mutex};
+use cortex_m::peripheral::NVIC;
+use f3::hal::stm32f30x::{rcc, tim2, Interrupt, RCC, TIM2};
+use futures::future::{self, Either};
+
+// APB1_TIMER_CLOCK = 72 MHz
+// PSC = 71
+// 72 MHz / (71 + 1) = 1 MHz
+const PSC: u16 = (clocks::APB1_TIMER_HZ / 1_000_000 - 1) as u16;
+
+// How many times the counter has wrapped around
+static WRAPS: AtomicU32 = AtomicU32::new(0);
+
+// How many tasks can sleep at the same time. If there are more, the rest keep polling until a slot
+// frees up.
+const SLEEPERS: usize = 8;
+
+static QUEUE: Mutex<RefCell<TimerQueue<SLEEPERS>>> = Mutex::new(RefCell::new(TimerQueue::new()));
+
+/// A point in time, in microseconds since `init`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn as_micros(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_millis(self) -> u64 {
+        self.as_micros() / 1_000
+    }
+
+    /// Seconds from `earlier` to `self`. In low-power builds the clock doesn't count the time spent
+    /// asleep, so there's no way to tell this.
+    #[cfg(not(feature = "low-power"))]
+    pub fn seconds_since(self, earlier: Instant) -> f32 {
+        self.0.saturating_sub(earlier.0) as f32 / 1e6
+    }
+}
+
+/// A value and when it was measured
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamped<T> {
+    pub at: Instant,
+    pub value: T,
+}
+
+/// Start counting from 0
+pub fn init() {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let tim2: &'static tim2::RegisterBlock = unsafe { &*TIM2::ptr() };
+
+    rcc.apb1enr.modify(|_, w| w.tim2en().set_bit());
+    tim2.psc.write(|w| unsafe { w.psc().bits(PSC) });
+    tim2.arr.write(|w| unsafe { w.bits(u32::MAX) });
+    // Load the prescaler, without counting that as a wrap
+    tim2.egr.write(|w| w.ug().set_bit());
+    tim2.sr.modify(|_, w| w.uif().clear_bit());
+    tim2.dier.write(|w| w.uie().set_bit());
+    tim2.cr1.write(|w| w.cen().set_bit());
+
+    unsafe { NVIC::unmask(Interrupt::TIM2) };
+}
+
+/// The current time
+pub fn now() -> Instant {
+    let tim2: &'static tim2::RegisterBlock = unsafe { &*TIM2::ptr() };
+    // With interrupts disabled, the wrap count can't change under our feet
+    cortex_m::interrupt::free(|_| {
+        let mut wraps = WRAPS.load(Ordering::Relaxed);
+        let count = tim2.cnt.read().bits();
+        // The counter has wrapped, but the interrupt hasn't run yet. If the count is small, we read
+        // it after the wrap.
+        if tim2.sr.read().uif().bit_is_set() && count < u32::MAX / 2 {
+            wraps += 1;
+        }
+        Instant(u64::from(wraps) << 32 | u64::from(count))
+    })
+}
+
+#[cfg(feature = "defmt")]
+defmt::timestamp!("{=u64:us}", now().as_micros());
+
+/// Wait until `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        slot: None,
+        _awake: Awake::new(),
+    }
+}
+
+/// Wait for `ms` milliseconds
+pub fn sleep(ms: u32) -> Sleep {
+    sleep_until(Instant(now().0 + u64::from(ms) * 1_000))
+}
+
+/// Returned by `with_timeout` when time ran out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timeout;
+
+/// Run `future`, but give up on it after `ms` milliseconds. Giving up drops `future` wherever it
+/// happens to be waiting, so it must be safe to cancel.
+pub async fn with_timeout<F: Future>(future: F, ms: u32) -> Result<F::Output, Timeout> {
+    futures::pin_mut!(future);
+    match future::select(future, sleep(ms)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Timeout),
+    }
+}
+
+/// Returned by `sleep` and `sleep_until`
+pub struct Sleep {
+    deadline: Instant,
+    // Our place in `QUEUE`, once we've been polled
+    slot: Option<usize>,
+    _awake: Awake,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let sleep = self.get_mut();
+        cortex_m::interrupt::free(|cs| {
+            let mut queue = QUEUE.borrow(cs).borrow_mut();
+            if now() >= sleep.deadline {
+                if let Some(slot) = sleep.slot.take() {
+                    queue.remove(slot);
+                }
+                return Poll::Ready(());
+            }
+
+            match sleep.slot {
+                Some(slot) => queue.register(slot, cx.waker()),
+                None => sleep.slot = queue.insert(sleep.deadline.0, cx.waker()),
+            }
+            if sleep.slot.is_none() {
+                // The queue is full, so nobody would wake us
+                cx.waker().wake_by_ref();
+            }
+            arm(cs, &queue);
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            cortex_m::interrupt::free(|cs| {
+                let mut queue = QUEUE.borrow(cs).borrow_mut();
+                queue.remove(slot);
+                arm(cs, &queue);
+            });
+        }
+    }
+}
+
+/// Make compare channel 1 interrupt at the earliest deadline in `queue`, or not at all if it's
+/// empty
+fn arm(_cs: &CriticalSection, queue: &TimerQueue<SLEEPERS>) {
+    let tim2: &'static tim2::RegisterBlock = unsafe { &*TIM2::ptr() };
+    match queue.next_deadline() {
+        Some(deadline) => {
+            tim2.ccr1.write(|w| unsafe { w.bits(deadline as u32) });
+            tim2.sr.modify(|_, w| w.cc1if().clear_bit());
+            tim2.dier.modify(|_, w| w.cc1ie().set_bit());
+            // If the deadline passed while we were setting this up, the counter won't come back
+            // around to it for another hour
+            if now().0 >= deadline {
+                NVIC::pend(Interrupt::TIM2);
+            }
+        }
+        None => tim2.dier.modify(|_, w| w.cc1ie().clear_bit()),
+    }
+}
+
+/// The TIM2 interrupt handler, see `interrupts`
+pub fn tim2() {
+    let tim2: &'static tim2::RegisterBlock = unsafe { &*TIM2::ptr() };
+    if tim2.sr.read().uif().bit_is_set() {
+        tim2.sr.modify(|_, w| w.uif().clear_bit());
+        WRAPS.fetch_add(1, Ordering::Relaxed);
+    }
+    tim2.sr.modify(|_, w| w.cc1if().clear_bit());
+
+    cortex_m::interrupt::free(|cs| {
+        let mut queue = QUEUE.borrow(cs).borrow_mut();
+        queue.expire(now().0);
+        arm(cs, &queue);
+    });
+}
@@ -0,0 +1,89 @@
+//! The clock tree
+//!
+//! The ST-LINK feeds an 8 MHz clock from its MCO into HSE, which we bypass rather than driving a
+//! crystal. The PLL multiplies it by 9, and that 72 MHz clock drives the core and the AHB:
+//!
+//! - SYSCLK = HCLK = 72 MHz
+//! - PCLK1 = HCLK / 2 = 36 MHz, the maximum for APB1
+//! - PCLK2 = HCLK / 1 = 72 MHz
+//! - USB = PLL / 1.5 = 48 MHz
+//!
+//! When an APB prescaler isn't 1, the timers on that bus count at twice its clock, so every timer
+//! counts at 72 MHz.
+//!
+//! HSI keeps running at 8 MHz, and the sensors' I2C peripheral stays on it unless its
+//! I2CxSW bit is set.
+//!
+//! Everything that derives a prescaler or a delay from the clock frequency should use the constants
+//! here rather than hard-coding a frequency.
+
+use f3::hal::stm32f30x::{flash, rcc, FLASH, RCC};
+
+/// The internal RC oscillator
+pub const HSI_HZ: u32 = 8_000_000;
+
+// The ST-LINK's MCO
+const HSE_HZ: u32 = 8_000_000;
+
+// PLL = HSE * 9 = 72 MHz
+const PLLMUL_9: u8 = 0b0111;
+const PLL_MULTIPLIER: u32 = 9;
+
+pub const SYSCLK_HZ: u32 = HSE_HZ * PLL_MULTIPLIER;
+pub const HCLK_HZ: u32 = SYSCLK_HZ;
+
+// PPRE1 = HCLK / 2
+const PPRE1_DIV2: u8 = 0b100;
+pub const PCLK1_HZ: u32 = HCLK_HZ / 2;
+pub const PCLK2_HZ: u32 = HCLK_HZ;
+
+/// TIM2, TIM3, TIM6 and TIM7. APB1 is divided, so its timers count at twice PCLK1.
+pub const APB1_TIMER_HZ: u32 = PCLK1_HZ * 2;
+/// TIM16, among others. APB2 isn't divided, so its timers count at PCLK2. Only `pwm` uses TIM16,
+/// and boards without it don't use this.
+#[cfg(not(feature = "nucleo-f303ze"))]
+pub const APB2_TIMER_HZ: u32 = PCLK2_HZ;
+
+// SW and SWS value for the PLL
+const SW_PLL: u8 = 0b10;
+
+// Two wait states for 48 MHz < SYSCLK ≤ 72 MHz
+const FLASH_LATENCY: u8 = 0b010;
+
+/// Switch the core from HSI to the PLL. This has to run before anything that depends on the clock
+/// frequencies is set up.
+pub fn init() {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let flash: &'static flash::RegisterBlock = unsafe { &*FLASH::ptr() };
+
+    // The flash has to be slowed down before the core speeds up
+    flash
+        .acr
+        .modify(|_, w| unsafe { w.latency().bits(FLASH_LATENCY).prftbe().set_bit() });
+
+    // USBPRES = 0: the USB clock is the PLL clock divided by 1.5
+    rcc.cfgr.modify(|_, w| unsafe {
+        w.pllsrc().set_bit();
+        w.pllmul().bits(PLLMUL_9);
+        w.ppre1().bits(PPRE1_DIV2);
+        w.ppre2().bits(0);
+        w.hpre().bits(0);
+        w.usbpres().clear_bit()
+    });
+
+    restore();
+}
+
+/// Start HSE and the PLL, and switch the core to the PLL. STOP mode turns both of them off and
+/// wakes up on HSI, but the rest of the configuration survives it.
+pub fn restore() {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+
+    rcc.cr.modify(|_, w| w.hseon().set_bit().hsebyp().set_bit());
+    while rcc.cr.read().hserdy().bit_is_clear() {}
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    while rcc.cr.read().pllrdy().bit_is_clear() {}
+
+    rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(SW_PLL) });
+    while rcc.cfgr.read().sws().bits() != SW_PLL {}
+}
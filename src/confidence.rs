@@ -0,0 +1,162 @@
+//! How far the heading can be trusted
+//!
+//! Two things give away a heading that's wrong. After calibration, the field's strength is the
+//! radius of the calibrated sphere no matter which way the board points, so a strength that's off
+//! means that something other than the earth is adding to the field. And the heading of a board
+//! that's held still doesn't jump around, so a heading that wanders means noise, or a board that's
+//! being shaken too hard for the tilt compensation.
+//!
+//! Neither needs a reference. We learn the radius of the sphere by following the strength with a
+//! very slow low-pass filter, and the heading's spread by following the unit vector of the heading
+//! with a fast one: the shorter the average vector, the more the headings disagree.
+
+use crate::trig;
+
+/// How much the heading can be trusted, from worst to best
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Confidence {
+    Low,
+    Medium,
+    High,
+}
+
+// How far the field's strength may deviate from the sphere, as a fraction of its radius
+const MEDIUM_FIELD_DEVIATION: f32 = 0.05;
+const LOW_FIELD_DEVIATION: f32 = 0.15;
+
+// How far the heading may spread, as a circular standard deviation in degrees
+const MEDIUM_HEADING_SPREAD_DEG: f32 = 3.0;
+const LOW_HEADING_SPREAD_DEG: f32 = 10.0;
+
+pub struct ConfidenceEstimator {
+    /// How long it takes, in seconds, for the radius to absorb most of a change
+    radius_time_constant_s: f32,
+    /// How long it takes, in seconds, for the heading's spread to forget an old heading
+    heading_time_constant_s: f32,
+    radius: Option<f32>,
+    deviation: f32,
+    // Low-pass filtered unit vector (cos, sin) of the heading
+    heading: Option<(f32, f32)>,
+}
+
+/// Move `average` towards `value` by a step that's `dt_s` seconds long
+fn low_pass(average: f32, value: f32, dt_s: f32, time_constant_s: f32) -> f32 {
+    let gain = dt_s / (time_constant_s + dt_s);
+    average + gain * (value - average)
+}
+
+impl ConfidenceEstimator {
+    pub fn new(radius_time_constant_s: f32, heading_time_constant_s: f32) -> Self {
+        ConfidenceEstimator {
+            radius_time_constant_s,
+            heading_time_constant_s,
+            radius: None,
+            deviation: 0.0,
+            heading: None,
+        }
+    }
+
+    /// Add a calibrated reading that came `dt_s` seconds after the previous one
+    pub fn update_field(&mut self, mag: (i16, i16, i16), dt_s: f32) {
+        let (x, y, z) = (f32::from(mag.0), f32::from(mag.1), f32::from(mag.2));
+        let strength = trig::sqrt(x * x + y * y + z * z);
+
+        let radius = match self.radius {
+            None => strength,
+            Some(radius) => low_pass(radius, strength, dt_s, self.radius_time_constant_s),
+        };
+        self.radius = Some(radius);
+        self.deviation = if radius < f32::EPSILON {
+            0.0
+        } else {
+            (strength - radius).abs() / radius
+        };
+    }
+
+    /// Add a heading in degrees that came `dt_s` seconds after the previous one
+    pub fn update_heading(&mut self, heading: f32, dt_s: f32) {
+        let (sin, cos) = trig::sin_cos(heading.to_radians());
+        self.heading = Some(match self.heading {
+            None => (cos, sin),
+            Some((x, y)) => (
+                low_pass(x, cos, dt_s, self.heading_time_constant_s),
+                low_pass(y, sin, dt_s, self.heading_time_constant_s),
+            ),
+        });
+    }
+
+    /// How far the latest field's strength deviates from the sphere, as a fraction of its radius
+    pub fn field_deviation(&self) -> f32 {
+        self.deviation
+    }
+
+    /// The recent headings' circular standard deviation in degrees. This is 0 before the first
+    /// heading.
+    pub fn heading_spread_deg(&self) -> f32 {
+        let (x, y) = self.heading.unwrap_or((1.0, 0.0));
+        let length = trig::sqrt(x * x + y * y).min(1.0);
+        if length < f32::EPSILON {
+            // The headings point every which way
+            return 180.0;
+        }
+        trig::sqrt(-2.0 * trig::ln(length)).to_degrees()
+    }
+
+    /// The worse of what the field's strength and the heading's spread say
+    pub fn confidence(&self) -> Confidence {
+        let field = if self.deviation > LOW_FIELD_DEVIATION {
+            Confidence::Low
+        } else if self.deviation > MEDIUM_FIELD_DEVIATION {
+            Confidence::Medium
+        } else {
+            Confidence::High
+        };
+
+        let spread_deg = self.heading_spread_deg();
+        let heading = if spread_deg > LOW_HEADING_SPREAD_DEG {
+            Confidence::Low
+        } else if spread_deg > MEDIUM_HEADING_SPREAD_DEG {
+            Confidence::Medium
+        } else {
+            Confidence::High
+        };
+
+        field.min(heading)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steady_readings_are_trusted() {
+        let mut estimator = ConfidenceEstimator::new(60.0, 1.0);
+        for _ in 0..100 {
+            estimator.update_field((300, 400, 0), 0.1);
+            estimator.update_heading(179.0, 0.1);
+            estimator.update_heading(-179.0, 0.1);
+        }
+        assert!(estimator.field_deviation() < 0.001);
+        assert!(estimator.heading_spread_deg() < 3.0);
+        assert_eq!(estimator.confidence(), Confidence::High);
+    }
+
+    #[test]
+    fn disturbed_field_or_wandering_heading_is_not_trusted() {
+        let mut estimator = ConfidenceEstimator::new(60.0, 1.0);
+        for _ in 0..100 {
+            estimator.update_field((300, 400, 0), 0.1);
+        }
+        estimator.update_field((300, 400, 400), 0.1);
+        assert_eq!(estimator.confidence(), Confidence::Low);
+
+        estimator.update_field((300, 400, 0), 0.1);
+        for i in 0..100 {
+            estimator.update_heading(if i % 2 == 0 { 5.0 } else { -5.0 }, 0.1);
+        }
+        assert!(estimator.heading_spread_deg() > 4.0);
+        assert_eq!(estimator.confidence(), Confidence::Medium);
+    }
+}
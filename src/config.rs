@@ -0,0 +1,215 @@
+//! Settings that the user can change, and that are kept in flash along with the calibration
+//!
+//! `Config` is stored as a fixed-size blob inside the `storage` record, which already has a version
+//! and a CRC. Decoding checks every field on top of that, so a blob with e.g. an unknown display
+//! mode doesn't turn into a half-valid `Config`: the whole record falls back to the defaults.
+
+use crate::sample_rate::SampleRate;
+
+/// Which filter drives the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HeadingFilter {
+    /// Gyro yaw rate corrected by the tilt-compensated magnetometer heading. Cheap, but only right
+    /// while the board is held still.
+    Complementary,
+    /// Full 9-DOF orientation, updated on every timer tick. Keeps working while the board moves.
+    Madgwick,
+    /// Like `Complementary`, but weighs the gyro and magnetometer by how uncertain each one is
+    Kalman,
+}
+
+/// How the heading is shown on the LEDs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DisplayMode {
+    /// Light the one LED that's closest to the heading
+    Single,
+    /// Light two neighboring LEDs when the heading is closer to the point between them than to
+    /// either of them
+    Interpolated,
+    /// Dim the two LEDs on either side of the heading in proportion to how close they are
+    Needle,
+}
+
+impl DisplayMode {
+    /// The mode that a double tap switches to
+    pub fn next(self) -> Self {
+        match self {
+            DisplayMode::Single => DisplayMode::Interpolated,
+            DisplayMode::Interpolated => DisplayMode::Needle,
+            DisplayMode::Needle => DisplayMode::Single,
+        }
+    }
+}
+
+/// What the heading goes out as, over USART1 and USB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OutputFormat {
+    /// NMEA 0183 `HDM` and `HDT` sentences. This comes first so that it's what records from before
+    /// the setting, with a zero in its place, decode to.
+    Nmea,
+    /// The true bearing in degrees, one per line
+    Text,
+    /// A binary `telemetry` frame for every magnetometer sample, one for the settings whenever
+    /// they change, and one whenever the off-course alarm goes off or stops
+    Telemetry,
+    /// A MAVLink `HEARTBEAT` once a second, and `ATTITUDE` and `SCALED_IMU` along with every
+    /// heading, see `compass::mavlink`
+    Mavlink,
+}
+
+// How often the main loop runs, until the button or the shell's `sample` command changes it
+const SAMPLE_RATE: SampleRate = SampleRate::Hz10;
+
+// A double tap switches to the next display mode
+const DISPLAY_MODE: DisplayMode = DisplayMode::Needle;
+
+const HEADING_FILTER: HeadingFilter = HeadingFilter::Madgwick;
+
+// Gradient descent step size of the Madgwick filter
+const MADGWICK_BETA: f32 = 0.1;
+
+// The shell's `format` command changes it
+const OUTPUT_FORMAT: OutputFormat = OutputFormat::Nmea;
+
+/// The size of an encoded `Config`
+pub const CONFIG_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// How often the main loop runs
+    pub sample_rate: SampleRate,
+    /// Magnetic declination in degrees, positive east
+    pub declination_deg: f32,
+    pub display_mode: DisplayMode,
+    pub heading_filter: HeadingFilter,
+    /// Gradient descent step size of the Madgwick filter
+    pub madgwick_beta: f32,
+    pub output_format: OutputFormat,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Config {
+            sample_rate: SAMPLE_RATE,
+            declination_deg: 0.0,
+            display_mode: DISPLAY_MODE,
+            heading_filter: HEADING_FILTER,
+            madgwick_beta: MADGWICK_BETA,
+            output_format: OUTPUT_FORMAT,
+        }
+    }
+
+    /// Little-endian floats for the declination, the beta and the sample rate in Hz, then a byte
+    /// each for the display mode, the heading filter and the output format, then a byte of padding
+    pub fn encode(&self) -> [u8; CONFIG_SIZE] {
+        let mut blob = [0u8; CONFIG_SIZE];
+        blob[0..4].copy_from_slice(&self.declination_deg.to_le_bytes());
+        blob[4..8].copy_from_slice(&self.madgwick_beta.to_le_bytes());
+        blob[8..12].copy_from_slice(&self.sample_rate.hz().to_le_bytes());
+        blob[12] = self.display_mode as u8;
+        blob[13] = self.heading_filter as u8;
+        blob[14] = self.output_format as u8;
+        blob
+    }
+
+    /// The `Config` in `blob`, or `None` if any of its fields isn't valid
+    pub fn decode(blob: &[u8; CONFIG_SIZE]) -> Option<Self> {
+        let f32_at =
+            |i: usize| f32::from_le_bytes([blob[i], blob[i + 1], blob[i + 2], blob[i + 3]]);
+
+        let declination_deg = f32_at(0);
+        let madgwick_beta = f32_at(4);
+        if !(-180.0..=180.0).contains(&declination_deg)
+            || !madgwick_beta.is_finite()
+            || madgwick_beta <= 0.0
+        {
+            return None;
+        }
+        let display_mode = match blob[12] {
+            0 => DisplayMode::Single,
+            1 => DisplayMode::Interpolated,
+            2 => DisplayMode::Needle,
+            _ => return None,
+        };
+        let heading_filter = match blob[13] {
+            0 => HeadingFilter::Complementary,
+            1 => HeadingFilter::Madgwick,
+            2 => HeadingFilter::Kalman,
+            _ => return None,
+        };
+        let output_format = match blob[14] {
+            0 => OutputFormat::Nmea,
+            1 => OutputFormat::Text,
+            2 => OutputFormat::Telemetry,
+            3 => OutputFormat::Mavlink,
+            _ => return None,
+        };
+
+        Some(Config {
+            sample_rate: SampleRate::from_hz(f32_at(8))?,
+            declination_deg,
+            display_mode,
+            heading_filter,
+            madgwick_beta,
+            output_format,
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let config = Config {
+            sample_rate: SampleRate::Hz50,
+            declination_deg: -12.5,
+            display_mode: DisplayMode::Interpolated,
+            heading_filter: HeadingFilter::Kalman,
+            madgwick_beta: 0.3,
+            output_format: OutputFormat::Mavlink,
+        };
+        assert_eq!(Config::decode(&config.encode()), Some(config));
+        assert_eq!(Config::decode(&Config::new().encode()), Some(Config::new()));
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let blob = Config::new().encode();
+
+        let mut declination = blob;
+        declination[0..4].copy_from_slice(&200.0f32.to_le_bytes());
+        assert_eq!(Config::decode(&declination), None);
+
+        let mut beta = blob;
+        beta[4..8].copy_from_slice(&f32::NAN.to_le_bytes());
+        assert_eq!(Config::decode(&beta), None);
+
+        let mut sample_rate = blob;
+        sample_rate[8..12].copy_from_slice(&7.0f32.to_le_bytes());
+        assert_eq!(Config::decode(&sample_rate), None);
+
+        let mut display_mode = blob;
+        display_mode[12] = 3;
+        assert_eq!(Config::decode(&display_mode), None);
+
+        let mut heading_filter = blob;
+        heading_filter[13] = 3;
+        assert_eq!(Config::decode(&heading_filter), None);
+
+        let mut output_format = blob;
+        output_format[14] = 4;
+        assert_eq!(Config::decode(&output_format), None);
+    }
+}
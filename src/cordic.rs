@@ -0,0 +1,100 @@
+//! CORDIC arctangent on integers
+//!
+//! CORDIC finds the angle of a vector by rotating it onto the X axis in steps of atan(2^-i), which
+//! only takes shifts and adds, and adding up the steps. With the `cordic` feature, `mag_to_angle`
+//! uses this instead of the `m` crate's floating-point `atan2`.
+
+// atan(2^-i) for each step, in 1/65536ths of a degree
+const STEPS: [i32; 16] = [
+    2_949_120, 1_740_967, 919_879, 466_945, 234_379, 117_304, 58_666, 29_335, 14_668, 7_334, 3_667,
+    1_833, 917, 458, 229, 115,
+];
+
+// How far to shift the inputs up before rotating, so that the shifts in the later steps don't
+// throw away all of their bits. An i16 shifted by this much, grown by √2 and by the CORDIC gain of
+// about 1.65, still fits an i32.
+const PRESCALE: u32 = 14;
+
+const CENTIDEGREES_PER_TURN: i32 = 36_000;
+
+/// The angle of (x, y) in hundredths of a degree, in the range (-18000, 18000], like `f32::atan2`.
+/// This is accurate to about 0.01°.
+pub fn atan2(y: i16, x: i16) -> i32 {
+    if x == 0 && y == 0 {
+        return 0;
+    }
+    let (mut x, mut y) = (i32::from(x) << PRESCALE, i32::from(y) << PRESCALE);
+
+    // CORDIC only converges within 90° of the X axis, so start by turning the other half around
+    let mut angle = 0;
+    if x < 0 {
+        angle = if y >= 0 { 180 << 16 } else { -180 << 16 };
+        x = -x;
+        y = -y;
+    }
+
+    for (i, &step) in STEPS.iter().enumerate() {
+        let (dx, dy) = (y >> i, x >> i);
+        if y > 0 {
+            x += dx;
+            y -= dy;
+            angle += step;
+        } else {
+            x -= dx;
+            y += dy;
+            angle -= step;
+        }
+    }
+
+    // Round to the nearest hundredth of a degree
+    let centidegrees = ((i64::from(angle) * 100 + (1 << 15)) >> 16) as i32;
+    if centidegrees <= -CENTIDEGREES_PER_TURN / 2 {
+        centidegrees + CENTIDEGREES_PER_TURN
+    } else {
+        centidegrees
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn float_atan2(y: i16, x: i16) -> f32 {
+        libm::atan2f(f32::from(y), f32::from(x)).to_degrees()
+    }
+
+    #[test]
+    fn axes() {
+        assert_eq!(atan2(0, 1000), 0);
+        assert_eq!(atan2(1000, 0), 9000);
+        assert_eq!(atan2(0, -1000), 18000);
+        assert_eq!(atan2(-1000, 0), -9000);
+        assert_eq!(atan2(0, 0), 0);
+    }
+
+    #[test]
+    fn close_to_floating_point_all_the_way_around() {
+        for tenths in -1799..=1800 {
+            let (sin, cos) = libm::sincosf((tenths as f32 / 10.0).to_radians());
+            let (y, x) = ((sin * 600.0) as i16, (cos * 600.0) as i16);
+            let error = atan2(y, x) as f32 / 100.0 - float_atan2(y, x);
+            let error = crate::fusion::wrap_degrees(error).abs();
+            assert!(error <= 0.015, "({}, {}): off by {}°", x, y, error);
+        }
+    }
+
+    #[test]
+    fn extremes_dont_overflow() {
+        for &(y, x) in &[
+            (i16::MAX, i16::MAX),
+            (i16::MIN, i16::MIN),
+            (i16::MIN, i16::MAX),
+            (i16::MAX, i16::MIN),
+            (1, i16::MIN),
+            (-1, i16::MIN),
+        ] {
+            let error = atan2(y, x) as f32 / 100.0 - float_atan2(y, x);
+            assert!(crate::fusion::wrap_degrees(error).abs() <= 0.015);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Dead reckoning: estimating where we are from the heading and the speed
+//!
+//! The position is in meters east and north of where we started, or of where it was last reset.
+//! Each update moves it along the unit vector of the bearing, so its length only depends on the
+//! speed model, never on the strength of the magnetic field.
+//!
+//! There's no way to measure speed, so it comes from a `SpeedModel`: either a constant speed, or a
+//! fixed stride for every step that the pedometer counts. Errors in the distance and the heading add
+//! up over time, so the estimate is only good for short distances.
+
+use crate::trig;
+
+/// Where the speed comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpeedModel {
+    /// Always moving at this many meters per second
+    Constant(f32),
+    /// Moving this many meters with every step, and not at all between steps
+    Stride(f32),
+}
+
+/// Position in meters, as (east, north)
+pub type Position = (f32, f32);
+
+pub struct DeadReckoning {
+    position: Position,
+    speed_model: SpeedModel,
+}
+
+impl DeadReckoning {
+    pub fn new(speed_model: SpeedModel) -> Self {
+        DeadReckoning {
+            position: (0.0, 0.0),
+            speed_model,
+        }
+    }
+
+    pub fn speed_model(&self) -> SpeedModel {
+        self.speed_model
+    }
+
+    pub fn set_speed_model(&mut self, speed_model: SpeedModel) {
+        self.speed_model = speed_model;
+    }
+
+    fn advance(&mut self, bearing: f32, distance: f32) {
+        let (east, north) = trig::sin_cos(bearing.to_radians());
+        self.position = (
+            self.position.0 + east * distance,
+            self.position.1 + north * distance,
+        );
+    }
+
+    /// Move on for `dt_s` seconds toward `bearing`, in degrees clockwise from north. This only
+    /// moves with a constant speed.
+    pub fn update(&mut self, bearing: f32, dt_s: f32) {
+        if let SpeedModel::Constant(speed) = self.speed_model {
+            self.advance(bearing, speed * dt_s);
+        }
+    }
+
+    /// Take a step toward `bearing`, in degrees clockwise from north. This only moves with a
+    /// stride.
+    pub fn step(&mut self, bearing: f32) {
+        if let SpeedModel::Stride(stride) = self.speed_model {
+            self.advance(bearing, stride);
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Make the current position the origin
+    pub fn reset(&mut self) {
+        self.position = (0.0, 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Position, expected: Position) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn starts_at_origin() {
+        let dead_reckoning = DeadReckoning::new(SpeedModel::Constant(1.0));
+        assert_eq!(dead_reckoning.position(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn moves_along_bearing() {
+        for &(bearing, expected) in &[
+            (0.0, (0.0, 2.0)),
+            (90.0, (2.0, 0.0)),
+            (180.0, (0.0, -2.0)),
+            (270.0, (-2.0, 0.0)),
+            (-90.0, (-2.0, 0.0)),
+        ] {
+            let mut dead_reckoning = DeadReckoning::new(SpeedModel::Constant(2.0));
+            dead_reckoning.update(bearing, 1.0);
+            assert_close(dead_reckoning.position(), expected);
+        }
+    }
+
+    #[test]
+    fn step_length_is_speed_times_time() {
+        let mut dead_reckoning = DeadReckoning::new(SpeedModel::Constant(1.5));
+        dead_reckoning.update(37.0, 0.1);
+        let (east, north) = dead_reckoning.position();
+        assert!((libm::hypotf(east, north) - 0.15).abs() < 1e-6);
+    }
+
+    #[test]
+    fn updates_add_up() {
+        let mut dead_reckoning = DeadReckoning::new(SpeedModel::Constant(1.0));
+        for _ in 0..10 {
+            dead_reckoning.update(0.0, 0.1);
+        }
+        for _ in 0..10 {
+            dead_reckoning.update(90.0, 0.1);
+        }
+        assert_close(dead_reckoning.position(), (1.0, 1.0));
+    }
+
+    #[test]
+    fn speed_model_can_change() {
+        let mut dead_reckoning = DeadReckoning::new(SpeedModel::Constant(1.0));
+        dead_reckoning.update(0.0, 1.0);
+        dead_reckoning.set_speed_model(SpeedModel::Constant(3.0));
+        assert_eq!(dead_reckoning.speed_model(), SpeedModel::Constant(3.0));
+        dead_reckoning.update(0.0, 1.0);
+        assert_close(dead_reckoning.position(), (0.0, 4.0));
+    }
+
+    #[test]
+    fn stride_moves_only_on_steps() {
+        let mut dead_reckoning = DeadReckoning::new(SpeedModel::Stride(0.75));
+        dead_reckoning.update(0.0, 10.0);
+        assert_eq!(dead_reckoning.position(), (0.0, 0.0));
+        dead_reckoning.step(0.0);
+        dead_reckoning.step(90.0);
+        assert_close(dead_reckoning.position(), (0.75, 0.75));
+    }
+
+    #[test]
+    fn constant_speed_ignores_steps() {
+        let mut dead_reckoning = DeadReckoning::new(SpeedModel::Constant(1.0));
+        dead_reckoning.step(0.0);
+        assert_eq!(dead_reckoning.position(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn reset_returns_to_origin() {
+        let mut dead_reckoning = DeadReckoning::new(SpeedModel::Constant(1.0));
+        dead_reckoning.update(45.0, 3.0);
+        dead_reckoning.reset();
+        assert_eq!(dead_reckoning.position(), (0.0, 0.0));
+        dead_reckoning.update(180.0, 1.0);
+        assert_close(dead_reckoning.position(), (0.0, -1.0));
+    }
+}
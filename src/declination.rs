@@ -0,0 +1,213 @@
+//! Magnetic declination: the angle between magnetic north and true north
+//!
+//! Declination depends on where you are (and slowly changes over the years), so it can't be a
+//! constant. Double-pressing the user button cycles through a list of presets, which is coarse but
+//! doesn't need anything other than the board. Look up the declination for your location, e.g.
+//! from NOAA's magnetic field calculator, and pick the closest preset.
+//!
+//! Given a position, `field_at` works the whole field out from the first six degrees of the
+//! International Geomagnetic Reference Field (IGRF-13), with its secular variation, and `at` takes
+//! the declination from that. The higher
+//! degrees, which it leaves out, mostly describe the crust rather than the core, and leaving them
+//! out costs about 2° at worst. It treats the Earth as a sphere, which costs much less than that.
+//! Near the poles, where the field points almost straight down, the declination isn't much use
+//! anyway.
+
+use crate::trig;
+
+/// Declination presets in degrees, positive when magnetic north is east of true north
+const PRESETS: [f32; 9] = [0.0, 5.0, 10.0, 15.0, 20.0, -20.0, -15.0, -10.0, -5.0];
+
+/// The preset after `declination_deg`. If `declination_deg` isn't a preset (e.g. because it came
+/// from an older version of the list), start over at the first one.
+pub fn next_preset(declination_deg: f32) -> f32 {
+    PRESETS
+        .iter()
+        .position(|&preset| preset == declination_deg)
+        .map(|index| PRESETS[(index + 1) % PRESETS.len()])
+        .unwrap_or(PRESETS[0])
+}
+
+// The year that the coefficients are for
+const MODEL_EPOCH: f32 = 2020.0;
+
+const MODEL_DEGREE: usize = 6;
+
+/// IGRF-13 coefficients in nT, and their rates of change in nT per year: (n, m, g, h, ġ, ḣ)
+#[rustfmt::skip]
+const COEFFICIENTS: [(usize, usize, f32, f32, f32, f32); 27] = [
+    (1, 0, -29404.8, 0.0, 5.7, 0.0),
+    (1, 1, -1450.9, 4652.5, 7.4, -25.9),
+    (2, 0, -2499.6, 0.0, -11.0, 0.0),
+    (2, 1, 2982.0, -2991.6, -7.0, -30.2),
+    (2, 2, 1677.0, -734.6, -2.1, -22.4),
+    (3, 0, 1363.2, 0.0, 2.2, 0.0),
+    (3, 1, -2381.2, -82.1, -5.9, 6.0),
+    (3, 2, 1236.2, 241.9, 3.1, -1.1),
+    (3, 3, 525.7, -543.4, -12.0, 0.5),
+    (4, 0, 903.0, 0.0, -1.2, 0.0),
+    (4, 1, 809.5, 281.9, -1.6, -0.1),
+    (4, 2, 86.3, -158.4, -5.9, 6.5),
+    (4, 3, -309.4, 199.7, 5.2, 3.6),
+    (4, 4, 48.0, -349.7, -5.1, -5.0),
+    (5, 0, -234.3, 0.0, -0.3, 0.0),
+    (5, 1, 363.2, 47.7, 0.5, 0.0),
+    (5, 2, 187.8, 208.3, -0.6, 2.5),
+    (5, 3, -140.7, -121.2, 0.2, -0.6),
+    (5, 4, -151.2, 32.3, 1.3, 3.0),
+    (5, 5, 13.5, 98.9, 0.9, 0.3),
+    (6, 0, 66.0, 0.0, -0.5, 0.0),
+    (6, 1, 65.5, -19.1, -0.3, 0.0),
+    (6, 2, 72.9, 25.1, 0.4, -1.6),
+    (6, 3, -121.5, 52.8, 1.3, -1.3),
+    (6, 4, -36.2, -64.5, -1.4, 0.8),
+    (6, 5, 13.5, 8.9, 0.0, 0.0),
+    (6, 6, -64.7, 68.1, 0.9, 1.0),
+];
+
+// Closer to the poles than this, the latitude is clamped, since the east component of the field
+// divides by the distance from the axis
+const MAX_LATITUDE_DEG: f32 = 89.9;
+
+/// The Earth's main field at a point on the surface, in nT
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Field {
+    pub north_nt: f32,
+    pub east_nt: f32,
+    pub down_nt: f32,
+}
+
+impl Field {
+    /// Degrees, positive when magnetic north is east of true north
+    pub fn declination_deg(&self) -> f32 {
+        trig::atan2(self.east_nt, self.north_nt).to_degrees()
+    }
+
+    /// How steeply the field points into the ground (the dip) in degrees, positive when it points
+    /// down, as it does in the northern hemisphere
+    pub fn inclination_deg(&self) -> f32 {
+        let horizontal = trig::sqrt(self.north_nt * self.north_nt + self.east_nt * self.east_nt);
+        trig::atan2(self.down_nt, horizontal).to_degrees()
+    }
+
+    /// The strength of the field in gauss, which is 100,000 nT
+    pub fn intensity_gauss(&self) -> f32 {
+        let Field {
+            north_nt,
+            east_nt,
+            down_nt,
+        } = *self;
+        trig::sqrt(north_nt * north_nt + east_nt * east_nt + down_nt * down_nt) / 100_000.0
+    }
+}
+
+/// The field at a position on the surface, in degrees north and east, in a decimal year like
+/// 2026.5
+pub fn field_at(latitude_deg: f32, longitude_deg: f32, year: f32) -> Field {
+    const SIZE: usize = MODEL_DEGREE + 1;
+    let colatitude = (90.0 - latitude_deg.clamp(-MAX_LATITUDE_DEG, MAX_LATITUDE_DEG)).to_radians();
+    let (sin_theta, cos_theta) = trig::sin_cos(colatitude);
+
+    // Associated Legendre functions of cos θ and their derivatives by θ, first Gauss-normalized,
+    // which have simple recurrences, and then scaled to the Schmidt semi-normalization that the
+    // coefficients use
+    let mut p = [[0.0; SIZE]; SIZE];
+    let mut dp = [[0.0; SIZE]; SIZE];
+    let mut schmidt = [[0.0; SIZE]; SIZE];
+    p[0][0] = 1.0;
+    schmidt[0][0] = 1.0;
+    for n in 1..SIZE {
+        for m in 0..=n {
+            if m == n {
+                p[n][n] = sin_theta * p[n - 1][n - 1];
+                dp[n][n] = sin_theta * dp[n - 1][n - 1] + cos_theta * p[n - 1][n - 1];
+            } else {
+                let (p2, dp2, k) = if n >= 2 {
+                    let k = ((n - 1) * (n - 1) - m * m) as f32 / ((2 * n - 1) * (2 * n - 3)) as f32;
+                    (p[n - 2][m], dp[n - 2][m], k)
+                } else {
+                    (0.0, 0.0, 0.0)
+                };
+                p[n][m] = cos_theta * p[n - 1][m] - k * p2;
+                dp[n][m] = cos_theta * dp[n - 1][m] - sin_theta * p[n - 1][m] - k * dp2;
+            }
+            schmidt[n][m] = if m == 0 {
+                schmidt[n - 1][0] * (2 * n - 1) as f32 / n as f32
+            } else {
+                let double = if m == 1 { 2 } else { 1 };
+                schmidt[n][m - 1] * trig::sqrt(((n - m + 1) * double) as f32 / (n + m) as f32)
+            };
+        }
+    }
+
+    // The field is minus the gradient of the potential, which on the surface makes each term's
+    // down component -(n + 1) times the potential itself
+    let years = year - MODEL_EPOCH;
+    let longitude = longitude_deg.to_radians();
+    let (mut north, mut east, mut down) = (0.0, 0.0, 0.0);
+    for &(n, m, g, h, g_rate, h_rate) in COEFFICIENTS.iter() {
+        let g = (g + g_rate * years) * schmidt[n][m];
+        let h = (h + h_rate * years) * schmidt[n][m];
+        let (sin_m, cos_m) = trig::sin_cos(m as f32 * longitude);
+        north += (g * cos_m + h * sin_m) * dp[n][m];
+        east += m as f32 * (g * sin_m - h * cos_m) * p[n][m] / sin_theta;
+        down -= (n + 1) as f32 * (g * cos_m + h * sin_m) * p[n][m];
+    }
+    Field {
+        north_nt: north,
+        east_nt: east,
+        down_nt: down,
+    }
+}
+
+/// The declination in degrees at a position on the surface, in degrees north and east, in a
+/// decimal year like 2026.5
+pub fn at(latitude_deg: f32, longitude_deg: f32, year: f32) -> f32 {
+    field_at(latitude_deg, longitude_deg, year).declination_deg()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_published_declinations() {
+        // NOAA's values for 2020, which come from the full model
+        for &(latitude, longitude, expected) in &[
+            (40.0, -105.3, 8.2),
+            (51.5, -0.13, 0.3),
+            (-33.87, 151.2, 12.8),
+            (35.68, 139.7, -7.6),
+            (40.7, -74.0, -12.9),
+        ] {
+            let declination = at(latitude, longitude, 2020.0);
+            assert!((declination - expected).abs() < 1.0, "{}", declination);
+        }
+        // Boulder's declination shrinks by about 0.1° a year
+        assert!((at(40.0, -105.3, 2025.0) - 7.7).abs() < 0.1);
+        assert!(at(90.0, 0.0, 2020.0).is_finite());
+    }
+
+    #[test]
+    fn matches_published_intensities_and_inclinations() {
+        // NOAA's values for 2020: latitude, longitude, gauss, degrees
+        for &(latitude, longitude, intensity, inclination) in &[
+            (40.0, -105.3, 0.5196, 65.9),
+            (51.5, -0.13, 0.4884, 66.4),
+            (-33.87, 151.2, 0.5728, -64.5),
+        ] {
+            let field = field_at(latitude, longitude, 2020.0);
+            assert!(
+                (field.intensity_gauss() / intensity - 1.0).abs() < 0.03,
+                "{:?}",
+                field
+            );
+            assert!(
+                (field.inclination_deg() - inclination).abs() < 1.5,
+                "{:?}",
+                field
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Async delays on the basic timers
+//!
+//! TIM6 and TIM7 are identical basic timers, so `Delay` works with any `BasicTimer`. The timer
+//! counts in one-pulse mode and its update interrupt wakes the waiting future. Only one delay can
+//! run on a timer at a time, so this is for timing that needs a finer resolution than
+//! `clock::sleep`, which any number of tasks can use at once. TIM7 counts microseconds for bus
+//! timing, and TIM6 is free.
+//!
+//! Waking up takes a few hundred cycles, so a microsecond delay can run a little long, but never short.
+
+use crate::clocks;
+use crate::wakers;
+use core::marker::PhantomData;
+use f3::hal::stm32f30x::{rcc, tim6, RCC, TIM7};
+use futures::task::AtomicWaker;
+
+/// A basic timer that can back a `Delay`
+pub trait BasicTimer {
+    /// The timer's registers. TIM7 has the same layout as TIM6.
+    fn registers() -> &'static tim6::RegisterBlock;
+
+    /// Woken by the timer's update interrupt
+    fn waker() -> &'static AtomicWaker;
+
+    /// Power on the timer
+    fn enable(rcc: &rcc::RegisterBlock);
+}
+
+pub struct Tim7;
+
+impl BasicTimer for Tim7 {
+    fn registers() -> &'static tim6::RegisterBlock {
+        unsafe { &*TIM7::ptr() }
+    }
+
+    fn waker() -> &'static AtomicWaker {
+        &wakers::TIM7_UP
+    }
+
+    fn enable(rcc: &rcc::RegisterBlock) {
+        rcc.apb1enr.modify(|_, w| w.tim7en().set_bit());
+    }
+}
+
+/// Waits on timer `T`. Only one delay can run on a timer at a time.
+pub struct Delay<T> {
+    _timer: PhantomData<T>,
+}
+
+// Derived `Clone` and `Copy` would require `T` to be `Clone` and `Copy` as well
+impl<T> Clone for Delay<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Delay<T> {}
+
+impl<T: BasicTimer> Delay<T> {
+    /// Power on the timer and make it count at `hz`, which must divide the 72 MHz timer clock
+    /// into at most 65536 steps
+    fn new(hz: u32) -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let tim = T::registers();
+
+        T::enable(rcc);
+
+        // OPM Select one pulse mode
+        // CEN Keep the counter disabled for now
+        tim.cr1.write(|w| w.opm().set_bit().cen().clear_bit());
+
+        // PSC = APB1_TIMER_CLOCK / hz - 1
+        tim.psc
+            .write(|w| w.psc().bits((clocks::APB1_TIMER_HZ / hz - 1) as u16));
+
+        // The prescaler only takes effect at the next update event, so generate one now rather
+        // than letting the first delay run at the full clock rate
+        tim.egr.write(|w| w.ug().set_bit());
+        tim.sr.modify(|_, w| w.uif().clear_bit());
+
+        Delay {
+            _timer: PhantomData,
+        }
+    }
+
+    /// Wait for `ticks` counts of the timer
+    pub async fn ticks(&self, ticks: u16) {
+        if ticks == 0 {
+            return;
+        }
+        let tim = T::registers();
+
+        // set timer to go off in `ticks` counts
+        tim.arr.write(|w| w.arr().bits(ticks));
+
+        // CEN: enable the counter
+        tim.cr1.modify(|_, w| w.cen().set_bit());
+
+        wakers::wait_for(
+            T::waker(),
+            || tim.sr.read().uif().bit_is_set(),
+            || tim.dier.modify(|_, w| w.uie().set_bit()),
+        )
+        .await;
+
+        // clear the update event flag
+        tim.sr.modify(|_, w| w.uif().clear_bit());
+    }
+}
+
+impl Delay<Tim7> {
+    /// Count microseconds on TIM7
+    pub fn micros() -> Self {
+        Delay::new(1_000_000)
+    }
+
+    pub async fn delay_us(&self, us: u16) {
+        self.ticks(us).await
+    }
+}
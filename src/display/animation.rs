@@ -0,0 +1,171 @@
+//! Short animations on the ring of LEDs
+//!
+//! An animation is a function of the time since it started. The main loop asks the `Animator` for a
+//! frame on every tick, and shows the heading only when no animation is playing. Animations have
+//! priorities, so that e.g. a mode change can't hide an error: a new animation only replaces the
+//! one that's playing if it's at least as important.
+//!
+//! Errors blink the North and South LEDs a number of times in a row, then pause:
+//!
+//! 1. the magnetometer failed its self-test
+//! 2. reading a sensor failed
+//!
+//! Like the fault codes, that can't be mistaken for a heading, which never lights two opposite LEDs.
+//! Neither can the East and West LEDs blinking together once a second, which means that there's no
+//! magnetometer, so there's no heading at all until it comes back.
+
+use super::MAX;
+use compass::heading::Direction;
+
+// The startup spinner goes around twice, one LED at a time
+const SPIN_STEP_MS: u32 = 60;
+const SPIN_TURNS: u32 = 2;
+
+// The calibration sweep goes around once a second
+const SWEEP_STEP_MS: u32 = 125;
+
+// How long each blink of an error code is on and off, and the pause after the last one
+const ERROR_BLINK_MS: u32 = 200;
+const ERROR_PAUSE_MS: u32 = 1_000;
+
+// How long the East and West LEDs are on and off while there's no magnetometer
+const NO_MAGNETOMETER_BLINK_MS: u32 = 500;
+
+// How long a mode change is shown
+const MODE_FLASH_MS: u32 = 500;
+
+// The demo's needle goes around once every four seconds
+const DEMO_TURN_MS: u32 = 4_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    Normal,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Animation {
+    /// A light that spins around the ring while the compass starts up
+    Startup,
+    /// A light that sweeps around the ring until calibration finishes
+    Calibration,
+    /// One round of an error code, see the module documentation
+    Error(u8),
+    /// Light the first `n` LEDs clockwise from North, for the `n`th mode
+    ModeChange(u8),
+    /// A needle that turns smoothly around the ring until demo mode ends
+    Demo,
+    /// East and West blinking until the magnetometer is back, see the module documentation
+    NoMagnetometer,
+}
+
+impl Animation {
+    pub fn priority(self) -> Priority {
+        match self {
+            Animation::Error(_) | Animation::NoMagnetometer => Priority::Error,
+            _ => Priority::Normal,
+        }
+    }
+
+    /// How long the animation plays, in ms, or `None` if it plays until it's stopped
+    pub fn duration_ms(self) -> Option<u32> {
+        match self {
+            Animation::Startup => Some(SPIN_STEP_MS * 8 * SPIN_TURNS),
+            Animation::Calibration | Animation::Demo | Animation::NoMagnetometer => None,
+            Animation::Error(code) => Some(u32::from(code) * 2 * ERROR_BLINK_MS + ERROR_PAUSE_MS),
+            Animation::ModeChange(_) => Some(MODE_FLASH_MS),
+        }
+    }
+
+    /// What the LEDs show `elapsed_ms` after the animation started
+    pub fn frame(self, elapsed_ms: u32) -> [u8; 8] {
+        let mut brightness = [0; 8];
+        match self {
+            Animation::Startup => comet(&mut brightness, elapsed_ms / SPIN_STEP_MS),
+            Animation::Calibration => comet(&mut brightness, elapsed_ms / SWEEP_STEP_MS),
+            Animation::Error(code) => {
+                let blink = elapsed_ms / (2 * ERROR_BLINK_MS);
+                let off = elapsed_ms / ERROR_BLINK_MS % 2 == 1;
+                if blink < u32::from(code) && !off {
+                    brightness[Direction::North as usize] = MAX;
+                    brightness[Direction::South as usize] = MAX;
+                }
+            }
+            Animation::ModeChange(n) => {
+                for value in brightness.iter_mut().take(usize::from(n)) {
+                    *value = MAX;
+                }
+            }
+            Animation::Demo => {
+                let turn = elapsed_ms % DEMO_TURN_MS;
+                brightness = super::needle(turn as f32 * 360.0 / DEMO_TURN_MS as f32);
+            }
+            Animation::NoMagnetometer => {
+                let off = elapsed_ms / NO_MAGNETOMETER_BLINK_MS % 2 == 1;
+                if !off {
+                    brightness[Direction::East as usize] = MAX;
+                    brightness[Direction::West as usize] = MAX;
+                }
+            }
+        }
+        brightness
+    }
+}
+
+/// Light the LED at `step` clockwise from North, with a dimmer tail behind it
+fn comet(brightness: &mut [u8; 8], step: u32) {
+    let head = step as usize % 8;
+    brightness[head] = MAX;
+    brightness[(head + 7) % 8] = MAX / 4;
+}
+
+/// Plays one animation at a time
+pub struct Animator {
+    // The animation that's playing, and when it started in ms
+    playing: Option<(Animation, u64)>,
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Animator { playing: None }
+    }
+
+    /// Start `animation` at `now_ms`, unless something more important is playing. If `animation`
+    /// is already playing, it carries on rather than starting over.
+    pub fn play(&mut self, animation: Animation, now_ms: u64) {
+        match self.playing {
+            Some((playing, _)) if playing == animation => {}
+            Some((playing, _)) if playing.priority() > animation.priority() => {}
+            _ => self.playing = Some((animation, now_ms)),
+        }
+    }
+
+    /// Stop `animation` if it's playing
+    pub fn stop(&mut self, animation: Animation) {
+        if let Some((playing, _)) = self.playing {
+            if playing == animation {
+                self.playing = None;
+            }
+        }
+    }
+
+    /// The animation that's playing, if it hasn't finished by the last frame
+    pub fn playing(&self) -> Option<Animation> {
+        self.playing.map(|(animation, _)| animation)
+    }
+
+    /// What the LEDs show at `now_ms`, or `None` if no animation is playing
+    pub fn frame(&mut self, now_ms: u64) -> Option<[u8; 8]> {
+        let (animation, started_ms) = self.playing?;
+        let elapsed_ms = now_ms.saturating_sub(started_ms) as u32;
+        match animation.duration_ms() {
+            Some(duration_ms) if elapsed_ms >= duration_ms => {
+                self.playing = None;
+                None
+            }
+            _ => Some(animation.frame(elapsed_ms)),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Showing the heading on the ring of LEDs
+//!
+//! With only 8 LEDs, a single LED can only point in 8 directions. Lighting two neighboring LEDs
+//! shows the point halfway between them, which doubles the resolution to 16 points. With PWM we can
+//! go further and light the two LEDs on either side of the heading in proportion to how close they
+//! are, which makes the needle move smoothly instead of jumping.
+//!
+//! An SSD1306 OLED on the I2C bus can show the heading as well, see `oled`, and so can a ring of
+//! WS2812 LEDs, see `ws2812`. Startup, calibration, errors and mode changes are shown with
+//! `animation`s instead of the heading.
+//!
+//! Each kind of display implements `CompassDisplay`, and the display task hands the main loop's
+//! views to every one of them, so the sensor pipeline doesn't know what's showing its results. The
+//! board's LEDs are dimmed with PWM (`pwm`), except on boards where `ring` just switches them.
+
+use compass::fusion::wrap_degrees;
+use compass::heading::mag_to_angle;
+use compass::modes::Mode;
+use compass::trig;
+
+pub mod animation;
+pub mod oled;
+#[cfg(not(feature = "nucleo-f303ze"))]
+pub mod pwm;
+#[cfg(feature = "nucleo-f303ze")]
+pub mod ring;
+pub mod ssd1306;
+pub mod ws2812;
+
+/// What drives the board's LEDs
+#[cfg(not(feature = "nucleo-f303ze"))]
+pub type BoardLeds = pwm::Pwm;
+#[cfg(feature = "nucleo-f303ze")]
+pub type BoardLeds = ring::LedRing;
+
+/// Brightness of a fully lit LED
+pub const MAX: u8 = 16;
+
+/// Something that shows what the compass is doing
+pub trait CompassDisplay {
+    /// The bearing in degrees clockwise from north, or `None` while the heading isn't being shown
+    fn set_heading(&mut self, bearing: Option<f32>);
+
+    /// The code of the error to show, like `Animation::Error`, or `None` once it's over
+    fn set_error(&mut self, code: Option<u8>);
+
+    fn set_mode(&mut self, mode: Mode);
+
+    /// The brightness of each LED of a ring of 8, from 0 to `MAX`, in the same order as `Leds`.
+    /// The main loop works these frames out from everything that it shows on a ring, including the
+    /// heading in the style of `Config::display_mode`, so displays that are a ring show everything
+    /// with them. Other displays ignore them.
+    fn set_leds(&mut self, _brightness: [u8; 8]) {}
+}
+
+// Angles are counted like in `angle_to_direction`: 0° lights the South LED and 180° the North LED,
+// going through East. This is the index in `Leds` of the South LED.
+const SOUTH: usize = 4;
+
+/// Index in `Leds` of the LED at `eighth` * 45°. `Leds` goes the other way around the ring from our
+/// angles, so this also works the other way around.
+fn led(eighth: usize) -> usize {
+    (SOUTH + 8 - eighth % 8) % 8
+}
+
+/// One of the 16 points of the compass, 22.5° apart. Even points fall on an LED, and odd points
+/// fall halfway between two of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompassPoint(u8);
+
+impl CompassPoint {
+    /// The point closest to `angle`, which must be in degrees in the range [0, 360)
+    pub fn from_angle(angle: f32) -> Self {
+        let point = ((angle + 11.25) / 22.5) as u8;
+        CompassPoint(point % 16)
+    }
+
+    /// Light the LED that shows this point at full brightness, plus its neighbor if the point is
+    /// between two LEDs
+    pub fn brightness(self) -> [u8; 8] {
+        let mut brightness = [0; 8];
+        let eighth = usize::from(self.0 / 2);
+        brightness[led(eighth)] = MAX;
+        if self.0 % 2 == 1 {
+            brightness[led(eighth + 1)] = MAX;
+        }
+        brightness
+    }
+}
+
+/// A needle pointing at `angle` in degrees. The LEDs within 45° of `angle` are lit in proportion to
+/// how close they are to it.
+pub fn needle(angle: f32) -> [u8; 8] {
+    let mut brightness = [0; 8];
+    for (index, value) in brightness.iter_mut().enumerate() {
+        let led_angle = (led(index) * 45) as f32;
+        let distance = wrap_degrees(angle - led_angle).abs();
+        if distance < 45.0 {
+            *value = ((1.0 - distance / 45.0) * f32::from(MAX) + 0.5) as u8;
+        }
+    }
+    brightness
+}
+
+// In `deviation`, how many degrees off the target each LED stands for
+const DEGREES_PER_LED: f32 = 30.0;
+
+/// How far the heading is off a target, for steering. `deviation` is in degrees, positive when the
+/// heading is clockwise of the target. Within `deadband` degrees of the target only the North LED is
+/// lit. Further off, LEDs light up from the North LED toward the side to turn to, one LED per
+/// `DEGREES_PER_LED` beyond the deadband, with the last one dimmed to show fractions of an LED.
+pub fn deviation(deviation: f32, deadband: f32) -> [u8; 8] {
+    let mut brightness = [0; 8];
+    if deviation.abs() <= deadband {
+        brightness[0] = MAX;
+        return brightness;
+    }
+
+    let max = usize::from(MAX);
+    let lit = ((deviation.abs() - deadband) / DEGREES_PER_LED * f32::from(MAX) + 0.5) as usize;
+    let lit = lit.clamp(1, 3 * max);
+    for step in 0..3 {
+        // Indices go clockwise from the North LED
+        let index = if deviation > 0.0 { 7 - step } else { 1 + step };
+        brightness[index] = lit.saturating_sub(step * max).min(max) as u8;
+    }
+    brightness
+}
+
+// In `turn`, how many degrees per second each LED stands for
+const DEGREES_PER_SECOND_PER_LED: f32 = 5.0;
+
+/// A turn indicator for a rate of turn in degrees per second, positive clockwise. LEDs light up
+/// from the North LED toward the side that we're turning to, one LED per
+/// `DEGREES_PER_SECOND_PER_LED`. Only the North LED is lit while turning slower than `deadband`.
+pub fn turn(rate_dps: f32, deadband: f32) -> [u8; 8] {
+    // `deviation` lights the side to turn back to, which is the other way around
+    let scale = DEGREES_PER_LED / DEGREES_PER_SECOND_PER_LED;
+    deviation(-rate_dps * scale, deadband * scale)
+}
+
+/// A bubble level for a tilt of `pitch_deg` and `roll_deg`, as `Tilt` measures them. Within
+/// `deadband` degrees of level every LED is lit. Further off, a needle points to the highest side of
+/// the board, where the bubble would float to.
+pub fn level(pitch_deg: f32, roll_deg: f32, deadband: f32) -> [u8; 8] {
+    // The part of the up vector in the board's plane, which points uphill and is as long as the
+    // sine of the tilt
+    let (sin_pitch, cos_pitch) = trig::sin_cos(pitch_deg.to_radians());
+    let (sin_roll, cos_roll) = trig::sin_cos(roll_deg.to_radians());
+    let uphill = (-sin_pitch, cos_pitch * sin_roll);
+    let horizontal = trig::sqrt(uphill.0 * uphill.0 + uphill.1 * uphill.1);
+    let tilt = trig::atan2(horizontal, cos_pitch * cos_roll).to_degrees();
+    if tilt <= deadband {
+        return [MAX; 8];
+    }
+    // Board directions map onto the ring the same way as the magnetic field does
+    let angle = mag_to_angle((uphill.0, uphill.1, 0.0), 0.0);
+    needle((angle + 360.0) % 360.0)
+}
+
+// How long one breath takes with no quality at all, and with almost enough
+const SLOWEST_BREATH_MS: f32 = 3_000.0;
+const FASTEST_BREATH_MS: f32 = 500.0;
+
+/// The brightness of an LED that breathes, `now_ms` into an endless series of breaths. The higher
+/// `quality` gets, the faster the breaths, until at 1 the LED stays on.
+pub fn breathing(quality: f32, now_ms: u64) -> u8 {
+    if quality >= 1.0 {
+        return MAX;
+    }
+    let period_ms = SLOWEST_BREATH_MS - quality.max(0.0) * (SLOWEST_BREATH_MS - FASTEST_BREATH_MS);
+    // Triangle wave from off to on and back
+    let phase = (now_ms as f32 % period_ms) / period_ms;
+    let level = 1.0 - (2.0 * phase - 1.0).abs();
+    (level * f32::from(MAX) + 0.5) as u8
+}
+
+/// A bar graph around the ring, starting at the North LED and going clockwise. `level` is the
+/// fraction of the ring to light, and the last LED is dimmed to show fractions of an LED.
+pub fn bar(level: f32) -> [u8; 8] {
+    let mut brightness = [0; 8];
+    // How many PWM steps to light in total, with `MAX` steps per LED
+    let lit = (level.clamp(0.0, 1.0) * 8.0 * f32::from(MAX) + 0.5) as usize;
+    for (index, value) in brightness.iter_mut().enumerate() {
+        let level = lit.saturating_sub(index * usize::from(MAX));
+        *value = level.min(usize::from(MAX)) as u8;
+    }
+    brightness
+}
+
+/// One LED for one of eight bands, counting clockwise from the North LED. `level` is how bright to
+/// make it, as a fraction of full brightness, and it's never dimmed all the way to off.
+pub fn band(band: usize, level: f32) -> [u8; 8] {
+    let mut brightness = [0; 8];
+    let value = (level.clamp(0.0, 1.0) * f32::from(MAX) + 0.5) as u8;
+    brightness[band.min(7)] = value.max(1);
+    brightness
+}
@@ -0,0 +1,200 @@
+//! Showing the heading on an SSD1306 OLED, in addition to the LEDs
+//!
+//! The left half of the display has the bearing in degrees and the nearest of the 8 compass
+//! points. The right half has a compass rose that turns with the board, so that its N always points
+//! north, with the top of the display being the direction that the board points in. In the modes
+//! that don't show the heading, the bearing is replaced with the name of the mode, and while
+//! there's an error its code is in the bottom left corner.
+//!
+//! Sending a frame takes a while even at 400 kHz, so the main loop only says what to show and a
+//! separate task redraws the display whenever that changes.
+
+use crate::display::ssd1306::{FrameBuffer, Ssd1306};
+use crate::display::CompassDisplay;
+use crate::i2c::{I2cDevice, I2cError};
+use compass::modes::Mode;
+use compass::trig;
+use core::cell::Cell;
+use core::task::Poll;
+use futures::future::poll_fn;
+use futures::task::AtomicWaker;
+
+const POINTS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+
+// The compass rose
+const ROSE_CENTER: (i32, i32) = (96, 32);
+const ROSE_RADIUS: i32 = 30;
+// Where the centers of the letters go
+const LABEL_RADIUS: f32 = 22.0;
+// How far the needle reaches, short of the N
+const NEEDLE_RADIUS: f32 = 16.0;
+
+/// What's on the display
+#[derive(Clone, Copy, PartialEq)]
+struct Screen {
+    /// The bearing in whole degrees
+    bearing: Option<u16>,
+    mode: Mode,
+    error: Option<u8>,
+}
+
+/// What the display shows instead of the bearing in `mode`, if it doesn't show one
+fn label(mode: Mode) -> Option<&'static str> {
+    match mode {
+        Mode::Compass => None,
+        Mode::TargetHold => Some("HOLD"),
+        Mode::TurnIndicator => Some("TURN"),
+        Mode::MetalDetector => Some("METAL"),
+        Mode::Level => Some("LEVEL"),
+        Mode::Vibration => Some("VIBE"),
+        Mode::Demo => Some("DEMO"),
+        Mode::Calibration => Some("CAL"),
+    }
+}
+
+/// The point at `radius` from the center of the rose, `angle` degrees clockwise from the top
+fn rose_point(angle: f32, radius: f32) -> (i32, i32) {
+    let (sin, cos) = trig::sin_cos(angle.to_radians());
+    (
+        ROSE_CENTER.0 + libm::roundf(radius * sin) as i32,
+        ROSE_CENTER.1 - libm::roundf(radius * cos) as i32,
+    )
+}
+
+/// Draw the bearing in whole degrees, or the mode or dashes if there isn't one
+fn render(frame: &mut FrameBuffer, screen: Screen) {
+    frame.clear();
+    let Screen {
+        bearing,
+        mode,
+        error,
+    } = screen;
+
+    match (bearing, label(mode)) {
+        (None, Some(label)) => frame.text((0, 16), label, 2),
+        _ => {
+            let digits = match bearing {
+                Some(bearing) => [
+                    b'0' + (bearing / 100) as u8,
+                    b'0' + (bearing / 10 % 10) as u8,
+                    b'0' + (bearing % 10) as u8,
+                ],
+                None => [b'-'; 3],
+            };
+            let digits = core::str::from_utf8(&digits).unwrap();
+            frame.text((0, 12), digits, 3);
+            frame.text((FrameBuffer::text_width(digits, 3) + 2, 12), "°", 2);
+        }
+    }
+
+    if let Some(code) = error {
+        let text = [b'E', b'R', b'R', b' ', b'0' + code % 10];
+        frame.text((0, 56), core::str::from_utf8(&text).unwrap(), 1);
+    }
+
+    frame.circle(ROSE_CENTER, ROSE_RADIUS);
+    // A mark at the top for the direction that the board points in
+    frame.line(
+        (ROSE_CENTER.0, ROSE_CENTER.1 - ROSE_RADIUS),
+        (ROSE_CENTER.0, ROSE_CENTER.1 - ROSE_RADIUS + 4),
+    );
+
+    if let Some(bearing) = bearing {
+        let point = POINTS[usize::from((bearing + 22) / 45 % 8)];
+        let width = FrameBuffer::text_width(point, 2);
+        frame.text((27 - width / 2, 42), point, 2);
+
+        let north = -f32::from(bearing);
+        frame.line(ROSE_CENTER, rose_point(north, NEEDLE_RADIUS));
+        for (index, label) in ["N", "E", "S", "W"].iter().enumerate() {
+            let (x, y) = rose_point(north + 90.0 * index as f32, LABEL_RADIUS);
+            frame.text((x - 2, y - 3), label, 1);
+        }
+    }
+}
+
+pub struct Oled {
+    /// What the display should show
+    screen: Cell<Screen>,
+    /// Set once the display has failed, e.g. because there isn't one
+    error: Cell<Option<I2cError>>,
+    waker: AtomicWaker,
+}
+
+impl Oled {
+    pub fn new() -> Self {
+        Oled {
+            screen: Cell::new(Screen {
+                bearing: None,
+                mode: Mode::Compass,
+                error: None,
+            }),
+            error: Cell::new(None),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Redraw with whatever `change` does to the screen. This never waits for the display.
+    fn update(&self, change: impl FnOnce(&mut Screen)) {
+        let mut screen = self.screen.get();
+        change(&mut screen);
+        if screen != self.screen.get() {
+            self.screen.set(screen);
+            self.waker.wake();
+        }
+    }
+
+    /// The error that stopped the display, if there is one that nobody has asked about yet
+    pub fn take_error(&self) -> Option<I2cError> {
+        self.error.take()
+    }
+
+    async fn draw_forever(&self, display: &mut Ssd1306<I2cDevice<'_>>) -> Result<(), I2cError> {
+        display.init().await?;
+
+        let mut frame = FrameBuffer::new();
+        // `None` means that nothing has been drawn yet
+        let mut drawn = None;
+        loop {
+            let screen = poll_fn(|cx| {
+                self.waker.register(cx.waker());
+                let screen = self.screen.get();
+                if drawn == Some(screen) {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(screen)
+                }
+            })
+            .await;
+
+            render(&mut frame, screen);
+            display.draw(&frame).await?;
+            drawn = Some(screen);
+        }
+    }
+
+    /// Initialize the display and keep it up to date. This only returns if something goes wrong,
+    /// and then the error is available from `take_error`.
+    pub async fn run(&self, mut display: Ssd1306<I2cDevice<'_>>) {
+        if let Err(error) = self.draw_forever(&mut display).await {
+            self.error.set(Some(error));
+        }
+    }
+}
+
+/// The display task only has a shared reference, since the OLED's own task reads the screen too
+impl CompassDisplay for &Oled {
+    /// Show `bearing` in degrees clockwise from north, or dashes if it's `None`
+    fn set_heading(&mut self, bearing: Option<f32>) {
+        let bearing = bearing.map(|bearing| (libm::roundf(bearing) as u16) % 360);
+        self.update(|screen| screen.bearing = bearing);
+    }
+
+    fn set_error(&mut self, code: Option<u8>) {
+        self.update(|screen| screen.error = code);
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.update(|screen| screen.mode = mode);
+    }
+}
@@ -0,0 +1,123 @@
+//! Software PWM for the compass LEDs
+//!
+//! On the F3 Discovery, the LEDs are on PE8 to PE15, and only some of those pins have timer
+//! channels, so instead of hardware PWM we let TIM16 interrupt at a fixed rate and switch each LED
+//! on or off depending on where we are in the PWM period. The whole ring is updated with a single
+//! write to BSRR. Boards with fewer LEDs just leave out the directions that they don't have, see
+//! `board`.
+
+use super::{CompassDisplay, MAX};
+use crate::board::{self, Leds};
+use crate::clocks;
+use crate::power::Awake;
+use compass::modes::Mode;
+use core::sync::atomic::{AtomicU8, Ordering};
+use cortex_m::peripheral::NVIC;
+use f3::hal::stm32f30x::{interrupt, rcc, tim16, Interrupt, RCC, TIM16};
+
+// Interrupt at 4 kHz, which makes the PWM frequency 4 kHz / 16 = 250 Hz, fast enough not to
+// flicker
+// APB2_TIMER_CLOCK = 72 MHz
+// ARR = 17999
+// 72 MHz / (17999 + 1) = 4 kHz
+const ARR: u16 = (clocks::APB2_TIMER_HZ / 4_000 - 1) as u16;
+
+// Written by `Pwm::set`, read by the interrupt handler
+static BRIGHTNESS: [AtomicU8; 8] = [
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+];
+
+pub struct Pwm {
+    // The pins are driven directly through their port, but owning the LEDs makes sure that nothing
+    // else does
+    _leds: Leds,
+    // TIM16 stops in STOP mode, which freezes the pins in whatever state they're in. That's only
+    // fine if every LED is either fully on or off.
+    awake: Option<Awake>,
+}
+
+impl Pwm {
+    /// Take over the LEDs and start the PWM timer, with all LEDs off
+    pub fn new(leds: Leds) -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let tim16: &'static tim16::RegisterBlock = unsafe { &*TIM16::ptr() };
+
+        rcc.apb2enr.modify(|_, w| w.tim16en().set_bit());
+
+        tim16.psc.write(|w| unsafe { w.psc().bits(0) });
+        tim16.arr.write(|w| unsafe { w.arr().bits(ARR) });
+        tim16.dier.write(|w| w.uie().set_bit());
+        tim16.cr1.write(|w| w.cen().set_bit());
+
+        unsafe { NVIC::unmask(Interrupt::TIM1_UP_TIM16) };
+
+        Pwm {
+            _leds: leds,
+            awake: None,
+        }
+    }
+
+    /// Set the brightness of each LED, from 0 (off) to `MAX`, in the same order as `Leds`
+    pub fn set(&mut self, brightness: [u8; 8]) {
+        for (led, &value) in BRIGHTNESS.iter().zip(brightness.iter()) {
+            led.store(value.min(MAX), Ordering::Relaxed);
+        }
+
+        if brightness.iter().all(|&value| value == 0 || value >= MAX) {
+            // The pins are the same at every phase, so set them right away rather than waiting for
+            // an interrupt that STOP mode may delay
+            write_pins(0);
+            self.awake = None;
+        } else {
+            self.awake.get_or_insert_with(Awake::new);
+        }
+    }
+}
+
+/// The LEDs show everything with the frames from `set_leds`
+impl CompassDisplay for Pwm {
+    fn set_heading(&mut self, _bearing: Option<f32>) {}
+
+    fn set_error(&mut self, _code: Option<u8>) {}
+
+    fn set_mode(&mut self, _mode: Mode) {}
+
+    fn set_leds(&mut self, brightness: [u8; 8]) {
+        self.set(brightness);
+    }
+}
+
+/// Switch each LED on or off according to where `phase` is in the PWM period
+fn write_pins(phase: u8) {
+    // The lower half of BSRR sets pins and the upper half resets them
+    let mut bsrr = 0;
+    for (brightness, pin) in BRIGHTNESS.iter().zip(board::LEDS.iter()) {
+        let Some(pin) = pin else {
+            continue;
+        };
+        if phase < brightness.load(Ordering::Relaxed) {
+            bsrr |= 1 << pin;
+        } else {
+            bsrr |= 1 << (pin + 16);
+        }
+    }
+    board::led_port().bsrr.write(|w| unsafe { w.bits(bsrr) });
+}
+
+/// `phase` counts the interrupts within the current PWM period
+fn tim1_up_tim16(phase: &mut u8) {
+    let tim16 = unsafe { &*TIM16::ptr() };
+    tim16.sr.modify(|_, w| w.uif().clear_bit());
+
+    write_pins(*phase);
+    *phase = (*phase + 1) % MAX;
+}
+
+interrupt!(TIM1_UP_TIM16, tim1_up_tim16, state: u8 = 0);
@@ -0,0 +1,52 @@
+//! The LEDs, switched on and off without PWM
+//!
+//! The NUCLEO-F303ZE only has a North and a South LED, and dimming one of two LEDs doesn't show
+//! anything that switching it doesn't, so it isn't worth the 4 kHz interrupt. Anything at least half
+//! as bright as `MAX` is on.
+
+use super::{CompassDisplay, MAX};
+use crate::board::{self, Leds};
+use compass::modes::Mode;
+
+pub struct LedRing {
+    // The pins are driven directly through their port, but owning the LEDs makes sure that nothing
+    // else does
+    _leds: Leds,
+}
+
+impl LedRing {
+    /// Take over the LEDs, which `board::init` leaves off
+    pub fn new(leds: Leds) -> Self {
+        LedRing { _leds: leds }
+    }
+
+    /// Switch each LED on or off, in the same order as `Leds`
+    pub fn set(&mut self, brightness: [u8; 8]) {
+        // The lower half of BSRR sets pins and the upper half resets them
+        let mut bsrr = 0;
+        for (&value, pin) in brightness.iter().zip(board::LEDS.iter()) {
+            let Some(pin) = pin else {
+                continue;
+            };
+            if value >= MAX / 2 {
+                bsrr |= 1 << pin;
+            } else {
+                bsrr |= 1 << (pin + 16);
+            }
+        }
+        board::led_port().bsrr.write(|w| unsafe { w.bits(bsrr) });
+    }
+}
+
+/// The LEDs show everything with the frames from `set_leds`
+impl CompassDisplay for LedRing {
+    fn set_heading(&mut self, _bearing: Option<f32>) {}
+
+    fn set_error(&mut self, _code: Option<u8>) {}
+
+    fn set_mode(&mut self, _mode: Mode) {}
+
+    fn set_leds(&mut self, brightness: [u8; 8]) {
+        self.set(brightness);
+    }
+}
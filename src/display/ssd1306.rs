@@ -0,0 +1,244 @@
+//! Driver for 128x64 SSD1306 OLED displays on I2C
+//!
+//! We draw into a frame buffer in RAM and send it to the display one page (a row of 8 pixels) at a
+//! time. Each page goes out in transactions of its own, so drawing a frame never holds the bus for
+//! longer than a few milliseconds and the magnetometer can get a word in between pages.
+
+use embedded_hal_async::i2c::I2c;
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+const PAGES: usize = HEIGHT / 8;
+
+// Slave address, with the D/C pin tied low
+const ADDRESS: u8 = 0x3c;
+
+// The first byte of a write says whether the rest are commands or display data
+const COMMANDS: u8 = 0x00;
+const DATA: u8 = 0x40;
+
+// Commands
+const DISPLAY_OFF: u8 = 0xae;
+const DISPLAY_ON: u8 = 0xaf;
+const SET_CLOCK_DIVIDE: u8 = 0xd5;
+const SET_MULTIPLEX: u8 = 0xa8;
+const SET_DISPLAY_OFFSET: u8 = 0xd3;
+const SET_START_LINE: u8 = 0x40;
+const CHARGE_PUMP: u8 = 0x8d;
+const SET_ADDRESSING_MODE: u8 = 0x20;
+const SEGMENT_REMAP: u8 = 0xa1;
+const COM_SCAN_DECREMENT: u8 = 0xc8;
+const SET_COM_PINS: u8 = 0xda;
+const SET_CONTRAST: u8 = 0x81;
+const SET_PRECHARGE: u8 = 0xd9;
+const SET_VCOMH: u8 = 0xdb;
+const RESUME_FROM_RAM: u8 = 0xa4;
+const NORMAL_DISPLAY: u8 = 0xa6;
+const SET_COLUMN_RANGE: u8 = 0x21;
+const SET_PAGE_RANGE: u8 = 0x22;
+
+// Display off, internal charge pump (the usual modules have no external supply), horizontal
+// addressing, and the column and row order flipped so that the pins are at the top
+const INIT: [u8; 25] = [
+    DISPLAY_OFF,
+    SET_CLOCK_DIVIDE,
+    0x80,
+    SET_MULTIPLEX,
+    (HEIGHT - 1) as u8,
+    SET_DISPLAY_OFFSET,
+    0x00,
+    SET_START_LINE,
+    CHARGE_PUMP,
+    0x14,
+    SET_ADDRESSING_MODE,
+    0x00,
+    SEGMENT_REMAP,
+    COM_SCAN_DECREMENT,
+    SET_COM_PINS,
+    0x12,
+    SET_CONTRAST,
+    0xcf,
+    SET_PRECHARGE,
+    0xf1,
+    SET_VCOMH,
+    0x40,
+    RESUME_FROM_RAM,
+    NORMAL_DISPLAY,
+    DISPLAY_ON,
+];
+
+// 5x7 glyphs, one byte per column with the top row in the LSB
+const GLYPH_WIDTH: usize = 5;
+const GLYPHS: [(char, [u8; GLYPH_WIDTH]); 29] = [
+    ('0', [0x3e, 0x51, 0x49, 0x45, 0x3e]),
+    ('1', [0x00, 0x42, 0x7f, 0x40, 0x00]),
+    ('2', [0x42, 0x61, 0x51, 0x49, 0x46]),
+    ('3', [0x21, 0x41, 0x45, 0x4b, 0x31]),
+    ('4', [0x18, 0x14, 0x12, 0x7f, 0x10]),
+    ('5', [0x27, 0x45, 0x45, 0x45, 0x39]),
+    ('6', [0x3c, 0x4a, 0x49, 0x49, 0x30]),
+    ('7', [0x01, 0x71, 0x09, 0x05, 0x03]),
+    ('8', [0x36, 0x49, 0x49, 0x49, 0x36]),
+    ('9', [0x06, 0x49, 0x49, 0x29, 0x1e]),
+    ('-', [0x08, 0x08, 0x08, 0x08, 0x08]),
+    ('A', [0x7e, 0x11, 0x11, 0x11, 0x7e]),
+    ('B', [0x7f, 0x49, 0x49, 0x49, 0x36]),
+    ('C', [0x3e, 0x41, 0x41, 0x41, 0x22]),
+    ('D', [0x7f, 0x41, 0x41, 0x22, 0x1c]),
+    ('E', [0x7f, 0x49, 0x49, 0x49, 0x41]),
+    ('H', [0x7f, 0x08, 0x08, 0x08, 0x7f]),
+    ('I', [0x00, 0x41, 0x7f, 0x41, 0x00]),
+    ('L', [0x7f, 0x40, 0x40, 0x40, 0x40]),
+    ('M', [0x7f, 0x02, 0x0c, 0x02, 0x7f]),
+    ('N', [0x7f, 0x04, 0x08, 0x10, 0x7f]),
+    ('O', [0x3e, 0x41, 0x41, 0x41, 0x3e]),
+    ('R', [0x7f, 0x09, 0x19, 0x29, 0x46]),
+    ('S', [0x46, 0x49, 0x49, 0x49, 0x31]),
+    ('T', [0x01, 0x01, 0x7f, 0x01, 0x01]),
+    ('U', [0x3f, 0x40, 0x40, 0x40, 0x3f]),
+    ('V', [0x1f, 0x20, 0x40, 0x20, 0x1f]),
+    ('W', [0x3f, 0x40, 0x38, 0x40, 0x3f]),
+    ('°', [0x00, 0x06, 0x09, 0x09, 0x06]),
+];
+
+/// One bit per pixel, in the same layout as the display's RAM: a byte is a column of 8 pixels
+/// within a page, with the top pixel in the LSB
+pub struct FrameBuffer {
+    pages: [[u8; WIDTH]; PAGES],
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        FrameBuffer {
+            pages: [[0; WIDTH]; PAGES],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages = [[0; WIDTH]; PAGES];
+    }
+
+    /// Light a pixel. Pixels off the screen are ignored, so shapes can be partly off the screen.
+    pub fn set(&mut self, x: i32, y: i32) {
+        if x >= 0 && y >= 0 && (x as usize) < WIDTH && (y as usize) < HEIGHT {
+            self.pages[y as usize / 8][x as usize] |= 1 << (y % 8);
+        }
+    }
+
+    /// A straight line from (x0, y0) to (x1, y1), using Bresenham's algorithm
+    pub fn line(&mut self, (mut x0, mut y0): (i32, i32), (x1, y1): (i32, i32)) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let step_x = if x0 < x1 { 1 } else { -1 };
+        let step_y = if y0 < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        loop {
+            self.set(x0, y0);
+            if x0 == x1 && y0 == y1 {
+                return;
+            }
+            if 2 * error >= dy {
+                error += dy;
+                x0 += step_x;
+            }
+            if 2 * error <= dx {
+                error += dx;
+                y0 += step_y;
+            }
+        }
+    }
+
+    /// A circle outline, using the midpoint circle algorithm
+    pub fn circle(&mut self, (cx, cy): (i32, i32), radius: i32) {
+        let mut x = radius;
+        let mut y = 0;
+        let mut error = 1 - radius;
+        while x >= y {
+            for &(px, py) in &[(x, y), (y, x), (-y, x), (-x, y)] {
+                self.set(cx + px, cy + py);
+                self.set(cx - px, cy - py);
+            }
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Draw `text` with its top left corner at (x, y), with every pixel of the font blown up to
+    /// `scale` x `scale` pixels. Characters that the font doesn't have are left blank.
+    pub fn text(&mut self, (x, y): (i32, i32), text: &str, scale: i32) {
+        for (index, character) in text.chars().enumerate() {
+            let left = x + index as i32 * (GLYPH_WIDTH as i32 + 1) * scale;
+            let glyph = GLYPHS.iter().find(|(c, _)| *c == character);
+            if let Some((_, columns)) = glyph {
+                for (column, bits) in columns.iter().enumerate() {
+                    for row in 0..8 {
+                        if bits & (1 << row) == 0 {
+                            continue;
+                        }
+                        for dx in 0..scale {
+                            for dy in 0..scale {
+                                self.set(left + column as i32 * scale + dx, y + row * scale + dy);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The width in pixels of `text` drawn at `scale`, not counting the space after the last
+    /// character
+    pub fn text_width(text: &str, scale: i32) -> i32 {
+        let characters = text.chars().count() as i32;
+        (characters * (GLYPH_WIDTH as i32 + 1) - 1).max(0) * scale
+    }
+}
+
+pub struct Ssd1306<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C: I2c> Ssd1306<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Ssd1306 { i2c }
+    }
+
+    async fn commands(&mut self, commands: &[u8]) -> Result<(), I2C::Error> {
+        let mut buffer = [0u8; INIT.len() + 1];
+        buffer[0] = COMMANDS;
+        buffer[1..=commands.len()].copy_from_slice(commands);
+        self.i2c.write(ADDRESS, &buffer[..=commands.len()]).await
+    }
+
+    /// Configure the display and turn it on. This needs to be called once before `draw`, and is
+    /// also a good way to find out whether there's a display at all.
+    pub async fn init(&mut self) -> Result<(), I2C::Error> {
+        self.commands(&INIT).await
+    }
+
+    /// Send the whole frame buffer to the display
+    pub async fn draw(&mut self, frame: &FrameBuffer) -> Result<(), I2C::Error> {
+        let mut buffer = [0u8; WIDTH + 1];
+        buffer[0] = DATA;
+        for (index, page) in frame.pages.iter().enumerate() {
+            let index = index as u8;
+            self.commands(&[
+                SET_COLUMN_RANGE,
+                0,
+                (WIDTH - 1) as u8,
+                SET_PAGE_RANGE,
+                index,
+                index,
+            ])
+            .await?;
+            buffer[1..].copy_from_slice(page);
+            self.i2c.write(ADDRESS, &buffer).await?;
+        }
+        Ok(())
+    }
+}
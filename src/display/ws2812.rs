@@ -0,0 +1,170 @@
+//! A ring of 8 WS2812 RGB LEDs ("NeoPixels"), in addition to the board's LEDs
+//!
+//! The ring shows the same as the board's LEDs, in a color that depends on the mode, and all red
+//! while there's an error. Its first LED goes at the front of the board and the rest go clockwise,
+//! like `Leds`. Its data input goes to PB5.
+//!
+//! WS2812s read one bit every 1.25 µs, where a long high pulse is a 1 and a short one is a 0. SPI3
+//! makes those pulses on its MOSI pin, PB5: at 2.25 MHz, each bit of color takes three SPI bits,
+//! 110 for a 1 and 100 for a 0. The LEDs latch their colors once the line has been low for 280 µs,
+//! which it always is between frames, since the main loop doesn't send them anywhere near that
+//! often.
+//!
+//! The LEDs would take a gap in the middle of a pulse for part of the pulse, so each frame goes out
+//! in one go, keeping the FIFO topped up rather than waiting for an interrupt between bytes. That
+//! takes about 260 µs, and only happens when the colors change. Without a ring, nobody notices.
+
+use super::{CompassDisplay, MAX};
+use compass::modes::Mode;
+use core::ptr;
+use f3::hal::stm32f30x::{gpiob, rcc, spi1, GPIOB, RCC, SPI3};
+
+// SPI3's MOSI, which is alternate function 6
+const MOSI: u32 = 5;
+
+// PCLK1 = 36 MHz
+// 36 MHz / 16 = 2.25 MHz
+const BR_DIV16: u8 = 0b011;
+
+const LEDS: usize = 8;
+
+// Three SPI bits for each of the 24 bits of color
+const FRAME_BYTES: usize = LEDS * 24 * 3 / 8;
+
+// Full brightness is blinding, and takes 60 mA per LED
+const MAX_LEVEL: u16 = 64;
+
+/// (red, green, blue) in each mode, at full brightness
+fn color(mode: Mode) -> (u8, u8, u8) {
+    match mode {
+        Mode::Compass | Mode::Demo => (255, 255, 255),
+        Mode::TargetHold => (0, 255, 0),
+        Mode::TurnIndicator => (0, 255, 255),
+        Mode::MetalDetector => (255, 255, 0),
+        Mode::Level => (0, 0, 255),
+        Mode::Vibration => (255, 0, 255),
+        Mode::Calibration => (255, 128, 0),
+    }
+}
+
+const ERROR_COLOR: (u8, u8, u8) = (255, 0, 0);
+
+/// Append the SPI bits for `byte`, most significant bit first, to `frame`, which has `*count` bits
+/// so far
+fn encode(frame: &mut [u8; FRAME_BYTES], count: &mut usize, byte: u8) {
+    for bit in (0..8).rev() {
+        let code = if byte & (1 << bit) != 0 { 0b110 } else { 0b100 };
+        for shift in (0..3).rev() {
+            if code & (1 << shift) != 0 {
+                frame[*count / 8] |= 0x80 >> (*count % 8);
+            }
+            *count += 1;
+        }
+    }
+}
+
+pub struct Ws2812 {
+    regs: &'static spi1::RegisterBlock,
+    brightness: [u8; LEDS],
+    mode: Mode,
+    error: Option<u8>,
+    // What the LEDs show, `None` before the first frame
+    sent: Option<[u8; FRAME_BYTES]>,
+}
+
+impl Ws2812 {
+    /// Power on SPI3 as a transmitter on PB5
+    pub fn new() -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let gpiob: &'static gpiob::RegisterBlock = unsafe { &*GPIOB::ptr() };
+        let regs: &'static spi1::RegisterBlock = unsafe { &*SPI3::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopben().set_bit());
+        rcc.apb1enr.modify(|_, w| w.spi3en().set_bit());
+
+        gpiob.afrl.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b1111 << (4 * MOSI))) | (6 << (4 * MOSI)))
+        });
+        gpiob.moder.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << (2 * MOSI))) | (0b10 << (2 * MOSI)))
+        });
+
+        // DS: 8-bit data size
+        regs.cr2.write(|w| unsafe { w.ds().bits(0b111) });
+        // BIDIMODE, BIDIOE: transmit only, so that nothing piles up in the receive FIFO
+        // SSM, SSI: software slave management, so that the NSS pin doesn't matter
+        regs.cr1.write(|w| unsafe {
+            w.bidimode().set_bit();
+            w.bidioe().set_bit();
+            w.mstr().set_bit();
+            w.br().bits(BR_DIV16);
+            w.ssm().set_bit();
+            w.ssi().set_bit();
+            w.spe().set_bit()
+        });
+
+        Ws2812 {
+            regs,
+            brightness: [0; LEDS],
+            mode: Mode::Compass,
+            error: None,
+            sent: None,
+        }
+    }
+
+    /// The SPI bits for the current state
+    fn frame(&self) -> [u8; FRAME_BYTES] {
+        let (red, green, blue) = match self.error {
+            Some(_) => ERROR_COLOR,
+            None => color(self.mode),
+        };
+        let mut frame = [0; FRAME_BYTES];
+        let mut count = 0;
+        for &brightness in self.brightness.iter() {
+            let scale = u16::from(brightness.min(MAX)) * MAX_LEVEL / u16::from(MAX);
+            let level = |channel: u8| (u16::from(channel) * scale / 255) as u8;
+            // WS2812s take green first
+            for channel in [green, red, blue].iter() {
+                encode(&mut frame, &mut count, level(*channel));
+            }
+        }
+        frame
+    }
+
+    /// Send the current state, if it has changed
+    fn update(&mut self) {
+        let frame = self.frame();
+        if self.sent == Some(frame) {
+            return;
+        }
+
+        // Writes to DR must be 8 bits wide, or the peripheral sends 16 bits
+        let dr = ptr::addr_of!(self.regs.dr) as *mut u8;
+        for &byte in frame.iter() {
+            while self.regs.sr.read().txe().bit_is_clear() {}
+            unsafe { ptr::write_volatile(dr, byte) };
+        }
+        while self.regs.sr.read().bsy().bit_is_set() {}
+        self.sent = Some(frame);
+    }
+}
+
+/// The ring shows everything with the frames from `set_leds`, like the board's LEDs
+impl CompassDisplay for Ws2812 {
+    fn set_heading(&mut self, _bearing: Option<f32>) {}
+
+    /// Shows with the next frame
+    fn set_error(&mut self, code: Option<u8>) {
+        self.error = code;
+    }
+
+    /// Shows with the next frame
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    fn set_leds(&mut self, brightness: [u8; 8]) {
+        self.brightness = brightness;
+        self.update();
+    }
+}
@@ -0,0 +1,97 @@
+//! The sensors' data-ready lines
+//!
+//! On the F3 Discovery, the LSM303DLHC's magnetometer DRDY pin is wired to PE2, its accelerometer
+//! INT1 pin to PE4, and the L3GD20's INT2 pin, which we use for data ready, to PE1. Each line goes
+//! high when the sensor has a new measurement and low again once we've read it, so we can wait for
+//! it through EXTI instead of polling the status register over the bus.
+
+use crate::wakers;
+use core::future::Future;
+use f3::hal::stm32f30x::{exti, gpioc, rcc, syscfg, EXTI, GPIOE, RCC, SYSCFG};
+use futures::task::AtomicWaker;
+
+// EXTICR value that routes an EXTI line to port E
+const PORT_E: u32 = 0b0100;
+
+pub struct DataReady {
+    exti: &'static exti::RegisterBlock,
+    gpioe: &'static gpioc::RegisterBlock,
+    /// Both the pin on port E and the EXTI line
+    pin: u32,
+    waker: &'static AtomicWaker,
+}
+
+impl DataReady {
+    /// Configure the pin as an input and its EXTI line to trigger on the rising edge. The
+    /// interrupt itself is only enabled while waiting.
+    fn new(pin: u32, waker: &'static AtomicWaker) -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let syscfg: &'static syscfg::RegisterBlock = unsafe { &*SYSCFG::ptr() };
+        let exti: &'static exti::RegisterBlock = unsafe { &*EXTI::ptr() };
+        let gpioe: &'static gpioc::RegisterBlock = unsafe { &*GPIOE::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopeen().set_bit());
+        rcc.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
+        gpioe
+            .moder
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(0b11 << (2 * pin))) });
+
+        // Each EXTICR register has 4 bits for each of 4 lines
+        let shift = 4 * (pin % 4);
+        let route = |bits: u32| (bits & !(0b1111 << shift)) | (PORT_E << shift);
+        match pin / 4 {
+            0 => syscfg
+                .exticr1
+                .modify(|r, w| unsafe { w.bits(route(r.bits())) }),
+            1 => syscfg
+                .exticr2
+                .modify(|r, w| unsafe { w.bits(route(r.bits())) }),
+            _ => unreachable!("None of the data-ready lines are above PE7"),
+        }
+        exti.rtsr1
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << pin)) });
+
+        DataReady {
+            exti,
+            gpioe,
+            pin,
+            waker,
+        }
+    }
+
+    /// The magnetometer's DRDY line
+    pub fn magnetometer() -> Self {
+        DataReady::new(2, &wakers::EXTI2)
+    }
+
+    /// The accelerometer's INT1 line, which only signals data ready if the accelerometer has been
+    /// told to
+    pub fn accelerometer() -> Self {
+        DataReady::new(4, &wakers::EXTI4_EV)
+    }
+
+    /// The gyro's INT2 line, which only signals data ready if the gyro has been told to
+    pub fn gyro() -> Self {
+        DataReady::new(1, &wakers::EXTI1_EV)
+    }
+
+    fn is_high(&self) -> bool {
+        self.gpioe.idr.read().bits() & (1 << self.pin) != 0
+    }
+
+    /// Wait until the sensor has a measurement that we haven't read yet
+    pub fn wait_for_drdy(&self) -> impl Future<Output = ()> + '_ {
+        wakers::wait_for(
+            self.waker,
+            move || self.is_high(),
+            move || {
+                // Clear any edge from before we started listening, then unmask the line
+                self.exti.pr1.write(|w| unsafe { w.bits(1 << self.pin) });
+                self.exti
+                    .imr1
+                    .modify(|r, w| unsafe { w.bits(r.bits() | (1 << self.pin)) });
+            },
+        )
+    }
+}
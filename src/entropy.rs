@@ -0,0 +1,98 @@
+//! Gathering a random seed from sensor noise
+//!
+//! The board has no hardware RNG, but its sensors are noisy and the time that a bus transaction
+//! takes jitters by a few cycles. A single reading only has a few bits of noise in its low bits, so
+//! we mix many of them into a pool, and only hash the pool down to a seed at the end. Every input
+//! changes every bit of the seed, so it doesn't matter which bits of an input are the noisy ones.
+//!
+//! This is good enough for a seed that makes each board behave a little differently, not for
+//! cryptography.
+
+// The multiplier and increment from Knuth's MMIX LCG, which mix each input into the pool
+const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+const INCREMENT: u64 = 1_442_695_040_888_963_407;
+
+/// Mixes inputs into a seed
+pub struct EntropyPool {
+    state: u64,
+    inputs: u32,
+}
+
+/// The SplitMix64 finalizer, which spreads every bit of `x` over every bit of the result
+fn finalize(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+impl EntropyPool {
+    pub fn new() -> Self {
+        EntropyPool {
+            state: 0,
+            inputs: 0,
+        }
+    }
+
+    /// Mix in a value, e.g. a timer capture
+    pub fn add(&mut self, value: u32) {
+        self.state = finalize(
+            (self.state ^ u64::from(value))
+                .wrapping_mul(MULTIPLIER)
+                .wrapping_add(INCREMENT),
+        );
+        self.inputs += 1;
+    }
+
+    /// Mix in a reading from a three-axis sensor
+    pub fn add_sample(&mut self, (x, y, z): (i16, i16, i16)) {
+        self.add(u32::from(x as u16) << 16 | u32::from(y as u16));
+        self.add(u32::from(z as u16));
+    }
+
+    /// How many values have been mixed in so far
+    pub fn inputs(&self) -> u32 {
+        self.inputs
+    }
+
+    /// Hash the pool down to a seed
+    pub fn seed(&self) -> u64 {
+        finalize(self.state ^ u64::from(self.inputs))
+    }
+}
+
+impl Default for EntropyPool {
+    fn default() -> Self {
+        EntropyPool::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seed(values: &[u32]) -> u64 {
+        let mut pool = EntropyPool::new();
+        for &value in values {
+            pool.add(value);
+        }
+        pool.seed()
+    }
+
+    #[test]
+    fn every_bit_of_every_input_counts() {
+        assert_eq!(seed(&[1, 2, 3]), seed(&[1, 2, 3]));
+        for bit in 0..32 {
+            let flipped = 1 << bit;
+            let difference = seed(&[1, 2, 3]) ^ seed(&[1, 2 ^ flipped, 3]);
+            // About half of the seed's bits should change
+            assert!((16..=48).contains(&difference.count_ones()));
+        }
+    }
+
+    #[test]
+    fn order_and_count_matter() {
+        assert_ne!(seed(&[1, 2]), seed(&[2, 1]));
+        assert_ne!(seed(&[]), seed(&[0]));
+        assert_ne!(seed(&[0]), seed(&[0, 0]));
+    }
+}
@@ -0,0 +1,114 @@
+//! A minimal executor that sleeps between polls
+//!
+//! `run` drives a fixed set of tasks, each of them a future that lives on `main`'s stack, so there
+//! is no allocation. Every task has its own wake flag, and the waker handed to a task just sets
+//! that flag. A pass over the tasks only polls the ones whose flag is set, and as long as none is,
+//! the executor sleeps (see the `power` module), so the MCU only wakes up when an interrupt fires.
+//! Together with the wakers in the `wakers` module this means that we only poll a task when the
+//! hardware has something new for it.
+//!
+//! `block_on` runs a single future the same way, for the setup before `run`.
+
+use crate::power;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use cortex_m::interrupt;
+use pin_utils::pin_mut;
+
+/// How many tasks `run` can drive
+pub const MAX_TASKS: usize = 8;
+
+/// A task: a future that never finishes, pinned wherever it lives
+pub type Task<'a> = Pin<&'a mut dyn Future<Output = ()>>;
+
+/// Set by the task's waker, cleared by the executor right before it polls the task
+static WOKEN: [AtomicBool; MAX_TASKS] = [
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+];
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+// The data pointer of a waker is the index of its task rather than a real pointer
+fn raw_waker(index: usize) -> RawWaker {
+    RawWaker::new(index as *const (), &VTABLE)
+}
+
+fn clone(index: *const ()) -> RawWaker {
+    raw_waker(index as usize)
+}
+
+fn wake(index: *const ()) {
+    WOKEN[index as usize].store(true, Ordering::Release);
+}
+
+fn drop(_: *const ()) {}
+
+/// Sleep until an interrupt is pending, unless a task has been woken since we last looked.
+///
+/// Check the flags with interrupts disabled so that an interrupt can't sneak in between the check
+/// and the `wfi`. A pending interrupt still wakes the core from `wfi`, and its handler runs as soon
+/// as we leave the critical section.
+fn sleep_unless_woken(tasks: usize) {
+    interrupt::free(|_| {
+        if !WOKEN[..tasks]
+            .iter()
+            .any(|woken| woken.load(Ordering::Acquire))
+        {
+            power::sleep();
+        }
+    });
+}
+
+/// Run `future` to completion, sleeping whenever it isn't ready to make progress.
+///
+/// This uses the first task's wake flag, so it must not be called from inside another `block_on`
+/// or from a task.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    pin_mut!(future);
+    let waker = unsafe { Waker::from_raw(raw_waker(0)) };
+    let mut cx = Context::from_waker(&waker);
+
+    WOKEN[0].store(true, Ordering::Release);
+    loop {
+        if WOKEN[0].swap(false, Ordering::Acquire) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        sleep_unless_woken(1);
+    }
+}
+
+/// Run `tasks` forever, polling each one whenever it has been woken. There can be up to
+/// `MAX_TASKS` of them.
+pub fn run<const N: usize>(mut tasks: [Task; N]) -> ! {
+    assert!(N <= MAX_TASKS, "Too many tasks");
+    let wakers: [Waker; N] =
+        core::array::from_fn(|index| unsafe { Waker::from_raw(raw_waker(index)) });
+
+    // A future must not be polled again once it's finished
+    let mut finished = [false; N];
+
+    // Every task gets polled once to get it started
+    for woken in &WOKEN[..N] {
+        woken.store(true, Ordering::Release);
+    }
+    loop {
+        for (index, task) in tasks.iter_mut().enumerate() {
+            if WOKEN[index].swap(false, Ordering::Acquire) && !finished[index] {
+                let mut cx = Context::from_waker(&wakers[index]);
+                finished[index] = task.as_mut().poll(&mut cx).is_ready();
+            }
+        }
+        sleep_unless_woken(N);
+    }
+}
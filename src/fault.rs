@@ -0,0 +1,125 @@
+//! What happens when the program panics or the CPU faults
+//!
+//! The message goes out over whatever the logger uses (ITM, RTT or defmt), followed by what the
+//! flight recorder recorded up to that point, see `black_box`. Then the board's fault LEDs (East
+//! and West on the F3 Discovery) blink forever, a number of times in a row that tells what went wrong:
+//!
+//! 1. a panic during startup, e.g. because a sensor didn't respond
+//! 2. a panic in the main loop
+//! 3. a HardFault
+//!
+//! That pattern can't be mistaken for a heading or for the North/South blinking of a failed
+//! self-test. Interrupts are disabled throughout, so this doesn't rely on anything but the LEDs' GPIO
+//! port and busy waiting, and it keeps reloading the watchdog so that the pattern stays up until somebody
+//! has had a chance to look at it.
+
+use crate::black_box;
+use crate::board::{self, FAULT_LEDS};
+use crate::clocks;
+use crate::watchdog;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::{asm, interrupt};
+use cortex_m_rt::{exception, ExceptionFrame};
+use f3::hal::stm32f30x::{rcc, RCC};
+
+// CORE_CLOCK = 72 MHz
+const CYCLES_PER_MS: u32 = clocks::SYSCLK_HZ / 1_000;
+
+const BLINK_MS: u32 = 200;
+const PAUSE_MS: u32 = 1_000;
+
+// Set once startup is over
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+enum Category {
+    Startup = 1,
+    Runtime = 2,
+    HardFault = 3,
+}
+
+/// Record that startup is over, so later panics are reported as happening in the main loop
+pub fn running() {
+    RUNNING.store(true, Ordering::Relaxed);
+}
+
+#[cfg(not(any(feature = "rtt", feature = "defmt")))]
+fn report(message: fmt::Arguments) {
+    use cortex_m::peripheral::ITM;
+    let itm = unsafe { &mut *(ITM::PTR as *mut <ITM as core::ops::Deref>::Target) };
+    cortex_m::itm::write_fmt(&mut itm.stim[0], format_args!("{}\n", message));
+}
+
+#[cfg(feature = "rtt")]
+fn report(message: fmt::Arguments) {
+    use core::fmt::Write;
+    // The logger owns channel 0, but it's never going to use it again
+    if let Some(mut channel) = unsafe { rtt_target::UpChannel::conjure(0) } {
+        writeln!(channel, "{}", message).ok();
+    }
+}
+
+#[cfg(feature = "defmt")]
+fn report(message: fmt::Arguments) {
+    defmt::error!("{}", defmt::Display2Format(&message));
+}
+
+fn delay_ms(ms: u32) {
+    for _ in 0..ms {
+        watchdog::reload();
+        asm::delay(CYCLES_PER_MS);
+    }
+}
+
+fn blink_forever(category: Category) -> ! {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let port = board::led_port();
+
+    // This may happen before the LEDs were set up
+    board::enable_led_port(rcc);
+    let (moder_mask, moder_output) = FAULT_LEDS.iter().fold((0, 0), |(mask, output), pin| {
+        (mask | 0b11 << (2 * pin), output | 0b01 << (2 * pin))
+    });
+    port.moder
+        .modify(|r, w| unsafe { w.bits((r.bits() & !moder_mask) | moder_output) });
+
+    // Every LED off, then the lower half of BSRR switches ours on and the upper half off
+    let all = board::LEDS
+        .iter()
+        .flatten()
+        .fold(0, |bits, pin| bits | 1 << pin);
+    port.bsrr.write(|w| unsafe { w.bits(all << 16) });
+    let on = FAULT_LEDS.iter().fold(0, |bits, pin| bits | 1 << pin);
+    let off = on << 16;
+    loop {
+        for _ in 0..category as u32 {
+            port.bsrr.write(|w| unsafe { w.bits(on) });
+            delay_ms(BLINK_MS);
+            port.bsrr.write(|w| unsafe { w.bits(off) });
+            delay_ms(BLINK_MS);
+        }
+        delay_ms(PAUSE_MS);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    interrupt::disable();
+    report(format_args!("{}", info));
+    black_box::dump(|record| report(format_args!("{:?}", record)));
+    blink_forever(if RUNNING.load(Ordering::Relaxed) {
+        Category::Runtime
+    } else {
+        Category::Startup
+    })
+}
+
+#[exception]
+fn HardFault(frame: &ExceptionFrame) -> ! {
+    interrupt::disable();
+    report(format_args!("HardFault: {:?}", frame));
+    black_box::dump(|record| report(format_args!("{:?}", record)));
+    blink_forever(Category::HardFault)
+}
@@ -0,0 +1,92 @@
+//! Radix-2 fast Fourier transform in fixed point
+//!
+//! The samples are integers and the twiddle factors are Q1.15, so every butterfly is integer
+//! arithmetic. Each of the log2(N) stages halves its results, which keeps them within the range of
+//! the input no matter how the energy is distributed, so nothing can overflow. The price is that
+//! the output is the spectrum divided by N, and that small bins lose their low bits.
+
+use crate::trig;
+use core::f32::consts::PI;
+
+// Twiddle factors are Q1.15
+const TWIDDLE_BITS: u32 = 15;
+
+fn twiddle(value: f32) -> i64 {
+    let scaled = value * (1 << TWIDDLE_BITS) as f32;
+    (if scaled >= 0.0 {
+        scaled + 0.5
+    } else {
+        scaled - 0.5
+    }) as i64
+}
+
+/// Transform `re` and `im` in place. Afterwards, bin k holds the component that turns k times over
+/// the window, divided by the window's length. Their lengths must be the same power of two.
+pub fn fft(re: &mut [i32], im: &mut [i32]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+    if n == 1 {
+        return;
+    }
+
+    // Put the samples into bit-reversed order, so that each stage combines neighboring blocks
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if j > i {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut size = 2;
+    while size <= n {
+        let half = size / 2;
+        for k in 0..half {
+            let (sin, cos) = trig::sin_cos(-2.0 * PI * k as f32 / size as f32);
+            let (w_re, w_im) = (twiddle(cos), twiddle(sin));
+            for start in (0..n).step_by(size) {
+                let (a, b) = (start + k, start + k + half);
+                let (b_re, b_im) = (i64::from(re[b]), i64::from(im[b]));
+                let t_re = (b_re * w_re - b_im * w_im) >> TWIDDLE_BITS;
+                let t_im = (b_re * w_im + b_im * w_re) >> TWIDDLE_BITS;
+                let (a_re, a_im) = (i64::from(re[a]), i64::from(im[a]));
+                re[a] = ((a_re + t_re) >> 1) as i32;
+                im[a] = ((a_im + t_im) >> 1) as i32;
+                re[b] = ((a_re - t_re) >> 1) as i32;
+                im[b] = ((a_im - t_im) >> 1) as i32;
+            }
+        }
+        size *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_goes_into_the_first_bin() {
+        let mut re = [100; 8];
+        let mut im = [0; 8];
+        fft(&mut re, &mut im);
+        assert_eq!(re, [100, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(im, [0; 8]);
+    }
+
+    #[test]
+    fn cosine_goes_into_its_bin_and_its_mirror() {
+        let mut re = [0; 64];
+        let mut im = [0; 64];
+        for (i, value) in re.iter_mut().enumerate() {
+            let (_, cos) = trig::sin_cos(2.0 * PI * 5.0 * i as f32 / 64.0);
+            *value = (cos * 1000.0) as i32;
+        }
+        fft(&mut re, &mut im);
+        for k in 0..64 {
+            let expected = if k == 5 || k == 59 { 500 } else { 0 };
+            assert!((re[k] - expected).abs() <= 3, "bin {}: {}", k, re[k]);
+            assert!(im[k].abs() <= 3, "bin {}: {}", k, im[k]);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Averaging decimator
+//!
+//! The magnetometer can measure much faster than the display needs, e.g. at 220 Hz when the LEDs
+//! only update at 10 Hz. Averaging every `N` samples into one cuts the noise by a factor of about
+//! √N, at the cost of up to `N` sample periods of latency, and leaves the rest of the pipeline
+//! with fewer samples to process.
+
+/// Averages every `factor` (x, y, z) samples into one
+pub struct Decimator {
+    factor: u16,
+    sum: (i32, i32, i32),
+    count: u16,
+}
+
+impl Decimator {
+    /// Average every `factor` samples. A factor of 1 passes every sample through, and so does 0.
+    pub fn new(factor: u16) -> Self {
+        Decimator {
+            factor: factor.max(1),
+            sum: (0, 0, 0),
+            count: 0,
+        }
+    }
+
+    pub fn factor(&self) -> u16 {
+        self.factor
+    }
+
+    /// Average every `factor` samples from now on, starting over with the next sample
+    pub fn set_factor(&mut self, factor: u16) {
+        *self = Decimator::new(factor);
+    }
+
+    /// Add a sample, and return the average once there are `factor` of them
+    pub fn update(&mut self, sample: (i16, i16, i16)) -> Option<(i16, i16, i16)> {
+        self.sum.0 += i32::from(sample.0);
+        self.sum.1 += i32::from(sample.1);
+        self.sum.2 += i32::from(sample.2);
+        self.count += 1;
+        if self.count < self.factor {
+            return None;
+        }
+
+        // Round to the nearest integer, away from zero on a tie
+        let count = i32::from(self.count);
+        let average = |sum: i32| ((sum + sum.signum() * count / 2) / count) as i16;
+        let result = (
+            average(self.sum.0),
+            average(self.sum.1),
+            average(self.sum.2),
+        );
+        self.sum = (0, 0, 0);
+        self.count = 0;
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_every_factor_samples() {
+        let mut decimator = Decimator::new(3);
+        assert_eq!(decimator.update((10, -10, 0)), None);
+        assert_eq!(decimator.update((11, -11, 1)), None);
+        assert_eq!(decimator.update((13, -13, 1)), Some((11, -11, 1)));
+        assert_eq!(decimator.update((i16::MAX, i16::MIN, 0)), None);
+        assert_eq!(decimator.update((i16::MAX, i16::MIN, 0)), None);
+        assert_eq!(
+            decimator.update((i16::MAX, i16::MIN, 0)),
+            Some((i16::MAX, i16::MIN, 0))
+        );
+    }
+
+    #[test]
+    fn changing_the_factor_starts_over() {
+        let mut decimator = Decimator::new(0);
+        assert_eq!(decimator.factor(), 1);
+        assert_eq!(decimator.update((1, 2, 3)), Some((1, 2, 3)));
+
+        let mut decimator = Decimator::new(2);
+        assert_eq!(decimator.update((100, 100, 100)), None);
+        decimator.set_factor(2);
+        assert_eq!(decimator.update((1, 2, 3)), None);
+        assert_eq!(decimator.update((2, 3, 4)), Some((2, 3, 4)));
+    }
+}
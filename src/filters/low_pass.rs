@@ -0,0 +1,119 @@
+//! Single-pole IIR low-pass filter
+//!
+//! Each output moves a fixed fraction of the way from the previous output towards the new sample.
+//! That fraction follows from the cutoff frequency and the time between samples, like for an RC
+//! circuit. Samples that don't arrive at a steady rate can bring their own time since the previous
+//! one.
+
+use core::f32::consts::PI;
+
+/// Low-pass filter for (x, y, z) samples
+pub struct LowPass {
+    // The time constant of the equivalent RC circuit, in seconds
+    rc: f32,
+    // The fraction for the sample period given to `new`
+    alpha: f32,
+    // `None` until the first sample, which we pass through unchanged rather than ramping up from 0
+    state: Option<(f32, f32, f32)>,
+}
+
+fn round(value: f32) -> i16 {
+    if value >= 0.0 {
+        (value + 0.5) as i16
+    } else {
+        (value - 0.5) as i16
+    }
+}
+
+impl LowPass {
+    /// `cutoff_hz` is the -3 dB frequency and `sample_period_s` is the time between samples. The
+    /// cutoff should stay well below half the sample rate.
+    pub fn new(cutoff_hz: f32, sample_period_s: f32) -> Self {
+        // alpha = dt / (RC + dt), where RC = 1 / (2π * cutoff)
+        let rc = 1.0 / (2.0 * PI * cutoff_hz);
+        LowPass {
+            rc,
+            alpha: sample_period_s / (rc + sample_period_s),
+            state: None,
+        }
+    }
+
+    /// Filter one sample and return the new output
+    pub fn update(&mut self, sample: (i16, i16, i16)) -> (i16, i16, i16) {
+        self.filter(sample, self.alpha)
+    }
+
+    /// Filter a sample that came `period_s` seconds after the previous one, instead of the sample
+    /// period given to `new`
+    pub fn update_after(&mut self, sample: (i16, i16, i16), period_s: f32) -> (i16, i16, i16) {
+        self.filter(sample, period_s / (self.rc + period_s))
+    }
+
+    fn filter(&mut self, sample: (i16, i16, i16), alpha: f32) -> (i16, i16, i16) {
+        let (x, y, z) = (
+            f32::from(sample.0),
+            f32::from(sample.1),
+            f32::from(sample.2),
+        );
+        let (x, y, z) = match self.state {
+            None => (x, y, z),
+            Some((fx, fy, fz)) => (
+                fx + alpha * (x - fx),
+                fy + alpha * (y - fy),
+                fz + alpha * (z - fz),
+            ),
+        };
+        self.state = Some((x, y, z));
+        (round(x), round(y), round(z))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_sample_passes_through() {
+        let mut filter = LowPass::new(1.0, 0.1);
+        assert_eq!(filter.update((100, -200, 300)), (100, -200, 300));
+    }
+
+    #[test]
+    fn step_response_is_gradual() {
+        let mut filter = LowPass::new(1.0, 0.1);
+        filter.update((0, 0, 0));
+        let (x, _, _) = filter.update((1000, 0, 0));
+        assert!(x > 0 && x < 1000);
+    }
+
+    #[test]
+    fn longer_periods_move_further() {
+        let mut steady = LowPass::new(1.0, 0.1);
+        let mut late = LowPass::new(1.0, 0.1);
+        steady.update((0, 0, 0));
+        late.update((0, 0, 0));
+        let (on_time, _, _) = steady.update_after((1000, 0, 0), 0.1);
+        let (after_a_gap, _, _) = late.update_after((1000, 0, 0), 0.3);
+        assert!(on_time < after_a_gap && after_a_gap < 1000);
+    }
+
+    #[test]
+    fn nominal_period_is_the_default() {
+        let mut filter = LowPass::new(1.0, 0.1);
+        let mut explicit = LowPass::new(1.0, 0.1);
+        for &sample in &[(0, 0, 0), (1000, 0, 0), (1000, -40, 7)] {
+            assert_eq!(filter.update(sample), explicit.update_after(sample, 0.1));
+        }
+    }
+
+    #[test]
+    fn converges_to_constant_input() {
+        let mut filter = LowPass::new(1.0, 0.1);
+        filter.update((0, 0, 0));
+        let mut output = (0, 0, 0);
+        for _ in 0..100 {
+            output = filter.update((1000, -1000, 5));
+        }
+        assert_eq!(output, (1000, -1000, 5));
+    }
+}
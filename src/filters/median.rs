@@ -0,0 +1,86 @@
+//! Median filter
+//!
+//! A corrupted read occasionally produces a wild sample. Averaging would smear it over the
+//! following outputs, but the median of a window ignores a single outlier completely.
+
+/// Median of the last `N` (x, y, z) samples, taken separately for each axis
+pub struct Median<const N: usize> {
+    samples: [(i16, i16, i16); N],
+    // Where the next sample goes
+    next: usize,
+    // How many of `samples` have been filled so far
+    len: usize,
+}
+
+fn median_of<const N: usize>(mut values: [i16; N], len: usize) -> i16 {
+    let values = &mut values[..len];
+    values.sort_unstable();
+    values[len / 2]
+}
+
+impl<const N: usize> Median<N> {
+    pub fn new() -> Self {
+        Median {
+            samples: [(0, 0, 0); N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Add a sample, replacing the oldest one once the window is full, and return the median of
+    /// the window. Until the window is full, this is the median of the samples so far.
+    pub fn update(&mut self, sample: (i16, i16, i16)) -> (i16, i16, i16) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+
+        let mut x = [0; N];
+        let mut y = [0; N];
+        let mut z = [0; N];
+        for (i, &(sx, sy, sz)) in self.samples.iter().enumerate() {
+            x[i] = sx;
+            y[i] = sy;
+            z[i] = sz;
+        }
+        (
+            median_of(x, self.len),
+            median_of(y, self.len),
+            median_of(z, self.len),
+        )
+    }
+}
+
+impl<const N: usize> Default for Median<N> {
+    fn default() -> Self {
+        Median::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_outlier_is_ignored() {
+        let mut filter = Median::<3>::new();
+        filter.update((10, 20, 30));
+        filter.update((11, 21, 31));
+        assert_eq!(filter.update((4000, 4000, 4000)), (11, 21, 31));
+    }
+
+    #[test]
+    fn oldest_sample_drops_out() {
+        let mut filter = Median::<3>::new();
+        for _ in 0..3 {
+            filter.update((0, 0, 0));
+        }
+        filter.update((5, 5, 5));
+        assert_eq!(filter.update((5, 5, 5)), (5, 5, 5));
+    }
+
+    #[test]
+    fn works_before_window_is_full() {
+        let mut filter = Median::<5>::new();
+        assert_eq!(filter.update((7, 8, 9)), (7, 8, 9));
+    }
+}
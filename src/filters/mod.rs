@@ -0,0 +1,5 @@
+//! Filters that clean up raw sensor samples before they reach the heading math
+
+pub mod decimator;
+pub mod low_pass;
+pub mod median;
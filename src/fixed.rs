@@ -0,0 +1,300 @@
+//! Fixed-point versions of the heading math
+//!
+//! Numbers are Q16.16: an `i32` that counts in steps of 1/65536, which covers ±32768 and so fits any
+//! raw sensor axis. Everything is integer arithmetic, which takes the same number of cycles no
+//! matter what the inputs are. This covers the tilt compensation and the heading. The sensor fusion
+//! filters stay in floating point.
+
+use core::ops::{Add, Div, Mul, Neg, Sub};
+
+const FRACTION_BITS: u32 = 16;
+
+/// A Q16.16 fixed-point number. Arithmetic saturates instead of overflowing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Q16(i32);
+
+fn saturate(value: i64) -> Q16 {
+    Q16(value.clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32)
+}
+
+/// The biggest integer whose square is at most `value`, bit by bit
+fn isqrt(value: u64) -> u64 {
+    let mut remainder = value;
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+impl Q16 {
+    pub const ZERO: Q16 = Q16(0);
+    pub const ONE: Q16 = Q16(1 << FRACTION_BITS);
+
+    pub const fn from_int(value: i16) -> Self {
+        Q16((value as i32) << FRACTION_BITS)
+    }
+
+    pub fn from_f32(value: f32) -> Self {
+        saturate((value * Q16::ONE.0 as f32) as i64)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        self.0 as f32 / Q16::ONE.0 as f32
+    }
+
+    /// The nearest integer, saturating to the range of `i16`
+    pub fn round(self) -> i16 {
+        let half = Q16::ONE.0 >> 1;
+        ((i64::from(self.0) + i64::from(half)) >> FRACTION_BITS)
+            .clamp(i64::from(i16::MIN), i64::from(i16::MAX)) as i16
+    }
+
+    pub fn abs(self) -> Self {
+        Q16(self.0.saturating_abs())
+    }
+
+    /// The square root, or 0 for negative numbers
+    pub fn sqrt(self) -> Self {
+        let raw = self.0.max(0) as u64;
+        Q16(isqrt(raw << FRACTION_BITS) as i32)
+    }
+}
+
+impl Add for Q16 {
+    type Output = Q16;
+
+    fn add(self, other: Q16) -> Q16 {
+        Q16(self.0.saturating_add(other.0))
+    }
+}
+
+impl Sub for Q16 {
+    type Output = Q16;
+
+    fn sub(self, other: Q16) -> Q16 {
+        Q16(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Neg for Q16 {
+    type Output = Q16;
+
+    fn neg(self) -> Q16 {
+        Q16(self.0.saturating_neg())
+    }
+}
+
+impl Mul for Q16 {
+    type Output = Q16;
+
+    fn mul(self, other: Q16) -> Q16 {
+        saturate((i64::from(self.0) * i64::from(other.0)) >> FRACTION_BITS)
+    }
+}
+
+impl Div for Q16 {
+    type Output = Q16;
+
+    /// Dividing by zero saturates toward the sign of `self`
+    fn div(self, other: Q16) -> Q16 {
+        if other.0 == 0 {
+            return saturate(i64::from(self.0.signum()) * i64::MAX);
+        }
+        saturate((i64::from(self.0) << FRACTION_BITS) / i64::from(other.0))
+    }
+}
+
+/// The length of `v`, saturating beyond the range of `Q16`
+pub fn magnitude(v: (Q16, Q16, Q16)) -> Q16 {
+    // The squares of three `i32`s don't fit an `i64`, but those of three halved ones do
+    let half_square = |value: Q16| (i64::from(value.0 >> 1) * i64::from(value.0 >> 1)) as u64;
+    let sum = half_square(v.0) + half_square(v.1) + half_square(v.2);
+    saturate((isqrt(sum) << 1) as i64)
+}
+
+/// `v` scaled to a length of 1, or `None` if it has no length
+pub fn normalize(v: (Q16, Q16, Q16)) -> Option<(Q16, Q16, Q16)> {
+    let length = magnitude(v);
+    if length == Q16::ZERO {
+        None
+    } else {
+        Some((v.0 / length, v.1 / length, v.2 / length))
+    }
+}
+
+// Coefficients of the arctangent approximation below, in degrees
+const ATAN_A: Q16 = Q16(918_833); // 0.2447 rad = 14.0203°
+const ATAN_B: Q16 = Q16(248_952); // 0.0663 rad = 3.7987°
+
+/// The arctangent of `z` in [0, 1], in degrees, to within about 0.1°. This is the approximation
+/// atan(z) ≈ 45°·z - z·(z - 1)·(14.02° + 3.80°·z) from Rajan et al., "Efficient approximations for
+/// the arctangent function".
+fn atan_unit(z: Q16) -> Q16 {
+    Q16::from_int(45) * z - z * (z - Q16::ONE) * (ATAN_A + ATAN_B * z)
+}
+
+/// The angle of (x, y) in degrees, in the range (-180, 180], like `f32::atan2`
+pub fn atan2(y: Q16, x: Q16) -> Q16 {
+    if x == Q16::ZERO && y == Q16::ZERO {
+        return Q16::ZERO;
+    }
+    let (ax, ay) = (x.abs(), y.abs());
+    // Fold into the first octant, where the ratio is at most 1
+    let angle = if ay <= ax {
+        atan_unit(ay / ax)
+    } else {
+        Q16::from_int(90) - atan_unit(ax / ay)
+    };
+    let angle = if x < Q16::ZERO {
+        Q16::from_int(180) - angle
+    } else {
+        angle
+    };
+    if y < Q16::ZERO {
+        -angle
+    } else {
+        angle
+    }
+}
+
+/// Wrap an angle in degrees into the range (-180, 180], like `fusion::wrap_degrees`
+pub fn wrap_degrees(angle: Q16) -> Q16 {
+    let turn = Q16::from_int(180).0 * 2;
+    let wrapped = Q16(angle.0 % turn);
+    if wrapped > Q16::from_int(180) {
+        Q16(wrapped.0 - turn)
+    } else if wrapped <= -Q16::from_int(180) {
+        Q16(wrapped.0 + turn)
+    } else {
+        wrapped
+    }
+}
+
+/// Like `tilt_compensation::compensate`: rotate `mag` into the horizontal plane using the tilt
+/// measured by `accel`, or return it unchanged if the accelerometer reading is unusable
+pub fn compensate(mag: (i16, i16, i16), accel: (i16, i16, i16)) -> (Q16, Q16, Q16) {
+    let (x, y, z) = (
+        Q16::from_int(mag.0),
+        Q16::from_int(mag.1),
+        Q16::from_int(mag.2),
+    );
+    let (ax, ay, az) = (
+        Q16::from_int(accel.0),
+        Q16::from_int(accel.1),
+        Q16::from_int(accel.2),
+    );
+
+    let yz_norm = magnitude((Q16::ZERO, ay, az));
+    let norm = magnitude((ax, ay, az));
+    if yz_norm == Q16::ZERO || norm == Q16::ZERO {
+        return (x, y, z);
+    }
+    let sin_roll = ay / yz_norm;
+    let cos_roll = az / yz_norm;
+    let sin_pitch = -ax / norm;
+    let cos_pitch = yz_norm / norm;
+
+    let x_h = x * cos_pitch + y * sin_pitch * sin_roll + z * sin_pitch * cos_roll;
+    let y_h = y * cos_roll - z * sin_roll;
+    let z_h = -x * sin_pitch + y * cos_pitch * sin_roll + z * cos_pitch * cos_roll;
+    (x_h, y_h, z_h)
+}
+
+/// Like `heading::mag_to_angle`
+pub fn mag_to_angle(mag: (Q16, Q16, Q16), declination_deg: Q16) -> Q16 {
+    let (x, y, _z) = mag;
+    wrap_degrees(atan2(y, x) + declination_deg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heading;
+    use crate::tilt_compensation;
+
+    #[test]
+    fn arithmetic() {
+        let (a, b) = (Q16::from_f32(2.5), Q16::from_f32(-0.5));
+        assert_eq!((a + b).to_f32(), 2.0);
+        assert_eq!((a - b).to_f32(), 3.0);
+        assert_eq!((a * b).to_f32(), -1.25);
+        assert_eq!((a / b).to_f32(), -5.0);
+        assert_eq!(Q16::from_int(32767) + Q16::from_int(1), Q16(i32::MAX));
+        assert_eq!(Q16::ONE / Q16::ZERO, Q16(i32::MAX));
+    }
+
+    #[test]
+    fn square_roots() {
+        assert_eq!(Q16::from_int(144).sqrt(), Q16::from_int(12));
+        assert_eq!(Q16::from_f32(0.25).sqrt(), Q16::from_f32(0.5));
+        assert!((Q16::from_int(2).sqrt().to_f32() - 2f32.sqrt()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn magnitude_and_normalize() {
+        let v = (Q16::from_int(3), Q16::from_int(4), Q16::from_int(12));
+        assert_eq!(magnitude(v), Q16::from_int(13));
+        let (x, y, z) = normalize(v).unwrap();
+        assert!((x.to_f32() - 3.0 / 13.0).abs() < 1e-4);
+        assert!((y.to_f32() - 4.0 / 13.0).abs() < 1e-4);
+        assert!((z.to_f32() - 12.0 / 13.0).abs() < 1e-4);
+        assert_eq!(normalize((Q16::ZERO, Q16::ZERO, Q16::ZERO)), None);
+        // Even the longest raw vector doesn't overflow
+        let max = Q16::from_int(i16::MIN);
+        assert_eq!(magnitude((max, max, max)), Q16(i32::MAX));
+    }
+
+    #[test]
+    fn atan2_is_close_all_the_way_around() {
+        for degrees in -179..=180 {
+            let (sin, cos) = libm::sincosf((degrees as f32).to_radians());
+            let angle = atan2(Q16::from_f32(500.0 * sin), Q16::from_f32(500.0 * cos));
+            let error = crate::fusion::wrap_degrees(angle.to_f32() - degrees as f32).abs();
+            assert!(error < 0.1, "{}° came out as {:?}", degrees, angle.to_f32());
+        }
+    }
+
+    #[test]
+    fn wraps_like_the_float_version() {
+        for &angle in &[-540.0, -180.0, -179.5, 0.0, 180.0, 181.0, 719.0] {
+            assert_eq!(
+                wrap_degrees(Q16::from_f32(angle)).to_f32(),
+                crate::fusion::wrap_degrees(angle)
+            );
+        }
+    }
+
+    #[test]
+    fn heading_matches_the_float_version() {
+        let cases = [
+            ((200, -150, -400), (0, 0, 16000)),
+            ((-300, 20, -380), (4000, -3000, 15000)),
+            ((50, 250, -420), (-8000, 6000, 12000)),
+            ((120, -90, 0), (0, 0, 0)),
+        ];
+        for &(mag, accel) in cases.iter() {
+            let float = heading::mag_to_angle(tilt_compensation::compensate(mag, accel), 10.0);
+            let fixed = mag_to_angle(compensate(mag, accel), Q16::from_int(10)).to_f32();
+            assert!(
+                crate::fusion::wrap_degrees(fixed - float).abs() < 0.2,
+                "{:?} {:?}: {} != {}",
+                mag,
+                accel,
+                fixed,
+                float
+            );
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! An append-only log of headings in flash, for keeping a long-term record without an SD card
+//!
+//! The log takes up `PAGES` pages, which it fills in turn, and when the last one is full it erases
+//! the first one and starts over. Every page gets erased just as often, and only once for every
+//! `PAGES` pages' worth of entries, so the log wears the flash out as slowly as it can.
+//!
+//! Each page starts with a header: a sequence number, which goes up by one for every page that's
+//! started, and then a magic number. Since pages are programmed in order, a page whose magic number
+//! is there has its sequence number too. After a reset, the page with the highest sequence number
+//! is the one to continue, at its first erased entry.
+//!
+//! Each entry is `ENTRY_SIZE` bytes: the session, the heading in tenths of a degree (0xffff for
+//! none), the timestamp, and a CRC-32 of all that. An entry that was cut off by a reset fails its
+//! CRC, and is skipped.
+//!
+//! The flash itself is behind the `Flash` trait, so that this can be tested on the host.
+
+use crate::crc::crc32;
+
+/// A region of flash, addressed in bytes from its start
+pub trait Flash {
+    type Error;
+
+    /// The size of the pages that `erase` erases
+    const PAGE_SIZE: usize;
+
+    fn read(&self, offset: usize, bytes: &mut [u8]);
+
+    /// Set every byte of the page that starts at `offset` to 0xff
+    fn erase(&mut self, offset: usize) -> Result<(), Self::Error>;
+
+    /// Write `bytes`, which are an even number, to erased flash at `offset`
+    fn program(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// A heading, and when it was logged
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Entry {
+    /// Goes up by one with every reset, so that the timestamps of different runs can be told apart
+    pub session: u16,
+    /// Seconds since the start of the session
+    pub timestamp_s: u32,
+    /// The bearing in degrees clockwise from north, to a tenth of a degree
+    pub heading: Option<f32>,
+}
+
+const PAGE_MAGIC: u32 = 0x464c_4f47; // "FLOG"
+
+// Sequence number and magic number
+const PAGE_HEADER_SIZE: usize = 8;
+
+pub const ENTRY_SIZE: usize = 12;
+
+const NO_HEADING: u16 = 0xffff;
+
+impl Entry {
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let heading = self
+            .heading
+            .map_or(NO_HEADING, |heading| (heading * 10.0 + 0.5) as u16 % 3600);
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[0..2].copy_from_slice(&self.session.to_le_bytes());
+        bytes[2..4].copy_from_slice(&heading.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.timestamp_s.to_le_bytes());
+        let crc = crc32(&bytes[0..8]);
+        bytes[8..12].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; ENTRY_SIZE]) -> Option<Self> {
+        let crc = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+        if crc != crc32(&bytes[0..8]) {
+            return None;
+        }
+        let heading = u16::from_le_bytes([bytes[2], bytes[3]]);
+        Some(Entry {
+            session: u16::from_le_bytes([bytes[0], bytes[1]]),
+            timestamp_s: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            heading: Some(heading)
+                .filter(|&heading| heading != NO_HEADING)
+                .map(|heading| f32::from(heading) / 10.0),
+        })
+    }
+}
+
+pub struct FlashLog<F: Flash, const PAGES: usize> {
+    flash: F,
+    /// The page that's being filled and its sequence number, if there is one
+    head: Option<(usize, u32)>,
+    /// Where the next entry goes in the head page
+    next: usize,
+    session: u16,
+}
+
+impl<F: Flash, const PAGES: usize> FlashLog<F, PAGES> {
+    const ENTRIES_PER_PAGE: usize = (F::PAGE_SIZE - PAGE_HEADER_SIZE) / ENTRY_SIZE;
+
+    /// Pick up where the log left off before the reset, and start a new session
+    pub fn open(flash: F) -> Self {
+        let mut log = FlashLog {
+            flash,
+            head: None,
+            next: PAGE_HEADER_SIZE,
+            session: 0,
+        };
+        log.head = (0..PAGES)
+            .filter_map(|page| log.sequence(page).map(|sequence| (page, sequence)))
+            .max_by_key(|&(_, sequence)| sequence);
+        if let Some((page, _)) = log.head {
+            let erased = (0..Self::ENTRIES_PER_PAGE).find(|&slot| log.is_erased(page, slot));
+            log.next = erased.map_or(F::PAGE_SIZE, |slot| Self::slot_offset(slot));
+        }
+        log.session = log
+            .entries()
+            .last()
+            .map_or(0, |entry| entry.session.wrapping_add(1));
+        log
+    }
+
+    pub fn session(&self) -> u16 {
+        self.session
+    }
+
+    /// How many entries the log holds before it starts dropping the oldest ones. Starting a new
+    /// page erases a whole page of them at once.
+    pub fn capacity(&self) -> usize {
+        PAGES * Self::ENTRIES_PER_PAGE
+    }
+
+    /// Add an entry for `heading` to the current session, starting a new page if this one is full
+    pub fn append(&mut self, timestamp_s: u32, heading: Option<f32>) -> Result<(), F::Error> {
+        let head = match self.head {
+            Some((page, sequence)) if self.next + ENTRY_SIZE <= F::PAGE_SIZE => (page, sequence),
+            head => self.start_page(head.map_or((0, 0), |(page, sequence)| {
+                ((page + 1) % PAGES, sequence.wrapping_add(1))
+            }))?,
+        };
+        let entry = Entry {
+            session: self.session,
+            timestamp_s,
+            heading,
+        };
+        let offset = head.0 * F::PAGE_SIZE + self.next;
+        // Even if programming fails, the entry isn't erased anymore, so don't try it again
+        self.next += ENTRY_SIZE;
+        self.flash.program(offset, &entry.encode())
+    }
+
+    /// The entries, oldest first
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        let first = self.head.map_or(0, |(page, _)| page + 1);
+        (0..PAGES)
+            .map(move |index| (first + index) % PAGES)
+            .filter(move |&page| self.sequence(page).is_some())
+            .flat_map(move |page| {
+                (0..Self::ENTRIES_PER_PAGE).filter_map(move |slot| self.entry(page, slot))
+            })
+    }
+
+    /// Erase every page. The next entry starts the page after the current one, so that erasing
+    /// doesn't wear out the first page any faster than the others.
+    pub fn erase(&mut self) -> Result<(), F::Error> {
+        for page in 0..PAGES {
+            self.flash.erase(page * F::PAGE_SIZE)?;
+        }
+        self.next = F::PAGE_SIZE;
+        Ok(())
+    }
+
+    fn start_page(&mut self, (page, sequence): (usize, u32)) -> Result<(usize, u32), F::Error> {
+        // If this fails, the head stays where it was, so the next entry tries again
+        self.flash.erase(page * F::PAGE_SIZE)?;
+        let mut header = [0; PAGE_HEADER_SIZE];
+        header[0..4].copy_from_slice(&sequence.to_le_bytes());
+        header[4..8].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        self.flash.program(page * F::PAGE_SIZE, &header)?;
+        self.head = Some((page, sequence));
+        self.next = PAGE_HEADER_SIZE;
+        Ok((page, sequence))
+    }
+
+    fn slot_offset(slot: usize) -> usize {
+        PAGE_HEADER_SIZE + slot * ENTRY_SIZE
+    }
+
+    /// The page's sequence number, if it's been started
+    fn sequence(&self, page: usize) -> Option<u32> {
+        let mut header = [0; PAGE_HEADER_SIZE];
+        self.flash.read(page * F::PAGE_SIZE, &mut header);
+        let magic = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Some(u32::from_le_bytes([
+            header[0], header[1], header[2], header[3],
+        ]))
+        .filter(|_| magic == PAGE_MAGIC)
+    }
+
+    fn read_slot(&self, page: usize, slot: usize) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0; ENTRY_SIZE];
+        self.flash
+            .read(page * F::PAGE_SIZE + Self::slot_offset(slot), &mut bytes);
+        bytes
+    }
+
+    fn is_erased(&self, page: usize, slot: usize) -> bool {
+        self.read_slot(page, slot).iter().all(|&byte| byte == 0xff)
+    }
+
+    fn entry(&self, page: usize, slot: usize) -> Option<Entry> {
+        Entry::decode(&self.read_slot(page, slot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Room for three entries per page
+    const PAGE_SIZE: usize = PAGE_HEADER_SIZE + 3 * ENTRY_SIZE;
+    const PAGES: usize = 3;
+
+    /// Flash in RAM, where programming can only clear bits, like the real thing
+    struct Ram([u8; PAGES * PAGE_SIZE]);
+
+    impl Flash for &mut Ram {
+        type Error = ();
+
+        const PAGE_SIZE: usize = PAGE_SIZE;
+
+        fn read(&self, offset: usize, bytes: &mut [u8]) {
+            bytes.copy_from_slice(&self.0[offset..offset + bytes.len()]);
+        }
+
+        fn erase(&mut self, offset: usize) -> Result<(), ()> {
+            self.0[offset..offset + PAGE_SIZE].fill(0xff);
+            Ok(())
+        }
+
+        fn program(&mut self, offset: usize, bytes: &[u8]) -> Result<(), ()> {
+            for (old, new) in self.0[offset..].iter_mut().zip(bytes) {
+                *old &= new;
+            }
+            Ok(())
+        }
+    }
+
+    fn timestamps(log: &FlashLog<&mut Ram, PAGES>) -> ([u32; 9], usize) {
+        let mut timestamps = [0; 9];
+        let mut len = 0;
+        for (timestamp, entry) in timestamps.iter_mut().zip(log.entries()) {
+            *timestamp = entry.timestamp_s;
+            len += 1;
+        }
+        (timestamps, len)
+    }
+
+    #[test]
+    fn rotates_through_the_pages_and_survives_a_reset() {
+        let mut ram = Ram([0xff; PAGES * PAGE_SIZE]);
+        let mut log = FlashLog::<_, PAGES>::open(&mut ram);
+        assert_eq!(log.capacity(), 9);
+        assert_eq!(log.entries().next(), None);
+        for timestamp_s in 1..=8 {
+            log.append(timestamp_s, Some(123.4)).unwrap();
+        }
+        assert_eq!(timestamps(&log), ([1, 2, 3, 4, 5, 6, 7, 8, 0], 8));
+        let entry = log.entries().next().unwrap();
+        assert_eq!(entry.session, 0);
+        assert!((entry.heading.unwrap() - 123.4).abs() < 0.01);
+
+        // The tenth entry starts over in the first page
+        log.append(9, None).unwrap();
+        log.append(10, None).unwrap();
+        assert_eq!(timestamps(&log), ([4, 5, 6, 7, 8, 9, 10, 0, 0], 7));
+
+        let mut log = FlashLog::<_, PAGES>::open(&mut ram);
+        assert_eq!(log.session(), 1);
+        log.append(11, None).unwrap();
+        assert_eq!(timestamps(&log), ([4, 5, 6, 7, 8, 9, 10, 11, 0], 8));
+        let entry = log.entries().last().unwrap();
+        assert_eq!((entry.session, entry.heading), (1, None));
+    }
+
+    #[test]
+    fn skips_entries_that_were_cut_off() {
+        let mut ram = Ram([0xff; PAGES * PAGE_SIZE]);
+        let mut log = FlashLog::<_, PAGES>::open(&mut ram);
+        log.append(1, None).unwrap();
+        log.append(2, None).unwrap();
+        // A reset in the middle of programming the second entry's CRC
+        ram.0[PAGE_HEADER_SIZE + 2 * ENTRY_SIZE - 1] = 0xff;
+
+        let mut log = FlashLog::<_, PAGES>::open(&mut ram);
+        assert_eq!(timestamps(&log), ([1, 0, 0, 0, 0, 0, 0, 0, 0], 1));
+        log.append(3, None).unwrap();
+        assert_eq!(timestamps(&log), ([1, 3, 0, 0, 0, 0, 0, 0, 0], 2));
+
+        log.erase().unwrap();
+        assert_eq!(log.entries().next(), None);
+        log.append(4, None).unwrap();
+        assert_eq!(timestamps(&log), ([4, 0, 0, 0, 0, 0, 0, 0, 0], 1));
+    }
+}
@@ -0,0 +1,103 @@
+//! The last few seconds of samples, for looking into a glitch after it happened
+//!
+//! A `FlightRecorder` is a ring buffer that keeps the newest `N` records and drops the oldest to
+//! make room. Recording is cheap enough to do for every sample, so it can run all the time, and
+//! there's nothing to set up in advance: when something goes wrong, the records leading up to it
+//! are already there to dump.
+
+/// What the main loop knew when a magnetometer sample arrived
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record {
+    pub timestamp_ms: u32,
+    /// Raw magnetometer reading, before calibration
+    pub mag: (i16, i16, i16),
+    /// The latest accelerometer reading
+    pub accel: (i16, i16, i16),
+    /// The heading in degrees clockwise from north, if there is one yet
+    pub heading: Option<f32>,
+}
+
+pub struct FlightRecorder<const N: usize> {
+    records: [Option<Record>; N],
+    /// Where the next record goes
+    next: usize,
+}
+
+impl<const N: usize> FlightRecorder<N> {
+    pub const fn new() -> Self {
+        FlightRecorder {
+            records: [None; N],
+            next: 0,
+        }
+    }
+
+    /// Add a record, overwriting the oldest one if the recorder is full
+    pub fn push(&mut self, record: Record) {
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % N;
+    }
+
+    /// The records, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Record> {
+        let (newer, older) = self.records.split_at(self.next);
+        older.iter().chain(newer).flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.iter().all(Option::is_none)
+    }
+
+    pub fn clear(&mut self) {
+        *self = FlightRecorder::new();
+    }
+}
+
+impl<const N: usize> Default for FlightRecorder<N> {
+    fn default() -> Self {
+        FlightRecorder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp_ms: u32) -> Record {
+        Record {
+            timestamp_ms,
+            mag: (0, 0, 0),
+            accel: (0, 0, 1000),
+            heading: None,
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_records_in_order() {
+        let mut recorder = FlightRecorder::<3>::new();
+        assert!(recorder.is_empty());
+        recorder.push(record(1));
+        recorder.push(record(2));
+        let timestamps = |recorder: &FlightRecorder<3>| {
+            let mut timestamps = [0; 3];
+            for (timestamp, record) in timestamps.iter_mut().zip(recorder.iter()) {
+                *timestamp = record.timestamp_ms;
+            }
+            (recorder.len(), timestamps)
+        };
+        assert_eq!(timestamps(&recorder), (2, [1, 2, 0]));
+
+        for timestamp_ms in 3..=5 {
+            recorder.push(record(timestamp_ms));
+        }
+        assert_eq!(timestamps(&recorder), (3, [3, 4, 5]));
+
+        recorder.clear();
+        assert!(recorder.is_empty());
+        assert_eq!(recorder.iter().next(), None);
+    }
+}
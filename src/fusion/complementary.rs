@@ -0,0 +1,85 @@
+//! Complementary filter for the heading
+//!
+//! The gyro is smooth and fast but drifts, while the magnetometer heading is noisy but doesn't
+//! drift. So we integrate the gyro's yaw rate at its full sample rate, and every so often pull the
+//! result a little bit towards the magnetometer heading. Over short time scales the heading follows
+//! the gyro, and over long time scales it follows the magnetometer.
+
+use super::wrap_degrees;
+
+pub struct ComplementaryFilter {
+    /// How long it takes, in seconds, for the magnetometer to correct most of the gyro's drift
+    time_constant_s: f32,
+    heading: Option<f32>,
+}
+
+impl ComplementaryFilter {
+    pub fn new(time_constant_s: f32) -> Self {
+        ComplementaryFilter {
+            time_constant_s,
+            heading: None,
+        }
+    }
+
+    /// Integrate a yaw rate in degrees per second over `dt_s` seconds. This does nothing until the
+    /// first magnetometer heading has arrived, since there's nothing to integrate from.
+    pub fn update_gyro(&mut self, yaw_rate_dps: f32, dt_s: f32) {
+        if let Some(heading) = self.heading {
+            self.heading = Some(wrap_degrees(heading + yaw_rate_dps * dt_s));
+        }
+    }
+
+    /// Correct the heading towards a magnetometer heading in degrees. `dt_s` is the time since
+    /// the previous correction.
+    pub fn update_mag(&mut self, mag_heading: f32, dt_s: f32) {
+        self.heading = Some(match self.heading {
+            None => wrap_degrees(mag_heading),
+            Some(heading) => {
+                // Go the short way around the circle
+                let error = wrap_degrees(mag_heading - heading);
+                let gain = dt_s / (self.time_constant_s + dt_s);
+                wrap_degrees(heading + gain * error)
+            }
+        });
+    }
+
+    /// The fused heading in degrees, in the range (-180, 180]
+    pub fn heading(&self) -> Option<f32> {
+        self.heading
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converges_to_the_magnetometer_without_rotation() {
+        let mut filter = ComplementaryFilter::new(1.0);
+        assert_eq!(filter.heading(), None);
+        filter.update_gyro(10.0, 0.1);
+        assert_eq!(filter.heading(), None);
+
+        filter.update_mag(0.0, 0.1);
+        // Ten time constants
+        for _ in 0..100 {
+            filter.update_gyro(0.0, 0.1);
+            filter.update_mag(90.0, 0.1);
+        }
+        let heading = filter.heading().unwrap();
+        assert!((heading - 90.0).abs() < 0.1, "{}", heading);
+    }
+
+    #[test]
+    fn corrects_the_short_way_around() {
+        let mut filter = ComplementaryFilter::new(1.0);
+        filter.update_mag(170.0, 0.1);
+        filter.update_mag(-170.0, 1.0);
+        let heading = filter.heading().unwrap();
+        assert!((heading - 180.0).abs() < 0.1, "{}", heading);
+
+        filter.update_mag(-170.0, 1.0);
+        let heading = filter.heading().unwrap();
+        assert!((heading + 175.0).abs() < 0.1, "{}", heading);
+    }
+}
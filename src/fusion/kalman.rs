@@ -0,0 +1,99 @@
+//! One-dimensional Kalman filter for the heading
+//!
+//! Like the complementary filter, this integrates the gyro's yaw rate and corrects the result with
+//! the magnetometer heading. The difference is that it also tracks how uncertain the heading is:
+//! integrating the gyro makes the variance grow, and each magnetometer heading shrinks it again.
+//! The gain of each correction follows from the two variances instead of a fixed time constant, and
+//! the variance tells downstream code how much to trust the heading.
+
+use super::wrap_degrees;
+
+pub struct KalmanFilter {
+    /// How fast the gyro's integration error grows, in degrees² per second
+    process_noise: f32,
+    /// Variance of a magnetometer heading, in degrees²
+    measurement_noise: f32,
+    /// The heading in degrees and its variance in degrees²
+    state: Option<(f32, f32)>,
+}
+
+impl KalmanFilter {
+    pub fn new(process_noise: f32, measurement_noise: f32) -> Self {
+        KalmanFilter {
+            process_noise,
+            measurement_noise,
+            state: None,
+        }
+    }
+
+    /// Predict step: integrate a yaw rate in degrees per second over `dt_s` seconds. This does
+    /// nothing until the first magnetometer heading has arrived, since there's nothing to integrate
+    /// from.
+    pub fn update_gyro(&mut self, yaw_rate_dps: f32, dt_s: f32) {
+        if let Some((heading, variance)) = self.state {
+            self.state = Some((
+                wrap_degrees(heading + yaw_rate_dps * dt_s),
+                variance + self.process_noise * dt_s,
+            ));
+        }
+    }
+
+    /// Update step: correct the heading with a magnetometer heading in degrees
+    pub fn update_mag(&mut self, mag_heading: f32) {
+        self.state = Some(match self.state {
+            None => (wrap_degrees(mag_heading), self.measurement_noise),
+            Some((heading, variance)) => {
+                // Go the short way around the circle
+                let innovation = wrap_degrees(mag_heading - heading);
+                let gain = variance / (variance + self.measurement_noise);
+                (
+                    wrap_degrees(heading + gain * innovation),
+                    (1.0 - gain) * variance,
+                )
+            }
+        });
+    }
+
+    /// The estimated heading in degrees, in the range (-180, 180]
+    pub fn heading(&self) -> Option<f32> {
+        self.state.map(|(heading, _)| heading)
+    }
+
+    /// The variance of the heading in degrees²
+    pub fn variance(&self) -> Option<f32> {
+        self.state.map(|(_, variance)| variance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predicting_grows_the_variance_and_updating_shrinks_it() {
+        let mut filter = KalmanFilter::new(2.0, 25.0);
+        assert_eq!(filter.variance(), None);
+        filter.update_mag(0.0);
+        assert_eq!(filter.variance(), Some(25.0));
+
+        filter.update_gyro(10.0, 0.5);
+        assert_eq!(filter.heading(), Some(5.0));
+        assert_eq!(filter.variance(), Some(26.0));
+
+        filter.update_mag(5.0);
+        let variance = filter.variance().unwrap();
+        let expected = 26.0 * 25.0 / (26.0 + 25.0);
+        assert!((variance - expected).abs() < 0.001, "{}", variance);
+        assert!(variance < 25.0);
+    }
+
+    #[test]
+    fn corrects_the_short_way_around() {
+        let mut filter = KalmanFilter::new(0.0, 1.0);
+        filter.update_mag(170.0);
+        // Equal variances, so the correction goes halfway
+        filter.update_mag(-170.0);
+        let heading = filter.heading().unwrap();
+        assert!((heading - 180.0).abs() < 0.001, "{}", heading);
+    }
+}
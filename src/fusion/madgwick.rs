@@ -0,0 +1,176 @@
+//! Madgwick's AHRS orientation filter
+//!
+//! This fuses the gyro, accelerometer and magnetometer into a full 3D orientation, stored as a
+//! quaternion. Each update integrates the gyro rates and then takes one gradient descent step
+//! towards the orientation in which gravity and the Earth's field point where the accelerometer
+//! and magnetometer say they do. Unlike tilt compensation, this keeps working while the board is
+//! being moved around, because short accelerations are smoothed out by the gyro.
+//!
+//! The algorithm follows Sebastian Madgwick's report "An efficient orientation filter for inertial
+//! and inertial/magnetic sensor arrays" and his reference C implementation.
+
+use crate::fusion::quaternion::Quaternion;
+use crate::trig;
+
+pub struct Madgwick {
+    /// Gradient descent step size. Larger values trust the accelerometer and magnetometer more
+    /// and the gyro less.
+    beta: f32,
+    /// Orientation of the Earth frame relative to the board
+    q: Quaternion,
+}
+
+fn normalize3(v: (f32, f32, f32)) -> Option<(f32, f32, f32)> {
+    let norm = trig::sqrt(v.0 * v.0 + v.1 * v.1 + v.2 * v.2);
+    if norm < f32::EPSILON {
+        None
+    } else {
+        Some((v.0 / norm, v.1 / norm, v.2 / norm))
+    }
+}
+
+impl Madgwick {
+    pub fn new(beta: f32) -> Self {
+        Madgwick {
+            beta,
+            q: Quaternion::IDENTITY,
+        }
+    }
+
+    /// Advance the filter by `dt_s` seconds.
+    ///
+    /// `gyro` is in radians per second. `accel` and `mag` can be in any unit since only their
+    /// directions matter. If either of them is zero, this only integrates the gyro.
+    pub fn update(
+        &mut self,
+        gyro: (f32, f32, f32),
+        accel: (f32, f32, f32),
+        mag: (f32, f32, f32),
+        dt_s: f32,
+    ) {
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = self.q;
+        let (gx, gy, gz) = gyro;
+
+        // Rate of change of the quaternion from the gyro
+        let mut q_dot = (self.q * Quaternion::new(0.0, gx, gy, gz)).scale(0.5);
+
+        if let (Some((ax, ay, az)), Some((mx, my, mz))) = (normalize3(accel), normalize3(mag)) {
+            // Auxiliary variables to avoid repeated arithmetic
+            let _2q0mx = 2.0 * q0 * mx;
+            let _2q0my = 2.0 * q0 * my;
+            let _2q0mz = 2.0 * q0 * mz;
+            let _2q1mx = 2.0 * q1 * mx;
+            let _2q0 = 2.0 * q0;
+            let _2q1 = 2.0 * q1;
+            let _2q2 = 2.0 * q2;
+            let _2q3 = 2.0 * q3;
+            let _2q0q2 = 2.0 * q0 * q2;
+            let _2q2q3 = 2.0 * q2 * q3;
+            let q0q0 = q0 * q0;
+            let q0q1 = q0 * q1;
+            let q0q2 = q0 * q2;
+            let q0q3 = q0 * q3;
+            let q1q1 = q1 * q1;
+            let q1q2 = q1 * q2;
+            let q1q3 = q1 * q3;
+            let q2q2 = q2 * q2;
+            let q2q3 = q2 * q3;
+            let q3q3 = q3 * q3;
+
+            // Reference direction of the Earth's field, with the horizontal part along X
+            let hx =
+                mx * q0q0 - _2q0my * q3 + _2q0mz * q2 + mx * q1q1 + _2q1 * my * q2 + _2q1 * mz * q3
+                    - mx * q2q2
+                    - mx * q3q3;
+            let hy = _2q0mx * q3 + my * q0q0 - _2q0mz * q1 + _2q1mx * q2 - my * q1q1
+                + my * q2q2
+                + _2q2 * mz * q3
+                - my * q3q3;
+            let _2bx = trig::sqrt(hx * hx + hy * hy);
+            let _2bz = -_2q0mx * q2 + _2q0my * q1 + mz * q0q0 + _2q1mx * q3 - mz * q1q1
+                + _2q2 * my * q3
+                - mz * q2q2
+                + mz * q3q3;
+            let _4bx = 2.0 * _2bx;
+            let _4bz = 2.0 * _2bz;
+
+            // Errors between the measured and the predicted directions
+            let fax = 2.0 * q1q3 - _2q0q2 - ax;
+            let fay = 2.0 * q0q1 + _2q2q3 - ay;
+            let faz = 1.0 - 2.0 * q1q1 - 2.0 * q2q2 - az;
+            let fmx = _2bx * (0.5 - q2q2 - q3q3) + _2bz * (q1q3 - q0q2) - mx;
+            let fmy = _2bx * (q1q2 - q0q3) + _2bz * (q0q1 + q2q3) - my;
+            let fmz = _2bx * (q0q2 + q1q3) + _2bz * (0.5 - q1q1 - q2q2) - mz;
+
+            // Gradient descent step
+            let s0 = -_2q2 * fax + _2q1 * fay - _2bz * q2 * fmx
+                + (-_2bx * q3 + _2bz * q1) * fmy
+                + _2bx * q2 * fmz;
+            let s1 = _2q3 * fax + _2q0 * fay - 4.0 * q1 * faz
+                + _2bz * q3 * fmx
+                + (_2bx * q2 + _2bz * q0) * fmy
+                + (_2bx * q3 - _4bz * q1) * fmz;
+            let s2 = -_2q0 * fax + _2q3 * fay - 4.0 * q2 * faz
+                + (-_4bx * q2 - _2bz * q0) * fmx
+                + (_2bx * q1 + _2bz * q3) * fmy
+                + (_2bx * q0 - _4bz * q2) * fmz;
+            let s3 = _2q1 * fax
+                + _2q2 * fay
+                + (-_4bx * q3 + _2bz * q1) * fmx
+                + (-_2bx * q0 + _2bz * q2) * fmy
+                + _2bx * q1 * fmz;
+            let step = Quaternion::new(s0, s1, s2, s3).normalize();
+            q_dot = q_dot - step.scale(self.beta);
+        }
+
+        self.q = (self.q + q_dot.scale(dt_s)).normalize();
+    }
+
+    /// The orientation as a unit quaternion
+    pub fn quaternion(&self) -> Quaternion {
+        self.q
+    }
+
+    /// The yaw in degrees, in the range (-180, 180]. This uses the same convention as
+    /// `mag_to_angle`: it's the direction of magnetic north in the board's XY plane.
+    pub fn yaw(&self) -> f32 {
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = self.q;
+        let north_x = q0 * q0 + q1 * q1 - q2 * q2 - q3 * q3;
+        let north_y = 2.0 * (q1 * q2 - q0 * q3);
+        trig::atan2(north_y, north_x).to_degrees()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heading::mag_to_angle;
+
+    /// Let a level board that doesn't turn settle on the field `mag`, and return the yaw
+    fn settle(madgwick: &mut Madgwick, mag: (f32, f32, f32)) -> f32 {
+        for _ in 0..2000 {
+            madgwick.update((0.0, 0.0, 0.0), (0.0, 0.0, 1.0), mag, 0.01);
+        }
+        madgwick.yaw()
+    }
+
+    #[test]
+    fn yaw_matches_mag_to_angle() {
+        let mut madgwick = Madgwick::new(0.5);
+        for &mag in &[(0.0, 0.2, -0.4), (0.2, 0.0, -0.4), (-0.1, -0.1, -0.4)] {
+            let yaw = settle(&mut madgwick, mag);
+            let expected = mag_to_angle(mag, 0.0);
+            assert!((yaw - expected).abs() < 0.5, "{} != {}", yaw, expected);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Sensor fusion: combining several sensors into one estimate that's better than any of them
+
+pub mod complementary;
+pub mod kalman;
+pub mod madgwick;
+pub mod quaternion;
+
+/// Wrap an angle in degrees into the range (-180, 180]
+pub fn wrap_degrees(angle: f32) -> f32 {
+    let wrapped = angle % 360.0;
+    if wrapped > 180.0 {
+        wrapped - 360.0
+    } else if wrapped <= -180.0 {
+        wrapped + 360.0
+    } else {
+        wrapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn angles_in_range_are_unchanged() {
+        for &angle in &[-179.0, -90.0, 0.0, 90.0, 180.0] {
+            assert_eq!(wrap_degrees(angle), angle);
+        }
+    }
+
+    #[test]
+    fn angles_out_of_range_wrap() {
+        assert_eq!(wrap_degrees(190.0), -170.0);
+        assert_eq!(wrap_degrees(-180.0), 180.0);
+        assert_eq!(wrap_degrees(360.0), 0.0);
+        assert_eq!(wrap_degrees(-450.0), -90.0);
+        assert_eq!(wrap_degrees(720.0 + 45.0), 45.0);
+    }
+}
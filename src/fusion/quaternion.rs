@@ -0,0 +1,166 @@
+//! Quaternions, for 3D orientations
+//!
+//! A unit quaternion represents a rotation without the gimbal lock of Euler angles, and composing
+//! two rotations is a single multiplication. Euler angles are still easier to read, so
+//! `Quaternion::euler` converts to them, using the aerospace convention: yaw about Z, then pitch
+//! about the new Y, then roll about the new X.
+
+use crate::trig;
+use core::ops::{Add, Mul, Sub};
+
+/// A quaternion w + xi + yj + zk
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+/// Roll, pitch and yaw in degrees. Roll and yaw are in the range [-180, 180], and pitch is in the
+/// range [-90, 90].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Euler {
+    pub roll_deg: f32,
+    pub pitch_deg: f32,
+    pub yaw_deg: f32,
+}
+
+impl Quaternion {
+    /// No rotation
+    pub const IDENTITY: Quaternion = Quaternion::new(1.0, 0.0, 0.0, 0.0);
+
+    pub const fn new(w: f32, x: f32, y: f32, z: f32) -> Self {
+        Quaternion { w, x, y, z }
+    }
+
+    pub fn norm(self) -> f32 {
+        trig::sqrt(self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z)
+    }
+
+    /// This quaternion scaled to a length of 1, or unchanged if it's too short to tell which way it
+    /// points
+    pub fn normalize(self) -> Self {
+        let norm = self.norm();
+        if norm < f32::EPSILON {
+            self
+        } else {
+            self.scale(1.0 / norm)
+        }
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        Quaternion::new(
+            self.w * factor,
+            self.x * factor,
+            self.y * factor,
+            self.z * factor,
+        )
+    }
+
+    /// The inverse rotation, for a unit quaternion
+    pub fn conjugate(self) -> Self {
+        Quaternion::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    /// The rotation as Euler angles. This must be a unit quaternion.
+    pub fn euler(self) -> Euler {
+        let Quaternion { w, x, y, z } = self;
+        // The roll terms are the cosine of the pitch times the sine and cosine of the roll
+        let (sin_roll, cos_roll) = (2.0 * (w * x + y * z), 1.0 - 2.0 * (x * x + y * y));
+        let roll = trig::atan2(sin_roll, cos_roll);
+        // Taking the cosine of the pitch from them rather than from the sine keeps the pitch
+        // accurate near ±90°, where an asin would lose precision
+        let cos_pitch = trig::sqrt(sin_roll * sin_roll + cos_roll * cos_roll);
+        let pitch = trig::atan2(2.0 * (w * y - z * x), cos_pitch);
+        let yaw = trig::atan2(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z));
+        Euler {
+            roll_deg: roll.to_degrees(),
+            pitch_deg: pitch.to_degrees(),
+            yaw_deg: yaw.to_degrees(),
+        }
+    }
+}
+
+impl Default for Quaternion {
+    fn default() -> Self {
+        Quaternion::IDENTITY
+    }
+}
+
+impl Add for Quaternion {
+    type Output = Quaternion;
+
+    fn add(self, other: Quaternion) -> Quaternion {
+        Quaternion::new(
+            self.w + other.w,
+            self.x + other.x,
+            self.y + other.y,
+            self.z + other.z,
+        )
+    }
+}
+
+impl Sub for Quaternion {
+    type Output = Quaternion;
+
+    fn sub(self, other: Quaternion) -> Quaternion {
+        self + other.scale(-1.0)
+    }
+}
+
+impl Mul for Quaternion {
+    type Output = Quaternion;
+
+    /// The Hamilton product, which rotates by `other` and then by `self`
+    fn mul(self, other: Quaternion) -> Quaternion {
+        let (a, b) = (self, other);
+        Quaternion::new(
+            a.w * b.w - a.x * b.x - a.y * b.y - a.z * b.z,
+            a.w * b.x + a.x * b.w + a.y * b.z - a.z * b.y,
+            a.w * b.y - a.x * b.z + a.y * b.w + a.z * b.x,
+            a.w * b.z + a.x * b.y - a.y * b.x + a.z * b.w,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rotation by `angle_deg` about the unit vector `axis`
+    fn about(axis: (f32, f32, f32), angle_deg: f32) -> Quaternion {
+        let (sin, cos) = trig::sin_cos(angle_deg.to_radians() / 2.0);
+        Quaternion::new(cos, axis.0 * sin, axis.1 * sin, axis.2 * sin)
+    }
+
+    fn assert_close(actual: Euler, expected: (f32, f32, f32)) {
+        assert!((actual.roll_deg - expected.0).abs() < 0.01, "{:?}", actual);
+        assert!((actual.pitch_deg - expected.1).abs() < 0.01, "{:?}", actual);
+        assert!((actual.yaw_deg - expected.2).abs() < 0.01, "{:?}", actual);
+    }
+
+    #[test]
+    fn rotations_compose_in_euler_order() {
+        let yaw = about((0.0, 0.0, 1.0), 30.0);
+        let pitch = about((0.0, 1.0, 0.0), 20.0);
+        let roll = about((1.0, 0.0, 0.0), -10.0);
+        let rotation = yaw * pitch * roll;
+        assert!((rotation.norm() - 1.0).abs() < 1e-6);
+        assert_close(rotation.euler(), (-10.0, 20.0, 30.0));
+        assert_close((rotation * rotation.conjugate()).euler(), (0.0, 0.0, 0.0));
+        assert_eq!(Quaternion::IDENTITY * yaw, yaw);
+    }
+
+    #[test]
+    fn pitch_is_accurate_straight_up() {
+        assert_close(about((0.0, 1.0, 0.0), 90.0).euler(), (0.0, 90.0, 0.0));
+        assert_eq!(
+            Quaternion::new(0.0, 0.0, 0.0, 2.0).normalize(),
+            Quaternion::new(0.0, 0.0, 0.0, 1.0)
+        );
+        assert_eq!(Quaternion::new(0.0, 0.0, 0.0, 0.0).normalize().w, 0.0);
+    }
+}
@@ -0,0 +1,273 @@
+//! Position, speed and course from a GPS receiver's NMEA 0183 sentences
+//!
+//! GPS receivers send a burst of sentences every second. Two of them have everything we need:
+//! `RMC` has the position, the speed over ground and the course over ground, and `GGA` has the
+//! position along with how good the fix is. Both look the same from any talker (`GP`, `GN`, `GL`,
+//! ...), so only the sentence type is checked. Sentences that fail their checksum are rejected, and
+//! other sentence types are skipped.
+//!
+//! While the receiver is moving, its course over ground is the direction of travel relative to true
+//! north. If the board points the same way, the difference between the course and the magnetic
+//! heading is the declination, which `DeclinationEstimator` averages.
+
+use crate::fusion::wrap_degrees;
+use crate::trig;
+use core::str::{FromStr, Split};
+
+// One knot in meters per second
+const KNOT_MPS: f32 = 1852.0 / 3600.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NmeaError {
+    /// The sentence doesn't start with `$` or doesn't have a checksum
+    Malformed,
+    /// The checksum doesn't match the sentence
+    Checksum,
+    /// The sentence has fewer fields than its type needs
+    MissingField,
+    /// A field isn't what its type needs, e.g. a number that doesn't parse
+    BadField,
+}
+
+/// Degrees north and east
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Position {
+    pub latitude_deg: f32,
+    pub longitude_deg: f32,
+}
+
+/// Recommended minimum data
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rmc {
+    /// `None` while the receiver doesn't have a fix, and then so are the speed and course
+    pub position: Option<Position>,
+    pub speed_mps: Option<f32>,
+    /// Course over ground in degrees clockwise from true north. Receivers leave this out while
+    /// they're standing still.
+    pub course_deg: Option<f32>,
+}
+
+/// Fix data
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Gga {
+    /// `None` while the receiver doesn't have a fix
+    pub position: Option<Position>,
+    pub satellites: u8,
+    /// Altitude above mean sea level in meters
+    pub altitude_m: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Sentence {
+    Rmc(Rmc),
+    Gga(Gga),
+}
+
+/// An optional field, which is empty if the receiver doesn't know it
+fn field<T: FromStr>(fields: &mut Split<char>) -> Result<Option<T>, NmeaError> {
+    match fields.next() {
+        None => Err(NmeaError::MissingField),
+        Some("") => Ok(None),
+        Some(field) => field.parse().map(Some).map_err(|_| NmeaError::BadField),
+    }
+}
+
+/// Latitude or longitude as degrees and minutes (`ddmm.mmmm` or `dddmm.mmmm`), followed by a
+/// hemisphere field
+fn coordinate(fields: &mut Split<char>, negative: &str) -> Result<Option<f32>, NmeaError> {
+    let value: Option<f32> = field(fields)?;
+    let hemisphere = fields.next().ok_or(NmeaError::MissingField)?;
+    Ok(value.map(|value| {
+        let degrees = (value / 100.0) as u32 as f32;
+        let degrees = degrees + (value - degrees * 100.0) / 60.0;
+        if hemisphere == negative {
+            -degrees
+        } else {
+            degrees
+        }
+    }))
+}
+
+fn position(fields: &mut Split<char>) -> Result<Option<Position>, NmeaError> {
+    let latitude_deg = coordinate(fields, "S")?;
+    let longitude_deg = coordinate(fields, "W")?;
+    Ok(latitude_deg
+        .zip(longitude_deg)
+        .map(|(latitude_deg, longitude_deg)| Position {
+            latitude_deg,
+            longitude_deg,
+        }))
+}
+
+fn rmc(fields: &mut Split<char>) -> Result<Rmc, NmeaError> {
+    let _time = fields.next();
+    let valid = fields.next().ok_or(NmeaError::MissingField)? == "A";
+    let position = position(fields)?;
+    let speed_knots: Option<f32> = field(fields)?;
+    let course_deg = field(fields)?;
+    if !valid {
+        return Ok(Rmc {
+            position: None,
+            speed_mps: None,
+            course_deg: None,
+        });
+    }
+    Ok(Rmc {
+        position,
+        speed_mps: speed_knots.map(|knots| knots * KNOT_MPS),
+        course_deg,
+    })
+}
+
+fn gga(fields: &mut Split<char>) -> Result<Gga, NmeaError> {
+    let _time = fields.next();
+    let position = position(fields)?;
+    let quality: Option<u8> = field(fields)?;
+    let satellites = field(fields)?.unwrap_or(0);
+    let _hdop: Option<f32> = field(fields)?;
+    let altitude_m = field(fields)?;
+    Ok(Gga {
+        position: position.filter(|_| quality.unwrap_or(0) > 0),
+        satellites,
+        altitude_m,
+    })
+}
+
+/// Parse one sentence, with its `$` and checksum but without its line ending. Returns `None` for
+/// sentence types other than `RMC` and `GGA`.
+pub fn parse(line: &str) -> Result<Option<Sentence>, NmeaError> {
+    let line = line.strip_prefix('$').ok_or(NmeaError::Malformed)?;
+    let (body, checksum) = line.split_once('*').ok_or(NmeaError::Malformed)?;
+    let checksum = u8::from_str_radix(checksum, 16).map_err(|_| NmeaError::Malformed)?;
+    if body.bytes().fold(0, |checksum, byte| checksum ^ byte) != checksum {
+        return Err(NmeaError::Checksum);
+    }
+
+    let mut fields = body.split(',');
+    let address = fields.next().ok_or(NmeaError::Malformed)?;
+    match address.get(2..) {
+        Some("RMC") => rmc(&mut fields).map(|rmc| Some(Sentence::Rmc(rmc))),
+        Some("GGA") => gga(&mut fields).map(|gga| Some(Sentence::Gga(gga))),
+        _ => Ok(None),
+    }
+}
+
+/// Estimates the declination from the difference between the GPS course and the magnetic heading
+pub struct DeclinationEstimator {
+    min_speed_mps: f32,
+    // How much each new difference counts
+    weight: f32,
+    // Running average of the difference as a unit vector, since averaging angles directly goes
+    // wrong around ±180°
+    sum: (f32, f32),
+    samples: u32,
+}
+
+// How many differences it takes before the estimate can be trusted
+const MIN_SAMPLES: u32 = 10;
+
+impl DeclinationEstimator {
+    /// Only courses at `min_speed_mps` or faster count, since the course of a receiver that's
+    /// barely moving is mostly noise. Each difference counts as much as one in `samples` of the
+    /// ones before.
+    pub fn new(min_speed_mps: f32, samples: u32) -> Self {
+        DeclinationEstimator {
+            min_speed_mps,
+            weight: 1.0 / samples.max(1) as f32,
+            sum: (0.0, 0.0),
+            samples: 0,
+        }
+    }
+
+    /// Add a course and the magnetic bearing at the same time, both in degrees clockwise
+    pub fn update(&mut self, course_deg: f32, speed_mps: f32, magnetic_bearing: f32) {
+        if speed_mps < self.min_speed_mps {
+            return;
+        }
+        let (sin, cos) = trig::sin_cos(wrap_degrees(course_deg - magnetic_bearing).to_radians());
+        // Ramp up from a plain average, so that the first difference doesn't count for too little
+        self.samples = self.samples.saturating_add(1);
+        let weight = self.weight.max(1.0 / self.samples as f32);
+        self.sum = (
+            self.sum.0 + (sin - self.sum.0) * weight,
+            self.sum.1 + (cos - self.sum.1) * weight,
+        );
+    }
+
+    /// The declination in degrees, positive east, once enough courses have counted
+    pub fn declination(&self) -> Option<f32> {
+        if self.samples < MIN_SAMPLES {
+            return None;
+        }
+        Some(trig::atan2(self.sum.0, self.sum.1).to_degrees())
+    }
+
+    pub fn reset(&mut self) {
+        self.sum = (0.0, 0.0);
+        self.samples = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rmc_and_gga() {
+        let rmc = parse("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A");
+        let Ok(Some(Sentence::Rmc(rmc))) = rmc else {
+            panic!("{:?}", rmc);
+        };
+        let position = rmc.position.unwrap();
+        assert!((position.latitude_deg - 48.1173).abs() < 1e-4);
+        assert!((position.longitude_deg - 11.516_667).abs() < 1e-4);
+        assert!((rmc.speed_mps.unwrap() - 11.52).abs() < 0.01);
+        assert_eq!(rmc.course_deg, Some(84.4));
+
+        let gga = parse("$GNGGA,092750.000,5321.6802,N,00630.3372,W,1,8,1.03,61.7,M,55.2,M,,*68");
+        let Ok(Some(Sentence::Gga(gga))) = gga else {
+            panic!("{:?}", gga);
+        };
+        let position = gga.position.unwrap();
+        assert!((position.latitude_deg - 53.361_337).abs() < 1e-4);
+        assert!((position.longitude_deg + 6.505_62).abs() < 1e-4);
+        assert_eq!((gga.satellites, gga.altitude_m), (8, Some(61.7)));
+
+        // No fix yet
+        assert_eq!(
+            parse("$GPRMC,,V,,,,,,,,,,N*53"),
+            Ok(Some(Sentence::Rmc(Rmc {
+                position: None,
+                speed_mps: None,
+                course_deg: None,
+            })))
+        );
+        assert_eq!(parse("$GPGSA,A,1,,,,,,,,,,,,,,,*1E"), Ok(None));
+        assert_eq!(
+            parse("$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6B"),
+            Err(NmeaError::Checksum)
+        );
+        assert_eq!(parse("GPRMC,123519*6A"), Err(NmeaError::Malformed));
+    }
+
+    #[test]
+    fn estimates_the_declination_across_north() {
+        let mut estimator = DeclinationEstimator::new(1.0, 20);
+        for i in 0..20 {
+            // Magnetic north is 5° west of true north, and we're heading about north
+            let course = if i % 4 < 2 { 358.0 } else { 2.0 };
+            estimator.update(course, 5.0, wrap_degrees(course + 5.0));
+            // Too slow to count
+            estimator.update(course, 0.5, 90.0);
+        }
+        assert!((estimator.declination().unwrap() + 5.0).abs() < 0.01);
+
+        estimator.reset();
+        assert_eq!(estimator.declination(), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Receiving NMEA sentences from a GPS receiver on USART2
+//!
+//! The receiver's TX goes to PA3, USART2's RX, at 9600 baud, 8N1, which is what almost every GPS
+//! module sends by default. We never send it anything, so TX isn't connected. See `compass::gps`
+//! for what we make of the sentences.
+
+use crate::clocks;
+use crate::uart::UartError;
+use crate::wakers;
+use compass::gps::{self, NmeaError, Sentence};
+use core::str;
+use f3::hal::stm32f30x::{gpioa, rcc, usart1, GPIOA, RCC, USART2};
+use futures::{stream, Stream};
+
+// RX pin, which is alternate function 7
+const RX: u32 = 3;
+
+// PCLK1 = 36 MHz
+// 36 MHz / 9600 baud = 3750
+const BRR: u16 = (clocks::PCLK1_HZ / 9_600) as u16;
+
+// NMEA sentences are at most 82 characters, including the line ending
+const MAX_SENTENCE: usize = 82;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GpsError {
+    /// Receiving failed
+    Read(UartError),
+    /// The sentence didn't fit into the buffer
+    TooLong,
+    Nmea(NmeaError),
+}
+
+pub struct GpsUart {
+    regs: &'static usart1::RegisterBlock,
+}
+
+impl GpsUart {
+    pub fn new() -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let gpioa: &'static gpioa::RegisterBlock = unsafe { &*GPIOA::ptr() };
+        let regs: &'static usart1::RegisterBlock = unsafe { &*USART2::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopaen().set_bit());
+        rcc.apb1enr.modify(|_, w| w.usart2en().set_bit());
+
+        gpioa.moder.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << (2 * RX))) | (0b10 << (2 * RX)))
+        });
+        // Pulled up, so that a missing receiver reads as an idle line rather than noise
+        gpioa.pupdr.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << (2 * RX))) | (0b01 << (2 * RX)))
+        });
+        gpioa
+            .afrl
+            .modify(|r, w| unsafe { w.bits((r.bits() & !(0b1111 << (4 * RX))) | (7 << (4 * RX))) });
+
+        regs.brr.write(|w| unsafe { w.bits(u32::from(BRR)) });
+        regs.cr1.write(|w| w.ue().set_bit().re().set_bit());
+
+        GpsUart { regs }
+    }
+
+    /// Wait for the next byte
+    async fn read_byte(&self) -> Result<u8, UartError> {
+        let usart2 = self.regs;
+        wakers::wait_for(
+            &wakers::USART2_RX,
+            || {
+                let isr = usart2.isr.read();
+                isr.rxne().bit_is_set() || isr.ore().bit_is_set()
+            },
+            || usart2.cr1.modify(|_, w| w.rxneie().set_bit()),
+        )
+        .await;
+
+        // Reading RDR clears RXNE, so read it even if there's an error to get rid of the byte
+        let isr = usart2.isr.read();
+        let byte = usart2.rdr.read().rdr().bits() as u8;
+        match UartError::from_isr(&isr) {
+            None => Ok(byte),
+            Some(error) => {
+                usart2
+                    .icr
+                    .write(|w| w.orecf().set_bit().fecf().set_bit().ncf().set_bit());
+                Err(error)
+            }
+        }
+    }
+
+    /// Read the next sentence, from its `$` up to its line ending. After an error, the rest of the
+    /// sentence is skipped.
+    async fn read_sentence(&self, buffer: &mut [u8; MAX_SENTENCE]) -> Result<usize, GpsError> {
+        let mut len = 0;
+        let mut error = None;
+        loop {
+            match self.read_byte().await {
+                // A sentence always starts over at its `$`, even if the last one was cut off
+                Ok(b'$') => {
+                    buffer[0] = b'$';
+                    len = 1;
+                    error = None;
+                }
+                Ok(b'\r') | Ok(b'\n') => match error {
+                    Some(error) => return Err(error),
+                    None if len > 0 => return Ok(len),
+                    None => {}
+                },
+                Ok(_) if error.is_some() || len == 0 => {}
+                Ok(byte) => match buffer.get_mut(len) {
+                    Some(slot) => {
+                        *slot = byte;
+                        len += 1;
+                    }
+                    None => error = Some(GpsError::TooLong),
+                },
+                Err(read_error) => error = Some(GpsError::Read(read_error)),
+            }
+        }
+    }
+}
+
+/// The `RMC` and `GGA` sentences from `uart`, as they arrive
+pub fn sentences_forever(uart: GpsUart) -> impl Stream<Item = Result<Sentence, GpsError>> {
+    stream::unfold(uart, |uart| async move {
+        let mut buffer = [0; MAX_SENTENCE];
+        loop {
+            let result = uart.read_sentence(&mut buffer).await.and_then(|len| {
+                let line = str::from_utf8(&buffer[..len])
+                    .map_err(|_| GpsError::Nmea(NmeaError::Malformed))?;
+                gps::parse(line).map_err(GpsError::Nmea)
+            });
+            match result {
+                Ok(None) => {}
+                Ok(Some(sentence)) => return Some((Ok(sentence), uart)),
+                Err(error) => return Some((Err(error), uart)),
+            }
+        }
+    })
+}
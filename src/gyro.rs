@@ -0,0 +1,93 @@
+//! Driver for the L3GD20 gyroscope
+
+use crate::drdy::DataReady;
+use embedded_hal_async::spi::SpiDevice;
+use futures::{stream, Stream};
+
+// Control registers
+const CTRL_REG1: u8 = 0x20;
+const CTRL_REG3: u8 = 0x22;
+const CTRL_REG4: u8 = 0x23;
+
+// STATUS_REG has the data-ready bit, followed by the angular rate data
+const STATUS_REG: u8 = 0x27;
+const ZYXDA: u8 = 1 << 3;
+
+// The first byte of a transaction is the register address; bit 7 selects read and bit 6 makes the
+// gyro auto-increment the address
+const READ: u8 = 0x80;
+const AUTO_INCREMENT: u8 = 0x40;
+
+// DR = 95 Hz, BW = 12.5 Hz, normal mode, X, Y and Z enabled
+const CTRL_REG1_VALUE: u8 = 0b0000_1111;
+
+// I2_DRDY: signal new data on INT2
+const CTRL_REG3_VALUE: u8 = 0b0000_1000;
+
+// BDU = 1 so that we never see the high and low bytes of different samples
+// FS = 250 dps
+const CTRL_REG4_VALUE: u8 = 0b1000_0000;
+
+// Sensitivity at 250 dps: 8.75 mdps/digit
+const DPS_PER_DIGIT: f32 = 0.008_75;
+
+/// Time between samples at the configured data rate of 95 Hz, in seconds
+pub const SAMPLE_PERIOD_S: f32 = 1.0 / 95.0;
+
+pub struct Gyro<SPI> {
+    spi: SPI,
+}
+
+impl<SPI: SpiDevice> Gyro<SPI> {
+    pub fn new(spi: SPI) -> Self {
+        Gyro { spi }
+    }
+
+    /// Power on the gyro, configure the data rate and range, and signal new data on INT2. This
+    /// needs to be called once before `get_gyro`.
+    pub async fn init(&mut self) -> Result<(), SPI::Error> {
+        self.spi.write(&[CTRL_REG1, CTRL_REG1_VALUE]).await?;
+        self.spi.write(&[CTRL_REG4, CTRL_REG4_VALUE]).await?;
+        self.spi.write(&[CTRL_REG3, CTRL_REG3_VALUE]).await
+    }
+
+    /// Wait for a sample that we haven't read yet, then return the angular rate around (x, y, z) in
+    /// degrees per second. Consecutive results are `SAMPLE_PERIOD_S` apart, so they can be
+    /// integrated without timestamps.
+    pub async fn get_gyro(&mut self) -> Result<(f32, f32, f32), SPI::Error> {
+        loop {
+            // Read the status register and the data in one go; the data only counts if the status
+            // register says that it's new
+            let mut buffer = [0u8; 8];
+            buffer[0] = STATUS_REG | READ | AUTO_INCREMENT;
+            self.spi.transfer_in_place(&mut buffer).await?;
+
+            // The first byte was clocked in while we were sending the address
+            if buffer[1] & ZYXDA == 0 {
+                continue;
+            }
+
+            let x = i16::from_le_bytes([buffer[2], buffer[3]]);
+            let y = i16::from_le_bytes([buffer[4], buffer[5]]);
+            let z = i16::from_le_bytes([buffer[6], buffer[7]]);
+
+            return Ok((
+                f32::from(x) * DPS_PER_DIGIT,
+                f32::from(y) * DPS_PER_DIGIT,
+                f32::from(z) * DPS_PER_DIGIT,
+            ));
+        }
+    }
+}
+
+/// Gyro readings, as fast as the gyro makes them. `drdy` must be the gyro's INT2 line.
+pub fn get_gyro_forever<SPI: SpiDevice>(
+    gyro: Gyro<SPI>,
+    drdy: DataReady,
+) -> impl Stream<Item = Result<(f32, f32, f32), SPI::Error>> {
+    stream::unfold((gyro, drdy), |(mut gyro, drdy)| async move {
+        drdy.wait_for_drdy().await;
+        let result = gyro.get_gyro().await;
+        Some((result, (gyro, drdy)))
+    })
+}
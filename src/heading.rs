@@ -0,0 +1,134 @@
+//! Turning a magnetic vector into a heading, and a heading into an LED or a bearing
+
+use crate::fusion::wrap_degrees;
+#[cfg(not(feature = "cordic"))]
+use crate::trig;
+
+/// The directions of the 8 compass LEDs, in the same order as the LEDs themselves
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    North,
+    Northeast,
+    East,
+    Southeast,
+    South,
+    Southwest,
+    West,
+    Northwest,
+}
+
+/// Takes a magnetic vector that has already been rotated into the horizontal plane. The result is
+/// corrected by `declination_deg` so that it's relative to true north rather than magnetic north.
+pub fn mag_to_angle(mag: (f32, f32, f32), declination_deg: f32) -> f32 {
+    let (x, y, _z) = mag;
+
+    let magnetic = atan2_degrees(y, x);
+    wrap_degrees(magnetic + declination_deg)
+}
+
+#[cfg(not(feature = "cordic"))]
+fn atan2_degrees(y: f32, x: f32) -> f32 {
+    trig::atan2(y, x).to_degrees()
+}
+
+#[cfg(feature = "cordic")]
+fn atan2_degrees(y: f32, x: f32) -> f32 {
+    // Scale the larger axis to 16384, which keeps all of the precision that fits into an i16
+    let largest = x.abs().max(y.abs());
+    if largest == 0.0 {
+        return 0.0;
+    }
+    let scale = 16384.0 / largest;
+    crate::cordic::atan2((y * scale) as i16, (x * scale) as i16) as f32 / 100.0
+}
+
+/// The LED that points north, for an angle in degrees in the range [0, 360)
+pub fn angle_to_direction(angle: f32) -> Direction {
+    let angle_chunked = (angle + 22.5) / 45.0;
+    let angle_rounded = angle_chunked as u32;
+    match angle_rounded {
+        0 => Direction::South,
+        1 => Direction::Southeast,
+        2 => Direction::East,
+        3 => Direction::Northeast,
+        4 => Direction::North,
+        5 => Direction::Northwest,
+        6 => Direction::West,
+        7 => Direction::Southwest,
+        8 => Direction::South,
+        _ => panic!("Illegal angle: {}", angle),
+    }
+}
+
+/// Convert an angle from `mag_to_angle` into a compass bearing: degrees clockwise from north, in
+/// the range [0, 360)
+pub fn angle_to_bearing(angle: f32) -> f32 {
+    // `angle_to_direction` lights the North LED at 180°
+    (wrap_degrees(angle) + 180.0) % 360.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn angle_follows_the_field() {
+        assert_close(mag_to_angle((1.0, 0.0, 0.0), 0.0), 0.0);
+        assert_close(mag_to_angle((0.0, 1.0, 0.0), 0.0), 90.0);
+        assert_close(mag_to_angle((-1.0, 0.0, 0.0), 0.0), 180.0);
+        assert_close(mag_to_angle((0.0, -1.0, 0.0), 0.0), -90.0);
+    }
+
+    #[test]
+    fn angle_ignores_vertical_field_and_magnitude() {
+        assert_close(mag_to_angle((300.0, 300.0, -500.0), 0.0), 45.0);
+    }
+
+    #[test]
+    fn declination_is_added_and_wrapped() {
+        assert_close(mag_to_angle((1.0, 0.0, 0.0), 10.0), 10.0);
+        assert_close(mag_to_angle((-1.0, 0.0, 0.0), 10.0), -170.0);
+    }
+
+    #[test]
+    fn directions() {
+        assert_eq!(angle_to_direction(0.0), Direction::South);
+        assert_eq!(angle_to_direction(90.0), Direction::East);
+        assert_eq!(angle_to_direction(180.0), Direction::North);
+        assert_eq!(angle_to_direction(270.0), Direction::West);
+        assert_eq!(angle_to_direction(359.0), Direction::South);
+    }
+
+    #[test]
+    fn directions_switch_halfway_between_leds() {
+        assert_eq!(angle_to_direction(22.0), Direction::South);
+        assert_eq!(angle_to_direction(23.0), Direction::Southeast);
+    }
+
+    #[test]
+    fn bearings() {
+        assert_close(angle_to_bearing(180.0), 0.0);
+        assert_close(angle_to_bearing(-90.0), 90.0);
+        assert_close(angle_to_bearing(0.0), 180.0);
+        assert_close(angle_to_bearing(90.0), 270.0);
+    }
+
+    #[test]
+    fn direction_points_north_from_bearing() {
+        // Turning the board clockwise turns north counterclockwise relative to the board
+        for &angle in &[0.0, 45.0, 90.0, 135.0, 180.0, 225.0, 270.0, 315.0] {
+            let bearing = angle_to_bearing(angle);
+            let index = libm::roundf((360.0 - bearing) / 45.0) as usize % 8;
+            assert_eq!(angle_to_direction(angle) as usize, index);
+        }
+    }
+}
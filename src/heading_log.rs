@@ -0,0 +1,47 @@
+//! The heading log, in the pages of flash just below the settings
+//!
+//! See `compass::flash_log` for the format. With a page of 170 entries and one entry every
+//! `LOG_INTERVAL_S` seconds in the main loop, each page is erased about once every six hours, so
+//! the flash's 10,000 erase cycles last for years of continuous logging.
+//!
+//! Erasing a page stalls the CPU for up to 40 ms, which only happens once a page is full.
+
+use crate::storage::{self, StorageError};
+use compass::flash_log::{Flash, FlashLog};
+use core::ptr;
+
+const PAGES: usize = 4;
+
+const LOG_ADDRESS: usize = storage::PAGE_ADDRESS - PAGES * storage::PAGE_SIZE;
+
+// The program stops at `DATA_ADDRESS`, so the log has to fit in above it
+const _: () = assert!(LOG_ADDRESS >= storage::DATA_ADDRESS);
+
+/// The log's pages of the on-chip flash
+pub struct InternalFlash;
+
+impl Flash for InternalFlash {
+    type Error = StorageError;
+
+    const PAGE_SIZE: usize = storage::PAGE_SIZE;
+
+    fn read(&self, offset: usize, bytes: &mut [u8]) {
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = unsafe { ptr::read_volatile((LOG_ADDRESS + offset + index) as *const u8) };
+        }
+    }
+
+    fn erase(&mut self, offset: usize) -> Result<(), StorageError> {
+        storage::unlocked(|| storage::erase_page(LOG_ADDRESS + offset))
+    }
+
+    fn program(&mut self, offset: usize, bytes: &[u8]) -> Result<(), StorageError> {
+        storage::unlocked(|| storage::program(LOG_ADDRESS + offset, bytes))
+    }
+}
+
+pub type HeadingLog = FlashLog<InternalFlash, PAGES>;
+
+pub fn open() -> HeadingLog {
+    FlashLog::open(InternalFlash)
+}
@@ -0,0 +1,68 @@
+//! Restarting the magnetometer when its samples stop
+//!
+//! Read errors come back from the magnetometer's stream, which retries and recovers the bus on its
+//! own. But if the magnetometer stops measuring, e.g. because a brownout put it back into its idle
+//! mode or its DRDY line is stuck, the stream just keeps waiting. So the main loop adds up how long
+//! it's been since the last sample on every tick, and once that's longer than the threshold,
+//! `MagHealth` tells the stream to stop waiting. The stream then resets the I2C peripheral, frees
+//! the bus, configures the magnetometer again and reports how that went, see
+//! `get_compass_forever`. The watchdog is still there for when that doesn't help.
+//!
+//! The age is counted in ticks rather than with `clock`, which stops in STOP mode.
+
+use core::cell::Cell;
+use core::task::Poll;
+use futures::future::poll_fn;
+use futures::task::AtomicWaker;
+
+pub struct MagHealth {
+    stall_ms: u32,
+    // Time since the last sample, or since the last restart
+    age_ms: Cell<u32>,
+    restart: Cell<bool>,
+    waker: AtomicWaker,
+}
+
+impl MagHealth {
+    /// Restart the magnetometer when there hasn't been a sample for `stall_ms`
+    pub fn new(stall_ms: u32) -> Self {
+        MagHealth {
+            stall_ms,
+            age_ms: Cell::new(0),
+            restart: Cell::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// A sample arrived
+    pub fn sample(&self) {
+        self.age_ms.set(0);
+    }
+
+    /// Another `period_ms` has passed. If that makes the last sample too old, this asks the stream
+    /// to restart, returns the age, and gives the restart as long again before asking again.
+    pub fn tick(&self, period_ms: u32) -> Option<u32> {
+        let age_ms = self.age_ms.get().saturating_add(period_ms);
+        if age_ms < self.stall_ms {
+            self.age_ms.set(age_ms);
+            return None;
+        }
+        self.age_ms.set(0);
+        self.restart.set(true);
+        self.waker.wake();
+        Some(age_ms)
+    }
+
+    /// Wait until a restart is due
+    pub async fn restart_requested(&self) {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            if self.restart.replace(false) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
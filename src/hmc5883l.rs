@@ -0,0 +1,312 @@
+//! Driver for the HMC5883L, for mounting the magnetometer on a breakout board away from the
+//! Discovery board's noise
+//!
+//! The LSM303DLHC's magnetometer grew out of the HMC5883L, so the register map is almost the same:
+//! the output registers are in the same place and order, and even the identification registers
+//! read "H43". What differs is what's in the configuration registers. The HMC5883L has one
+//! sensitivity for all three axes, can average several measurements into one, has no temperature
+//! sensor and can't measure at 220 Hz.
+//!
+//! Since it also answers at the same slave address, it can't share a bus with an LSM303DLHC.
+
+use crate::magnetometer::{self, MagSample, Magnetometer, SelfTestError};
+use embedded_hal_async::i2c::I2c;
+
+// Slave address
+const HMC5883L: u8 = 0b001_1110;
+
+// Configuration registers
+const CONFIG_A: u8 = 0x00;
+const CONFIG_B: u8 = 0x01;
+const MODE: u8 = 0x02;
+
+// The first output register. Like on the LSM303DLHC, they're ordered X, Z, Y, each high byte first.
+const DATA_X_MSB: u8 = 0x03;
+
+// The status register, and its data-ready bit
+const STATUS: u8 = 0x09;
+const RDY: u8 = 1 << 0;
+
+// Identification registers
+const IDENTIFICATION_A: u8 = 0x0a;
+const IDENTITY: [u8; 3] = *b"H43";
+
+/// Output data rate in continuous mode, the DO bits of configuration register A
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRate {
+    Hz0_75 = 0b000,
+    Hz1_5 = 0b001,
+    Hz3 = 0b010,
+    Hz7_5 = 0b011,
+    Hz15 = 0b100,
+    Hz30 = 0b101,
+    Hz75 = 0b110,
+}
+
+impl DataRate {
+    /// Time between measurements in continuous mode, in seconds
+    pub fn period_s(self) -> f32 {
+        let hz = match self {
+            DataRate::Hz0_75 => 0.75,
+            DataRate::Hz1_5 => 1.5,
+            DataRate::Hz3 => 3.0,
+            DataRate::Hz7_5 => 7.5,
+            DataRate::Hz15 => 15.0,
+            DataRate::Hz30 => 30.0,
+            DataRate::Hz75 => 75.0,
+        };
+        1.0 / hz
+    }
+}
+
+/// How many measurements are averaged into each output, the MA bits of configuration register A
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Averaging {
+    One = 0b00,
+    Two = 0b01,
+    Four = 0b10,
+    Eight = 0b11,
+}
+
+/// Measurement range, the GN bits of configuration register B. A bigger range means less
+/// resolution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gain {
+    Gauss0_88 = 0b000,
+    Gauss1_3 = 0b001,
+    Gauss1_9 = 0b010,
+    Gauss2_5 = 0b011,
+    Gauss4_0 = 0b100,
+    Gauss4_7 = 0b101,
+    Gauss5_6 = 0b110,
+    Gauss8_1 = 0b111,
+}
+
+impl Gain {
+    /// Sensitivity of every axis, in LSB per gauss
+    pub fn lsb_per_gauss(self) -> f32 {
+        match self {
+            Gain::Gauss0_88 => 1370.0,
+            Gain::Gauss1_3 => 1090.0,
+            Gain::Gauss1_9 => 820.0,
+            Gain::Gauss2_5 => 660.0,
+            Gain::Gauss4_0 => 440.0,
+            Gain::Gauss4_7 => 390.0,
+            Gain::Gauss5_6 => 330.0,
+            Gain::Gauss8_1 => 230.0,
+        }
+    }
+}
+
+/// Operating mode, the MD bits of the mode register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Measure continuously at the configured data rate
+    Continuous = 0b00,
+    /// Measure once, then go idle. Every read triggers a measurement and waits for it.
+    Single = 0b01,
+    /// Don't measure at all. `read` returns whatever is in the output registers.
+    Idle = 0b11,
+}
+
+/// Settings for `Hmc5883l::configure`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hmc5883lConfig {
+    data_rate: DataRate,
+    averaging: Averaging,
+    gain: Gain,
+    mode: Mode,
+}
+
+impl Hmc5883lConfig {
+    /// 15 Hz, no averaging, ±1.3 gauss, continuous. Those are the reset values, except that the
+    /// HMC5883L starts out in single-measurement mode.
+    pub fn new() -> Self {
+        Hmc5883lConfig {
+            data_rate: DataRate::Hz15,
+            averaging: Averaging::One,
+            gain: Gain::Gauss1_3,
+            mode: Mode::Continuous,
+        }
+    }
+
+    pub fn data_rate(mut self, data_rate: DataRate) -> Self {
+        self.data_rate = data_rate;
+        self
+    }
+
+    pub fn averaging(mut self, averaging: Averaging) -> Self {
+        self.averaging = averaging;
+        self
+    }
+
+    pub fn gain(mut self, gain: Gain) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl Default for Hmc5883lConfig {
+    fn default() -> Self {
+        Hmc5883lConfig::new()
+    }
+}
+
+pub struct Hmc5883l<I2C> {
+    i2c: I2C,
+    config: Hmc5883lConfig,
+    // The most recent measurement that we've read
+    latest: Option<MagSample>,
+}
+
+impl<I2C: I2c> Hmc5883l<I2C> {
+    /// The HMC5883L starts out in single-measurement mode. Call `configure` before reading.
+    pub fn new(i2c: I2C) -> Self {
+        Hmc5883l {
+            i2c,
+            config: Hmc5883lConfig::new(),
+            latest: None,
+        }
+    }
+
+    /// Access the underlying bus, e.g. to recover it after an error
+    pub fn bus(&mut self) -> &mut I2C {
+        &mut self.i2c
+    }
+
+    /// Whether something that identifies as an HMC5883L answers on the bus
+    pub async fn probe(&mut self) -> bool {
+        let mut identity = [0u8; 3];
+        let result = self
+            .i2c
+            .write_read(HMC5883L, &[IDENTIFICATION_A], &mut identity)
+            .await;
+        result.is_ok() && identity == IDENTITY
+    }
+
+    /// Whether the HMC5883L has a measurement that we haven't read yet
+    async fn data_ready(&mut self) -> Result<bool, I2C::Error> {
+        let mut status = [0u8];
+        self.i2c
+            .write_read(HMC5883L, &[STATUS], &mut status)
+            .await?;
+        Ok(status[0] & RDY != 0)
+    }
+
+    /// Read the output registers, whether they hold a new measurement or not
+    async fn read_output(&mut self) -> Result<MagSample, I2C::Error> {
+        let mut buffer = [0u8; 6];
+        self.i2c
+            .write_read(HMC5883L, &[DATA_X_MSB], &mut buffer)
+            .await?;
+
+        let x = i16::from_be_bytes([buffer[0], buffer[1]]);
+        let z = i16::from_be_bytes([buffer[2], buffer[3]]);
+        let y = i16::from_be_bytes([buffer[4], buffer[5]]);
+
+        let lsb_per_gauss = self.config.gain.lsb_per_gauss();
+        Ok(MagSample {
+            raw: (x, y, z),
+            lsb_per_gauss: (lsb_per_gauss, lsb_per_gauss),
+        })
+    }
+
+    /// Wait for a measurement that we haven't read yet, then return it. In single-measurement mode, this starts the measurement first. An axis that's out
+    /// of range for the gain reads -4096.
+    pub async fn read_fresh(&mut self) -> Result<MagSample, I2C::Error> {
+        if self.config.mode == Mode::Single {
+            self.i2c
+                .write(HMC5883L, &[MODE, Mode::Single as u8])
+                .await?;
+        }
+        while !self.data_ready().await? {}
+
+        let sample = self.read_output().await?;
+        self.latest = Some(sample);
+        Ok(sample)
+    }
+}
+
+impl<I2C: I2c> Magnetometer for Hmc5883l<I2C> {
+    type Config = Hmc5883lConfig;
+    type Error = I2C::Error;
+
+    /// Write the data rate, averaging, gain and mode to the HMC5883L
+    async fn configure(&mut self, config: Hmc5883lConfig) -> Result<(), I2C::Error> {
+        let config_a = (config.averaging as u8) << 5 | (config.data_rate as u8) << 2;
+        self.i2c.write(HMC5883L, &[CONFIG_A, config_a]).await?;
+        self.i2c
+            .write(HMC5883L, &[CONFIG_B, (config.gain as u8) << 5])
+            .await?;
+        self.i2c.write(HMC5883L, &[MODE, config.mode as u8]).await?;
+        self.config = config;
+        self.latest = None;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<MagSample, I2C::Error> {
+        match self.config.mode {
+            Mode::Single => self.read_fresh().await,
+            Mode::Idle => self.read_output().await,
+            Mode::Continuous => {
+                let latest = self.latest;
+                match latest {
+                    Some(latest) if !self.data_ready().await? => Ok(latest),
+                    _ => self.read_fresh().await,
+                }
+            }
+        }
+    }
+
+    async fn self_test(&mut self) -> Result<(), SelfTestError<I2C::Error>> {
+        let mut identity = [0u8; 3];
+        self.i2c
+            .write_read(HMC5883L, &[IDENTIFICATION_A], &mut identity)
+            .await
+            .map_err(SelfTestError::Bus)?;
+        if identity != IDENTITY {
+            return Err(SelfTestError::Identity(identity));
+        }
+
+        let sample = self.read_fresh().await.map_err(SelfTestError::Bus)?;
+        magnetometer::check_field(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{now, Registers};
+
+    fn hmc5883l() -> Hmc5883l<Registers> {
+        let mut registers = Registers::new(HMC5883L);
+        registers.set(IDENTIFICATION_A, &IDENTITY);
+        registers.set(STATUS, &[RDY]);
+        Hmc5883l::new(registers)
+    }
+
+    #[test]
+    fn configure_writes_every_setting() {
+        let mut hmc = hmc5883l();
+        let config = Hmc5883lConfig::new()
+            .data_rate(DataRate::Hz75)
+            .averaging(Averaging::Eight)
+            .gain(Gain::Gauss2_5);
+        now(hmc.configure(config)).unwrap();
+        assert_eq!(hmc.bus().get(CONFIG_A, 3), [0b0111_1000, 0b0110_0000, 0b00]);
+        assert!(now(hmc.probe()));
+    }
+
+    #[test]
+    fn outputs_are_ordered_x_z_y() {
+        let mut hmc = hmc5883l();
+        hmc.bus()
+            .set(DATA_X_MSB, &[0x01, 0x02, 0xff, 0xfe, 0x00, 0x10]);
+        assert_eq!(now(hmc.read()).map(|s| s.raw), Ok((0x0102, 0x0010, -2)));
+    }
+}
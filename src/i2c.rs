@@ -0,0 +1,393 @@
+//! Interrupt-driven async driver for the I2C peripheral that the sensors are on, see `board`
+//!
+//! This implements the `embedded-hal-async` I2C trait so that device drivers don't need to know
+//! that they're talking to an STM32F3.
+//!
+//! `I2cBus` owns the peripheral and hands out an `I2cDevice` to each driver that shares the bus.
+//! Every transaction locks the bus with an async mutex, so a transaction started by one driver
+//! never interleaves with another driver's transaction, and the other tasks keep running while a
+//! driver waits for its turn. Bus recovery takes the same lock, so it never cuts another driver's
+//! transaction short either.
+
+use crate::board;
+use crate::bus_recovery;
+use crate::clocks;
+use crate::delay::{Delay, Tim7};
+use crate::power::Awake;
+use crate::wakers;
+use compass::i2c_scan::{self, Devices};
+use compass::i2c_timing::{BusSpeed, Timing};
+use compass::mutex::Mutex;
+use embedded_hal_async::i2c::{self, ErrorKind, NoAcknowledgeSource, Operation, SevenBitAddress};
+use f3::hal::stm32f30x::{i2c1, RCC};
+
+// NBYTES is an 8-bit field, so longer transfers have to be split up using RELOAD
+const MAX_CHUNK: usize = 255;
+
+// How many times to check for the STOP condition when aborting a transaction, which is a few
+// hundred µs. A STOP takes at most 10 µs, even at 100 kHz, unless a slave is stretching the clock.
+const ABORT_POLLS: usize = 1_000;
+
+/// The clock that TIMINGR counts. The peripheral is clocked by either HSI or SYSCLK, depending on
+/// its bit in CFGR3. We leave it on HSI, which doesn't change when the core switches clocks.
+fn clock_hz() -> u32 {
+    let rcc = unsafe { &*RCC::ptr() };
+    if board::i2c_on_sysclk(rcc) {
+        clocks::SYSCLK_HZ
+    } else {
+        clocks::HSI_HZ
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum I2cError {
+    /// The slave didn't acknowledge its address or a data byte
+    Nack,
+    /// Misplaced START or STOP condition
+    Bus,
+    /// Another master won arbitration
+    ArbitrationLost,
+    /// A received byte was overwritten before we read it
+    Overrun,
+    /// The transaction didn't finish in time, e.g. because a slave kept stretching the clock
+    Timeout,
+}
+
+impl I2cError {
+    fn from_isr(isr: &i2c1::isr::R) -> Option<Self> {
+        if isr.nackf().bit_is_set() {
+            Some(I2cError::Nack)
+        } else if isr.berr().bit_is_set() {
+            Some(I2cError::Bus)
+        } else if isr.arlo().bit_is_set() {
+            Some(I2cError::ArbitrationLost)
+        } else if isr.ovr().bit_is_set() {
+            Some(I2cError::Overrun)
+        } else {
+            None
+        }
+    }
+}
+
+impl i2c::Error for I2cError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            I2cError::Nack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+            I2cError::Bus => ErrorKind::Bus,
+            I2cError::ArbitrationLost => ErrorKind::ArbitrationLoss,
+            I2cError::Overrun => ErrorKind::Overrun,
+            I2cError::Timeout => ErrorKind::Other,
+        }
+    }
+}
+
+/// Aborts the transaction in progress when it's dropped, unless it's been defused. This is what
+/// makes a transaction safe to cancel: dropping its future drops this too.
+struct Abort {
+    regs: &'static i2c1::RegisterBlock,
+}
+
+impl Abort {
+    /// The transaction ran to the end, one way or the other
+    fn defuse(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for Abort {
+    /// Drop can't wait for an interrupt, so this polls for the STOP condition for a little while.
+    /// If a slave is holding the clock, that doesn't come, and we reset the peripheral instead,
+    /// which lets go of both lines. That may leave the slave holding SDA, which is for the next
+    /// `recover_if_stuck` to deal with.
+    fn drop(&mut self) {
+        let i2c1 = self.regs;
+        i2c1.cr1.modify(|_, w| {
+            w.txie().clear_bit();
+            w.rxie().clear_bit();
+            w.tcie().clear_bit();
+            w.stopie().clear_bit();
+            w.nackie().clear_bit();
+            w.errie().clear_bit()
+        });
+
+        if i2c1.isr.read().busy().bit_is_set() {
+            i2c1.cr2.modify(|_, w| w.stop().set_bit());
+            let stopped = (0..ABORT_POLLS).any(|_| i2c1.isr.read().stopf().bit_is_set());
+            if !stopped {
+                // PE has to stay clear for at least 3 APB clock cycles, which the read takes care of
+                i2c1.cr1.modify(|_, w| w.pe().clear_bit());
+                while i2c1.cr1.read().pe().bit_is_set() {}
+                i2c1.cr1.modify(|_, w| w.pe().set_bit());
+            }
+        }
+
+        // Forget about the transfer, so that the next transaction starts from a clean slate
+        i2c1.cr2.reset();
+        i2c1.icr.write(|w| {
+            w.nackcf().set_bit();
+            w.berrcf().set_bit();
+            w.arlocf().set_bit();
+            w.ovrcf().set_bit();
+            w.stopcf().set_bit()
+        });
+    }
+}
+
+/// The I2C peripheral, one transaction at a time
+pub struct I2c {
+    regs: &'static i2c1::RegisterBlock,
+    // Times the clock pulses of bus recovery
+    micros: Delay<Tim7>,
+}
+
+impl I2c {
+    /// The peripheral must already be powered on and connected to its pins, by `board::init`. This
+    /// reprograms its timing to run SCL at `speed`. Nothing else may use TIM7 while bus recovery
+    /// runs.
+    pub fn new(regs: &'static i2c1::RegisterBlock, micros: Delay<Tim7>, speed: BusSpeed) -> Self {
+        // TIMINGR can only be written while the peripheral is disabled
+        regs.cr1.modify(|_, w| w.pe().clear_bit());
+        while regs.cr1.read().pe().bit_is_set() {}
+        let timing = Timing::new(clock_hz(), speed);
+        regs.timingr.write(|w| unsafe { w.bits(timing.bits()) });
+        regs.cr1.modify(|_, w| w.pe().set_bit());
+        I2c { regs, micros }
+    }
+
+    /// Run the bus recovery routine if a slave is holding SDA low
+    async fn recover_if_stuck(&mut self) {
+        if bus_recovery::bus_is_stuck(self.regs) {
+            bus_recovery::recover(self.regs, &self.micros).await;
+        }
+    }
+
+    /// Unconditionally run the bus recovery routine and reset the peripheral
+    async fn recover(&mut self) {
+        bus_recovery::recover(self.regs, &self.micros).await;
+    }
+
+    /// Wait until `flag` is set in the ISR, or until one of the error flags is set. `listen` must
+    /// enable the interrupt that corresponds to `flag`.
+    ///
+    /// On error, this clears the error flags and makes sure that the transaction is terminated so
+    /// that the next one can start from a clean slate.
+    async fn wait_for_flag(
+        &self,
+        flag: impl Fn(&i2c1::isr::R) -> bool,
+        listen: impl Fn(&mut i2c1::cr1::W) -> &mut i2c1::cr1::W,
+    ) -> Result<(), I2cError> {
+        let i2c1 = self.regs;
+        wakers::wait_for(
+            &wakers::I2C_EV,
+            || {
+                let isr = i2c1.isr.read();
+                flag(&isr) || I2cError::from_isr(&isr).is_some()
+            },
+            || {
+                i2c1.cr1
+                    .modify(|_, w| listen(w).nackie().set_bit().errie().set_bit())
+            },
+        )
+        .await;
+
+        match I2cError::from_isr(&i2c1.isr.read()) {
+            None => Ok(()),
+            Some(error) => {
+                // After a NACK in a transfer without AUTOEND we are responsible for the STOP. After
+                // a bus error or arbitration loss the peripheral has already released the bus.
+                if i2c1.isr.read().busy().bit_is_set() {
+                    i2c1.cr2.modify(|_, w| w.stop().set_bit());
+                }
+                i2c1.icr.write(|w| {
+                    w.nackcf().set_bit();
+                    w.berrcf().set_bit();
+                    w.arlocf().set_bit();
+                    w.ovrcf().set_bit();
+                    w.stopcf().set_bit()
+                });
+                Err(error)
+            }
+        }
+    }
+
+    /// Adjacent operations of the same kind are merged into a single transfer, and each change of
+    /// direction issues a RESTART. There is a single STOP at the very end.
+    ///
+    /// This is safe to cancel: if the returned future is dropped before it's done, the transaction
+    /// is aborted with a STOP, and the bus is left ready for the next one.
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), I2cError> {
+        let abort = Abort { regs: self.regs };
+        let result = self.transfer(address, operations).await;
+        // Errors have already been cleaned up after by `wait_for_flag`
+        abort.defuse();
+        result
+    }
+
+    async fn transfer(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), I2cError> {
+        let i2c1 = self.regs;
+
+        let mut run_start = 0;
+        while run_start < operations.len() {
+            let is_read = matches!(operations[run_start], Operation::Read(_));
+            let run_end = operations[run_start..]
+                .iter()
+                .position(|op| matches!(op, Operation::Read(_)) != is_read)
+                .map_or(operations.len(), |offset| run_start + offset);
+            let mut remaining: usize = operations[run_start..run_end]
+                .iter()
+                .map(|op| match op {
+                    Operation::Read(buffer) => buffer.len(),
+                    Operation::Write(buffer) => buffer.len(),
+                })
+                .sum();
+
+            // Broadcast START (or RESTART) and the address with the R/W bit
+            let mut chunk = remaining.min(MAX_CHUNK);
+            i2c1.cr2.write(|w| {
+                w.start().set_bit();
+                w.sadd1().bits(address);
+                w.rd_wrn().bit(is_read);
+                w.nbytes().bits(chunk as u8);
+                w.reload().bit(remaining > MAX_CHUNK);
+                w.autoend().clear_bit()
+            });
+
+            for operation in &mut operations[run_start..run_end] {
+                let length = match operation {
+                    Operation::Read(buffer) => buffer.len(),
+                    Operation::Write(buffer) => buffer.len(),
+                };
+                for index in 0..length {
+                    if chunk == 0 {
+                        // Wait until the previous chunk is done and then ask for the next one
+                        self.wait_for_flag(|isr| isr.tcr().bit_is_set(), |w| w.tcie().set_bit())
+                            .await?;
+                        chunk = remaining.min(MAX_CHUNK);
+                        i2c1.cr2.modify(|_, w| {
+                            w.nbytes().bits(chunk as u8);
+                            w.reload().bit(remaining > MAX_CHUNK)
+                        });
+                    }
+
+                    match operation {
+                        Operation::Read(buffer) => {
+                            // Wait until we have received something
+                            self.wait_for_flag(
+                                |isr| isr.rxne().bit_is_set(),
+                                |w| w.rxie().set_bit(),
+                            )
+                            .await?;
+                            buffer[index] = i2c1.rxdr.read().rxdata().bits();
+                        }
+                        Operation::Write(buffer) => {
+                            // Wait until we can send more data
+                            self.wait_for_flag(
+                                |isr| isr.txis().bit_is_set(),
+                                |w| w.txie().set_bit(),
+                            )
+                            .await?;
+                            i2c1.txdr.write(|w| w.txdata().bits(buffer[index]));
+                        }
+                    }
+                    chunk -= 1;
+                    remaining -= 1;
+                }
+            }
+
+            // Wait until the last byte of this run has been transferred
+            self.wait_for_flag(|isr| isr.tc().bit_is_set(), |w| w.tcie().set_bit())
+                .await?;
+
+            run_start = run_end;
+        }
+
+        // Broadcast STOP and wait until it's actually on the bus
+        i2c1.cr2.modify(|_, w| w.stop().set_bit());
+        self.wait_for_flag(|isr| isr.stopf().bit_is_set(), |w| w.stopie().set_bit())
+            .await?;
+        i2c1.icr.write(|w| w.stopcf().set_bit());
+
+        Ok(())
+    }
+}
+
+/// Shares the I2C peripheral between the drivers of the devices on the bus
+pub struct I2cBus {
+    i2c: Mutex<I2c>,
+}
+
+impl I2cBus {
+    pub fn new(i2c: I2c) -> Self {
+        I2cBus {
+            i2c: Mutex::new(i2c),
+        }
+    }
+
+    /// A handle for the driver of one device on the bus
+    pub fn device(&self) -> I2cDevice<'_> {
+        I2cDevice { bus: self }
+    }
+}
+
+/// One driver's access to the bus. Each transaction holds the bus until it's done, and the
+/// peripheral needs its clock until then, so it also keeps the MCU out of STOP mode.
+#[derive(Clone)]
+pub struct I2cDevice<'a> {
+    bus: &'a I2cBus,
+}
+
+impl I2cDevice<'_> {
+    /// Run the bus recovery routine if a slave is holding SDA low
+    pub async fn recover_if_stuck(&mut self) {
+        let mut i2c = self.bus.i2c.lock().await;
+        let _awake = Awake::new();
+        i2c.recover_if_stuck().await;
+    }
+
+    /// Unconditionally run the bus recovery routine and reset the peripheral
+    pub async fn recover(&mut self) {
+        let mut i2c = self.bus.i2c.lock().await;
+        let _awake = Awake::new();
+        i2c.recover().await;
+    }
+
+    /// Find every address that acknowledges a read. Each address is a transaction of its own, so
+    /// other drivers carry on in between.
+    pub async fn scan(&mut self) -> Devices {
+        let mut devices = Devices::new();
+        for address in i2c_scan::FIRST_ADDRESS..=i2c_scan::LAST_ADDRESS {
+            // Reading is safer than writing an empty message, which some parts take as a command
+            let mut byte = [0];
+            if i2c::I2c::read(self, address, &mut byte).await.is_ok() {
+                devices.insert(address);
+            }
+        }
+        devices
+    }
+}
+
+impl i2c::ErrorType for I2cDevice<'_> {
+    type Error = I2cError;
+}
+
+impl i2c::I2c<SevenBitAddress> for I2cDevice<'_> {
+    /// See `I2c::transaction`, which is safe to cancel. Cancelling also releases the bus.
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut i2c = self.bus.i2c.lock().await;
+        let _awake = Awake::new();
+        i2c.transaction(address, operations).await
+    }
+}
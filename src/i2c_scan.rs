@@ -0,0 +1,83 @@
+//! Finding out what's on the I2C bus, to debug the wiring of external sensors and displays
+//!
+//! A scan reads a byte from every 7-bit address outside the reserved ones at either end, and each
+//! address that acknowledges goes into `Devices`. `known` names the parts that the firmware has
+//! drivers for, so that the log says e.g. "0x0d QMC5883L" instead of leaving the datasheets to the
+//! user. Several parts share an address, in which case all of them are named.
+
+/// Addresses below this are reserved, e.g. for general calls and CBUS
+pub const FIRST_ADDRESS: u8 = 0x08;
+/// Addresses above this are reserved, e.g. for 10-bit addressing
+pub const LAST_ADDRESS: u8 = 0x77;
+
+/// The addresses that answered, as a bit per address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Devices(u128);
+
+impl Devices {
+    pub const fn new() -> Self {
+        Devices(0)
+    }
+
+    pub fn insert(&mut self, address: u8) {
+        self.0 |= 1 << (address & 0x7f);
+    }
+
+    pub fn contains(&self, address: u8) -> bool {
+        self.0 & 1 << (address & 0x7f) != 0
+    }
+
+    pub fn len(&self) -> u32 {
+        self.0.count_ones()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The addresses in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=0x7f).filter(move |&address| self.contains(address))
+    }
+}
+
+/// What's usually at `address`, out of the parts that we have drivers for
+pub fn known(address: u8) -> Option<&'static str> {
+    match address {
+        0x0d => Some("QMC5883L"),
+        0x19 => Some("LSM303DLHC accelerometer"),
+        0x1e => Some("LSM303DLHC magnetometer or HMC5883L"),
+        0x30 => Some("MMC5983MA"),
+        0x3c | 0x3d => Some("SSD1306"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_devices_in_order() {
+        let mut devices = Devices::new();
+        assert!(devices.is_empty());
+        for &address in [0x3c, 0x0d, 0x19, 0x1e, 0x0d, LAST_ADDRESS].iter() {
+            devices.insert(address);
+        }
+        assert_eq!(devices.len(), 5);
+        assert!(devices.contains(0x19));
+        assert!(!devices.contains(0x1a));
+
+        let mut addresses = devices.iter();
+        assert_eq!(addresses.next(), Some(0x0d));
+        assert_eq!(addresses.next(), Some(0x19));
+        assert_eq!(addresses.next(), Some(0x1e));
+        assert_eq!(addresses.next(), Some(0x3c));
+        assert_eq!(addresses.next(), Some(LAST_ADDRESS));
+        assert_eq!(addresses.next(), None);
+
+        assert_eq!(known(0x3c), Some("SSD1306"));
+        assert_eq!(known(LAST_ADDRESS), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! I2C bus timing
+//!
+//! The STM32F3's I2C peripheral times SCL with the fields of its TIMINGR register, which count
+//! cycles of the I2C clock divided by PRESC + 1. This works out TIMINGR for a bus speed and an I2C
+//! clock, following the example timings in the reference manual (RM0316, "Examples of timings
+//! settings"): PRESC is picked so that the prescaled clock runs at 4 MHz in standard mode and 8 MHz
+//! in fast mode, and then the other fields are the same for every clock.
+//!
+//! A read of the magnetometer moves 9 bytes including the addresses, which takes about 1 ms in
+//! standard mode and about 250 µs in fast mode.
+
+/// The speed of SCL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BusSpeed {
+    /// 100 kHz
+    Standard,
+    /// 400 kHz
+    Fast,
+}
+
+impl BusSpeed {
+    pub fn hz(self) -> u32 {
+        match self {
+            BusSpeed::Standard => 100_000,
+            BusSpeed::Fast => 400_000,
+        }
+    }
+}
+
+/// The fields of TIMINGR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timing {
+    pub presc: u8,
+    pub scldel: u8,
+    pub sdadel: u8,
+    pub sclh: u8,
+    pub scll: u8,
+}
+
+impl Timing {
+    /// The timing for `speed` with an I2C clock of `clock_hz`, which must be at least 4 MHz for
+    /// standard mode and 8 MHz for fast mode, and at most 64 MHz. A clock that isn't a multiple of
+    /// those runs the bus a little slower than `speed`, never faster.
+    pub fn new(clock_hz: u32, speed: BusSpeed) -> Self {
+        let prescaled_hz = match speed {
+            BusSpeed::Standard => 4_000_000,
+            BusSpeed::Fast => 8_000_000,
+        };
+        // Round up, so that the prescaled clock isn't faster than it should be
+        let presc = clock_hz.div_ceil(prescaled_hz).clamp(1, 16) - 1;
+        // In cycles of the prescaled clock. The data setup time of at least 250 ns in standard
+        // mode and 100 ns in fast mode is one SCLDEL + 1 cycle either way.
+        let (scldel, sdadel, sclh, scll) = match speed {
+            // SCL is low for 5.0 µs and high for 4.0 µs
+            BusSpeed::Standard => (0x4, 0x2, 0xf, 0x13),
+            // SCL is low for 1.25 µs and high for 0.5 µs
+            BusSpeed::Fast => (0x3, 0x1, 0x3, 0x9),
+        };
+        Timing {
+            presc: presc as u8,
+            scldel,
+            sdadel,
+            sclh,
+            scll,
+        }
+    }
+
+    /// The value of TIMINGR
+    pub fn bits(self) -> u32 {
+        u32::from(self.presc) << 28
+            | u32::from(self.scldel) << 20
+            | u32::from(self.sdadel) << 16
+            | u32::from(self.sclh) << 8
+            | u32::from(self.scll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_manual_at_8_mhz() {
+        assert_eq!(
+            Timing::new(8_000_000, BusSpeed::Standard).bits(),
+            0x1042_0f13
+        );
+        assert_eq!(Timing::new(8_000_000, BusSpeed::Fast).bits(), 0x0031_0309);
+    }
+
+    #[test]
+    fn prescaler_follows_the_clock() {
+        assert_eq!(Timing::new(48_000_000, BusSpeed::Standard).presc, 0xb);
+        assert_eq!(Timing::new(48_000_000, BusSpeed::Fast).presc, 0x5);
+        assert_eq!(Timing::new(16_000_000, BusSpeed::Fast).presc, 0x1);
+        // 36 MHz doesn't divide down to 8 MHz, so SCL ends up a bit slower
+        assert_eq!(Timing::new(36_000_000, BusSpeed::Fast).presc, 0x4);
+        assert_eq!(Timing::new(72_000_000, BusSpeed::Fast).presc, 0x8);
+    }
+}
@@ -0,0 +1,37 @@
+//! Which interrupts run the handlers in `wakers` and `clock`
+//!
+//! The handlers live next to the futures that they wake, but they're bound here, so that the RTIC
+//! build (`src/bin/rtic.rs`) can share those modules and bind the same handlers to its hardware
+//! tasks instead. Drivers that nothing else shares, like `buzzer` and `display::pwm`, bind their
+//! own.
+
+use crate::{clock, wakers};
+use cortex_m_rt::exception;
+use f3::hal::stm32f30x::interrupt;
+
+#[exception]
+fn SysTick() {
+    wakers::systick();
+}
+
+#[cfg(not(feature = "nucleo-f303ze"))]
+interrupt!(I2C1_EV_EXTI23, wakers::i2c_ev);
+#[cfg(not(feature = "nucleo-f303ze"))]
+interrupt!(I2C1_ER, wakers::i2c_er);
+#[cfg(feature = "nucleo-f303ze")]
+interrupt!(I2C2_EV_EXTI24, wakers::i2c_ev);
+#[cfg(feature = "nucleo-f303ze")]
+interrupt!(I2C2_ER, wakers::i2c_er);
+interrupt!(SPI1, wakers::spi1);
+interrupt!(SPI2, wakers::spi2);
+interrupt!(TIM2, clock::tim2);
+interrupt!(TIM7, wakers::tim7);
+interrupt!(EXTI0, wakers::exti0);
+interrupt!(EXTI1, wakers::exti1);
+interrupt!(EXTI2_TSC, wakers::exti2_tsc);
+interrupt!(EXTI4, wakers::exti4);
+interrupt!(EXTI9_5, wakers::exti9_5);
+interrupt!(RTC_WKUP, wakers::rtc_wkup);
+interrupt!(USART1_EXTI25, wakers::usart1_exti25);
+interrupt!(USART2_EXTI26, wakers::usart2_exti26);
+interrupt!(USB_LP_CAN_RX0, wakers::usb_lp_can_rx0);
@@ -0,0 +1,38 @@
+//! Periodic ticks from SysTick
+//!
+//! TIM2 already paces the main loop, so SysTick is left for slow background jobs, like reading the
+//! temperature. SysTick counts HCLK / 8 with a 24-bit reload value, which limits the period to
+//! about 1.8 seconds. Counting the core clock itself would limit it to 233 ms.
+
+use crate::clocks;
+use crate::wakers;
+use core::future::Future;
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+
+// SysTick's external clock is HCLK / 8 = 9 MHz
+const CYCLES_PER_MS: u32 = clocks::HCLK_HZ / 8 / 1_000;
+
+pub struct Interval {
+    // Owning SYST makes sure that nothing else reconfigures it
+    _syst: SYST,
+}
+
+impl Interval {
+    /// Start SysTick with a period of `ms` milliseconds, which must be at most 1864
+    pub fn new(mut syst: SYST, ms: u32) -> Self {
+        syst.set_clock_source(SystClkSource::External);
+        syst.set_reload(ms * CYCLES_PER_MS - 1);
+        syst.clear_current();
+        syst.enable_interrupt();
+        syst.enable_counter();
+
+        Interval { _syst: syst }
+    }
+
+    /// Wait until the next period has elapsed. If more than one period has elapsed since the
+    /// previous call, this returns immediately, but only once.
+    pub fn tick(&mut self) -> impl Future<Output = ()> + '_ {
+        wakers::wait_for(&wakers::SYSTICK, wakers::systick_elapsed, || ())
+    }
+}
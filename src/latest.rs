@@ -0,0 +1,119 @@
+//! The most recent value of something, for any number of readers
+//!
+//! A `LatestValue` is for samples where only the freshest one matters, so unlike a `Channel` it
+//! never fills up: `set` replaces whatever was there, and every reader gets a copy of the newest
+//! value whenever it asks. A `Subscriber` also remembers which value it saw last, so that a task
+//! that runs on its own schedule can tell whether anything new has arrived since.
+//!
+//! Neither side ever waits, so it can be shared with interrupt handlers. There are two slots, and
+//! `set` always writes the one that readers aren't being pointed at before switching them over.
+//! A read that was interrupted by a `set` can only be torn if a second `set` came along and wrote
+//! the slot that it was reading, in which case the version has moved on and it reads again. That
+//! relies on there being one writer at a time.
+
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub struct LatestValue<T> {
+    slots: [UnsafeCell<T>; 2],
+    // Counts the values that have been set, and picks the slot that readers use
+    version: AtomicU32,
+}
+
+// Readers only ever get copies, and `set` takes care not to write the slot that they read
+unsafe impl<T: Copy + Send> Sync for LatestValue<T> {}
+
+impl<T: Copy> LatestValue<T> {
+    pub const fn new(value: T) -> Self {
+        LatestValue {
+            slots: [UnsafeCell::new(value), UnsafeCell::new(value)],
+            version: AtomicU32::new(0),
+        }
+    }
+
+    /// Replace the value. Only one task or interrupt handler may set it.
+    pub fn set(&self, value: T) {
+        let version = self.version.load(Ordering::Relaxed).wrapping_add(1);
+        let slot = self.slots[version as usize % 2].get();
+        unsafe { ptr::write_volatile(slot, value) };
+        self.version.store(version, Ordering::Release);
+    }
+
+    /// The newest value and its version
+    fn read(&self) -> (T, u32) {
+        loop {
+            let version = self.version.load(Ordering::Acquire);
+            let slot = self.slots[version as usize % 2].get();
+            let value = unsafe { ptr::read_volatile(slot) };
+            if self.version.load(Ordering::Acquire) == version {
+                return (value, version);
+            }
+        }
+    }
+
+    /// The newest value
+    pub fn get(&self) -> T {
+        self.read().0
+    }
+
+    /// A reader that starts out having seen the current value
+    pub fn subscribe(&self) -> Subscriber<'_, T> {
+        Subscriber {
+            latest: self,
+            seen: self.version.load(Ordering::Acquire),
+        }
+    }
+}
+
+/// One reader of a `LatestValue`
+pub struct Subscriber<'a, T> {
+    latest: &'a LatestValue<T>,
+    // The version of the last value that this reader got
+    seen: u32,
+}
+
+impl<T: Copy> Subscriber<'_, T> {
+    /// The newest value, whether or not we've seen it already
+    pub fn get(&mut self) -> T {
+        let (value, version) = self.latest.read();
+        self.seen = version;
+        value
+    }
+
+    /// The newest value, if it's been set since we last got one
+    pub fn changed(&mut self) -> Option<T> {
+        let (value, version) = self.latest.read();
+        if version == self.seen {
+            return None;
+        }
+        self.seen = version;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readers_see_the_newest_value_independently() {
+        let latest = LatestValue::new((0, 0, 0));
+        let mut display = latest.subscribe();
+        let mut telemetry = latest.subscribe();
+        assert_eq!(display.changed(), None);
+
+        latest.set((1, 2, 3));
+        latest.set((4, 5, 6));
+        assert_eq!(latest.get(), (4, 5, 6));
+        // Only the newest value counts, however many were set in between
+        assert_eq!(display.changed(), Some((4, 5, 6)));
+        assert_eq!(display.changed(), None);
+
+        latest.set((7, 8, 9));
+        assert_eq!(display.changed(), Some((7, 8, 9)));
+        assert_eq!(telemetry.changed(), Some((7, 8, 9)));
+        assert_eq!(telemetry.get(), (7, 8, 9));
+        assert_eq!(telemetry.changed(), None);
+    }
+}
@@ -0,0 +1,62 @@
+//! The compass's math, without any hardware
+//!
+//! Everything in here is plain computation on numbers, except for the magnetometer drivers, which
+//! only talk to the bus through the `embedded-hal-async` I2C trait, and the timer queue, the
+//! channels and the mutex, which only deal in `Waker`s. So besides running on the board, it builds
+//! and runs its unit tests on the host:
+//!
+//! ```text
+//! cargo test-host
+//! ```
+//!
+//! The firmware in main.rs reads the sensors, feeds the readings through this library and shows
+//! the result. The simulator in sim/ does the same with mocked peripherals, see `cargo sim`.
+
+#![no_std]
+
+pub mod alarm;
+pub mod anomaly;
+pub mod bluetooth;
+pub mod calibration;
+pub mod capture;
+pub mod channel;
+pub mod confidence;
+pub mod config;
+pub mod cordic;
+pub mod crc;
+pub mod dead_reckoning;
+pub mod declination;
+pub mod entropy;
+pub mod fft;
+pub mod filters;
+pub mod fixed;
+pub mod flash_log;
+pub mod flight_recorder;
+pub mod fusion;
+pub mod gps;
+pub mod heading;
+pub mod hmc5883l;
+pub mod i2c_scan;
+pub mod i2c_timing;
+pub mod latest;
+pub mod log_buffer;
+pub mod lsm303dlhc;
+pub mod magnetometer;
+pub mod mavlink;
+pub mod mmc5983ma;
+#[cfg(test)]
+mod mock;
+pub mod modes;
+pub mod mutex;
+pub mod pedometer;
+pub mod plausibility;
+pub mod qmc5883l;
+pub mod rate_of_turn;
+pub mod sample_rate;
+pub mod shake;
+pub mod smoothing;
+pub mod stored;
+pub mod tilt_compensation;
+pub mod timer_queue;
+pub mod trig;
+pub mod vibration;
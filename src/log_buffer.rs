@@ -0,0 +1,117 @@
+//! A buffer of log messages that the writer never has to wait for
+//!
+//! Messages go in whole, each ending with a line feed, and come out a byte at a time as fast as
+//! whatever sends them can take them. When a message doesn't fit, the oldest messages are dropped
+//! to make room for it, since the latest ones say the most about what's happening now. `dropped`
+//! counts them, so that the log can say that something is missing.
+
+pub struct LogBuffer<const N: usize> {
+    bytes: [u8; N],
+    // Where the oldest byte is, and how many there are
+    start: usize,
+    len: usize,
+    // Messages dropped since the last `take_dropped`
+    dropped: u32,
+}
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> Self {
+        LogBuffer {
+            bytes: [0; N],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Add `message`, dropping the oldest messages until it fits. A message that's longer than the
+    /// whole buffer is dropped itself.
+    pub fn push(&mut self, message: &[u8]) {
+        if message.len() > N {
+            self.dropped += 1;
+            return;
+        }
+        while N - self.len < message.len() {
+            self.drop_oldest();
+        }
+        for &byte in message {
+            self.bytes[(self.start + self.len) % N] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Drop everything up to and including the first line feed. If the oldest message has already
+    /// been partly sent, that's only the rest of it.
+    fn drop_oldest(&mut self) {
+        while let Some(byte) = self.pop() {
+            if byte == b'\n' {
+                break;
+            }
+        }
+        self.dropped += 1;
+    }
+
+    /// The oldest byte, if there is one
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many messages have been dropped since the last call
+    pub fn take_dropped(&mut self) -> u32 {
+        core::mem::take(&mut self.dropped)
+    }
+}
+
+impl<const N: usize> Default for LogBuffer<N> {
+    fn default() -> Self {
+        LogBuffer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain<const N: usize>(buffer: &mut LogBuffer<N>) -> heapless::Vec<u8, N> {
+        let mut bytes = heapless::Vec::new();
+        while let Some(byte) = buffer.pop() {
+            bytes.push(byte).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn drops_the_oldest_messages() {
+        let mut buffer = LogBuffer::<16>::new();
+        buffer.push(b"one\n");
+        buffer.push(b"two\n");
+        buffer.push(b"three\n");
+        assert_eq!(buffer.take_dropped(), 0);
+
+        // Needs room for 6 bytes, and only "one" has to go
+        buffer.push(b"four!\n");
+        assert_eq!(buffer.take_dropped(), 1);
+        assert_eq!(drain(&mut buffer), b"two\nthree\nfour!\n"[..]);
+        assert!(buffer.is_empty());
+
+        // Wrapped around the end, and bigger than everything else
+        buffer.push(b"a\n");
+        buffer.push(b"fifteen bytes!\n");
+        assert_eq!(buffer.take_dropped(), 1);
+        assert_eq!(drain(&mut buffer), b"fifteen bytes!\n"[..]);
+
+        buffer.push(b"seventeen bytes!\n");
+        assert_eq!(buffer.take_dropped(), 1);
+        assert!(buffer.is_empty());
+    }
+}
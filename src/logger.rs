@@ -0,0 +1,216 @@
+//! Debug output over ITM or, with the `rtt` feature, over RTT
+//!
+//! ITM needs a probe that supports SWO, and not every probe does. RTT works with any probe that
+//! can read memory while the program is running.
+//!
+//! With the `defmt` feature, messages go over RTT in defmt's compact binary encoding instead and
+//! are formatted on the host, which is much cheaper than formatting them on the board. Either way,
+//! log with `error!`, `warn!`, `info!` and `debug!`, using format strings that work with both
+//! `core::fmt` and defmt, and arguments that implement both `Debug` and `defmt::Format`. Every
+//! message is stamped with the time from `clock`, so that it can be matched up with the telemetry.
+//!
+//! Writing to ITM waits for room in its FIFO, which only empties as fast as SWO runs, and not at all
+//! if nothing is reading it. So messages for ITM go into a `LogBuffer` instead, and `drain` sends
+//! as much of it as the FIFO takes without waiting. Logging never holds up the main loop, but when
+//! the buffer fills up faster than it drains, the oldest messages are dropped, which `take_dropped`
+//! counts. RTT and defmt-rtt already skip messages that don't fit into their buffers rather than
+//! wait for the host.
+
+#[cfg(not(any(feature = "rtt", feature = "defmt")))]
+use compass::log_buffer::LogBuffer;
+use core::fmt;
+use cortex_m::peripheral::ITM;
+#[cfg(feature = "rtt")]
+use rtt_target::{rtt_init_default, UpChannel};
+
+#[cfg(all(feature = "rtt", feature = "defmt"))]
+compile_error!("defmt has its own RTT logger, so only one of `rtt` and `defmt` can be enabled");
+
+// Links in defmt's global logger
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+
+// About as much as SWO at 2 MHz sends in 10 ms
+#[cfg(not(any(feature = "rtt", feature = "defmt")))]
+const BUFFER_SIZE: usize = 2048;
+
+// Longer messages are cut short
+#[cfg(not(any(feature = "rtt", feature = "defmt")))]
+const MAX_MESSAGE: usize = 256;
+
+pub struct Logger {
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    itm: ITM,
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    buffer: LogBuffer<BUFFER_SIZE>,
+    // The message that's being written, up to its line feed
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    message: heapless::Vec<u8, MAX_MESSAGE>,
+    #[cfg(feature = "rtt")]
+    channel: UpChannel,
+}
+
+impl Logger {
+    /// Log over ITM stimulus port 0
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    pub fn new(itm: ITM) -> Self {
+        Logger {
+            itm,
+            buffer: LogBuffer::new(),
+            message: heapless::Vec::new(),
+        }
+    }
+
+    /// Send as much of the buffer as ITM takes right now
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    pub fn drain(&mut self) {
+        let port = &mut self.itm.stim[0];
+        while port.is_fifo_ready() {
+            match self.buffer.pop() {
+                Some(byte) => port.write_u8(byte),
+                None => break,
+            }
+        }
+    }
+
+    /// Wait until everything has been sent, for output that's worth waiting for, like a dump that
+    /// the user asked for and that's bigger than the buffer
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    pub fn flush(&mut self) {
+        while !self.buffer.is_empty() {
+            self.drain();
+        }
+    }
+
+    /// How many messages have been dropped since the last call
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    pub fn take_dropped(&mut self) -> u32 {
+        self.buffer.take_dropped()
+    }
+
+    /// Log over RTT up channel 0. ITM isn't used.
+    #[cfg(feature = "rtt")]
+    pub fn new(_itm: ITM) -> Self {
+        let channels = rtt_init_default!();
+        Logger {
+            channel: channels.up.0,
+        }
+    }
+
+    /// defmt has its own global logger, so this doesn't do anything. ITM isn't used.
+    #[cfg(feature = "defmt")]
+    pub fn new(_itm: ITM) -> Self {
+        Logger {}
+    }
+
+    /// Messages go out as they're written, so there's nothing to drain
+    #[cfg(any(feature = "rtt", feature = "defmt"))]
+    pub fn drain(&mut self) {}
+
+    #[cfg(any(feature = "rtt", feature = "defmt"))]
+    pub fn flush(&mut self) {}
+
+    /// The RTT buffer doesn't say how much it has skipped
+    #[cfg(any(feature = "rtt", feature = "defmt"))]
+    pub fn take_dropped(&mut self) -> u32 {
+        0
+    }
+}
+
+impl fmt::Write for Logger {
+    /// Collect the message until its line feed, then buffer it and send what ITM takes
+    #[cfg(not(any(feature = "rtt", feature = "defmt")))]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte == b'\n' {
+                // A message that was cut short still ends its line
+                if self.message.is_full() {
+                    self.message.pop();
+                }
+                self.message.push(byte).ok();
+                self.buffer.push(&self.message);
+                self.message.clear();
+            } else {
+                // Whatever doesn't fit is dropped
+                self.message.push(byte).ok();
+            }
+        }
+        self.drain();
+        Ok(())
+    }
+
+    #[cfg(feature = "rtt")]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.channel.write_str(s)
+    }
+
+    #[cfg(feature = "defmt")]
+    fn write_str(&mut self, _s: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+/// defmt-rtt takes a critical section around each message, and Embassy around its queues. This is
+/// a single core, so disabling interrupts is enough.
+#[cfg(any(feature = "defmt", feature = "embassy"))]
+mod critical_section_impl {
+    use cortex_m::{interrupt, register::primask};
+
+    struct SingleCore;
+    critical_section::set_impl!(SingleCore);
+
+    unsafe impl critical_section::Impl for SingleCore {
+        unsafe fn acquire() -> bool {
+            let was_active = primask::read().is_active();
+            interrupt::disable();
+            was_active
+        }
+
+        unsafe fn release(was_active: bool) {
+            if was_active {
+                interrupt::enable();
+            }
+        }
+    }
+}
+
+/// Log a line, prefixed with the time in seconds and its level
+#[cfg(not(feature = "defmt"))]
+macro_rules! log {
+    ($logger:expr, $level:ident, $($arg:tt)*) => {{
+        use core::fmt::Write as _;
+        let ms = crate::clock::now().as_millis();
+        // There's nothing useful to do if logging fails
+        write!($logger, "{}.{:03} {} ", ms / 1000, ms % 1000, stringify!($level)).ok();
+        writeln!($logger, $($arg)*).ok();
+    }};
+}
+
+/// Hand the message to defmt, which records the level itself
+#[cfg(feature = "defmt")]
+macro_rules! log {
+    ($logger:expr, $level:ident, $($arg:tt)*) => {{
+        let _ = &$logger;
+        defmt::$level!($($arg)*);
+    }};
+}
+
+/// Something went wrong
+macro_rules! error {
+    ($logger:expr, $($arg:tt)*) => { log!($logger, error, $($arg)*) };
+}
+
+/// Something might go wrong
+macro_rules! warn {
+    ($logger:expr, $($arg:tt)*) => { log!($logger, warn, $($arg)*) };
+}
+
+/// Something that the user did or should know about
+macro_rules! info {
+    ($logger:expr, $($arg:tt)*) => { log!($logger, info, $($arg)*) };
+}
+
+/// Values that are only interesting while debugging, possibly many times a second
+macro_rules! debug {
+    ($logger:expr, $($arg:tt)*) => { log!($logger, debug, $($arg)*) };
+}
@@ -0,0 +1,326 @@
+//! Driver for the magnetometer half of the LSM303DLHC, which is on the F3 Discovery board
+
+use crate::magnetometer::{self, MagSample, Magnetometer, SelfTestError};
+use embedded_hal_async::i2c::I2c;
+
+// Slave address
+const MAGNETOMETER: u8 = 0b001_1110;
+
+// Configuration registers
+const CRA_REG_M: u8 = 0x00;
+const CRB_REG_M: u8 = 0x01;
+const MR_REG_M: u8 = 0x02;
+
+// Addresses of the magnetometer's register that has the magnetic data
+const OUT_X_H_M: u8 = 0x03;
+
+// CRA_REG_M bit that enables the temperature sensor
+const TEMP_EN: u8 = 1 << 7;
+
+// The temperature, high byte first
+const TEMP_OUT_H_M: u8 = 0x31;
+
+// The temperature is 12 bits, left-justified in 16 bits, at 8 LSB per °C
+const TEMP_SHIFT: u32 = 4;
+const TEMP_LSB_PER_C: f32 = 8.0;
+
+// Identification registers, which always read "H43"
+const IRA_REG_M: u8 = 0x0a;
+const IDENTITY: [u8; 3] = *b"H43";
+
+// The status register, and its data-ready bit
+const SR_REG_M: u8 = 0x09;
+const DRDY: u8 = 1 << 0;
+
+/// Output data rate, the DO bits of CRA_REG_M
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRate {
+    Hz0_75 = 0b000,
+    Hz1_5 = 0b001,
+    Hz3 = 0b010,
+    Hz7_5 = 0b011,
+    Hz15 = 0b100,
+    Hz30 = 0b101,
+    Hz75 = 0b110,
+    Hz220 = 0b111,
+}
+
+impl DataRate {
+    /// Time between measurements in continuous mode, in seconds
+    pub fn period_s(self) -> f32 {
+        let hz = match self {
+            DataRate::Hz0_75 => 0.75,
+            DataRate::Hz1_5 => 1.5,
+            DataRate::Hz3 => 3.0,
+            DataRate::Hz7_5 => 7.5,
+            DataRate::Hz15 => 15.0,
+            DataRate::Hz30 => 30.0,
+            DataRate::Hz75 => 75.0,
+            DataRate::Hz220 => 220.0,
+        };
+        1.0 / hz
+    }
+}
+
+/// Measurement range, the GN bits of CRB_REG_M. A bigger range means less resolution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gain {
+    Gauss1_3 = 0b001,
+    Gauss1_9 = 0b010,
+    Gauss2_5 = 0b011,
+    Gauss4_0 = 0b100,
+    Gauss4_7 = 0b101,
+    Gauss5_6 = 0b110,
+    Gauss8_1 = 0b111,
+}
+
+impl Gain {
+    /// Sensitivity of the X and Y axes, and of the Z axis, in LSB per gauss
+    pub fn lsb_per_gauss(self) -> (f32, f32) {
+        match self {
+            Gain::Gauss1_3 => (1100.0, 980.0),
+            Gain::Gauss1_9 => (855.0, 760.0),
+            Gain::Gauss2_5 => (670.0, 600.0),
+            Gain::Gauss4_0 => (450.0, 400.0),
+            Gain::Gauss4_7 => (400.0, 355.0),
+            Gain::Gauss5_6 => (330.0, 295.0),
+            Gain::Gauss8_1 => (230.0, 205.0),
+        }
+    }
+}
+
+/// Operating mode, the MD bits of MR_REG_M
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Measure continuously at the configured data rate
+    Continuous = 0b00,
+    /// Measure once, then go to sleep. Every read triggers a measurement and waits for it.
+    Single = 0b01,
+    /// Don't measure at all. `read` returns whatever is in the output registers.
+    Sleep = 0b11,
+}
+
+/// Settings for `Lsm303dlhc::configure`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lsm303dlhcConfig {
+    data_rate: DataRate,
+    gain: Gain,
+    mode: Mode,
+    temperature: bool,
+}
+
+impl Lsm303dlhcConfig {
+    /// The magnetometer's reset values: 15 Hz, ±1.3 gauss, continuous, temperature sensor off
+    pub fn new() -> Self {
+        Lsm303dlhcConfig {
+            data_rate: DataRate::Hz15,
+            gain: Gain::Gauss1_3,
+            mode: Mode::Continuous,
+            temperature: false,
+        }
+    }
+
+    pub fn data_rate(mut self, data_rate: DataRate) -> Self {
+        self.data_rate = data_rate;
+        self
+    }
+
+    pub fn gain(mut self, gain: Gain) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Enable the temperature sensor, which is needed for `get_temperature`
+    pub fn temperature(mut self, enabled: bool) -> Self {
+        self.temperature = enabled;
+        self
+    }
+}
+
+impl Default for Lsm303dlhcConfig {
+    fn default() -> Self {
+        Lsm303dlhcConfig::new()
+    }
+}
+
+pub struct Lsm303dlhc<I2C> {
+    i2c: I2C,
+    config: Lsm303dlhcConfig,
+    // The most recent measurement that we've read
+    latest: Option<MagSample>,
+}
+
+impl<I2C: I2c> Lsm303dlhc<I2C> {
+    /// This assumes that the magnetometer is in continuous mode. Call `configure` to make sure.
+    pub fn new(i2c: I2C) -> Self {
+        Lsm303dlhc {
+            i2c,
+            config: Lsm303dlhcConfig::new(),
+            latest: None,
+        }
+    }
+
+    /// Access the underlying bus, e.g. to recover it after an error
+    pub fn bus(&mut self) -> &mut I2C {
+        &mut self.i2c
+    }
+
+    /// Whether the magnetometer has a measurement that we haven't read yet
+    async fn data_ready(&mut self) -> Result<bool, I2C::Error> {
+        let mut sr = [0u8];
+        self.i2c
+            .write_read(MAGNETOMETER, &[SR_REG_M], &mut sr)
+            .await?;
+        Ok(sr[0] & DRDY != 0)
+    }
+
+    /// Read the output registers, whether they hold a new measurement or not
+    async fn read_output(&mut self) -> Result<MagSample, I2C::Error> {
+        let mut buffer = [0u8; 6];
+        self.i2c
+            .write_read(MAGNETOMETER, &[OUT_X_H_M], &mut buffer)
+            .await?;
+
+        // The registers are ordered X, Z, Y, each high byte first
+        let x = i16::from_be_bytes([buffer[0], buffer[1]]);
+        let z = i16::from_be_bytes([buffer[2], buffer[3]]);
+        let y = i16::from_be_bytes([buffer[4], buffer[5]]);
+
+        Ok(MagSample {
+            raw: (x, y, z),
+            lsb_per_gauss: self.config.gain.lsb_per_gauss(),
+        })
+    }
+
+    /// Wait for a measurement that we haven't read yet, then return it. In single-conversion
+    /// mode, this starts the measurement first.
+    pub async fn read_fresh(&mut self) -> Result<MagSample, I2C::Error> {
+        if self.config.mode == Mode::Single {
+            self.i2c
+                .write(MAGNETOMETER, &[MR_REG_M, Mode::Single as u8])
+                .await?;
+        }
+        while !self.data_ready().await? {}
+
+        let sample = self.read_output().await?;
+        self.latest = Some(sample);
+        Ok(sample)
+    }
+
+    /// Read the temperature in °C. The sensor isn't calibrated at the factory, so this is off by a
+    /// constant that differs from chip to chip, and only changes in temperature are meaningful.
+    /// The temperature sensor must be enabled with `Lsm303dlhcConfig::temperature`.
+    pub async fn get_temperature(&mut self) -> Result<f32, I2C::Error> {
+        let mut buffer = [0u8; 2];
+        self.i2c
+            .write_read(MAGNETOMETER, &[TEMP_OUT_H_M], &mut buffer)
+            .await?;
+
+        let raw = i16::from_be_bytes(buffer) >> TEMP_SHIFT;
+        Ok(f32::from(raw) / TEMP_LSB_PER_C)
+    }
+}
+
+impl<I2C: I2c> Magnetometer for Lsm303dlhc<I2C> {
+    type Config = Lsm303dlhcConfig;
+    type Error = I2C::Error;
+
+    /// Write the data rate, gain and mode to the magnetometer
+    async fn configure(&mut self, config: Lsm303dlhcConfig) -> Result<(), I2C::Error> {
+        let temp_en = if config.temperature { TEMP_EN } else { 0 };
+        self.i2c
+            .write(
+                MAGNETOMETER,
+                &[CRA_REG_M, temp_en | (config.data_rate as u8) << 2],
+            )
+            .await?;
+        self.i2c
+            .write(MAGNETOMETER, &[CRB_REG_M, (config.gain as u8) << 5])
+            .await?;
+        self.i2c
+            .write(MAGNETOMETER, &[MR_REG_M, config.mode as u8])
+            .await?;
+        self.config = config;
+        self.latest = None;
+        Ok(())
+    }
+
+    /// In continuous mode, we only read the output registers when the status register says that
+    /// there's a new measurement, so we never see one that's only partly updated.
+    async fn read(&mut self) -> Result<MagSample, I2C::Error> {
+        match self.config.mode {
+            Mode::Single => self.read_fresh().await,
+            Mode::Sleep => self.read_output().await,
+            Mode::Continuous => {
+                let latest = self.latest;
+                match latest {
+                    Some(latest) if !self.data_ready().await? => Ok(latest),
+                    _ => self.read_fresh().await,
+                }
+            }
+        }
+    }
+
+    async fn self_test(&mut self) -> Result<(), SelfTestError<I2C::Error>> {
+        let mut identity = [0u8; 3];
+        self.i2c
+            .write_read(MAGNETOMETER, &[IRA_REG_M], &mut identity)
+            .await
+            .map_err(SelfTestError::Bus)?;
+        if identity != IDENTITY {
+            return Err(SelfTestError::Identity(identity));
+        }
+
+        let sample = self.read_fresh().await.map_err(SelfTestError::Bus)?;
+        magnetometer::check_field(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{now, Registers};
+
+    fn lsm303dlhc() -> Lsm303dlhc<Registers> {
+        let mut registers = Registers::new(MAGNETOMETER);
+        registers.set(IRA_REG_M, &IDENTITY);
+        registers.set(SR_REG_M, &[DRDY]);
+        Lsm303dlhc::new(registers)
+    }
+
+    #[test]
+    fn configure_writes_every_setting() {
+        let mut lsm = lsm303dlhc();
+        let config = Lsm303dlhcConfig::new()
+            .data_rate(DataRate::Hz75)
+            .gain(Gain::Gauss4_0)
+            .mode(Mode::Single)
+            .temperature(true);
+        now(lsm.configure(config)).unwrap();
+        assert_eq!(
+            lsm.bus().get(CRA_REG_M, 3),
+            [0b1001_1000, 0b1000_0000, 0b01]
+        );
+
+        now(lsm.configure(Lsm303dlhcConfig::new())).unwrap();
+        assert_eq!(
+            lsm.bus().get(CRA_REG_M, 3),
+            [0b0001_0000, 0b0010_0000, 0b00]
+        );
+    }
+
+    #[test]
+    fn outputs_are_big_endian_x_z_y() {
+        let mut lsm = lsm303dlhc();
+        lsm.bus()
+            .set(OUT_X_H_M, &[0x01, 0x02, 0xff, 0xfe, 0x00, 0x10]);
+        let sample = now(lsm.read()).unwrap();
+        assert_eq!(sample.raw, (0x0102, 0x0010, -2));
+        assert_eq!(sample.lsb_per_gauss, Gain::Gauss1_3.lsb_per_gauss());
+    }
+}
@@ -0,0 +1,210 @@
+//! What the rest of the firmware needs from a magnetometer
+//!
+//! The pipeline only ever talks to a magnetometer through the `Magnetometer` trait, so it doesn't
+//! care whether the samples come from the LSM303DLHC, a breakout board, a mock or a recording.
+//! Each driver has its own settings, and its own extras on top of the trait, like a temperature
+//! sensor.
+//!
+//! `Corrected` wraps any of them to apply the per-axis part of the calibration to every sample, in
+//! integer math, while keeping the uncorrected sample around for logging.
+
+use crate::calibration::AxisCorrection;
+
+/// One measurement of the magnetic field
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MagSample {
+    /// The field as (x, y, z), in whatever unit the chip measures in
+    pub raw: (i16, i16, i16),
+    /// Sensitivity of the X and Y axes, and of the Z axis, in LSB per gauss
+    pub lsb_per_gauss: (f32, f32),
+}
+
+impl MagSample {
+    /// The field as (x, y, z) in gauss
+    pub fn gauss(self) -> (f32, f32, f32) {
+        let (x, y, z) = self.raw;
+        let (xy_lsb_per_gauss, z_lsb_per_gauss) = self.lsb_per_gauss;
+        (
+            f32::from(x) / xy_lsb_per_gauss,
+            f32::from(y) / xy_lsb_per_gauss,
+            f32::from(z) / z_lsb_per_gauss,
+        )
+    }
+
+    /// The strength of the field in gauss
+    pub fn field_gauss(self) -> f32 {
+        let (x, y, z) = self.gauss();
+        crate::trig::sqrt(x * x + y * y + z * z)
+    }
+}
+
+/// Why `Magnetometer::self_test` failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SelfTestError<E> {
+    /// Couldn't talk to the magnetometer at all
+    Bus(E),
+    /// Something answered, but the identification registers are wrong. Chips with fewer than three
+    /// of them are padded with zeros.
+    Identity([u8; 3]),
+    /// The strength of the field in milligauss is nothing like the earth's
+    Field(u32),
+}
+
+// The earth's field is between about 0.25 and 0.65 gauss. Leave some room for the offsets from
+// nearby metal, since self-tests run before calibration.
+const MIN_FIELD_GAUSS: f32 = 0.1;
+const MAX_FIELD_GAUSS: f32 = 1.0;
+
+/// Check that `sample` looks like the earth's field, for the drivers' self-tests
+pub(crate) fn check_field<E>(sample: MagSample) -> Result<(), SelfTestError<E>> {
+    let field_gauss = sample.field_gauss();
+    if (MIN_FIELD_GAUSS..=MAX_FIELD_GAUSS).contains(&field_gauss) {
+        Ok(())
+    } else {
+        Err(SelfTestError::Field((field_gauss * 1000.0) as u32))
+    }
+}
+
+/// A source of magnetometer samples
+// Everything runs on one executor, so nobody needs the futures to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait Magnetometer {
+    /// The settings that `configure` takes
+    type Config;
+    type Error;
+
+    /// Apply `config`. Until then, the magnetometer may not be measuring at all.
+    async fn configure(&mut self, config: Self::Config) -> Result<(), Self::Error>;
+
+    /// Return the latest measurement without waiting for a new one, unless there hasn't been any
+    /// yet
+    async fn read(&mut self) -> Result<MagSample, Self::Error>;
+
+    /// Check that the magnetometer identifies itself correctly and measures something that looks
+    /// like the earth's field. This must be called after `configure`.
+    async fn self_test(&mut self) -> Result<(), SelfTestError<Self::Error>>;
+}
+
+/// A magnetometer whose samples come out with an `AxisCorrection` applied
+pub struct Corrected<M> {
+    mag: M,
+    axes: AxisCorrection,
+    // The field of the last sample that `read` returned, before the correction
+    raw: (i16, i16, i16),
+}
+
+impl<M> Corrected<M> {
+    /// Starts out leaving the samples as they are
+    pub fn new(mag: M) -> Self {
+        Corrected {
+            mag,
+            axes: AxisCorrection::IDENTITY,
+            raw: (0, 0, 0),
+        }
+    }
+
+    pub fn axes(&self) -> AxisCorrection {
+        self.axes
+    }
+
+    /// Correct the samples with `axes` from the next `read` on
+    pub fn set_axes(&mut self, axes: AxisCorrection) {
+        self.axes = axes;
+    }
+
+    /// The field of the last sample that `read` returned, as it came from the magnetometer, or
+    /// zeros before the first one
+    pub fn last_raw(&self) -> (i16, i16, i16) {
+        self.raw
+    }
+}
+
+impl<M: Magnetometer> Magnetometer for Corrected<M> {
+    type Config = M::Config;
+    type Error = M::Error;
+
+    async fn configure(&mut self, config: Self::Config) -> Result<(), Self::Error> {
+        self.mag.configure(config).await
+    }
+
+    async fn read(&mut self) -> Result<MagSample, Self::Error> {
+        let sample = self.mag.read().await?;
+        self.raw = sample.raw;
+        Ok(MagSample {
+            raw: self.axes.apply(sample.raw),
+            ..sample
+        })
+    }
+
+    /// The self-test checks the field before calibration, so it goes straight to the magnetometer
+    async fn self_test(&mut self) -> Result<(), SelfTestError<Self::Error>> {
+        self.mag.self_test().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixed::Q16;
+    use crate::mock::now;
+
+    /// Always measures the same thing
+    struct Fixed(MagSample);
+
+    impl Magnetometer for Fixed {
+        type Config = ();
+        type Error = ();
+
+        async fn configure(&mut self, _config: ()) -> Result<(), ()> {
+            Ok(())
+        }
+
+        async fn read(&mut self) -> Result<MagSample, ()> {
+            Ok(self.0)
+        }
+
+        async fn self_test(&mut self) -> Result<(), SelfTestError<()>> {
+            check_field(self.0)
+        }
+    }
+
+    #[test]
+    fn samples_convert_to_gauss() {
+        let sample = MagSample {
+            raw: (330, -440, 245),
+            lsb_per_gauss: (1100.0, 980.0),
+        };
+        assert_eq!(sample.gauss(), (0.3, -0.4, 0.25));
+        assert!((sample.field_gauss() - 0.559).abs() < 0.001);
+        assert_eq!(check_field::<()>(sample), Ok(()));
+
+        let sample = MagSample {
+            raw: (0, 0, 9800),
+            lsb_per_gauss: (1100.0, 980.0),
+        };
+        assert_eq!(check_field::<()>(sample), Err(SelfTestError::Field(10_000)));
+    }
+
+    #[test]
+    fn corrects_samples_and_keeps_the_raw_ones() {
+        let sample = MagSample {
+            raw: (330, -440, 245),
+            lsb_per_gauss: (1100.0, 980.0),
+        };
+        let mut mag = Corrected::new(Fixed(sample));
+        assert_eq!(mag.last_raw(), (0, 0, 0));
+        assert_eq!(now(mag.read()), Ok(sample));
+
+        mag.set_axes(AxisCorrection {
+            offset: (30, -40, 45),
+            scale: (Q16::ONE, Q16::from_f32(0.5), Q16::from_f32(2.0)),
+        });
+        let corrected = now(mag.read()).unwrap();
+        assert_eq!(corrected.raw, (300, -200, 400));
+        assert_eq!(corrected.lsb_per_gauss, sample.lsb_per_gauss);
+        assert_eq!(mag.last_raw(), sample.raw);
+        assert_eq!(now(mag.self_test()), Ok(()));
+    }
+}
@@ -1,233 +1,1824 @@
 #![no_main]
 #![no_std]
 
-use aux14::i2c1;
-use aux14::{entry, Direction, iprintln};
+use cortex_m_rt::entry;
+use futures::future::{self, Either};
 use futures::stream::StreamExt;
 use futures::{stream, Stream};
+use pin_utils::pin_mut;
 
-use core::f32::consts::PI;
-use inefficient::BoolFuture;
-// this trait provides the `atan2` method
-use f3::hal::stm32f30x::{rcc, tim6, RCC, TIM6};
-use futures::future::Either;
-use m::Float;
-
-// Slave address
-const MAGNETOMETER: u8 = 0b001_1110;
-
-// Addresses of the magnetometer's register that has the magnetic data
-const OUT_X_H_M: u8 = 0x03;
-
-/// Inefficient but (I think) valid implementations of handy async functions in Rust
-mod inefficient {
-    use core::future::Future;
-    use core::pin::Pin;
-    use core::task::{Context, Poll};
-
-    /// Convert a function that returns bool into a valid but very inefficient future.
-    /// This will return `Poll::Ready` if and only if the function returns true.
-    /// The key trick
-    /// to make this valid is that we always call the waker if we are going to return `Pending`.
-    /// That way the executor is guaranteed to continue polling us. This doesn't actually matter if
-    /// we're using the `block_on` executor from this mod, but it would matter if we used a normal
-    /// executor. I got this trick from user HadrienG in [this Rust forum post](https://users.rust-lang.org/t/polling-in-new-era-futures/30531/2).
-    pub struct BoolFuture<F: Fn() -> bool>(pub F);
-
-    impl<F: Fn() -> bool> Future for BoolFuture<F> {
-        type Output = ();
-
-        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-            if self.0() {
-                Poll::Ready(())
-            } else {
-                cx.waker().wake_by_ref();
-                Poll::Pending
-            }
-        }
-    }
-}
-
-/// It's only legal to call this function once at a time, i.e. you can't call get_compass while
-/// another copy of get_compass is running. Also, in order to leave the I2C bus in a valid state,
-/// you must run this function to completion.
-async fn get_compass(i2c1: &'static i2c1::RegisterBlock) -> (i16, i16, i16) {
-    i2c1.cr2.write(|w| {
-        w.start().set_bit();
-        w.sadd1().bits(MAGNETOMETER);
-        w.rd_wrn().clear_bit();
-        w.nbytes().bits(1);
-        w.autoend().clear_bit()
-    });
+use accel::{Accelerometer, Tap};
+use adc::Adc;
+use board::Board;
+use button::{ButtonEvent, UserButton};
+use buzzer::Buzzer;
+use click::ClickLine;
+use clock::{with_timeout, Instant, Timestamped};
+use compass::alarm::{AlarmActions, AlarmEvent, OffCourseAlarm};
+use compass::anomaly::AnomalyDetector;
+use compass::calibration::{AxisCorrection, Calibrator, MagCalibration, TemperatureDrift};
+use compass::capture::Sample;
+use compass::channel::{Channel, Receiver};
+use compass::confidence::{Confidence, ConfidenceEstimator};
+use compass::config::{Config, DisplayMode, HeadingFilter, OutputFormat};
+use compass::dead_reckoning::{DeadReckoning, SpeedModel};
+use compass::declination;
+use compass::entropy::EntropyPool;
+use compass::filters::decimator::Decimator;
+use compass::filters::low_pass::LowPass;
+use compass::filters::median::Median;
+use compass::flight_recorder::Record;
+use compass::fusion::complementary::ComplementaryFilter;
+use compass::fusion::kalman::KalmanFilter;
+use compass::fusion::madgwick::Madgwick;
+use compass::fusion::wrap_degrees;
+use compass::gps::{DeclinationEstimator, Position, Sentence};
+use compass::heading::{angle_to_bearing, angle_to_direction, Direction};
+#[cfg(feature = "hmc5883l")]
+use compass::hmc5883l::{Averaging, DataRate, Gain, Hmc5883l, Hmc5883lConfig, Mode};
+use compass::i2c_scan::{self, Devices};
+use compass::i2c_timing::BusSpeed;
+use compass::latest::LatestValue;
+#[cfg(not(feature = "hmc5883l"))]
+use compass::lsm303dlhc::{DataRate, Gain, Lsm303dlhc, Lsm303dlhcConfig, Mode};
+#[cfg(not(feature = "hmc5883l"))]
+use compass::magnetometer::SelfTestError;
+use compass::magnetometer::{Corrected, MagSample, Magnetometer};
+use compass::mavlink::{self, Attitude, Message as MavlinkMessage, ScaledImu};
+#[cfg(not(feature = "hmc5883l"))]
+use compass::mmc5983ma::{self, Bandwidth, Mmc5983ma, Mmc5983maConfig, PeriodicSet};
+use compass::modes::{Action, Actions, Mode as AppMode, ModeMachine, Press};
+use compass::pedometer::Pedometer;
+use compass::plausibility::{FieldCheck, Suspicion};
+#[cfg(not(feature = "hmc5883l"))]
+use compass::qmc5883l::{self, Oversampling, Qmc5883l, Qmc5883lConfig, Range};
+use compass::rate_of_turn::RateOfTurn;
+use compass::sample_rate::SampleRate;
+use compass::shake::ShakeDetector;
+use compass::smoothing::HeadingSmoother;
+use compass::stored::Stored;
+use compass::tilt_compensation::Tilt;
+use compass::trig;
+use compass::vibration::{Vibration, VibrationAnalyzer};
+use compass_schema::{Alarm, Message, Telemetry};
+use core::cell::Cell;
+use core::fmt::Write;
+use cortex_m::peripheral::SYST;
+use delay::Delay;
+use display::animation::{Animation, Animator};
+use display::oled::Oled;
+use display::ssd1306::Ssd1306;
+use display::ws2812::Ws2812;
+use display::CompassPoint;
+use display::{BoardLeds, CompassDisplay};
+use drdy::DataReady;
+use gps_uart::{GpsError, GpsUart};
+use gyro::Gyro;
+use health::MagHealth;
+use i2c::{I2c, I2cBus, I2cDevice, I2cError};
+#[cfg(not(feature = "hmc5883l"))]
+use interval::Interval;
+use logger::Logger;
+use profiling::{Profiler, Stage};
+use sd::log::Recorder;
+use sd::SdCard;
+use shell::{Command, ShellError};
+use spi::{Spi1, SpiError};
+use telemetry::Frame;
+use uart::{Line, UartError, Usart1};
+use usb::UsbSerial;
+use usb_device::UsbError;
+use watchdog::{Supervisor, Task};
 
-    // Wait until we can send more data
-    BoolFuture(|| i2c1.isr.read().txis().bit_is_set()).await;
+mod accel;
+mod adc;
+mod black_box;
+#[cfg(any(feature = "hc05", feature = "hm10"))]
+mod bluetooth_uart;
+mod board;
+mod bus_recovery;
+mod button;
+mod buzzer;
+mod click;
+mod clock;
+mod clocks;
+mod delay;
+mod display;
+mod drdy;
+mod executor;
+mod fault;
+mod gps_uart;
+mod gyro;
+mod heading_log;
+mod health;
+mod i2c;
+mod interrupts;
+#[cfg(not(feature = "hmc5883l"))]
+mod interval;
+#[macro_use]
+mod logger;
+mod nmea;
+mod power;
+mod profiling;
+#[cfg(feature = "low-power")]
+mod rtc;
+mod sd;
+mod shell;
+mod spi;
+mod storage;
+mod telemetry;
+mod uart;
+mod usb;
+mod wakers;
+mod watchdog;
 
-    // Send the address of the register that we want to read: OUT_X_H_M
-    i2c1.txdr.write(|w| w.txdata().bits(OUT_X_H_M));
+// How many times to attempt a compass read before giving up and reporting the error
+const I2C_ATTEMPTS: usize = 3;
 
-    // Wait until the previous byte has been transmitted
-    BoolFuture(|| i2c1.isr.read().tc().bit_is_set()).await;
+// How fast to run the I2C bus. A compass read takes about 1 ms in standard mode and about 250 µs
+// in fast mode. Every device on the bus supports fast mode.
+const I2C_SPEED: BusSpeed = BusSpeed::Fast;
 
-    // Broadcast RESTART
-    // Broadcast the MAGNETOMETER address with the R/W bit set to Read
-    i2c1.cr2.modify(|_, w| {
-        w.start().set_bit();
-        w.nbytes().bits(6);
-        w.rd_wrn().set_bit();
-        w.autoend().set_bit()
-    });
+// How long a compass read may take. Even in standard mode it takes about a millisecond.
+const I2C_TIMEOUT_MS: u32 = 10;
+
+/// Read the compass, retrying up to `I2C_ATTEMPTS` times. If all attempts fail, return the last
+/// error.
+///
+/// Before each attempt, this checks whether a slave is holding the bus hostage, and if so, runs
+/// the bus recovery routine. An attempt that takes longer than `I2C_TIMEOUT_MS` is abandoned, which
+/// aborts its transaction. This also runs recovery after the last attempt fails so that the next
+/// call starts with a fresh peripheral. `bus` is the bus that `mag` is on.
+async fn get_compass_with_retries(
+    mag: &mut impl Magnetometer<Error = I2cError>,
+    bus: &mut I2cDevice<'_>,
+) -> Result<MagSample, I2cError> {
+    let mut result = Err(I2cError::Bus);
+    for _ in 0..I2C_ATTEMPTS {
+        bus.recover_if_stuck().await;
+        result = with_timeout(mag.read(), I2C_TIMEOUT_MS)
+            .await
+            .unwrap_or(Err(I2cError::Timeout));
+        if result.is_ok() {
+            return result;
+        }
+    }
+    bus.recover().await;
+    result
+}
 
-    let mut buffer = [0u8; 6];
-    for byte in &mut buffer {
-        // Wait until we have received something
-        BoolFuture(|| i2c1.isr.read().rxne().bit_is_set()).await;
+// How many samples the magnetometer median filter looks at
+const MEDIAN_WINDOW: usize = 5;
 
-        *byte = i2c1.rxdr.read().rxdata().bits();
+/// How long it's been since the magnetometer sample at `previous`, if there was one
+#[cfg(not(feature = "low-power"))]
+fn mag_period_s(at: Instant, previous: Option<Instant>) -> f32 {
+    match previous {
+        Some(previous) => at.seconds_since(previous),
+        None => mag_output_period_s(),
     }
-    // Broadcast STOP (automatic because of `AUTOEND = 1`)
+}
 
-    let x_h = u16::from(buffer[0]);
-    let x_l = u16::from(buffer[1]);
-    let z_h = u16::from(buffer[2]);
-    let z_l = u16::from(buffer[3]);
-    let y_h = u16::from(buffer[4]);
-    let y_l = u16::from(buffer[5]);
+/// How long it's been since the magnetometer sample at `previous`. TIM2 stops while we sleep in
+/// STOP mode, so the clock can't tell us that; assume the nominal data rate instead.
+#[cfg(feature = "low-power")]
+fn mag_period_s(_at: Instant, _previous: Option<Instant>) -> f32 {
+    mag_output_period_s()
+}
 
-    let x = ((x_h << 8) + x_l) as i16;
-    let y = ((y_h << 8) + y_l) as i16;
-    let z = ((z_h << 8) + z_l) as i16;
+/// The nominal time between the samples that come out of the decimator
+fn mag_output_period_s() -> f32 {
+    MAG_DATA_RATE.period_s() * f32::from(MAG_DECIMATION)
+}
 
-    (x, y, z)
+/// The magnetometer that the heading comes from: a breakout if there is one on the bus, and
+/// otherwise the LSM303DLHC's
+#[cfg(not(feature = "hmc5883l"))]
+enum Mag<'a> {
+    Lsm303(Lsm303dlhc<I2cDevice<'a>>),
+    Qmc5883l(Qmc5883l<I2cDevice<'a>>),
+    Mmc5983ma(Mmc5983ma<I2cDevice<'a>>),
 }
 
-fn get_compass_forever(i2c1: &'static i2c1::RegisterBlock) -> impl Stream<Item = (i16, i16, i16)> {
-    stream::repeat(()).then(move |()| get_compass(i2c1))
+/// Settings for whichever magnetometer `Mag` turns out to be
+#[cfg(not(feature = "hmc5883l"))]
+#[derive(Clone, Copy)]
+struct MagConfigs {
+    lsm303: Lsm303dlhcConfig,
+    qmc5883l: Qmc5883lConfig,
+    mmc5983ma: Mmc5983maConfig,
 }
 
-pub fn init_timer() -> &'static tim6::RegisterBlock {
-    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+#[cfg(not(feature = "hmc5883l"))]
+impl Magnetometer for Mag<'_> {
+    type Config = MagConfigs;
+    type Error = I2cError;
+
+    /// Apply the settings for the magnetometer that this is. An MMC5983MA is degaussed afterwards.
+    async fn configure(&mut self, configs: MagConfigs) -> Result<(), I2cError> {
+        match self {
+            Mag::Lsm303(mag) => mag.configure(configs.lsm303).await,
+            Mag::Qmc5883l(mag) => mag.configure(configs.qmc5883l).await,
+            Mag::Mmc5983ma(mag) => {
+                mag.configure(configs.mmc5983ma).await?;
+                mag.degauss().await
+            }
+        }
+    }
+
+    async fn read(&mut self) -> Result<MagSample, I2cError> {
+        match self {
+            Mag::Lsm303(mag) => mag.read().await,
+            Mag::Qmc5883l(mag) => mag.read().await,
+            Mag::Mmc5983ma(mag) => mag.read().await,
+        }
+    }
 
-    // Power on the TIM6 timer
-    rcc.apb1enr.modify(|_, w| w.tim6en().set_bit());
+    async fn self_test(&mut self) -> Result<(), SelfTestError<I2cError>> {
+        match self {
+            Mag::Lsm303(mag) => mag.self_test().await,
+            Mag::Qmc5883l(mag) => mag.self_test().await,
+            Mag::Mmc5983ma(mag) => mag.self_test().await,
+        }
+    }
+}
 
-    let tim6: &'static tim6::RegisterBlock = unsafe { &*TIM6::ptr() };
+/// Set up the magnetometer for the heading, and return it along with its DRDY line if that's wired
+/// up, the settings that it was configured with, and whether configuring it worked. A QMC5883L or
+/// MMC5983MA breakout takes over from the LSM303DLHC if one answers. Either way, the LSM303DLHC's
+/// temperature sensor is turned on for the drift compensation.
+///
+/// If configuring fails, e.g. because the magnetometer isn't there, it's returned anyway, so that
+/// its stream can keep trying to restart it, see `health`.
+#[cfg(not(feature = "hmc5883l"))]
+async fn magnetometer(
+    i2c: I2cDevice<'_>,
+    drdy: DataReady,
+) -> (
+    impl Magnetometer<Error = I2cError, Config = MagConfigs> + '_,
+    Option<DataReady>,
+    MagConfigs,
+    Result<(), I2cError>,
+) {
+    let configs = MagConfigs {
+        lsm303: Lsm303dlhcConfig::new()
+            .data_rate(MAG_DATA_RATE)
+            .gain(Gain::Gauss1_3)
+            .mode(Mode::Continuous)
+            .temperature(true),
+        // Measure faster than we read, and SET the sensor every few seconds
+        mmc5983ma: Mmc5983maConfig::new()
+            .data_rate(mmc5983ma::DataRate::Hz50)
+            .bandwidth(Bandwidth::Hz100)
+            .mode(mmc5983ma::Mode::Continuous)
+            .periodic_set(Some(PeriodicSet::Every250)),
+        // Measure faster than we read, so that there's always a recent measurement
+        qmc5883l: Qmc5883lConfig::new()
+            .data_rate(qmc5883l::DataRate::Hz50)
+            .range(Range::Gauss2)
+            .oversampling(Oversampling::X512)
+            .mode(qmc5883l::Mode::Continuous),
+    };
+    let mut lsm303 = Lsm303dlhc::new(i2c.clone());
+    let configured = lsm303.configure(configs.lsm303).await;
 
-    // OPM Select one pulse mode
-    // CEN Keep the counter disabled for now
-    tim6.cr1.write(|w| w.opm().set_bit().cen().clear_bit());
+    let mut mmc = Mmc5983ma::new(i2c.clone());
+    let mut qmc = Qmc5883l::new(i2c);
+    let (mut mag, drdy) = if mmc.probe().await {
+        (Mag::Mmc5983ma(mmc), None)
+    } else if qmc.probe().await {
+        (Mag::Qmc5883l(qmc), None)
+    } else {
+        // Already configured above
+        return (Mag::Lsm303(lsm303), Some(drdy), configs, configured);
+    };
+    let configured = mag.configure(configs).await;
+    (mag, drdy, configs, configured)
+}
 
-    // Configure the prescaler to have the counter operate at 1 KHz
-    // APB1_CLOCK = 8 MHz
-    // PSC = 7999
-    // 8 MHz / (7999 + 1) = 1 KHz
-    // The counter (CNT) will increase on every millisecond
-    tim6.psc.write(|w| w.psc().bits(7_999));
+/// Set up an HMC5883L breakout for the heading. It measures about as often as the LSM303DLHC, and
+/// averages a few measurements into each sample, which takes some of the noise out. Its DRDY pin
+/// isn't wired up. Like the LSM303DLHC, it's returned even if configuring it fails.
+#[cfg(feature = "hmc5883l")]
+async fn magnetometer(
+    i2c: I2cDevice<'_>,
+    _drdy: DataReady,
+) -> (
+    impl Magnetometer<Error = I2cError, Config = Hmc5883lConfig> + '_,
+    Option<DataReady>,
+    Hmc5883lConfig,
+    Result<(), I2cError>,
+) {
+    let mut mag = Hmc5883l::new(i2c);
+    let config = Hmc5883lConfig::new()
+        .data_rate(MAG_DATA_RATE)
+        .averaging(Averaging::Four)
+        .gain(Gain::Gauss1_3)
+        .mode(Mode::Continuous);
+    let configured = mag.configure(config).await;
+    (mag, None, config, configured)
+}
 
-    tim6
+/// Wait for the magnetometer to raise its DRDY line. Without one, this waits for the nominal data
+/// rate instead, and the read tells whether there's really a new measurement.
+async fn wait_for_mag(drdy: &Option<DataReady>) {
+    match drdy {
+        Some(drdy) => drdy.wait_for_drdy().await,
+        None => clock::sleep((MAG_DATA_RATE.period_s() * 1000.0) as u32).await,
+    }
 }
 
-pub async fn delay(ms: u16, tim6: &'static tim6::RegisterBlock) {
-    // set timer to go off in `ms` milliseconds
-    tim6.arr.write(|w| w.arr().bits(ms));
+/// The filters that a stream of magnetometer samples goes through, see `get_compass_forever`
+struct MagFilters {
+    decimator: Decimator,
+    median: Median<MEDIAN_WINDOW>,
+    low_pass: LowPass,
+}
 
-    // CEN: enable the counter
-    tim6.cr1.modify(|_, w| w.cen().set_bit());
+impl MagFilters {
+    fn new() -> Self {
+        MagFilters {
+            decimator: Decimator::new(MAG_DECIMATION),
+            median: Median::new(),
+            low_pass: LowPass::new(MAG_CUTOFF_HZ, mag_output_period_s()),
+        }
+    }
 
-    BoolFuture(|| tim6.sr.read().uif().bit_is_set()).await;
+    /// Drop whatever was half averaged
+    fn restart(&mut self) {
+        self.decimator = Decimator::new(MAG_DECIMATION);
+    }
 
-    // clear the update event flag
-    tim6.sr.modify(|_, w| w.uif().clear_bit());
+    /// Filter a decimated sample that came `period_s` after the previous one
+    fn update(&mut self, sample: (i16, i16, i16), period_s: f32) -> (i16, i16, i16) {
+        self.low_pass
+            .update_after(self.median.update(sample), period_s)
+    }
 }
 
-fn delay_forever(ms: u16, tim6: &'static tim6::RegisterBlock) -> impl Stream<Item = ()> {
-    stream::repeat(()).then(move |()| delay(ms, tim6))
+/// Read the compass whenever it has a new measurement, as signaled by its DRDY line. The decimator
+/// averages every few samples into one, and then each sample goes through a median filter, which
+/// drops spikes, and a low-pass filter. Each sample is stamped with the time that DRDY went high for
+/// the last measurement that went into it, and the low-pass filter uses the actual time between
+/// samples, so a late or missed sample doesn't throw it off. `bus` is the bus that `mag` is on.
+///
+/// `mag` corrects each sample with whatever `axes` holds at the time, see `Corrected`. The
+/// uncorrected samples go through filters of their own, and both are passed on, so that logging
+/// can keep the uncorrected ones.
+///
+/// When `health` says that the samples have stopped, this stops waiting, resets the bus and
+/// configures `mag` with `config` again, see `health`.
+fn get_compass_forever<'a, M>(
+    mag: M,
+    config: M::Config,
+    bus: I2cDevice<'a>,
+    drdy: Option<DataReady>,
+    axes: &'a Cell<AxisCorrection>,
+    health: &'a MagHealth,
+    profiler: &'a Profiler,
+) -> impl Stream<Item = Result<Timestamped<MagReading>, MagError>> + 'a
+where
+    M: Magnetometer<Error = I2cError> + 'a,
+    M::Config: Copy,
+{
+    let state = (
+        Corrected::new(mag),
+        bus,
+        drdy,
+        MagFilters::new(),
+        MagFilters::new(),
+        None,
+    );
+    stream::unfold(
+        state,
+        move |(mut mag, mut bus, drdy, mut raw_filters, mut corrected_filters, mut previous)| async move {
+            let (at, result) = loop {
+                let stalled = {
+                    let sample = wait_for_mag(&drdy);
+                    let restart = health.restart_requested();
+                    pin_mut!(sample, restart);
+                    matches!(future::select(sample, restart).await, Either::Right(_))
+                };
+                if stalled {
+                    bus.recover().await;
+                    // Whatever was half averaged is from before the stall
+                    raw_filters.restart();
+                    corrected_filters.restart();
+                    let result = mag.configure(config).await;
+                    break (clock::now(), Err(MagError::Restarted(result)));
+                }
+                let at = clock::now();
+                let start = profiling::now();
+                mag.set_axes(axes.get());
+                let result = get_compass_with_retries(&mut mag, &mut bus).await;
+                profiler.record(Stage::Transaction, start);
+                match result {
+                    // The decimators go in step, so they're done with the same sample
+                    Ok(sample) => match (
+                        raw_filters.decimator.update(mag.last_raw()),
+                        corrected_filters.decimator.update(sample.raw),
+                    ) {
+                        (Some(raw), Some(corrected)) => {
+                            break (at, Ok((raw, corrected, sample.lsb_per_gauss)))
+                        }
+                        _ => continue,
+                    },
+                    Err(error) => break (at, Err(MagError::Read(error))),
+                }
+            };
+            let result = result.map(|(raw, corrected, lsb_per_gauss)| {
+                let start = profiling::now();
+                let period_s = mag_period_s(at, previous);
+                let raw = raw_filters.update(raw, period_s);
+                let corrected = corrected_filters.update(corrected, period_s);
+                profiler.record(Stage::Filter, start);
+                previous = Some(at);
+                Timestamped {
+                    at,
+                    value: MagReading {
+                        raw,
+                        corrected,
+                        lsb_per_gauss,
+                    },
+                }
+            });
+            Some((
+                result,
+                (mag, bus, drdy, raw_filters, corrected_filters, previous),
+            ))
+        },
+    )
 }
 
-fn mag_to_angle(mag: (i16, i16, i16)) -> f32 {
-    let (x, y, _z) = mag;
+/// Read the LSM303DLHC's temperature every `TEMPERATURE_MS`, timed by SysTick. Only the
+/// temperature is read, which doesn't depend on the state of the magnetometer that the heading
+/// comes from.
+#[cfg(not(feature = "hmc5883l"))]
+fn get_temperature_forever(
+    i2c: I2cDevice<'_>,
+    syst: SYST,
+) -> impl Stream<Item = Result<f32, I2cError>> + '_ {
+    let mag = Lsm303dlhc::new(i2c);
+    let interval = Interval::new(syst, TEMPERATURE_MS);
+    stream::unfold((mag, interval), |(mut mag, mut interval)| async move {
+        interval.tick().await;
+        let result = mag.get_temperature().await;
+        Some((result, (mag, interval)))
+    })
+}
 
-    (y as f32).atan2(x as f32) / PI * 180.0 // in degrees
+/// The HMC5883L doesn't have a temperature sensor, so there's never a temperature, and the drift
+/// compensation stays off
+#[cfg(feature = "hmc5883l")]
+fn get_temperature_forever(
+    _i2c: I2cDevice<'_>,
+    _syst: SYST,
+) -> impl Stream<Item = Result<f32, I2cError>> + '_ {
+    stream::pending()
 }
 
-// Angle in degrees
-fn angle_to_direction(angle: f32) -> Direction {
-    let angle_chunked = (angle + 22.5) / 45.0;
-    let angle_rounded = angle_chunked as u32;
-    match angle_rounded {
-        0 => Direction::South,
-        1 => Direction::Southeast,
-        2 => Direction::East,
-        3 => Direction::Northeast,
-        4 => Direction::North,
-        5 => Direction::Northwest,
-        6 => Direction::West,
-        7 => Direction::Southwest,
-        8 => Direction::South,
-        _ => panic!("Illegal angle: {}", angle)
+// Room for one waiting scan, which covers any more requests. A channel holds one fewer than this.
+const SCAN_REQUESTS: usize = 2;
+
+/// Scan the I2C bus whenever the shell asks for it. What it finds goes to the main loop, which owns
+/// the logger.
+fn scans_forever<'a>(
+    i2c: I2cDevice<'a>,
+    requests: &'a Receiver<'a, (), SCAN_REQUESTS>,
+) -> impl Stream<Item = Devices> + 'a {
+    stream::unfold(i2c, move |mut i2c| async move {
+        requests.recv().await;
+        let devices = i2c.scan().await;
+        Some((devices, i2c))
+    })
+}
+
+/// Log each device that a scan found, with what it probably is if it's one of ours
+fn log_devices(logger: &mut Logger, devices: Devices) {
+    for address in devices.iter() {
+        match i2c_scan::known(address) {
+            Some(part) => info!(logger, "I2C device at {:#x}: {}", address, part),
+            None => info!(logger, "I2C device at {:#x}", address),
+        }
+    }
+    info!(logger, "Found {} I2C devices", devices.len());
+}
+
+/// Mix the low bits of many magnetometer, accelerometer, temperature and ADC readings into an
+/// entropy pool, along with the cycle counter after each one, which jitters with the bus timing.
+/// Each round waits a little so that the sensors have new measurements. The ADC's noise doesn't
+/// depend on the magnetic field, so the seed still varies where the field is quiet, or where
+/// there's no magnetometer to read.
+async fn gather_entropy(
+    mag: &mut impl Magnetometer<Error = I2cError>,
+    mag_bus: &mut I2cDevice<'_>,
+    accel: &mut Accelerometer<I2cDevice<'_>>,
+    temperature_bus: I2cDevice<'_>,
+    adc: &mut Adc,
+) -> Result<EntropyPool, I2cError> {
+    let mut pool = EntropyPool::new();
+    for _ in 0..ENTROPY_ROUNDS {
+        for _ in 0..ADC_READINGS_PER_ROUND {
+            for &channel in adc::Channel::ALL.iter() {
+                pool.add(u32::from(adc.read(channel)));
+            }
+        }
+        pool.add(profiling::now());
+        if let Ok(sample) = get_compass_with_retries(mag, mag_bus).await {
+            pool.add_sample(sample.raw);
+        }
+        pool.add(profiling::now());
+        pool.add_sample(accel.get_accel().await?);
+        pool.add(profiling::now());
+        if let Some(temperature) = read_temperature(temperature_bus.clone()).await? {
+            pool.add(temperature.to_bits());
+        }
+        pool.add(profiling::now());
+        clock::sleep(ENTROPY_ROUND_MS).await;
+        pool.add(profiling::now());
     }
+    Ok(pool)
+}
+
+/// Read the LSM303DLHC's temperature once
+#[cfg(not(feature = "hmc5883l"))]
+async fn read_temperature(i2c: I2cDevice<'_>) -> Result<Option<f32>, I2cError> {
+    Lsm303dlhc::new(i2c).get_temperature().await.map(Some)
 }
 
-const TIMER_MS: u16 = 100;
-const TIMER_S: f32 = 0.1;
+/// The HMC5883L doesn't have a temperature sensor
+#[cfg(feature = "hmc5883l")]
+async fn read_temperature(_i2c: I2cDevice<'_>) -> Result<Option<f32>, I2cError> {
+    Ok(None)
+}
+
+/// Blink an error code forever, see `animation`
+async fn show_error(display: &mut impl CompassDisplay, code: u8) {
+    let mut animator = Animator::new();
+    loop {
+        let now_ms = clock::now().as_millis();
+        animator.play(Animation::Error(code), now_ms);
+        if let Some(frame) = animator.frame(now_ms) {
+            display.set_leds(frame);
+        }
+        clock::sleep(ANIMATION_FRAME_MS).await;
+    }
+}
+
+/// Ticks at whatever `rate` is at the time
+#[cfg(not(feature = "low-power"))]
+fn ticks_forever(rate: &Cell<SampleRate>) -> impl Stream<Item = ()> + '_ {
+    stream::repeat(()).then(move |()| clock::sleep(u32::from(rate.get().period_ms())))
+}
+
+/// Ticks at whatever `rate` is at the time
+#[cfg(feature = "low-power")]
+fn ticks_forever(rate: &Cell<SampleRate>) -> impl Stream<Item = ()> + '_ {
+    let wakeup = rtc::Wakeup::new(u32::from(rate.get().period_ms()));
+    stream::unfold(
+        (wakeup, rate.get()),
+        move |(mut wakeup, mut current)| async move {
+            if rate.get() != current {
+                current = rate.get();
+                wakeup.set_period(u32::from(current.period_ms()));
+            }
+            wakeup.tick().await;
+            Some(((), (wakeup, current)))
+        },
+    )
+}
+
+/// The angle of the horizontal part of the field, like `mag_to_angle`, with the board's tilt taken
+/// out using `accel`
+#[cfg(not(feature = "fixed-point"))]
+fn tilt_compensated_angle(
+    mag: (i16, i16, i16),
+    accel: (i16, i16, i16),
+    declination_deg: f32,
+) -> f32 {
+    use compass::heading::mag_to_angle;
+    use compass::tilt_compensation::compensate;
+    mag_to_angle(compensate(mag, accel), declination_deg)
+}
+
+/// The angle of the horizontal part of the field, like `mag_to_angle`, with the board's tilt taken
+/// out using `accel`
+#[cfg(feature = "fixed-point")]
+fn tilt_compensated_angle(
+    mag: (i16, i16, i16),
+    accel: (i16, i16, i16),
+    declination_deg: f32,
+) -> f32 {
+    use compass::fixed::{compensate, mag_to_angle, Q16};
+    mag_to_angle(compensate(mag, accel), Q16::from_f32(declination_deg)).to_f32()
+}
+
+/// Replace the calibration with `result`, and learn the temperature drift from the difference
+/// between the two
+fn apply_calibration(stored: &mut Stored, result: MagCalibration, temperature: Option<f32>) {
+    stored.drift = match temperature {
+        Some(temperature) => stored
+            .drift
+            .recalibrated(&stored.calibration, &result, temperature),
+        None => TemperatureDrift {
+            reference_c: None,
+            ..stored.drift
+        },
+    };
+    stored.calibration = result;
+}
+
+/// Write the settings to flash. There's nothing else to do if that fails, so just log it.
+fn save_settings(stored: &Stored, logger: &mut Logger) {
+    if let Err(error) = storage::save(stored) {
+        error!(logger, "Couldn't save settings: {:?}", error);
+    }
+}
+
+/// Why the magnetometer's stream has no sample
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum MagError {
+    Read(I2cError),
+    /// The samples had stopped, so the stream reset the bus and configured the magnetometer again,
+    /// with this result
+    Restarted(Result<(), I2cError>),
+}
+
+/// A filtered magnetometer sample, before and after the per-axis part of the calibration
+#[derive(Clone, Copy)]
+struct MagReading {
+    raw: (i16, i16, i16),
+    corrected: (i16, i16, i16),
+    /// The magnetometer's sensitivity, see `MagSample`
+    lsb_per_gauss: (f32, f32),
+}
+
+/// Everything that the main loop reacts to
+enum Event {
+    Mag(Result<Timestamped<MagReading>, MagError>),
+    Accel(Result<(i16, i16, i16), I2cError>),
+    Gyro(Result<(f32, f32, f32), SpiError>),
+    Temperature(Result<f32, I2cError>),
+    Tap(Result<Tap, I2cError>),
+    Button(ButtonEvent),
+    Command(Result<Command, ShellError<UartError>>),
+    UsbCommand(Result<Command, ShellError<UsbError>>),
+    Gps(Result<Sentence, GpsError>),
+    I2cScan(Devices),
+    Tick,
+}
+
+/// What the display task shows
+#[derive(Clone, Copy)]
+struct View {
+    /// The brightness of each LED, or `None` to leave them as they are
+    leds: Option<[u8; 8]>,
+    /// The bearing, if the LEDs show one
+    bearing: Option<f32>,
+    mode: AppMode,
+    /// The code of the error animation that's playing, if any
+    error: Option<u8>,
+    /// The bearing that the buzzer guides toward, if any
+    target: Option<f32>,
+    /// Whether the buzzer sounds the off-course alarm instead
+    alarm: bool,
+}
+
+// How many views can wait for the display task
+const VIEWS: usize = 4;
+
+/// Show each `View` from the main loop on every display and the buzzer
+async fn show_views_forever(
+    views: &Receiver<'_, View, VIEWS>,
+    mut displays: [&mut dyn CompassDisplay; 3],
+    mut buzzer: Buzzer,
+    profiler: &Profiler,
+    supervisor: &Supervisor,
+) {
+    loop {
+        let View {
+            leds,
+            bearing,
+            mode,
+            error,
+            target,
+            alarm,
+        } = views.recv().await;
+        let start = profiling::now();
+        for display in displays.iter_mut() {
+            display.set_mode(mode);
+            display.set_error(error);
+            display.set_heading(bearing);
+            if let Some(leds) = leds {
+                display.set_leds(leds);
+            }
+        }
+        supervisor.check_in(Task::Display);
+        if alarm {
+            buzzer.alarm();
+        } else {
+            buzzer.guide(match (bearing, target) {
+                (Some(bearing), Some(target)) => Some(wrap_degrees(bearing - target)),
+                _ => None,
+            });
+        }
+        profiler.record(Stage::Display, start);
+    }
+}
+
+/// A line or a telemetry frame to send to the host
+// Without a heap there's nowhere to box the bigger variant, and it's only ever in the channel
+#[allow(clippy::large_enum_variant)]
+enum Output {
+    Line(Line),
+    Frame(Frame),
+    Packet(mavlink::Packet),
+}
+
+// How many outputs can wait for the output task
+const OUTPUTS: usize = 4;
+
+/// Send everything from the main loop to both ports. USB doesn't wait, so it goes first.
+async fn send_outputs_forever(
+    outputs: &Receiver<'_, Output, OUTPUTS>,
+    uart: &Usart1,
+    usb: &UsbSerial<'_>,
+) {
+    loop {
+        let output = outputs.recv().await;
+        let bytes = match &output {
+            Output::Line(line) => line.as_bytes(),
+            Output::Frame(frame) => frame.as_bytes(),
+            Output::Packet(packet) => packet.as_bytes(),
+        };
+        usb.write(bytes);
+        uart.write_all(bytes).await;
+    }
+}
+
+/// The raw sample in `event`, for `capture on`
+fn captured(event: &Event) -> Option<Sample> {
+    match *event {
+        Event::Mag(Ok(mag)) => Some(Sample::Mag(mag.value.raw)),
+        Event::Accel(Ok(accel)) => Some(Sample::Accel(accel)),
+        Event::Gyro(Ok(gyro)) => Some(Sample::Gyro(gyro)),
+        Event::Temperature(Ok(temperature)) => Some(Sample::Temperature(temperature)),
+        Event::Tick => Some(Sample::Tick),
+        _ => None,
+    }
+}
+
+// The MAVLink system that the board belongs to, which is the flight controller's when it's an
+// external compass
+const MAVLINK_SYSTEM_ID: u8 = 1;
+const MAVLINK_HEARTBEAT_MS: u64 = 1_000;
+
+// How quickly the magnetometer corrects gyro drift in the complementary filter
+const HEADING_TIME_CONSTANT_S: f32 = 2.0;
+
+// Growth of the Kalman filter's heading variance while integrating the gyro, in degrees² per second
+const KALMAN_PROCESS_NOISE: f32 = 1.0;
+
+// Variance of the tilt-compensated magnetometer heading, in degrees²
+// A standard deviation of 5°
+const KALMAN_MEASUREMENT_NOISE: f32 = 25.0;
+
+// Holding the button for this long makes a long press, which switches to the next mode
+const LONG_PRESS_MS: u32 = 1_000;
+
+// Holding it for this long selects the next sample rate instead
+const VERY_LONG_PRESS_MS: u32 = 3_000;
+
+// A second short press within this long of the first one makes a double press, which selects the
+// next declination preset
+const DOUBLE_PRESS_MS: u32 = 500;
+
+// In hold mode, headings this close to the target count as on course, until the shell's `deadband`
+// command changes it
+const HOLD_DEADBAND_DEG: f32 = 5.0;
+
+// Dead reckoning moves this far with every step, until the shell's `speed` or `stride` command
+// changes it
+const STRIDE_M: f32 = 0.75;
+
+// The GPS's course only counts toward the declination at this speed or faster, and each course
+// counts as much as one in this many of the ones before it. With a fix every second, that's about a
+// minute.
+const GPS_MIN_SPEED_MPS: f32 = 2.0;
+const GPS_DECLINATION_SAMPLES: u32 = 60;
+
+// The year to work the declination out for, from a position. It changes by a fraction of a degree
+// a year.
+const DECLINATION_YEAR: f32 = 2026.5;
+
+// The declination from the GPS only replaces the current one when they're this far apart, so that
+// host tools don't hear about a new one every second
+const GPS_DECLINATION_CHANGE_DEG: f32 = 0.5;
+
+// How long it takes the metal detector to get used to a change in the field
+const ANOMALY_TIME_CONSTANT_S: f32 = 10.0;
+
+// In metal detector mode, a deviation of this fraction of the baseline lights the whole ring
+const ANOMALY_FULL_SCALE: f32 = 0.5;
+
+// How far the field can be from the Earth's before it's flagged as suspect, see
+// `compass::plausibility`
+const FIELD_INTENSITY_TOLERANCE: f32 = 0.15;
+const FIELD_DIP_TOLERANCE_DEG: f32 = 10.0;
+
+// How long it takes the confidence estimate to get used to a change in the field's strength, and
+// to forget an old heading
+const CONFIDENCE_RADIUS_TIME_CONSTANT_S: f32 = 60.0;
+const CONFIDENCE_HEADING_TIME_CONSTANT_S: f32 = 1.0;
+
+// While the heading can't be trusted, it blinks on and off this often
+const LOW_CONFIDENCE_BLINK_MS: u64 = 250;
+
+// Once the calibration readings cover this fraction of the sphere, they're enough for a good fit
+const CALIBRATION_COVERAGE: f32 = 0.9;
+
+// How long it takes the rate of turn to settle after a change
+const RATE_OF_TURN_TIME_CONSTANT_S: f32 = 0.5;
+
+// In turn indicator mode, turning slower than this many degrees per second counts as going
+// straight
+const TURN_DEADBAND_DPS: f32 = 1.0;
+
+// In level mode, a tilt of less than this many degrees counts as level
+const LEVEL_DEADBAND_DEG: f32 = 1.0;
+
+// In vibration mode, the spectrum is taken over this many accelerometer samples, vibrations weaker
+// than the first number of milli-g are ignored, and the LED is at its brightest at the second
+const VIBRATION_WINDOW: usize = 64;
+const VIBRATION_MIN_MG: f32 = 5.0;
+const VIBRATION_FULL_SCALE_MG: f32 = 200.0;
+
+// While the off-course alarm is going off, the LEDs flash on and off this often
+const ALARM_FLASH_MS: u64 = 200;
+
+// How often to write the heading to the heading log in flash
+const LOG_INTERVAL_S: u32 = 30;
+
+// How many timer ticks the displayed heading is averaged over
+const SMOOTHING_WINDOW: usize = 5;
+
+// How often to read the magnetometer's temperature sensor
+#[cfg(not(feature = "hmc5883l"))]
+const TEMPERATURE_MS: u32 = 1_000;
+
+// How many rounds of readings go into the RNG's seed, and how long to wait between them. Every
+// magnetometer has a new measurement after 70 ms, so this takes about a second.
+const ENTROPY_ROUNDS: usize = 16;
+const ENTROPY_ROUND_MS: u32 = 70;
+
+// How many times to read each ADC channel in each round. A conversion takes well under a µs, so
+// these are nearly free.
+const ADC_READINGS_PER_ROUND: usize = 4;
+
+// How often `show_error` updates the LEDs
+const ANIMATION_FRAME_MS: u32 = 20;
+
+// The error codes for `Animation::Error`
+const SELF_TEST_ERROR: u8 = 1;
+const SENSOR_ERROR: u8 = 2;
+
+// How often to send the heading over USART1, in the configured `OutputFormat`, until the shell's `rate` command
+// changes it
+const HEADING_OUTPUT_HZ: f32 = 2.0;
+
+// How often to log how long each stage of the pipeline takes
+const PROFILE_REPORT_MS: u32 = 10_000;
+
+// The MCU resets if the magnetometer or the main loop get stuck for this long
+const WATCHDOG_MS: u32 = 2_000;
+
+// The magnetometer is restarted if it hasn't delivered a sample for this long, which is about 7
+// samples, and well before the watchdog would reset everything
+const MAG_STALL_MS: u32 = 500;
+
+// Measure at 75 Hz and average every 5 measurements, which comes out at 15 Hz with less noise than
+// measuring at 15 Hz
+const MAG_DATA_RATE: DataRate = DataRate::Hz75;
+const MAG_DECIMATION: u16 = 5;
+
+// Cutoff of the magnetometer low-pass filter, well below the magnetometer's data rate
+const MAG_CUTOFF_HZ: f32 = 1.0;
 
 #[entry]
 fn main() -> ! {
-    let (mut leds, i2c1, _delay, mut itm) = aux14::init();
-    let timer = init_timer();
+    let Board {
+        leds,
+        i2c,
+        syst,
+        itm,
+    } = board::init();
+    let mut logger = Logger::new(itm);
+    if watchdog::caused_reset() {
+        warn!(logger, "Reset by the watchdog");
+    }
+    let mut board_leds = BoardLeds::new(leds);
+    let mut ring = Ws2812::new();
+    let buzzer = Buzzer::new();
+    power::init();
+    profiling::init();
+    clock::init();
+    wakers::init();
+    let i2c1 = I2cBus::new(I2c::new(i2c, Delay::micros(), I2C_SPEED));
+    // Before anything talks to the bus, so that a wiring problem shows up first thing in the log
+    log_devices(&mut logger, executor::block_on(i2c1.device().scan()));
+    let oled_display = Ssd1306::new(i2c1.device());
+    let mut accel_clicks = Accelerometer::new(i2c1.device());
+    let mut accel = Accelerometer::new(i2c1.device());
+    let mut mag_bus = i2c1.device();
+    let (mut mag, drdy, mag_config, configured) =
+        executor::block_on(magnetometer(i2c1.device(), DataReady::magnetometer()));
+    // Without a magnetometer there's no heading, but everything else still works
+    let mut mag_missing = match configured {
+        Ok(()) => false,
+        Err(error) => {
+            error!(logger, "No magnetometer: {:?}", error);
+            true
+        }
+    };
+    if !mag_missing {
+        if let Err(error) = executor::block_on(mag.self_test()) {
+            error!(logger, "Magnetometer self-test failed: {:?}", error);
+            executor::block_on(show_error(&mut board_leds, SELF_TEST_ERROR));
+        }
+    }
+    executor::block_on(accel.init()).expect("Couldn't configure the accelerometer");
+    executor::block_on(accel_clicks.enable_clicks()).expect("Couldn't configure tap detection");
+    let click_line = ClickLine::new();
+    let mut gyro = Gyro::new(Spi1::new());
+    executor::block_on(gyro.init()).expect("Couldn't configure the gyro");
+    let button = UserButton::new();
+    let accel_drdy = DataReady::accelerometer();
+    let gyro_drdy = DataReady::gyro();
+    let uart = Usart1::new();
+    #[cfg(any(feature = "hc05", feature = "hm10"))]
+    match executor::block_on(bluetooth_uart::setup(&uart)) {
+        Ok(()) => info!(logger, "Set up the Bluetooth module"),
+        Err(error) => info!(logger, "Left the Bluetooth module as it is: {:?}", error),
+    }
+    let gps = GpsUart::new();
+    let usb_bus = usb::init();
+    let usb = UsbSerial::new(&usb_bus);
+    let recorder = Recorder::new();
+    let oled = Oled::new();
 
     use rand::{Rng, SeedableRng};
     use rand::rngs::SmallRng;
 
-    // Use data from the compass to seed the RNG
-    let (x, y, z) = spin_on::spin_on(get_compass(i2c1));
-    let mut seed = 0u64;
-    seed += u64::from(u16::from_be_bytes(x.to_be_bytes()));
-    seed += u64::from(u16::from_be_bytes(y.to_be_bytes())) << 16;
-    seed += u64::from(u16::from_be_bytes(z.to_be_bytes())) << 32;
-    let mut rng = SmallRng::seed_from_u64(seed);
+    // Seed the RNG from sensor noise
+    let mut adc = executor::block_on(Adc::new(&Delay::micros()));
+    let pool = executor::block_on(gather_entropy(
+        &mut mag,
+        &mut mag_bus,
+        &mut accel,
+        i2c1.device(),
+        &mut adc,
+    ))
+    .expect("Couldn't read the sensors to seed the RNG");
+    let mut rng = SmallRng::seed_from_u64(pool.seed());
     let rand_angle = rng.gen::<f32>() * 360.0;
-    iprintln!(&mut itm.stim[0], "Random angle: {:?}", rand_angle);
+    debug!(logger, "Random angle: {:?}", rand_angle);
 
-    let mut position_xy_m = (0.0, 0.0);
+    let mut pedometer = Pedometer::new();
+    let mut shake_detector = ShakeDetector::new();
+    let mut dead_reckoning = DeadReckoning::new(SpeedModel::Stride(STRIDE_M));
+    let mut gps_position: Option<Position> = None;
+    let mut gps_declination = DeclinationEstimator::new(GPS_MIN_SPEED_MPS, GPS_DECLINATION_SAMPLES);
+    let mut position_output = false;
+    // Whether to send the raw samples instead of the heading
+    let mut capturing = false;
     let mut timer_cycle = 0usize;
-    let mut last_mag = (0, 0, 0);
-    spin_on::spin_on(
-        stream::select(
-            get_compass_forever(i2c1).map(Either::Left),
-            delay_forever(TIMER_MS, timer).map(Either::Right),
-        )
-        .for_each(|either| {
-            match either {
-                Either::Left(mag) => {
-                    last_mag = mag;
-                }
-                Either::Right(()) => {
-                    timer_cycle = (timer_cycle + 1) % 2;
-                    let (x, y, _z) = last_mag;
-                    let x = f32::from(x);
-                    let y = f32::from(y);
-                    let norm = (x * x + y * y).sqrt();
-                    if norm < f32::EPSILON {
-                        let x = x / norm;
-                        let y = y / norm;
-                        position_xy_m = (
-                            position_xy_m.0 + x * TIMER_S,
-                            position_xy_m.1 + y * TIMER_S,
-                        );
-                        // iprintln!(&mut itm.stim[0], "{:?}", position_xy_m)
-                    }
-                }
-            }
-
-            let angle = (mag_to_angle(last_mag) + 360.0 + rand_angle) % 360.0;
-            let mag_dir = angle_to_direction(angle);
-
-            leds.iter_mut().for_each(|led| led.off());
-            leds[mag_dir].on();
-
-            futures::future::ready(())
-        }),
+    let profiler = Profiler::new();
+    // How long it's been since we last logged the profile
+    let mut profile_ms = 0u32;
+    let mut output_cycle = 0usize;
+    let mut mavlink = mavlink::Encoder::new(MAVLINK_SYSTEM_ID);
+    let mut next_heartbeat_ms = 0;
+    let mut stored = match storage::load() {
+        Some(stored) => {
+            info!(logger, "Loaded settings: {:?}", stored);
+            stored
+        }
+        None => Stored::default(),
+    };
+    let mut heading_log = heading_log::open();
+    info!(logger, "Heading log session {}", heading_log.session());
+    // When the next heading goes into the heading log
+    let mut next_log_ms = 0;
+    let sample_rate = Cell::new(stored.config.sample_rate);
+    // The per-axis part of the calibration, which the magnetometer's stream applies. Each sample
+    // updates it for the next one.
+    let axes = Cell::new(stored.calibration.axes());
+    let mut output_hz = HEADING_OUTPUT_HZ;
+    let mut output_ticks = stored.config.sample_rate.ticks_per_output(output_hz);
+    // When the last magnetometer sample was measured
+    let mut last_mag_at = None;
+    // Whether host tools and the SD card log have yet to hear about the current settings
+    let mut config_changed = true;
+    // The calibrated field, for whichever task wants the newest one
+    let latest_mag = LatestValue::new((0, 0, 0));
+    let mut last_accel = (0, 0, 0);
+    let mut vibration_analyzer =
+        VibrationAnalyzer::<VIBRATION_WINDOW>::new(1.0 / accel::SAMPLE_PERIOD_S);
+    let mut last_vibration: Option<Vibration> = None;
+    let mut last_gyro = (0.0, 0.0, 0.0);
+    // The calibrated field in gauss
+    let mut last_gauss = (0.0, 0.0, 0.0);
+    let mut last_temperature = None;
+    let mut complementary = ComplementaryFilter::new(HEADING_TIME_CONSTANT_S);
+    let mut madgwick = Madgwick::new(stored.config.madgwick_beta);
+    let mut kalman = KalmanFilter::new(KALMAN_PROCESS_NOISE, KALMAN_MEASUREMENT_NOISE);
+    let mut smoother = HeadingSmoother::<SMOOTHING_WINDOW>::new();
+    let mut rate_of_turn = RateOfTurn::new(RATE_OF_TURN_TIME_CONSTANT_S);
+    let mut button_down = false;
+    // When a short press ended, if it could still become a double press
+    let mut pending_press: Option<Instant> = None;
+    let mut modes = ModeMachine::new();
+    let mut anomaly_detector = AnomalyDetector::new(ANOMALY_TIME_CONSTANT_S);
+    let mut anomaly = 0.0;
+    let mut field_check = FieldCheck::new(FIELD_INTENSITY_TOLERANCE, FIELD_DIP_TOLERANCE_DEG);
+    // Why the latest reading didn't look like the Earth's field, if it didn't
+    let mut suspicion: Option<Suspicion> = None;
+    let mut confidence_estimator = ConfidenceEstimator::new(
+        CONFIDENCE_RADIUS_TIME_CONSTANT_S,
+        CONFIDENCE_HEADING_TIME_CONSTANT_S,
     );
-    unreachable!("Because the stream is infinite")
+    let mut confidence = Confidence::High;
+    // The bearing that the buzzer guides toward, if any
+    let mut target: Option<f32> = None;
+    let mut deadband = HOLD_DEADBAND_DEG;
+    let mut alarm = OffCourseAlarm::new();
+    let mut alarm_actions = AlarmActions::new();
+    let mut animator = Animator::new();
+    animator.play(Animation::Startup, clock::now().as_millis());
+    // Only `Some` in calibration mode
+    let mut calibration: Option<Calibrator> = None;
+    // TIM2 stops in STOP mode, but the RTC doesn't, so in low-power builds the ticks come from the
+    // RTC
+    let ticks = ticks_forever(&sample_rate);
+    let supervisor = Supervisor::start(WATCHDOG_MS);
+    let mag_health = MagHealth::new(MAG_STALL_MS);
+    fault::running();
+    let mut view_channel = Channel::<View, VIEWS>::new();
+    let (views, view_receiver) = view_channel.split();
+    let mut output_channel = Channel::<Output, OUTPUTS>::new();
+    let (outputs, output_receiver) = output_channel.split();
+    let outputs = &outputs;
+    let mut scan_channel = Channel::<(), SCAN_REQUESTS>::new();
+    let (scan_requests, scan_receiver) = scan_channel.split();
+    let main_loop = stream::select(
+        stream::select(
+            stream::select(
+                get_compass_forever(mag, mag_config, mag_bus, drdy, &axes, &mag_health, &profiler)
+                    .map(Event::Mag),
+                accel::get_accel_forever(accel, accel_drdy).map(Event::Accel),
+            ),
+            stream::select(
+                gyro::get_gyro_forever(gyro, gyro_drdy).map(Event::Gyro),
+                stream::select(
+                    get_temperature_forever(i2c1.device(), syst).map(Event::Temperature),
+                    scans_forever(i2c1.device(), &scan_receiver).map(Event::I2cScan),
+                ),
+            ),
+        ),
+        stream::select(
+            stream::select(
+                shell::commands_forever(uart.clone()).map(Event::Command),
+                stream::select(
+                    shell::commands_forever(&usb).map(Event::UsbCommand),
+                    gps_uart::sentences_forever(gps).map(Event::Gps),
+                ),
+            ),
+            stream::select(
+                stream::select(
+                    click::get_taps_forever(accel_clicks, click_line).map(Event::Tap),
+                    button::events_forever(button, LONG_PRESS_MS).map(Event::Button),
+                ),
+                ticks.map(|()| Event::Tick),
+            ),
+        ),
+    )
+    .for_each(|event| {
+        // A line or telemetry frame to send over USART1 once we're done with this event
+        let mut output = None;
+        let mut frame = None;
+        // Or MAVLink packets
+        let mut packets = heapless::Vec::<mavlink::Packet, 3>::new();
+        let capture = if capturing { captured(&event) } else { None };
+        // What the mode machine wants done once we're done with this event
+        let mut actions = Actions::new();
+        let previous_mode = modes.mode();
+        match event {
+            Event::Mag(Ok(Timestamped { at, value: MagReading { raw: mag, corrected, lsb_per_gauss } })) => {
+                supervisor.check_in(Task::Sensors);
+                mag_health.sample();
+                if mag_missing {
+                    info!(logger, "Found the magnetometer");
+                    mag_missing = false;
+                }
+                if let Some(calibration) = &mut calibration {
+                    calibration.add(mag);
+                }
+                let calibration = match last_temperature {
+                    Some(temperature) => stored.calibration.at_temperature(&stored.drift, temperature),
+                    None => stored.calibration,
+                };
+                // `axes` is still what corrected this sample, so the rest of the calibration picks
+                // up from there
+                let calibrated = calibration.after(&axes.get()).apply(corrected);
+                latest_mag.set(calibrated);
+                axes.set(calibration.axes());
+                anomaly = anomaly_detector.update(calibrated, mag_period_s(at, last_mag_at));
+                confidence_estimator.update_field(calibrated, mag_period_s(at, last_mag_at));
+                last_mag_at = Some(at);
+                let gauss = MagSample { raw: calibrated, lsb_per_gauss }.gauss();
+                last_gauss = gauss;
+                let verdict = field_check.check(gauss, last_accel).err();
+                if verdict != suspicion {
+                    match verdict {
+                        Some(verdict) => warn!(logger, "Suspect field: {:?}", verdict),
+                        None => info!(logger, "Field looks like the Earth's again"),
+                    }
+                    suspicion = verdict;
+                }
+
+                let sample = Telemetry {
+                    timestamp_ms: at.as_millis() as u32,
+                    raw: mag,
+                    calibrated,
+                    heading: smoother.heading().map(angle_to_bearing),
+                    rate_of_turn: rate_of_turn.rate(),
+                    orientation: {
+                        let q = madgwick.quaternion();
+                        (q.w, q.x, q.y, q.z)
+                    },
+                    suspect: suspicion.is_some(),
+                };
+                black_box::record(Record {
+                    timestamp_ms: sample.timestamp_ms,
+                    mag,
+                    accel: last_accel,
+                    heading: sample.heading,
+                });
+                if at.as_millis() >= next_log_ms {
+                    next_log_ms = at.as_millis() + u64::from(LOG_INTERVAL_S) * 1_000;
+                    if let Err(error) = heading_log.append((at.as_millis() / 1_000) as u32, sample.heading) {
+                        warn!(logger, "Heading log error: {:?}", error);
+                    }
+                }
+                let sample = Frame::new(&Message::Telemetry(sample));
+                recorder.push(sample.as_bytes());
+                if let OutputFormat::Telemetry = stored.config.output_format {
+                    frame = Some(sample);
+                }
+            }
+            Event::Mag(Err(MagError::Read(error))) => {
+                warn!(logger, "Compass error: {:?}", error);
+                animator.play(Animation::Error(SENSOR_ERROR), clock::now().as_millis());
+            }
+            Event::Mag(Err(MagError::Restarted(result))) => {
+                // The stream is still going, even if the magnetometer isn't
+                supervisor.check_in(Task::Sensors);
+                match result {
+                    Ok(()) => {
+                        info!(logger, "Restarted the magnetometer");
+                        animator.play(Animation::Error(SENSOR_ERROR), clock::now().as_millis());
+                    }
+                    Err(error) if !mag_missing => {
+                        error!(logger, "Lost the magnetometer: {:?}", error);
+                        mag_missing = true;
+                    }
+                    // It's still missing, which has already been said
+                    Err(_) => {}
+                }
+            }
+            Event::Accel(Ok(accel)) => {
+                last_accel = accel;
+                if let Some(vibration) = vibration_analyzer.update(accel) {
+                    last_vibration = Some(vibration).filter(|vibration| vibration.amplitude >= VIBRATION_MIN_MG);
+                }
+                if let (true, Some(heading)) = (pedometer.update(accel, accel::SAMPLE_PERIOD_S), smoother.heading()) {
+                    dead_reckoning.step(angle_to_bearing(heading));
+                }
+                // Shaking the board starts calibration, which then needs a press or `cal stop` to
+                // finish
+                if shake_detector.update(accel, accel::SAMPLE_PERIOD_S) && modes.mode() != AppMode::Calibration {
+                    info!(logger, "Shaken");
+                    actions = modes.switch(AppMode::Calibration);
+                }
+            }
+            Event::Accel(Err(error)) => {
+                warn!(logger, "Accelerometer error: {:?}", error);
+                animator.play(Animation::Error(SENSOR_ERROR), clock::now().as_millis());
+            }
+            Event::Gyro(Ok(gyro)) => {
+                last_gyro = gyro;
+                // Turning the board counterclockwise makes the field turn clockwise relative
+                // to the board, so the angle from `mag_to_angle` goes the opposite way
+                complementary.update_gyro(-gyro.2, gyro::SAMPLE_PERIOD_S);
+                kalman.update_gyro(-gyro.2, gyro::SAMPLE_PERIOD_S);
+            }
+            Event::Gyro(Err(error)) => {
+                warn!(logger, "Gyro error: {:?}", error);
+                animator.play(Animation::Error(SENSOR_ERROR), clock::now().as_millis());
+            }
+            Event::Temperature(Ok(temperature)) => {
+                debug!(logger, "Temperature: {}", temperature);
+                last_temperature = Some(temperature);
+            }
+            Event::Temperature(Err(error)) => {
+                warn!(logger, "Temperature error: {:?}", error);
+            }
+            Event::Tap(Ok(Tap::Double)) => {
+                stored.config.display_mode = stored.config.display_mode.next();
+                info!(logger, "Display mode: {:?}", stored.config.display_mode);
+                save_settings(&stored, &mut logger);
+            }
+            // Every double tap starts with a single one, so single taps don't do anything
+            Event::Tap(Ok(Tap::Single)) => {}
+            Event::Tap(Err(error)) => {
+                warn!(logger, "Tap detection error: {:?}", error);
+            }
+            Event::Command(Ok(command)) | Event::UsbCommand(Ok(command)) => {
+                let mut reply = Line::new();
+                match command {
+                    Command::StartCalibration => {
+                        actions = modes.switch(AppMode::Calibration);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    // The shell has to hear whether the fit worked, so it fits the readings itself
+                    // rather than leave that to `Action::FinishCalibration`
+                    Command::FinishCalibration => match calibration.take().map(|calibrator| calibrator.finish()) {
+                        Some(Some(result)) => {
+                            actions = modes.leave_calibration();
+                            apply_calibration(&mut stored, result, last_temperature);
+                            info!(logger, "Calibration: {:?}", stored.calibration);
+                            config_changed = true;
+                            save_settings(&stored, &mut logger);
+                            write!(reply, "ok\r\n").unwrap();
+                        }
+                        Some(None) => {
+                            actions = modes.leave_calibration();
+                            write!(reply, "error: not enough data to calibrate\r\n").unwrap();
+                        }
+                        None => write!(reply, "error: not calibrating\r\n").unwrap(),
+                    },
+                    Command::SetDeclination(declination_deg) => {
+                        stored.config.declination_deg = declination_deg;
+                        info!(logger, "Declination: {:?}", stored.config.declination_deg);
+                        config_changed = true;
+                        save_settings(&stored, &mut logger);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetLocation(latitude_deg, longitude_deg) => {
+                        let field = declination::field_at(latitude_deg, longitude_deg, DECLINATION_YEAR);
+                        field_check.set_expected(Some(field));
+                        stored.config.declination_deg = field.declination_deg();
+                        info!(logger, "Declination: {:?}", stored.config.declination_deg);
+                        config_changed = true;
+                        save_settings(&stored, &mut logger);
+                        write!(reply, "declination {:.1}\r\n", stored.config.declination_deg).unwrap();
+                    }
+                    Command::SetRate(hz) => {
+                        output_hz = hz;
+                        output_ticks = sample_rate.get().ticks_per_output(output_hz);
+                        output_cycle = 0;
+                        write!(reply, "ok: {:.2} Hz\r\n", sample_rate.get().hz() / output_ticks as f32).unwrap();
+                    }
+                    Command::SetOutputFormat(format) => {
+                        stored.config.output_format = format;
+                        info!(logger, "Output format: {:?}", format);
+                        // A host that just switched to telemetry hasn't seen the settings yet
+                        config_changed = true;
+                        // And a ground station waits for a heartbeat before it listens to anything else
+                        next_heartbeat_ms = 0;
+                        save_settings(&stored, &mut logger);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetSampleRate(rate) => {
+                        sample_rate.set(rate);
+                        info!(logger, "Sample rate: {:?}", rate);
+                        output_ticks = rate.ticks_per_output(output_hz);
+                        output_cycle = 0;
+                        stored.config.sample_rate = rate;
+                        save_settings(&stored, &mut logger);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::ResetConfig => {
+                        stored.config = Config::default();
+                        info!(logger, "Config: {:?}", stored.config);
+                        let rate = stored.config.sample_rate;
+                        sample_rate.set(rate);
+                        output_ticks = rate.ticks_per_output(output_hz);
+                        output_cycle = 0;
+                        madgwick = Madgwick::new(stored.config.madgwick_beta);
+                        config_changed = true;
+                        save_settings(&stored, &mut logger);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetTarget(bearing) => {
+                        target = bearing;
+                        info!(logger, "Target: {:?}", target);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetDeadband(degrees) => {
+                        deadband = degrees;
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::ShowPosition => {
+                        let (east, north) = dead_reckoning.position();
+                        write!(reply, "pos {:.1} {:.1}, {} steps\r\n", east, north, pedometer.steps()).unwrap();
+                        if let Some(Position { latitude_deg, longitude_deg }) = gps_position {
+                            write!(reply, "gps {:.6} {:.6}\r\n", latitude_deg, longitude_deg).unwrap();
+                        }
+                    }
+                    Command::ResetPosition => {
+                        dead_reckoning.reset();
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetPositionOutput(enabled) => {
+                        position_output = enabled;
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetSpeed(speed) => {
+                        dead_reckoning.set_speed_model(SpeedModel::Constant(speed));
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetStride(stride) => {
+                        dead_reckoning.set_speed_model(SpeedModel::Stride(stride));
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetCapture(enabled) => {
+                        capturing = enabled;
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetAlarm(settings) => {
+                        alarm.set(settings);
+                        info!(logger, "Alarm: {:?}", settings);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::SetAlarmAction(action, enabled) => {
+                        alarm_actions.set(action, enabled);
+                        write!(reply, "ok\r\n").unwrap();
+                    }
+                    Command::Dump => {
+                        let MagCalibration { offset, matrix } = stored.calibration;
+                        write!(reply, "declination {:.1}\r\n", stored.config.declination_deg).unwrap();
+                        write!(reply, "offset {:.1} {:.1} {:.1}\r\n", offset.0, offset.1, offset.2).unwrap();
+                        write!(reply, "matrix").unwrap();
+                        for value in matrix.iter().flatten() {
+                            write!(reply, " {:.3}", value).unwrap();
+                        }
+                        write!(reply, "\r\n").unwrap();
+                        let (dx, dy, dz) = stored.drift.offset_per_c;
+                        write!(reply, "drift {:.2} {:.2} {:.2} per C\r\n", dx, dy, dz).unwrap();
+                        write!(reply, "rate {:.2} Hz\r\n", sample_rate.get().hz() / output_ticks as f32).unwrap();
+                        write!(reply, "sample {:.0} Hz\r\n", sample_rate.get().hz()).unwrap();
+                        write!(reply, "format {:?}\r\n", stored.config.output_format).unwrap();
+                        write!(reply, "display {:?}\r\n", stored.config.display_mode).unwrap();
+                        write!(reply, "filter {:?}, beta {:.3}\r\n", stored.config.heading_filter, stored.config.madgwick_beta).unwrap();
+                        match target {
+                            Some(target) => write!(reply, "target {:.1}\r\n", target).unwrap(),
+                            None => write!(reply, "target off\r\n").unwrap(),
+                        }
+                        write!(reply, "deadband {:.1}\r\n", deadband).unwrap();
+                        match alarm.settings() {
+                            Some(settings) => write!(
+                                reply,
+                                "alarm {:.1} {:.1} {:.1}\r\n",
+                                settings.course, settings.tolerance_deg, settings.delay_s
+                            )
+                            .unwrap(),
+                            None => write!(reply, "alarm off\r\n").unwrap(),
+                        }
+                        match dead_reckoning.speed_model() {
+                            SpeedModel::Constant(speed) => write!(reply, "speed {:.2} m/s\r\n", speed).unwrap(),
+                            SpeedModel::Stride(stride) => write!(reply, "stride {:.2} m\r\n", stride).unwrap(),
+                        }
+                        write!(reply, "sd dropped {}\r\n", recorder.dropped()).unwrap();
+                    }
+                    Command::DumpFlightRecorder => {
+                        // Going through the log rather than the reply keeps the records off the
+                        // telemetry, and matches what a panic dumps
+                        // Waiting is the point here, and the buffer can't hold all of it
+                        let records = black_box::dump(|record| {
+                            info!(logger, "{:?}", record);
+                            logger.flush();
+                        });
+                        write!(reply, "flight {} records\r\n", records).unwrap();
+                    }
+                    Command::DumpHeadingLog => {
+                        let mut entries = 0;
+                        for entry in heading_log.entries() {
+                            info!(logger, "{:?}", entry);
+                            logger.flush();
+                            entries += 1;
+                        }
+                        write!(reply, "log {} of {} entries\r\n", entries, heading_log.capacity()).unwrap();
+                    }
+                    Command::EraseHeadingLog => match heading_log.erase() {
+                        Ok(()) => write!(reply, "ok\r\n").unwrap(),
+                        Err(error) => write!(reply, "error: {:?}\r\n", error).unwrap(),
+                    },
+                    // The reply comes with the result, see `Event::I2cScan`
+                    Command::ScanI2c => {
+                        let _ = scan_requests.try_send(());
+                    }
+                }
+                output = Some(reply);
+            }
+            Event::I2cScan(devices) => {
+                log_devices(&mut logger, devices);
+                let mut reply = Line::new();
+                write!(reply, "scan {} devices\r\n", devices.len()).unwrap();
+                output = Some(reply);
+            }
+            Event::Command(Err(error)) => {
+                let mut reply = Line::new();
+                write!(reply, "error: {:?}\r\n", error).unwrap();
+                output = Some(reply);
+            }
+            Event::UsbCommand(Err(error)) => {
+                let mut reply = Line::new();
+                write!(reply, "error: {:?}\r\n", error).unwrap();
+                output = Some(reply);
+            }
+            // The GPS's ground speed replaces a constant speed for dead reckoning, but not a stride,
+            // which counts steps rather than time. The declination comes from comparing its course
+            // with the heading once we've been moving for long enough, since that also takes in
+            // local disturbances, and from its position until then. The field at its position is
+            // also what the field check expects.
+            Event::Gps(Ok(Sentence::Rmc(rmc))) => {
+                if rmc.position.is_some() {
+                    gps_position = rmc.position;
+                }
+                if let (Some(speed), SpeedModel::Constant(_)) = (rmc.speed_mps, dead_reckoning.speed_model()) {
+                    dead_reckoning.set_speed_model(SpeedModel::Constant(speed));
+                }
+                if let (Some(course), Some(speed), Some(heading)) = (rmc.course_deg, rmc.speed_mps, smoother.heading()) {
+                    let magnetic = angle_to_bearing(heading) - stored.config.declination_deg;
+                    gps_declination.update(course, speed, magnetic);
+                }
+                let modeled = gps_position.map(|position| declination::field_at(position.latitude_deg, position.longitude_deg, DECLINATION_YEAR));
+                if let Some(field) = modeled {
+                    field_check.set_expected(Some(field));
+                }
+                if let Some(declination_deg) = gps_declination.declination().or(modeled.map(|field| field.declination_deg())) {
+                    if (declination_deg - stored.config.declination_deg).abs() >= GPS_DECLINATION_CHANGE_DEG {
+                        stored.config.declination_deg = declination_deg;
+                        info!(logger, "Declination from GPS: {:?}", declination_deg);
+                        config_changed = true;
+                    }
+                }
+            }
+            Event::Gps(Ok(Sentence::Gga(gga))) => {
+                if gga.position.is_some() {
+                    gps_position = gga.position;
+                }
+            }
+            Event::Gps(Err(error)) => {
+                warn!(logger, "GPS error: {:?}", error);
+            }
+            // A short press does whatever the current mode does with it, and a long press switches
+            // to the next mode, see `compass::modes`. A double press selects the next declination
+            // preset, and holding the button down even longer than a long press selects the next
+            // sample rate.
+            Event::Button(ButtonEvent::Pressed) => button_down = true,
+            Event::Button(ButtonEvent::Released) => {
+                button_down = false;
+                if pending_press.take().is_some() {
+                    stored.config.declination_deg = declination::next_preset(stored.config.declination_deg);
+                    info!(logger, "Declination: {:?}", stored.config.declination_deg);
+                    config_changed = true;
+                    save_settings(&stored, &mut logger);
+                } else {
+                    pending_press = Some(clock::now());
+                }
+            }
+            Event::Button(ButtonEvent::LongPress { held_ms }) => {
+                button_down = false;
+                pending_press = None;
+                if held_ms >= VERY_LONG_PRESS_MS {
+                    let rate = sample_rate.get().next();
+                    sample_rate.set(rate);
+                    info!(logger, "Sample rate: {:?}", rate);
+                    output_ticks = rate.ticks_per_output(output_hz);
+                    output_cycle = 0;
+                    stored.config.sample_rate = rate;
+                    save_settings(&stored, &mut logger);
+                } else {
+                    actions = modes.press(Press::Long);
+                }
+            }
+            Event::Tick => {
+                supervisor.service();
+                // Messages only go out while something is logged or the FIFO has room on a tick, so
+                // that a stalled debugger never holds up the sensors
+                logger.drain();
+                if let Some(age_ms) = mag_health.tick(u32::from(sample_rate.get().period_ms())) {
+                    warn!(logger, "No magnetometer sample for {} ms, restarting it", age_ms);
+                }
+                let dropped = logger.take_dropped();
+                if dropped > 0 {
+                    warn!(logger, "Dropped {} log messages", dropped);
+                }
+                timer_cycle = (timer_cycle + 1) % 2;
+                let rate = sample_rate.get();
+                let period_ms = u32::from(rate.period_ms());
+                let period_s = rate.period_s();
+
+                profile_ms += period_ms;
+                if profile_ms >= PROFILE_REPORT_MS {
+                    profile_ms = 0;
+                    for (stage, stats) in profiler.take() {
+                        debug!(logger, "{:?}: {:?}", stage, stats);
+                    }
+                }
+
+                // Only act on a short press once it's too late for it to become a double press
+                if let (Some(released_at), false) = (pending_press, button_down) {
+                    if clock::now().as_millis() - released_at.as_millis() >= u64::from(DOUBLE_PRESS_MS) {
+                        pending_press = None;
+                        actions = modes.press(Press::Short);
+                    }
+                }
+
+                let last_mag = latest_mag.get();
+                let mag_heading = tilt_compensated_angle(last_mag, last_accel, stored.config.declination_deg);
+                complementary.update_mag(mag_heading, period_s);
+                kalman.update_mag(mag_heading);
+
+                let (gx, gy, gz) = last_gyro;
+                let (ax, ay, az) = last_accel;
+                let (mx, my, mz) = last_mag;
+                madgwick.update(
+                    (gx.to_radians(), gy.to_radians(), gz.to_radians()),
+                    (f32::from(ax), f32::from(ay), f32::from(az)),
+                    (f32::from(mx), f32::from(my), f32::from(mz)),
+                    period_s,
+                );
+                if timer_cycle == 0 {
+                    debug!(logger, "Orientation: {:?}", madgwick.quaternion());
+                    if let (Some(heading), Some(variance)) = (kalman.heading(), kalman.variance()) {
+                        debug!(logger, "Heading: {} ± {}", heading, trig::sqrt(variance));
+                    }
+                }
+                let heading = match stored.config.heading_filter {
+                    HeadingFilter::Complementary => complementary.heading(),
+                    HeadingFilter::Madgwick => Some(wrap_degrees(madgwick.yaw() + stored.config.declination_deg)),
+                    HeadingFilter::Kalman => kalman.heading(),
+                };
+                if let Some(heading) = heading {
+                    smoother.add(heading);
+                    confidence_estimator.update_heading(heading, period_s);
+                    rate_of_turn.update(angle_to_bearing(heading), period_s);
+                }
+                if confidence_estimator.confidence() != confidence {
+                    confidence = confidence_estimator.confidence();
+                    info!(
+                        logger,
+                        "Heading confidence: {:?} (field off by {}, heading spread {}°)",
+                        confidence,
+                        confidence_estimator.field_deviation(),
+                        confidence_estimator.heading_spread_deg()
+                    );
+                }
+                if let Some(heading) = smoother.heading() {
+                    dead_reckoning.update(angle_to_bearing(heading), period_s);
+                }
+
+                if let OutputFormat::Mavlink = stored.config.output_format {
+                    let now_ms = clock::now().as_millis();
+                    if now_ms >= next_heartbeat_ms {
+                        next_heartbeat_ms = now_ms + MAVLINK_HEARTBEAT_MS;
+                        let _ = packets.push(mavlink.encode(&MavlinkMessage::Heartbeat));
+                    }
+                }
+                output_cycle = (output_cycle + 1) % output_ticks;
+                if let (0, Some(heading)) = (output_cycle, smoother.heading()) {
+                    let bearing = angle_to_bearing(heading);
+                    let mut line = Line::new();
+                    match stored.config.output_format {
+                        OutputFormat::Text => write!(line, "{:.1}\r\n", bearing).unwrap(),
+                        OutputFormat::Nmea => {
+                            let magnetic = (bearing - stored.config.declination_deg + 360.0) % 360.0;
+                            nmea::hdm(&mut line, magnetic).unwrap();
+                            nmea::hdt(&mut line, bearing).unwrap();
+                            if let Some(rate) = rate_of_turn.rate() {
+                                nmea::rot(&mut line, rate).unwrap();
+                            }
+                        }
+                        // The heading goes out with every magnetometer sample instead
+                        OutputFormat::Telemetry => {}
+                        OutputFormat::Mavlink => {
+                            let time_boot_ms = clock::now().as_millis() as u32;
+                            let (roll_deg, pitch_deg) = Tilt::from_accel(last_accel)
+                                .map_or((0.0, 0.0), |tilt| (tilt.roll_deg(), tilt.pitch_deg()));
+                            let attitude = Attitude::new(time_boot_ms, roll_deg, pitch_deg, bearing, last_gyro);
+                            let imu = ScaledImu::new(time_boot_ms, last_accel, last_gyro, last_gauss);
+                            for message in [MavlinkMessage::Attitude(attitude), MavlinkMessage::ScaledImu(imu)].iter() {
+                                let _ = packets.push(mavlink.encode(message));
+                            }
+                        }
+                    }
+                    if position_output {
+                        let (east, north) = dead_reckoning.position();
+                        write!(line, "pos {:.1} {:.1}\r\n", east, north).unwrap();
+                    }
+                    output = Some(line);
+                }
+                let alarm_event = smoother.heading().and_then(|heading| alarm.update(angle_to_bearing(heading), period_s));
+                if let Some(event) = alarm_event {
+                    warn!(logger, "Off-course alarm: {:?}", event);
+                }
+                if let (Some(event), true) = (alarm_event, alarm_actions.event) {
+                    let (raised, deviation_deg) = match event {
+                        AlarmEvent::Raised { deviation_deg } => (true, deviation_deg),
+                        AlarmEvent::Cleared => (false, 0.0),
+                    };
+                    let message = Alarm { timestamp_ms: clock::now().as_millis() as u32, raised, deviation_deg };
+                    let message = Frame::new(&Message::Alarm(message));
+                    recorder.push(message.as_bytes());
+                    match stored.config.output_format {
+                        OutputFormat::Telemetry => frame = Some(message),
+                        OutputFormat::Text | OutputFormat::Nmea => {
+                            let line = output.get_or_insert_with(Line::new);
+                            match event {
+                                AlarmEvent::Raised { deviation_deg } => write!(line, "alarm on {:.1}\r\n", deviation_deg).unwrap(),
+                                AlarmEvent::Cleared => write!(line, "alarm off\r\n").unwrap(),
+                            }
+                        }
+                        // Text would garble the packets, and ground stations watch the heading
+                        // themselves
+                        OutputFormat::Mavlink => {}
+                    }
+                }
+                // There's only room for one frame per event, so the settings wait for the next tick if
+                // an alarm took it
+                if config_changed && frame.is_none() {
+                    let config = Frame::new(&Message::Config(telemetry::config(&stored)));
+                    recorder.push(config.as_bytes());
+                    if let OutputFormat::Telemetry = stored.config.output_format {
+                        frame = Some(config);
+                    }
+                    config_changed = false;
+                }
+                if let Some(error) = recorder.take_error() {
+                    warn!(logger, "SD card error, logging stopped: {:?}", error);
+                }
+                if let Some(error) = oled.take_error() {
+                    info!(logger, "No OLED display: {:?}", error);
+                }
+            }
+        }
+        let mode = modes.mode();
+        if mode != previous_mode {
+            info!(logger, "Mode: {:?}", mode);
+            // Calibration has an animation of its own
+            if mode != AppMode::Calibration {
+                animator.play(Animation::ModeChange(mode.number()), clock::now().as_millis());
+            }
+        }
+        for action in actions {
+            match action {
+                Action::StartCalibration => {
+                    info!(
+                        logger,
+                        "Calibrating: rotate the board in every direction, then press the button or send `cal stop`"
+                    );
+                    calibration = Some(Calibrator::new());
+                }
+                Action::FinishCalibration => match calibration.take().map(|calibrator| calibrator.finish()) {
+                    Some(Some(result)) => {
+                        apply_calibration(&mut stored, result, last_temperature);
+                        info!(logger, "Calibration: {:?}", stored.calibration);
+                        config_changed = true;
+                        save_settings(&stored, &mut logger);
+                    }
+                    Some(None) => warn!(logger, "Not enough data to calibrate"),
+                    None => {}
+                },
+                Action::StopCalibration => calibration = None,
+                Action::LockTarget => {
+                    if let Some(heading) = smoother.heading() {
+                        target = Some(angle_to_bearing(heading));
+                        info!(logger, "Target: {:?}", target);
+                    }
+                }
+                Action::ResetBaseline => anomaly_detector.reset(),
+            }
+        }
+
+        // While capturing, the raw samples replace the usual output, so that a recording of it can
+        // be replayed as it is
+        if let Some(sample) = capture {
+            let mut line = Line::new();
+            sample.write(&mut line).unwrap();
+            output = Some(line);
+        }
+
+        let now_ms = clock::now().as_millis();
+        // Sweep around the ring so it's obvious that we aren't showing a heading
+        if mode == AppMode::Calibration {
+            animator.play(Animation::Calibration, now_ms);
+        } else {
+            animator.stop(Animation::Calibration);
+        }
+        if mode == AppMode::Demo {
+            animator.play(Animation::Demo, now_ms);
+        } else {
+            animator.stop(Animation::Demo);
+        }
+        if mag_missing {
+            animator.play(Animation::NoMagnetometer, now_ms);
+        } else {
+            animator.stop(Animation::NoMagnetometer);
+        }
+        let mut leds = if let Some(frame) = animator.frame(now_ms) {
+            Some(frame)
+        } else if alarm.is_raised() && alarm_actions.flash {
+            let off = clock::now().as_millis() / ALARM_FLASH_MS % 2 == 1;
+            Some(if off { [0; 8] } else { [display::MAX; 8] })
+        } else if mode == AppMode::MetalDetector {
+            Some(display::bar(anomaly / ANOMALY_FULL_SCALE))
+        } else if mode == AppMode::Level {
+            Tilt::from_accel(last_accel).map(|tilt| display::level(tilt.pitch_deg(), tilt.roll_deg(), LEVEL_DEADBAND_DEG))
+        } else if mode == AppMode::Vibration {
+            // Eight bands from 0 Hz up to the Nyquist frequency
+            let nyquist_hz = 0.5 / accel::SAMPLE_PERIOD_S;
+            last_vibration.map(|vibration| {
+                let band = (vibration.frequency_hz / nyquist_hz * 8.0) as usize;
+                display::band(band, vibration.amplitude / VIBRATION_FULL_SCALE_MG)
+            })
+        } else if mode == AppMode::TurnIndicator {
+            rate_of_turn.rate().map(|rate| display::turn(rate, TURN_DEADBAND_DPS))
+        } else if let (AppMode::TargetHold, Some(heading), Some(target)) = (mode, smoother.heading(), target) {
+            Some(display::deviation(wrap_degrees(angle_to_bearing(heading) - target), deadband))
+        } else if confidence == Confidence::Low
+            && smoother.heading().is_some()
+            && clock::now().as_millis() / LOW_CONFIDENCE_BLINK_MS % 2 == 1
+        {
+            // Blink the heading so that nobody relies on it
+            Some([0; 8])
+        } else if let Some(heading) = smoother.heading() {
+            let angle = (heading + 360.0 + rand_angle) % 360.0;
+            Some(match stored.config.display_mode {
+                DisplayMode::Single => {
+                    let mut brightness = [0; 8];
+                    brightness[angle_to_direction(angle) as usize] = display::MAX;
+                    brightness
+                }
+                DisplayMode::Interpolated => CompassPoint::from_angle(angle).brightness(),
+                DisplayMode::Needle => display::needle(angle),
+            })
+        } else {
+            None
+        };
+        // While calibrating, the North LED breathes faster and faster as the readings cover more of
+        // the sphere, and stays on once they're enough
+        if let (Some(calibrator), Some(leds)) = (&calibration, &mut leds) {
+            let quality = calibrator.coverage() / CALIBRATION_COVERAGE;
+            leds[Direction::North as usize] = display::breathing(quality, now_ms);
+        }
+        // The OLED and the buzzer only have something to say about the heading while the LEDs show it
+        let bearing = match mode {
+            AppMode::Compass | AppMode::TargetHold => smoother.heading().map(angle_to_bearing),
+            _ => None,
+        };
+        // The display only cares about the latest view, and the next one is never far behind, so
+        // if the display task hasn't caught up, skip this one rather than wait
+        let alarm = alarm.is_raised() && alarm_actions.buzzer;
+        let error = match animator.playing() {
+            Some(Animation::Error(code)) => Some(code),
+            _ => None,
+        };
+        let _ = views.try_send(View { leds, bearing, mode, error, target, alarm });
+
+        // Nothing gets lost on the way out, so the main loop waits for the output task if it has
+        // to
+        async move {
+            if let Some(line) = output {
+                outputs.send(Output::Line(line)).await;
+            }
+            if let Some(frame) = frame {
+                outputs.send(Output::Frame(frame)).await;
+            }
+            for packet in packets {
+                outputs.send(Output::Packet(packet)).await;
+            }
+        }
+    });
+    let mut oled_view = &oled;
+    let displays: [&mut dyn CompassDisplay; 3] = [&mut board_leds, &mut ring, &mut oled_view];
+    let display = show_views_forever(&view_receiver, displays, buzzer, &profiler, &supervisor);
+    let output = send_outputs_forever(&output_receiver, &uart, &usb);
+    let sd_log = recorder.run(SdCard::new());
+    let oled_updates = oled.run(oled_display);
+    pin_mut!(main_loop, display, output, sd_log, oled_updates);
+    executor::run([main_loop, display, output, sd_log, oled_updates])
 }
@@ -0,0 +1,310 @@
+//! MAVLink v2 messages, so that the board can stand in for an external compass
+//!
+//! Ground stations and flight controllers talk MAVLink. We only ever send, and only three messages:
+//! `HEARTBEAT`, which makes the board show up as a component at all, `ATTITUDE` with the heading as
+//! the yaw, and `SCALED_IMU` with the raw sensor readings. A packet is a 10-byte header, the
+//! payload with its trailing zero bytes cut off, and a CRC-16/MCRF4XX over everything after the
+//! start byte followed by a per-message "CRC extra" byte that stands for the message's layout.
+//!
+//! MAVLink's body frame is X forward, Y right and Z down. The sensors count in the board's X
+//! forward, Y left and Z up axes, the same as `Tilt`, so the constructors flip Y and Z.
+
+use crate::fusion::wrap_degrees;
+
+const STX: u8 = 0xfd;
+const HEADER_SIZE: usize = 10;
+const CHECKSUM_SIZE: usize = 2;
+
+/// The longest payload that we send, `ATTITUDE`'s
+const MAX_PAYLOAD: usize = 28;
+
+pub const MAX_PACKET_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD + CHECKSUM_SIZE;
+
+// HEARTBEAT fields for a component that isn't an autopilot
+const MAV_TYPE_GENERIC: u8 = 0;
+const MAV_AUTOPILOT_INVALID: u8 = 8;
+const MAV_STATE_ACTIVE: u8 = 4;
+const MAVLINK_VERSION: u8 = 3;
+
+/// From `MAV_COMP_ID_PERIPHERAL`, for devices that don't have parameters
+pub const COMPONENT_ID: u8 = 158;
+
+/// Attitude in radians and angular rates in radians per second, in MAVLink's axes
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Attitude {
+    pub time_boot_ms: u32,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub rollspeed: f32,
+    pub pitchspeed: f32,
+    pub yawspeed: f32,
+}
+
+impl Attitude {
+    /// From `Tilt`'s roll and pitch, the bearing in degrees clockwise from north, and the gyro in
+    /// degrees per second
+    pub fn new(
+        time_boot_ms: u32,
+        roll_deg: f32,
+        pitch_deg: f32,
+        bearing: f32,
+        gyro_dps: (f32, f32, f32),
+    ) -> Self {
+        let (x, y, z) = gyro_dps;
+        Attitude {
+            time_boot_ms,
+            roll: roll_deg.to_radians(),
+            // `Tilt` counts pitch positive nose down
+            pitch: -pitch_deg.to_radians(),
+            yaw: wrap_degrees(bearing).to_radians(),
+            rollspeed: x.to_radians(),
+            pitchspeed: -y.to_radians(),
+            yawspeed: -z.to_radians(),
+        }
+    }
+}
+
+/// Accelerations in mG, angular rates in mrad/s and the field in mgauss, in MAVLink's axes
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScaledImu {
+    pub time_boot_ms: u32,
+    pub accel: (i16, i16, i16),
+    pub gyro: (i16, i16, i16),
+    pub mag: (i16, i16, i16),
+}
+
+impl ScaledImu {
+    /// From the accelerometer in mG, the gyro in degrees per second and the magnetometer in gauss
+    pub fn new(
+        time_boot_ms: u32,
+        accel_mg: (i16, i16, i16),
+        gyro_dps: (f32, f32, f32),
+        mag_gauss: (f32, f32, f32),
+    ) -> Self {
+        let (ax, ay, az) = accel_mg;
+        let scale = |value: f32, per_unit: f32| libm::roundf(value * per_unit) as i16;
+        let gyro = |value: f32| scale(value.to_radians(), 1000.0);
+        let mag = |value: f32| scale(value, 1000.0);
+        let (gx, gy, gz) = gyro_dps;
+        let (mx, my, mz) = mag_gauss;
+        ScaledImu {
+            time_boot_ms,
+            accel: (ax, ay.saturating_neg(), az.saturating_neg()),
+            gyro: (gyro(gx), gyro(-gy), gyro(-gz)),
+            mag: (mag(mx), mag(-my), mag(-mz)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Message {
+    Heartbeat,
+    Attitude(Attitude),
+    ScaledImu(ScaledImu),
+}
+
+/// Appends little-endian fields to a payload
+struct Payload {
+    buffer: [u8; MAX_PAYLOAD],
+    len: usize,
+}
+
+impl Payload {
+    fn put(&mut self, bytes: &[u8]) {
+        self.buffer[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn put_triple(&mut self, (x, y, z): (i16, i16, i16)) {
+        for value in [x, y, z].iter() {
+            self.put(&value.to_le_bytes());
+        }
+    }
+}
+
+impl Message {
+    fn id(&self) -> u32 {
+        match self {
+            Message::Heartbeat => 0,
+            Message::ScaledImu(_) => 26,
+            Message::Attitude(_) => 30,
+        }
+    }
+
+    /// Stands for the message's fields in the checksum, from the message definitions
+    fn crc_extra(&self) -> u8 {
+        match self {
+            Message::Heartbeat => 50,
+            Message::ScaledImu(_) => 170,
+            Message::Attitude(_) => 39,
+        }
+    }
+
+    /// The fields go out largest type first, and in the order of the definition within a type
+    fn payload(&self) -> Payload {
+        let mut payload = Payload {
+            buffer: [0; MAX_PAYLOAD],
+            len: 0,
+        };
+        match self {
+            Message::Heartbeat => {
+                // custom_mode
+                payload.put(&0u32.to_le_bytes());
+                payload.put(&[
+                    MAV_TYPE_GENERIC,
+                    MAV_AUTOPILOT_INVALID,
+                    // base_mode
+                    0,
+                    MAV_STATE_ACTIVE,
+                    MAVLINK_VERSION,
+                ]);
+            }
+            Message::Attitude(attitude) => {
+                payload.put(&attitude.time_boot_ms.to_le_bytes());
+                for value in [
+                    attitude.roll,
+                    attitude.pitch,
+                    attitude.yaw,
+                    attitude.rollspeed,
+                    attitude.pitchspeed,
+                    attitude.yawspeed,
+                ]
+                .iter()
+                {
+                    payload.put(&value.to_le_bytes());
+                }
+            }
+            Message::ScaledImu(imu) => {
+                payload.put(&imu.time_boot_ms.to_le_bytes());
+                payload.put_triple(imu.accel);
+                payload.put_triple(imu.gyro);
+                payload.put_triple(imu.mag);
+                // The temperature extension, where 0 means that there isn't one
+                payload.put(&0i16.to_le_bytes());
+            }
+        }
+        payload
+    }
+}
+
+/// CRC-16/MCRF4XX, which MAVLink calls X.25
+fn accumulate(crc: u16, byte: u8) -> u16 {
+    let tmp = byte ^ crc as u8;
+    let tmp = tmp ^ (tmp << 4);
+    (crc >> 8) ^ (u16::from(tmp) << 8) ^ (u16::from(tmp) << 3) ^ (u16::from(tmp) >> 4)
+}
+
+/// An encoded packet, ready to send
+pub struct Packet {
+    buffer: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+/// Numbers the packets from one component
+pub struct Encoder {
+    system_id: u8,
+    sequence: u8,
+}
+
+impl Encoder {
+    /// Send as component `COMPONENT_ID` of `system_id`, which is the flight controller's when the
+    /// board is an external compass
+    pub fn new(system_id: u8) -> Self {
+        Encoder {
+            system_id,
+            sequence: 0,
+        }
+    }
+
+    pub fn encode(&mut self, message: &Message) -> Packet {
+        let payload = message.payload();
+        // MAVLink 2 cuts off trailing zeros, but always sends at least one byte
+        let len = payload.buffer[..payload.len]
+            .iter()
+            .rposition(|&byte| byte != 0)
+            .map_or(1, |last| last + 1);
+        let id = message.id().to_le_bytes();
+
+        let mut packet = Packet {
+            buffer: [0; MAX_PACKET_SIZE],
+            len: HEADER_SIZE + len + CHECKSUM_SIZE,
+        };
+        packet.buffer[..HEADER_SIZE].copy_from_slice(&[
+            STX,
+            len as u8,
+            // Incompatibility and compatibility flags: not signed
+            0,
+            0,
+            self.sequence,
+            self.system_id,
+            COMPONENT_ID,
+            id[0],
+            id[1],
+            id[2],
+        ]);
+        packet.buffer[HEADER_SIZE..HEADER_SIZE + len].copy_from_slice(&payload.buffer[..len]);
+
+        let crc = packet.buffer[1..HEADER_SIZE + len]
+            .iter()
+            .chain(core::iter::once(&message.crc_extra()))
+            .fold(0xffff, |crc, &byte| accumulate(crc, byte));
+        packet.buffer[HEADER_SIZE + len..packet.len].copy_from_slice(&crc.to_le_bytes());
+
+        self.sequence = self.sequence.wrapping_add(1);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        let crc = b"123456789"
+            .iter()
+            .fold(0xffff, |crc, &byte| accumulate(crc, byte));
+        assert_eq!(crc, 0x6f91);
+    }
+
+    #[test]
+    fn frames_and_truncates_payloads() {
+        let mut encoder = Encoder::new(1);
+
+        let heartbeat = encoder.encode(&Message::Heartbeat);
+        let bytes = heartbeat.as_bytes();
+        assert_eq!(
+            bytes[..HEADER_SIZE],
+            [STX, 9, 0, 0, 0, 1, COMPONENT_ID, 0, 0, 0]
+        );
+        assert_eq!(
+            bytes[HEADER_SIZE..bytes.len() - 2],
+            [0, 0, 0, 0, 0, 8, 0, 4, 3]
+        );
+
+        // Level, pointing east, at rest in a field that points north and down
+        let imu = ScaledImu::new(1_000, (0, 0, 1000), (0.0, 0.0, 0.0), (0.0, -0.2, -0.4));
+        assert_eq!(imu.accel, (0, 0, -1000));
+        assert_eq!(imu.mag, (0, 200, 400));
+        let packet = encoder.encode(&Message::ScaledImu(imu));
+        let bytes = packet.as_bytes();
+        // The gyro and the temperature are zero, but the field after the gyro isn't
+        assert_eq!(bytes[1], 22);
+        assert_eq!(bytes[4], 1);
+        assert_eq!(bytes[7], 26);
+        assert_eq!(bytes.len(), HEADER_SIZE + 22 + CHECKSUM_SIZE);
+
+        let attitude = Attitude::new(1_000, 0.0, 0.0, 270.0, (0.0, 0.0, 0.0));
+        assert!((attitude.yaw + core::f32::consts::FRAC_PI_2).abs() < 1e-6);
+    }
+}
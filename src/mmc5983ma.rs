@@ -0,0 +1,528 @@
+//! Driver for the MMC5983MA, an 18-bit magnetometer for when a heading has to hold still to well
+//! under a degree
+//!
+//! The MMC5983MA is an AMR sensor, like the HMC5883L. Its bridges pick up an offset of their own,
+//! which drifts with temperature and jumps whenever a strong field magnetizes the sensor. To get rid
+//! of it, the chip can drive a current pulse through its SET/RESET coil, which magnetizes the sensor
+//! one way (SET) or the other (RESET). After a RESET, the bridges measure the field the other way
+//! around, but the offset stays the same, so the difference between a SET and a RESET measurement
+//! is twice the field, and their average is the offset. `degauss` does that and subtracts the
+//! offset from then on. A SET on its own also wipes out whatever a strong field left behind, and
+//! the chip can do that by itself every so often, see `Mmc5983maConfig::periodic_set`.
+//!
+//! It answers at its own slave address, so it can share the bus with the LSM303DLHC.
+
+use crate::magnetometer::{self, MagSample, Magnetometer, SelfTestError};
+use embedded_hal_async::i2c::I2c;
+
+// Slave address
+const MMC5983MA: u8 = 0b011_0000;
+
+// The first output register. Each axis has its top 16 bits in two registers, high byte first, in
+// the order X, Y, Z, and then one more register holds the bottom 2 bits of every axis.
+const XOUT0: u8 = 0x00;
+
+// The temperature, at 0.8 °C per LSB from -75 °C
+const TOUT: u8 = 0x07;
+const TEMP_C_PER_LSB: f32 = 0.8;
+const TEMP_MIN_C: f32 = -75.0;
+
+// The status register, and its bits that tell that a measurement is done
+const STATUS: u8 = 0x08;
+const MEAS_M_DONE: u8 = 1 << 0;
+const MEAS_T_DONE: u8 = 1 << 1;
+
+// The control registers, which can only be written
+const CONTROL_0: u8 = 0x09;
+const CONTROL_1: u8 = 0x0a;
+const CONTROL_2: u8 = 0x0b;
+
+// CONTROL_0 bits
+const TM_M: u8 = 1 << 0;
+const TM_T: u8 = 1 << 1;
+const SET: u8 = 1 << 3;
+const RESET: u8 = 1 << 4;
+const AUTO_SR_EN: u8 = 1 << 5;
+
+// CONTROL_2 bits, besides the measurement and SET periods
+const CMM_EN: u8 = 1 << 3;
+const EN_PRD_SET: u8 = 1 << 7;
+
+// The product ID register, which always reads 0x30
+const PRODUCT_ID: u8 = 0x2f;
+const IDENTITY: u8 = 0x30;
+
+// The output is unsigned, with no field in the middle of its 18-bit range
+const NULL_FIELD: i32 = 1 << 17;
+
+// `read` drops the bottom two bits to fit a `MagSample`, which leaves this sensitivity. Every
+// axis has a range of ±8 gauss.
+const SHIFT_16_BIT: u32 = 2;
+const LSB_PER_GAUSS_16_BIT: f32 = 4_096.0;
+
+/// How often to measure in continuous mode, the Cm_freq bits of control register 2
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRate {
+    Hz1 = 0b001,
+    Hz10 = 0b010,
+    Hz20 = 0b011,
+    Hz50 = 0b100,
+    Hz100 = 0b101,
+    /// Needs a bandwidth of at least 200 Hz
+    Hz200 = 0b110,
+    /// Needs a bandwidth of 800 Hz
+    Hz1000 = 0b111,
+}
+
+impl DataRate {
+    /// Time between measurements in continuous mode, in seconds
+    pub fn period_s(self) -> f32 {
+        let hz = match self {
+            DataRate::Hz1 => 1.0,
+            DataRate::Hz10 => 10.0,
+            DataRate::Hz20 => 20.0,
+            DataRate::Hz50 => 50.0,
+            DataRate::Hz100 => 100.0,
+            DataRate::Hz200 => 200.0,
+            DataRate::Hz1000 => 1000.0,
+        };
+        1.0 / hz
+    }
+}
+
+/// Bandwidth of the decimation filter, the BW bits of control register 1. A narrower bandwidth
+/// means less noise, but each measurement takes longer: 8 ms at 100 Hz, down to 0.5 ms at 800 Hz.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bandwidth {
+    Hz100 = 0b00,
+    Hz200 = 0b01,
+    Hz400 = 0b10,
+    Hz800 = 0b11,
+}
+
+/// How many measurements the chip takes between SETs of its own in continuous mode, the Prd_set
+/// bits of control register 2
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeriodicSet {
+    Every1 = 0b000,
+    Every25 = 0b001,
+    Every75 = 0b010,
+    Every100 = 0b011,
+    Every250 = 0b100,
+    Every500 = 0b101,
+    Every1000 = 0b110,
+    Every2000 = 0b111,
+}
+
+/// Operating mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Measure continuously at the configured data rate
+    Continuous,
+    /// Measure only when asked to. Every read triggers a measurement and waits for it.
+    Single,
+}
+
+/// Settings for `Mmc5983ma::configure`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mmc5983maConfig {
+    data_rate: DataRate,
+    bandwidth: Bandwidth,
+    mode: Mode,
+    periodic_set: Option<PeriodicSet>,
+}
+
+impl Mmc5983maConfig {
+    /// 10 Hz at a bandwidth of 100 Hz, continuous, without periodic SETs
+    pub fn new() -> Self {
+        Mmc5983maConfig {
+            data_rate: DataRate::Hz10,
+            bandwidth: Bandwidth::Hz100,
+            mode: Mode::Continuous,
+            periodic_set: None,
+        }
+    }
+
+    pub fn data_rate(mut self, data_rate: DataRate) -> Self {
+        self.data_rate = data_rate;
+        self
+    }
+
+    pub fn bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// In continuous mode, have the chip SET itself every so often, which undoes the effect of a
+    /// strong field on the sensor
+    pub fn periodic_set(mut self, periodic_set: Option<PeriodicSet>) -> Self {
+        self.periodic_set = periodic_set;
+        self
+    }
+
+    /// The value of control register 0, besides the bits that trigger something
+    fn control_0(self) -> u8 {
+        if self.periodic_set.is_some() {
+            AUTO_SR_EN
+        } else {
+            0
+        }
+    }
+
+    /// The value of control register 2
+    fn control_2(self) -> u8 {
+        match self.mode {
+            Mode::Single => 0,
+            Mode::Continuous => {
+                let periodic_set = match self.periodic_set {
+                    Some(every) => EN_PRD_SET | (every as u8) << 4,
+                    None => 0,
+                };
+                periodic_set | CMM_EN | self.data_rate as u8
+            }
+        }
+    }
+}
+
+impl Default for Mmc5983maConfig {
+    fn default() -> Self {
+        Mmc5983maConfig::new()
+    }
+}
+
+pub struct Mmc5983ma<I2C> {
+    i2c: I2C,
+    config: Mmc5983maConfig,
+    // The offset of the bridges, as measured by `degauss`, in 18-bit LSB
+    offset: (i32, i32, i32),
+    // The most recent measurement that we've read, without the offset
+    latest: Option<(i32, i32, i32)>,
+}
+
+impl<I2C: I2c> Mmc5983ma<I2C> {
+    /// The MMC5983MA starts out measuring only when asked to. Call `configure` before reading.
+    pub fn new(i2c: I2C) -> Self {
+        Mmc5983ma {
+            i2c,
+            config: Mmc5983maConfig::new(),
+            offset: (0, 0, 0),
+            latest: None,
+        }
+    }
+
+    /// Access the underlying bus, e.g. to recover it after an error
+    pub fn bus(&mut self) -> &mut I2C {
+        &mut self.i2c
+    }
+
+    /// Whether something that identifies as an MMC5983MA answers on the bus
+    pub async fn probe(&mut self) -> bool {
+        let mut identity = [0u8];
+        let result = self
+            .i2c
+            .write_read(MMC5983MA, &[PRODUCT_ID], &mut identity)
+            .await;
+        result.is_ok() && identity[0] == IDENTITY
+    }
+
+    /// Set one of the bits of control register 0 that trigger something, leaving the others as
+    /// they are
+    async fn trigger(&mut self, bit: u8) -> Result<(), I2C::Error> {
+        self.i2c
+            .write(MMC5983MA, &[CONTROL_0, self.config.control_0() | bit])
+            .await
+    }
+
+    /// Wait until the status register has `bit` set, then clear it
+    async fn wait_for(&mut self, bit: u8) -> Result<(), I2C::Error> {
+        while !self.is_done(bit).await? {}
+        self.i2c.write(MMC5983MA, &[STATUS, bit]).await
+    }
+
+    /// Whether the status register has `bit` set
+    async fn is_done(&mut self, bit: u8) -> Result<bool, I2C::Error> {
+        let mut status = [0u8];
+        self.i2c
+            .write_read(MMC5983MA, &[STATUS], &mut status)
+            .await?;
+        Ok(status[0] & bit != 0)
+    }
+
+    /// Read the output registers as 18-bit (x, y, z), whether they hold a new measurement or not,
+    /// without removing the offset
+    async fn read_output(&mut self) -> Result<(i32, i32, i32), I2C::Error> {
+        let mut buffer = [0u8; 7];
+        self.i2c
+            .write_read(MMC5983MA, &[XOUT0], &mut buffer)
+            .await?;
+
+        let axis = |high: u8, low: u8, shift: u32| {
+            let bottom = (buffer[6] >> shift) & 0b11;
+            let raw = u32::from(high) << 10 | u32::from(low) << 2 | u32::from(bottom);
+            raw as i32 - NULL_FIELD
+        };
+        Ok((
+            axis(buffer[0], buffer[1], 6),
+            axis(buffer[2], buffer[3], 4),
+            axis(buffer[4], buffer[5], 2),
+        ))
+    }
+
+    /// Take a single measurement and wait for it, without removing the offset
+    async fn measure(&mut self) -> Result<(i32, i32, i32), I2C::Error> {
+        self.trigger(TM_M).await?;
+        self.wait_for(MEAS_M_DONE).await?;
+        self.read_output().await
+    }
+
+    /// Magnetize the sensor the normal way around, which undoes the effect of a strong field
+    pub async fn set(&mut self) -> Result<(), I2C::Error> {
+        self.trigger(SET).await
+    }
+
+    /// Measure the offset of the bridges by measuring once after a RESET and once after a SET, and
+    /// subtract it from every measurement from now on. This leaves the sensor SET. Continuous mode
+    /// is paused while this runs.
+    pub async fn degauss(&mut self) -> Result<(), I2C::Error> {
+        self.i2c.write(MMC5983MA, &[CONTROL_2, 0]).await?;
+        self.trigger(RESET).await?;
+        let reset = self.measure().await?;
+        self.set().await?;
+        let set = self.measure().await?;
+        self.i2c
+            .write(MMC5983MA, &[CONTROL_2, self.config.control_2()])
+            .await?;
+
+        self.offset = (
+            (set.0 + reset.0) / 2,
+            (set.1 + reset.1) / 2,
+            (set.2 + reset.2) / 2,
+        );
+        self.latest = None;
+        Ok(())
+    }
+
+    fn without_offset(&self, (x, y, z): (i32, i32, i32)) -> (i32, i32, i32) {
+        (x - self.offset.0, y - self.offset.1, z - self.offset.2)
+    }
+
+    /// Wait for a measurement that we haven't read yet, then return the magnetic field as 18-bit
+    /// (x, y, z), at 16384 LSB per gauss. In single-measurement mode, this starts the measurement
+    /// first.
+    pub async fn read_fresh_18_bit(&mut self) -> Result<(i32, i32, i32), I2C::Error> {
+        let sample = match self.config.mode {
+            Mode::Single => self.measure().await?,
+            Mode::Continuous => {
+                self.wait_for(MEAS_M_DONE).await?;
+                self.read_output().await?
+            }
+        };
+        let sample = self.without_offset(sample);
+        self.latest = Some(sample);
+        Ok(sample)
+    }
+
+    /// Return the latest magnetic field as 18-bit (x, y, z) without waiting for a new measurement,
+    /// unless there hasn't been any yet. This works the same way as `Lsm303dlhc`'s `read`.
+    pub async fn read_18_bit(&mut self) -> Result<(i32, i32, i32), I2C::Error> {
+        match (self.config.mode, self.latest) {
+            (Mode::Continuous, Some(latest)) if !self.is_done(MEAS_M_DONE).await? => Ok(latest),
+            _ => self.read_fresh_18_bit().await,
+        }
+    }
+
+    /// Measure the temperature in °C. This is only roughly calibrated at the factory.
+    pub async fn get_temperature(&mut self) -> Result<f32, I2C::Error> {
+        self.trigger(TM_T).await?;
+        self.wait_for(MEAS_T_DONE).await?;
+        let mut tout = [0u8];
+        self.i2c.write_read(MMC5983MA, &[TOUT], &mut tout).await?;
+        Ok(TEMP_MIN_C + f32::from(tout[0]) * TEMP_C_PER_LSB)
+    }
+}
+
+/// A measurement with the bottom two bits dropped
+fn to_16_bit((x, y, z): (i32, i32, i32)) -> MagSample {
+    MagSample {
+        raw: (
+            (x >> SHIFT_16_BIT) as i16,
+            (y >> SHIFT_16_BIT) as i16,
+            (z >> SHIFT_16_BIT) as i16,
+        ),
+        lsb_per_gauss: (LSB_PER_GAUSS_16_BIT, LSB_PER_GAUSS_16_BIT),
+    }
+}
+
+impl<I2C: I2c> Magnetometer for Mmc5983ma<I2C> {
+    type Config = Mmc5983maConfig;
+    type Error = I2C::Error;
+
+    /// Write the bandwidth, data rate, mode and periodic SETs to the MMC5983MA
+    async fn configure(&mut self, config: Mmc5983maConfig) -> Result<(), I2C::Error> {
+        self.i2c
+            .write(MMC5983MA, &[CONTROL_1, config.bandwidth as u8])
+            .await?;
+        self.i2c
+            .write(MMC5983MA, &[CONTROL_0, config.control_0()])
+            .await?;
+        self.i2c
+            .write(MMC5983MA, &[CONTROL_2, config.control_2()])
+            .await?;
+        self.config = config;
+        self.latest = None;
+        Ok(())
+    }
+
+    /// Like `read_18_bit`, but with the bottom two bits dropped, at 4096 LSB per gauss
+    async fn read(&mut self) -> Result<MagSample, I2C::Error> {
+        Ok(to_16_bit(self.read_18_bit().await?))
+    }
+
+    async fn self_test(&mut self) -> Result<(), SelfTestError<I2C::Error>> {
+        let mut identity = [0u8];
+        self.i2c
+            .write_read(MMC5983MA, &[PRODUCT_ID], &mut identity)
+            .await
+            .map_err(SelfTestError::Bus)?;
+        if identity[0] != IDENTITY {
+            // The MMC5983MA only has the one identification register
+            return Err(SelfTestError::Identity([identity[0], 0, 0]));
+        }
+
+        let sample = self.read_fresh_18_bit().await.map_err(SelfTestError::Bus)?;
+        magnetometer::check_field(to_16_bit(sample))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::now;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, Operation};
+
+    /// Measures a constant field through bridges with a constant offset, and knows just enough of
+    /// the register map for the driver
+    struct Sensor {
+        field: (i32, i32, i32),
+        offset: (i32, i32, i32),
+        // Whether the last pulse was a SET rather than a RESET
+        set: bool,
+        output: [u8; 7],
+        status: u8,
+        pointer: u8,
+    }
+
+    impl Sensor {
+        fn new(field: (i32, i32, i32), offset: (i32, i32, i32)) -> Self {
+            Sensor {
+                field,
+                offset,
+                set: true,
+                output: [0; 7],
+                status: 0,
+                pointer: 0,
+            }
+        }
+
+        fn measure(&mut self) {
+            let sign = if self.set { 1 } else { -1 };
+            let axes = [
+                sign * self.field.0 + self.offset.0,
+                sign * self.field.1 + self.offset.1,
+                sign * self.field.2 + self.offset.2,
+            ];
+            self.output[6] = 0;
+            for (axis, raw) in axes.iter().enumerate() {
+                let raw = (raw + NULL_FIELD) as u32;
+                self.output[2 * axis] = (raw >> 10) as u8;
+                self.output[2 * axis + 1] = (raw >> 2) as u8;
+                self.output[6] |= ((raw & 0b11) as u8) << (6 - 2 * axis);
+            }
+            self.status |= MEAS_M_DONE;
+        }
+
+        fn write_register(&mut self, register: u8, value: u8) {
+            match register {
+                CONTROL_0 => {
+                    if value & SET != 0 {
+                        self.set = true;
+                    }
+                    if value & RESET != 0 {
+                        self.set = false;
+                    }
+                    if value & TM_M != 0 {
+                        self.measure();
+                    }
+                }
+                STATUS => self.status &= !value,
+                _ => {}
+            }
+        }
+
+        fn read_register(&self, register: u8) -> u8 {
+            match register {
+                XOUT0..=0x06 => self.output[usize::from(register)],
+                STATUS => self.status,
+                PRODUCT_ID => IDENTITY,
+                _ => 0,
+            }
+        }
+    }
+
+    impl ErrorType for Sensor {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for Sensor {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), ErrorKind> {
+            assert_eq!(address, MMC5983MA);
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        self.pointer = bytes[0];
+                        for &byte in &bytes[1..] {
+                            self.write_register(self.pointer, byte);
+                            self.pointer += 1;
+                        }
+                    }
+                    Operation::Read(buffer) => {
+                        for byte in buffer.iter_mut() {
+                            *byte = self.read_register(self.pointer);
+                            self.pointer += 1;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn outputs_have_18_bits() {
+        let mut mmc = Mmc5983ma::new(Sensor::new((8193, -3, -70_000), (0, 0, 0)));
+        now(mmc.configure(Mmc5983maConfig::new().mode(Mode::Single))).unwrap();
+        assert!(now(mmc.probe()));
+        assert_eq!(now(mmc.read_18_bit()), Ok((8193, -3, -70_000)));
+        assert_eq!(now(mmc.read()).map(|s| s.raw), Ok((2048, -1, -17_500)));
+    }
+
+    #[test]
+    fn degauss_removes_the_offset() {
+        let mut mmc = Mmc5983ma::new(Sensor::new((4000, -2000, 7000), (300, 120, -900)));
+        now(mmc.configure(Mmc5983maConfig::new().mode(Mode::Single))).unwrap();
+        assert_eq!(now(mmc.read_18_bit()), Ok((4300, -1880, 6100)));
+
+        now(mmc.degauss()).unwrap();
+        assert!(mmc.bus().set);
+        assert_eq!(now(mmc.read_18_bit()), Ok((4000, -2000, 7000)));
+    }
+}
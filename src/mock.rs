@@ -0,0 +1,81 @@
+//! What the drivers' unit tests run on instead of a bus
+
+use core::future::Future;
+use core::task::{Context, Poll};
+use embedded_hal_async::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+use futures::pin_mut;
+use futures::task::noop_waker;
+
+/// Registers that read back what was written, like a chip's with auto-increment, and nothing else
+/// on the bus
+pub struct Registers {
+    address: u8,
+    pub registers: [u8; 0x40],
+    pub pointer: usize,
+}
+
+impl Registers {
+    /// The chip at `address`, with every register zero
+    pub fn new(address: u8) -> Self {
+        Registers {
+            address,
+            registers: [0; 0x40],
+            pointer: 0,
+        }
+    }
+
+    /// Set the registers from `register` on
+    pub fn set(&mut self, register: u8, bytes: &[u8]) {
+        self.registers[usize::from(register)..][..bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// The registers from `register` on
+    pub fn get(&self, register: u8, len: usize) -> &[u8] {
+        &self.registers[usize::from(register)..][..len]
+    }
+}
+
+impl ErrorType for Registers {
+    type Error = ErrorKind;
+}
+
+impl I2c for Registers {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), ErrorKind> {
+        if address != self.address {
+            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+        }
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => {
+                    self.pointer = usize::from(bytes[0]);
+                    for &byte in &bytes[1..] {
+                        self.registers[self.pointer] = byte;
+                        self.pointer += 1;
+                    }
+                }
+                Operation::Read(buffer) => {
+                    for byte in buffer.iter_mut() {
+                        *byte = self.registers[self.pointer];
+                        self.pointer += 1;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run `future`, which must not wait for anything. The mocks never have to, so everything finishes
+/// on the first poll.
+pub fn now<F: Future>(future: F) -> F::Output {
+    pin_mut!(future);
+    let waker = noop_waker();
+    match future.poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("The mock never waits"),
+    }
+}
@@ -0,0 +1,244 @@
+//! What the board is being used as, and how the button switches between them
+//!
+//! The modes are the states of a state machine. A long press switches to the next mode in the
+//! cycle, and a short press does whatever the current mode does with it. Calibration isn't in the
+//! cycle: a short press in compass mode enters it, and any press leaves it for the mode it was
+//! entered from, a short one with the new calibration and a long one without.
+//!
+//! The state machine doesn't touch any hardware. Every press returns the `Actions` that it implies,
+//! in order: what the press does in the current mode, what the mode does when it's left, and what
+//! the next mode does when it's entered. The main loop carries them out.
+
+use heapless::Vec;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// Show the heading
+    Compass,
+    /// Show how far the heading is off a target. Entering the mode or a short press locks in the
+    /// current heading as the target.
+    TargetHold,
+    /// Show which way the heading is turning, and how fast
+    TurnIndicator,
+    /// Show how much the strength of the field deviates from its recent baseline. Entering the
+    /// mode or a short press starts the baseline over.
+    MetalDetector,
+    /// Show which way the board is tilted, like a bubble level
+    Level,
+    /// Show which band the strongest frequency of vibration is in, and how strong it is
+    Vibration,
+    /// Spin a needle around the ring without looking at the sensors, for showing the board off
+    Demo,
+    /// Collect readings for a new calibration
+    Calibration,
+}
+
+impl Mode {
+    /// The mode that a long press switches to
+    pub fn next(self) -> Mode {
+        match self {
+            Mode::Compass => Mode::TargetHold,
+            Mode::TargetHold => Mode::TurnIndicator,
+            Mode::TurnIndicator => Mode::MetalDetector,
+            Mode::MetalDetector => Mode::Level,
+            Mode::Level => Mode::Vibration,
+            Mode::Vibration => Mode::Demo,
+            Mode::Demo => Mode::Compass,
+            // Calibration isn't in the cycle, a long press cancels it instead, see
+            // `ModeMachine::press`
+            Mode::Calibration => Mode::Calibration,
+        }
+    }
+
+    /// The mode's position in the cycle, counting from 1 for compass mode, or 0 for calibration
+    pub fn number(self) -> u8 {
+        match self {
+            Mode::Compass => 1,
+            Mode::TargetHold => 2,
+            Mode::TurnIndicator => 3,
+            Mode::MetalDetector => 4,
+            Mode::Level => 5,
+            Mode::Vibration => 6,
+            Mode::Demo => 7,
+            Mode::Calibration => 0,
+        }
+    }
+
+    fn on_entry(self) -> Option<Action> {
+        match self {
+            Mode::TargetHold => Some(Action::LockTarget),
+            Mode::MetalDetector => Some(Action::ResetBaseline),
+            Mode::Calibration => Some(Action::StartCalibration),
+            Mode::Compass | Mode::TurnIndicator | Mode::Level | Mode::Vibration | Mode::Demo => {
+                None
+            }
+        }
+    }
+
+    fn on_exit(self) -> Option<Action> {
+        match self {
+            Mode::Calibration => Some(Action::StopCalibration),
+            _ => None,
+        }
+    }
+}
+
+/// How long the button was held down
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Press {
+    Short,
+    Long,
+}
+
+/// Something that the main loop has to do because the mode changed, or because of a press
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    /// Start collecting calibration readings
+    StartCalibration,
+    /// Fit the calibration readings, and keep the result
+    FinishCalibration,
+    /// Throw away whatever calibration readings are left
+    StopCalibration,
+    /// Make the current heading the target
+    LockTarget,
+    /// Start the metal detector's baseline over
+    ResetBaseline,
+}
+
+/// The actions that one press or switch implies, in the order to carry them out
+pub type Actions = Vec<Action, 3>;
+
+pub struct ModeMachine {
+    mode: Mode,
+    // The mode to go back to when calibration is over
+    previous: Mode,
+}
+
+impl ModeMachine {
+    /// Start in compass mode
+    pub fn new() -> Self {
+        ModeMachine {
+            mode: Mode::Compass,
+            previous: Mode::Compass,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Handle a press of the button
+    pub fn press(&mut self, press: Press) -> Actions {
+        match (self.mode, press) {
+            (Mode::Calibration, Press::Short) => {
+                let mut actions = Actions::new();
+                actions.push(Action::FinishCalibration).unwrap();
+                self.switch_into(self.previous, actions)
+            }
+            (Mode::Calibration, Press::Long) => self.leave_calibration(),
+            (Mode::Compass, Press::Short) => self.switch(Mode::Calibration),
+            (Mode::TargetHold, Press::Short) => Vec::from_slice(&[Action::LockTarget]).unwrap(),
+            (Mode::MetalDetector, Press::Short) => {
+                Vec::from_slice(&[Action::ResetBaseline]).unwrap()
+            }
+            (Mode::TurnIndicator, Press::Short)
+            | (Mode::Level, Press::Short)
+            | (Mode::Vibration, Press::Short)
+            | (Mode::Demo, Press::Short) => Actions::new(),
+            (mode, Press::Long) => self.switch(mode.next()),
+        }
+    }
+
+    /// Switch to `mode`, even if it isn't next in the cycle. Switching to the current mode doesn't
+    /// do anything.
+    pub fn switch(&mut self, mode: Mode) -> Actions {
+        self.switch_into(mode, Actions::new())
+    }
+
+    /// Leave calibration for the mode it was entered from without fitting the readings, like a
+    /// long press would. This doesn't do anything outside of calibration.
+    pub fn leave_calibration(&mut self) -> Actions {
+        if self.mode == Mode::Calibration {
+            self.switch(self.previous)
+        } else {
+            Actions::new()
+        }
+    }
+
+    fn switch_into(&mut self, mode: Mode, mut actions: Actions) -> Actions {
+        if mode == self.mode {
+            return actions;
+        }
+        if mode == Mode::Calibration {
+            self.previous = self.mode;
+        }
+        // There's room for a press's action, an exit action and an entry action
+        if let Some(action) = self.mode.on_exit() {
+            actions.push(action).unwrap();
+        }
+        if let Some(action) = mode.on_entry() {
+            actions.push(action).unwrap();
+        }
+        self.mode = mode;
+        actions
+    }
+}
+
+impl Default for ModeMachine {
+    fn default() -> Self {
+        ModeMachine::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_presses_cycle_through_the_modes() {
+        let mut machine = ModeMachine::new();
+        assert_eq!(machine.press(Press::Long), [Action::LockTarget]);
+        assert_eq!(machine.mode(), Mode::TargetHold);
+        assert_eq!(machine.press(Press::Short), [Action::LockTarget]);
+        assert_eq!(machine.press(Press::Long), []);
+        assert_eq!(machine.press(Press::Long), [Action::ResetBaseline]);
+        assert_eq!(machine.press(Press::Long), []);
+        assert_eq!(machine.mode(), Mode::Level);
+        assert_eq!(machine.press(Press::Long), []);
+        assert_eq!(machine.mode(), Mode::Vibration);
+        assert_eq!(machine.press(Press::Long), []);
+        assert_eq!(machine.mode(), Mode::Demo);
+        assert_eq!(machine.press(Press::Short), []);
+        assert_eq!(machine.press(Press::Long), []);
+        assert_eq!(machine.mode(), Mode::Compass);
+    }
+
+    #[test]
+    fn calibration_returns_to_the_mode_it_was_entered_from() {
+        let mut machine = ModeMachine::new();
+        assert_eq!(machine.press(Press::Short), [Action::StartCalibration]);
+        assert_eq!(machine.mode(), Mode::Calibration);
+        assert_eq!(
+            machine.press(Press::Short),
+            [Action::FinishCalibration, Action::StopCalibration]
+        );
+        assert_eq!(machine.mode(), Mode::Compass);
+        assert_eq!(machine.leave_calibration(), []);
+
+        machine.switch(Mode::MetalDetector);
+        assert_eq!(
+            machine.switch(Mode::Calibration),
+            [Action::StartCalibration]
+        );
+        assert_eq!(machine.switch(Mode::Calibration), []);
+        // Coming back to the metal detector starts its baseline over
+        assert_eq!(
+            machine.press(Press::Long),
+            [Action::StopCalibration, Action::ResetBaseline]
+        );
+        assert_eq!(machine.mode(), Mode::MetalDetector);
+    }
+}
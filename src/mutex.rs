@@ -0,0 +1,129 @@
+//! An async mutex for sharing something between tasks
+//!
+//! `lock` waits until nobody else holds the lock, rather than blocking, so other tasks keep
+//! running in the meantime. This is for tasks on the same executor: it isn't `Sync`, so it can't
+//! be shared with interrupt handlers.
+//!
+//! Every task that's waiting leaves its waker, and unlocking wakes all of them, since any of them
+//! might be the one to get the lock next. Up to `WAITERS` tasks can wait at once. Any more than
+//! that keep polling until a place frees up.
+
+use core::cell::{Cell, RefCell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+use core::task::{Poll, Waker};
+use futures::future::poll_fn;
+use heapless::Vec;
+
+// How many tasks can wait for the lock at the same time
+const WAITERS: usize = 8;
+
+pub struct Mutex<T> {
+    locked: Cell<bool>,
+    value: UnsafeCell<T>,
+    waiters: RefCell<Vec<Waker, WAITERS>>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: Cell::new(false),
+            value: UnsafeCell::new(value),
+            waiters: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The lock, if nobody else holds it
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.locked.replace(true) {
+            None
+        } else {
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    /// Wait until nobody else holds the lock and take it
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        poll_fn(|cx| {
+            if let Some(guard) = self.try_lock() {
+                return Poll::Ready(guard);
+            }
+            let mut waiters = self.waiters.borrow_mut();
+            if !waiters.iter().any(|waiter| waiter.will_wake(cx.waker()))
+                && waiters.push(cx.waker().clone()).is_err()
+            {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Holds the lock until it's dropped
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Only the guard can get at the value, and there's only one guard at a time
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.set(false);
+        let waiters = core::mem::take(&mut *self.mutex.waiters.borrow_mut());
+        for waiter in waiters {
+            waiter.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+    use core::task::Context;
+    use futures::pin_mut;
+    use futures::task::noop_waker;
+
+    #[test]
+    fn one_holder_at_a_time() {
+        let mutex = Mutex::new(0);
+        let mut guard = mutex.try_lock().unwrap();
+        *guard += 1;
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert_eq!(*mutex.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn waiters_get_the_lock_once_it_is_released() {
+        let mutex = Mutex::new(());
+        let guard = mutex.try_lock().unwrap();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let lock = mutex.lock();
+        pin_mut!(lock);
+        assert!(lock.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(mutex.waiters.borrow().len(), 1);
+        // Polling again doesn't take up another place
+        assert!(lock.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(mutex.waiters.borrow().len(), 1);
+
+        drop(guard);
+        assert!(mutex.waiters.borrow().is_empty());
+        assert!(lock.poll(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,35 @@
+//! NMEA 0183 heading sentences
+//!
+//! Chartplotters and marine software read the heading from `$--HDM` (magnetic) and `$--HDT` (true)
+//! sentences, and how fast it's changing from `$--ROT` sentences. `HC` is the talker ID for a magnetic compass. Every sentence ends with a checksum:
+//! the XOR of all characters between the `$` and the `*`, in hex.
+
+use crate::uart::Line;
+use core::fmt::{self, Write};
+
+/// Write a complete sentence with `$`, checksum and line ending, given everything in between
+fn write_sentence(out: &mut Line, body: fmt::Arguments) -> fmt::Result {
+    let mut sentence = Line::new();
+    sentence.write_fmt(body)?;
+    let checksum = sentence
+        .as_bytes()
+        .iter()
+        .fold(0, |checksum, byte| checksum ^ byte);
+    write!(out, "${}*{:02X}\r\n", sentence.as_str(), checksum)
+}
+
+/// Heading relative to magnetic north, in degrees clockwise
+pub fn hdm(out: &mut Line, bearing: f32) -> fmt::Result {
+    write_sentence(out, format_args!("HCHDM,{:.1},M", bearing))
+}
+
+/// Heading relative to true north, in degrees clockwise
+pub fn hdt(out: &mut Line, bearing: f32) -> fmt::Result {
+    write_sentence(out, format_args!("HCHDT,{:.1},T", bearing))
+}
+
+/// Rate of turn in degrees per second, positive clockwise. The sentence counts in degrees per
+/// minute, negative when the bow turns to port.
+pub fn rot(out: &mut Line, rate_dps: f32) -> fmt::Result {
+    write_sentence(out, format_args!("HCROT,{:.1},A", rate_dps * 60.0))
+}
@@ -0,0 +1,125 @@
+//! Counting steps with the accelerometer
+//!
+//! Every step jolts the board, which shows up as a peak in the strength of the acceleration. We
+//! smooth the strength with a low-pass filter to get rid of the jitter within a step, and subtract
+//! a slow baseline, which is mostly gravity. A step is a rise of the rest above `THRESHOLD_G` after
+//! it has dipped below zero, at least `MIN_STEP_INTERVAL_S` after the previous step.
+
+use crate::trig;
+use core::f32::consts::PI;
+
+// Smooths out everything faster than a brisk walk
+const SMOOTHING_HZ: f32 = 3.0;
+
+// How long it takes, in seconds, for the baseline to absorb most of a change
+const BASELINE_TIME_CONSTANT_S: f32 = 2.0;
+
+// How far above the baseline a peak has to go to count as a step
+const THRESHOLD_G: f32 = 0.15;
+
+// Nobody takes more than a bit over 3 steps per second
+const MIN_STEP_INTERVAL_S: f32 = 0.3;
+
+pub struct Pedometer {
+    smoothed: Option<f32>,
+    baseline: Option<f32>,
+    /// Whether the signal has dipped below the baseline since the last step
+    armed: bool,
+    /// Time since the last step, up to `MIN_STEP_INTERVAL_S`
+    since_step_s: f32,
+    steps: u32,
+}
+
+impl Pedometer {
+    pub fn new() -> Self {
+        Pedometer {
+            smoothed: None,
+            baseline: None,
+            armed: false,
+            since_step_s: MIN_STEP_INTERVAL_S,
+            steps: 0,
+        }
+    }
+
+    /// Add a reading in milli-g that came `dt_s` seconds after the previous one, and return
+    /// whether it completed a step
+    pub fn update(&mut self, accel: (i16, i16, i16), dt_s: f32) -> bool {
+        let (x, y, z) = (f32::from(accel.0), f32::from(accel.1), f32::from(accel.2));
+        let strength = trig::sqrt(x * x + y * y + z * z) / 1000.0;
+
+        // alpha = dt / (RC + dt), where RC = 1 / (2π * cutoff)
+        let rc = 1.0 / (2.0 * PI * SMOOTHING_HZ);
+        let smoothed = match self.smoothed {
+            None => strength,
+            Some(smoothed) => smoothed + dt_s / (rc + dt_s) * (strength - smoothed),
+        };
+        self.smoothed = Some(smoothed);
+
+        let baseline = match self.baseline {
+            None => smoothed,
+            Some(baseline) => {
+                let gain = dt_s / (BASELINE_TIME_CONSTANT_S + dt_s);
+                baseline + gain * (smoothed - baseline)
+            }
+        };
+        self.baseline = Some(baseline);
+
+        self.since_step_s = (self.since_step_s + dt_s).min(MIN_STEP_INTERVAL_S);
+        let signal = smoothed - baseline;
+        if signal < 0.0 {
+            self.armed = true;
+        }
+        if self.armed && signal > THRESHOLD_G && self.since_step_s >= MIN_STEP_INTERVAL_S {
+            self.armed = false;
+            self.since_step_s = 0.0;
+            self.steps = self.steps.wrapping_add(1);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How many steps we've counted since starting
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+}
+
+impl Default for Pedometer {
+    fn default() -> Self {
+        Pedometer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT_S: f32 = 0.02;
+
+    #[test]
+    fn counts_the_steps_of_a_walk() {
+        let mut pedometer = Pedometer::new();
+        // Ten seconds at two steps per second, each one a bump of 0.3 g up and down. The first bump
+        // doesn't count, since nothing has dipped below the baseline before it.
+        let mut steps = 0;
+        for i in 0..500 {
+            let t = i as f32 * DT_S;
+            let z = 1000.0 + 300.0 * trig::sin_cos(2.0 * PI * 2.0 * t).0;
+            if pedometer.update((0, 0, z as i16), DT_S) {
+                steps += 1;
+            }
+        }
+        assert_eq!(steps, 19);
+        assert_eq!(pedometer.steps(), steps);
+    }
+
+    #[test]
+    fn standing_still_takes_no_steps() {
+        let mut pedometer = Pedometer::new();
+        for _ in 0..500 {
+            assert!(!pedometer.update((0, 0, 1000), DT_S));
+        }
+        assert_eq!(pedometer.steps(), 0);
+    }
+}
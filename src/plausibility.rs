@@ -0,0 +1,134 @@
+//! Checking that the magnetometer is measuring the Earth's field
+//!
+//! Near steel, magnets or wires carrying current, the magnetometer measures the Earth's field plus
+//! whatever the interference adds, and the heading from that is wrong. Calibration only takes out
+//! interference that moves with the board. Whatever is left almost always changes how strong the
+//! field is, or how steeply it points into the ground (the dip), and both of those can be compared
+//! with what the Earth's field should be.
+//!
+//! Without a position, all we know is that the Earth's field is somewhere between about 0.22 and
+//! 0.67 gauss, so only the strength is checked. Given the field at the position from
+//! `compass::declination::field_at`, the strength and the dip have to be close to it.
+//!
+//! The dip comes from the angle between the field and gravity, so it's only right while the board
+//! isn't accelerating.
+
+use crate::declination::Field;
+use crate::trig;
+
+// The weakest and strongest the Earth's field gets, in gauss, in the South Atlantic and near the
+// south magnetic pole
+const MIN_INTENSITY_GAUSS: f32 = 0.22;
+const MAX_INTENSITY_GAUSS: f32 = 0.67;
+
+/// Why a reading doesn't look like the Earth's field
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Suspicion {
+    TooWeak,
+    TooStrong,
+    /// The field points into the ground at the wrong angle
+    WrongDip,
+}
+
+/// The dip of `mag` in degrees, positive when the field points down, given `accel` measured at the
+/// same time. `mag` and `accel` can be in any units. Returns `None` if either is zero.
+pub fn dip_deg(mag: (f32, f32, f32), accel: (i16, i16, i16)) -> Option<f32> {
+    let accel = (f32::from(accel.0), f32::from(accel.1), f32::from(accel.2));
+    let accel_norm = trig::sqrt(accel.0 * accel.0 + accel.1 * accel.1 + accel.2 * accel.2);
+    let mag_norm = trig::sqrt(mag.0 * mag.0 + mag.1 * mag.1 + mag.2 * mag.2);
+    if accel_norm < f32::EPSILON || mag_norm < f32::EPSILON {
+        return None;
+    }
+    // At rest, the accelerometer measures up, so the part of the field along it points up
+    let down = -(mag.0 * accel.0 + mag.1 * accel.1 + mag.2 * accel.2) / accel_norm;
+    let horizontal = trig::sqrt((mag_norm * mag_norm - down * down).max(0.0));
+    Some(trig::atan2(down, horizontal).to_degrees())
+}
+
+/// Compares readings with the Earth's field
+pub struct FieldCheck {
+    expected: Option<Field>,
+    // How far off the expected strength still counts, as a fraction of it
+    intensity_tolerance: f32,
+    dip_tolerance_deg: f32,
+}
+
+impl FieldCheck {
+    /// Readings count as the Earth's field if their strength is within `intensity_tolerance` (a
+    /// fraction) of what's expected, and their dip within `dip_tolerance_deg`
+    pub fn new(intensity_tolerance: f32, dip_tolerance_deg: f32) -> Self {
+        FieldCheck {
+            expected: None,
+            intensity_tolerance,
+            dip_tolerance_deg,
+        }
+    }
+
+    /// The field where we are, or `None` if we don't know where that is
+    pub fn set_expected(&mut self, expected: Option<Field>) {
+        self.expected = expected;
+    }
+
+    /// Check `mag`, in gauss, with `accel` measured at the same time
+    pub fn check(&self, mag: (f32, f32, f32), accel: (i16, i16, i16)) -> Result<(), Suspicion> {
+        let intensity = trig::sqrt(mag.0 * mag.0 + mag.1 * mag.1 + mag.2 * mag.2);
+        let (min, max) = match self.expected {
+            Some(expected) => (expected.intensity_gauss(), expected.intensity_gauss()),
+            None => (MIN_INTENSITY_GAUSS, MAX_INTENSITY_GAUSS),
+        };
+        if intensity < min * (1.0 - self.intensity_tolerance) {
+            return Err(Suspicion::TooWeak);
+        }
+        if intensity > max * (1.0 + self.intensity_tolerance) {
+            return Err(Suspicion::TooStrong);
+        }
+        if let (Some(expected), Some(dip)) = (self.expected, dip_deg(mag, accel)) {
+            if (dip - expected.inclination_deg()).abs() > self.dip_tolerance_deg {
+                return Err(Suspicion::WrongDip);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::declination;
+
+    #[test]
+    fn flags_fields_that_are_not_the_earths() {
+        // Level, with Boulder's field of about 0.52 gauss pointing 66° down toward the north
+        let level = (0, 0, 1000);
+        let boulder = (0.21, 0.0, -0.475);
+        assert!((dip_deg(boulder, level).unwrap() - 66.1).abs() < 0.1);
+
+        let mut check = FieldCheck::new(0.15, 10.0);
+        assert_eq!(check.check(boulder, level), Ok(()));
+        // Anywhere on Earth could have a dip of 0°
+        assert_eq!(check.check((0.52, 0.0, 0.0), level), Ok(()));
+        assert_eq!(
+            check.check((0.1, 0.0, -0.1), level),
+            Err(Suspicion::TooWeak)
+        );
+        assert_eq!(
+            check.check((0.5, 0.0, -0.9), level),
+            Err(Suspicion::TooStrong)
+        );
+
+        check.set_expected(Some(declination::field_at(40.0, -105.3, 2020.0)));
+        assert_eq!(check.check(boulder, level), Ok(()));
+        // Tilting the board doesn't change the dip
+        assert_eq!(check.check((0.21, 0.475, 0.0), (0, -1000, 0)), Ok(()));
+        assert_eq!(
+            check.check((0.52, 0.0, 0.0), level),
+            Err(Suspicion::WrongDip)
+        );
+        // Fine anywhere, but not in Boulder
+        assert_eq!(
+            check.check((0.11, 0.0, -0.25), level),
+            Err(Suspicion::TooWeak)
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Low-power sleep between events
+//!
+//! The executor goes to sleep whenever nothing is ready to make progress. Normally that's a plain
+//! `wfi`, which stops the core but keeps every clock running. With the `low-power` feature, it
+//! enters STOP mode instead, which also stops HSI, HSE and the PLL, and with them every peripheral
+//! clock. Only EXTI events wake the MCU from STOP: the sensors' data-ready lines, the tap line, the
+//! user button and the RTC wakeup timer, which replaces TIM2 as the source of the main loop's
+//! ticks.
+//!
+//! Anything that needs its clock to keep running, like a transfer in progress or an LED that's
+//! being dimmed by PWM, holds an `Awake`. While there is at least one, we fall back to `wfi`.
+//!
+//! Limitations of STOP mode:
+//!
+//! - SysTick doesn't count while the core is stopped, so `Interval` ticks (and with them the
+//!   temperature readings) only advance during the time that the MCU is awake.
+//! - USART1 can't receive while stopped, so shell commands typed while it's asleep get lost. USB
+//!   keeps the MCU awake while it's configured, so use that for the shell instead.
+//! - The debugger loses the connection in STOP unless DBGMCU_CR.DBG_STOP is set.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m::asm;
+use f3::hal::stm32f30x::{rcc, RCC};
+#[cfg(feature = "low-power")]
+use {
+    crate::clocks,
+    cortex_m::peripheral::SCB,
+    f3::hal::stm32f30x::{pwr, PWR},
+};
+
+// The number of `Awake`s that currently exist
+static AWAKE: AtomicUsize = AtomicUsize::new(0);
+
+// SCB_SCR.SLEEPDEEP makes `wfi` enter STOP (or STANDBY) rather than sleep
+#[cfg(feature = "low-power")]
+const SLEEPDEEP: u32 = 1 << 2;
+
+/// Keeps the MCU out of STOP mode for as long as it exists
+pub struct Awake(());
+
+impl Awake {
+    pub fn new() -> Self {
+        AWAKE.fetch_add(1, Ordering::Relaxed);
+        Awake(())
+    }
+}
+
+impl Drop for Awake {
+    fn drop(&mut self) {
+        AWAKE.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Enable the clock of the power controller, which the RTC and STOP mode need
+pub fn init() {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+}
+
+/// Sleep until an interrupt is pending. This must be called with interrupts disabled.
+pub fn sleep() {
+    #[cfg(feature = "low-power")]
+    {
+        if AWAKE.load(Ordering::Relaxed) == 0 {
+            stop();
+            return;
+        }
+    }
+    asm::wfi();
+}
+
+/// Enter STOP mode with the voltage regulator in low-power mode, and restore the clocks after
+/// waking up. The MCU always wakes up on HSI, so the PLL has to be started again before anything
+/// that counts on 72 MHz runs.
+#[cfg(feature = "low-power")]
+fn stop() {
+    let pwr: &'static pwr::RegisterBlock = unsafe { &*PWR::ptr() };
+    let scb = unsafe { &*SCB::ptr() };
+
+    pwr.cr.modify(|_, w| w.pdds().clear_bit().lpds().set_bit());
+    unsafe { scb.scr.modify(|scr| scr | SLEEPDEEP) };
+    asm::wfi();
+    unsafe { scb.scr.modify(|scr| scr & !SLEEPDEEP) };
+
+    clocks::restore();
+}
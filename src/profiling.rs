@@ -0,0 +1,128 @@
+//! Timing with the DWT cycle counter
+//!
+//! The DWT's CYCCNT counts core clock cycles, so it measures how long something takes with a
+//! resolution of one cycle, without a timer or an interrupt. It wraps around every 2^32 cycles
+//! (about a minute at 72 MHz), and durations are computed with wrapping arithmetic, so anything
+//! shorter than that comes out right.
+//!
+//! Each `Stage` collects the min, mean and max of its durations, which the main loop logs and
+//! resets every few seconds.
+
+use crate::clocks;
+use core::cell::Cell;
+use cortex_m::peripheral::{DCB, DWT};
+
+// CORE_CLOCK = 72 MHz
+const CYCLES_PER_US: u32 = clocks::SYSCLK_HZ / 1_000_000;
+
+// DEMCR bit that enables the DWT, and DWT_CTRL bit that starts CYCCNT
+const TRCENA: u32 = 1 << 24;
+const CYCCNTENA: u32 = 1 << 0;
+
+/// Start the cycle counter
+pub fn init() {
+    let dcb = unsafe { &*DCB::PTR };
+    let dwt = unsafe { &*DWT::PTR };
+    unsafe {
+        dcb.demcr.modify(|demcr| demcr | TRCENA);
+        dwt.cyccnt.write(0);
+        dwt.ctrl.modify(|ctrl| ctrl | CYCCNTENA);
+    }
+}
+
+/// The current value of the cycle counter
+pub fn now() -> u32 {
+    let dwt = unsafe { &*DWT::PTR };
+    dwt.cyccnt.read()
+}
+
+/// The parts of the pipeline that we time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Stage {
+    /// Reading the magnetometer over I2C, including the wait for the bus
+    Transaction,
+    /// The median and low-pass filters
+    Filter,
+    /// Updating the LEDs, the OLED and the buzzer
+    Display,
+}
+
+const STAGES: [Stage; 3] = [Stage::Transaction, Stage::Filter, Stage::Display];
+
+/// Durations in cycles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Durations {
+    count: u32,
+    min: u32,
+    max: u32,
+    total: u64,
+}
+
+impl Durations {
+    const EMPTY: Durations = Durations {
+        count: 0,
+        min: u32::MAX,
+        max: 0,
+        total: 0,
+    };
+}
+
+/// The min, mean and max duration of a stage, in µs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    pub count: u32,
+    pub min_us: u32,
+    pub mean_us: u32,
+    pub max_us: u32,
+}
+
+pub struct Profiler {
+    durations: [Cell<Durations>; 3],
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            durations: [
+                Cell::new(Durations::EMPTY),
+                Cell::new(Durations::EMPTY),
+                Cell::new(Durations::EMPTY),
+            ],
+        }
+    }
+
+    /// Record that `stage` ran from `start`, a value of `now`, until now
+    pub fn record(&self, stage: Stage, start: u32) {
+        let cycles = now().wrapping_sub(start);
+        let cell = &self.durations[stage as usize];
+        let durations = cell.get();
+        cell.set(Durations {
+            count: durations.count + 1,
+            min: durations.min.min(cycles),
+            max: durations.max.max(cycles),
+            total: durations.total + u64::from(cycles),
+        });
+    }
+
+    /// The stats for each stage that ran since the last call, and start over
+    pub fn take(&self) -> impl Iterator<Item = (Stage, Stats)> + '_ {
+        STAGES.iter().filter_map(move |&stage| {
+            let durations = self.durations[stage as usize].replace(Durations::EMPTY);
+            if durations.count == 0 {
+                return None;
+            }
+            let mean = durations.total / u64::from(durations.count);
+            Some((
+                stage,
+                Stats {
+                    count: durations.count,
+                    min_us: durations.min / CYCLES_PER_US,
+                    mean_us: mean as u32 / CYCLES_PER_US,
+                    max_us: durations.max / CYCLES_PER_US,
+                },
+            ))
+        })
+    }
+}
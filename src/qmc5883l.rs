@@ -0,0 +1,313 @@
+//! Driver for the QMC5883L, which is what most GY-271 breakout boards carry nowadays
+//!
+//! The QMC5883L took the place of the HMC5883L, but apart from measuring the same thing it has
+//! little in common with it: it answers at a different slave address, its output registers are
+//! little-endian and ordered X, Y, Z, and it has no single-measurement mode. Like the LSM303DLHC, it
+//! has an uncalibrated temperature sensor.
+//!
+//! Since it answers at its own address, it can share the bus with the LSM303DLHC, and `probe` tells
+//! whether one is connected.
+
+use crate::magnetometer::{self, MagSample, Magnetometer, SelfTestError};
+use embedded_hal_async::i2c::I2c;
+
+// Slave address
+const QMC5883L: u8 = 0b000_1101;
+
+// The first output register
+const DATA_X_LSB: u8 = 0x00;
+
+// The status register, and its data-ready bit
+const STATUS: u8 = 0x06;
+const DRDY: u8 = 1 << 0;
+
+// The temperature, low byte first, at 100 LSB per °C
+const TOUT_LSB: u8 = 0x07;
+const TEMP_LSB_PER_C: f32 = 100.0;
+
+// Control registers
+const CONTROL_1: u8 = 0x09;
+const CONTROL_2: u8 = 0x0a;
+const SET_RESET_PERIOD: u8 = 0x0b;
+
+// CONTROL_2 bit that resets every register
+const SOFT_RST: u8 = 1 << 7;
+
+// The datasheet asks for this SET/RESET period
+const SET_RESET_PERIOD_RECOMMENDED: u8 = 0x01;
+
+// The identification register, which always reads 0xff
+const CHIP_ID: u8 = 0x0d;
+const IDENTITY: u8 = 0xff;
+
+/// Output data rate in continuous mode, the ODR bits of control register 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRate {
+    Hz10 = 0b00,
+    Hz50 = 0b01,
+    Hz100 = 0b10,
+    Hz200 = 0b11,
+}
+
+impl DataRate {
+    /// Time between measurements in continuous mode, in seconds
+    pub fn period_s(self) -> f32 {
+        let hz = match self {
+            DataRate::Hz10 => 10.0,
+            DataRate::Hz50 => 50.0,
+            DataRate::Hz100 => 100.0,
+            DataRate::Hz200 => 200.0,
+        };
+        1.0 / hz
+    }
+}
+
+/// Measurement range, the RNG bits of control register 1. A bigger range means less resolution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Range {
+    Gauss2 = 0b00,
+    Gauss8 = 0b01,
+}
+
+impl Range {
+    /// Sensitivity of every axis, in LSB per gauss
+    pub fn lsb_per_gauss(self) -> f32 {
+        match self {
+            Range::Gauss2 => 12_000.0,
+            Range::Gauss8 => 3_000.0,
+        }
+    }
+}
+
+/// How much each measurement is oversampled, the OSR bits of control register 1. More
+/// oversampling means less noise, but more power.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Oversampling {
+    X512 = 0b00,
+    X256 = 0b01,
+    X128 = 0b10,
+    X64 = 0b11,
+}
+
+/// Operating mode, the MODE bits of control register 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Don't measure at all. `read` returns whatever is in the output registers.
+    Standby = 0b00,
+    /// Measure continuously at the configured data rate
+    Continuous = 0b01,
+}
+
+/// Settings for `Qmc5883l::configure`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Qmc5883lConfig {
+    data_rate: DataRate,
+    range: Range,
+    oversampling: Oversampling,
+    mode: Mode,
+}
+
+impl Qmc5883lConfig {
+    /// 10 Hz, ±2 gauss, 512 times oversampling, continuous. Those are the reset values, except
+    /// that the QMC5883L starts out in standby.
+    pub fn new() -> Self {
+        Qmc5883lConfig {
+            data_rate: DataRate::Hz10,
+            range: Range::Gauss2,
+            oversampling: Oversampling::X512,
+            mode: Mode::Continuous,
+        }
+    }
+
+    pub fn data_rate(mut self, data_rate: DataRate) -> Self {
+        self.data_rate = data_rate;
+        self
+    }
+
+    pub fn range(mut self, range: Range) -> Self {
+        self.range = range;
+        self
+    }
+
+    pub fn oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.oversampling = oversampling;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl Default for Qmc5883lConfig {
+    fn default() -> Self {
+        Qmc5883lConfig::new()
+    }
+}
+
+pub struct Qmc5883l<I2C> {
+    i2c: I2C,
+    config: Qmc5883lConfig,
+    // The most recent measurement that we've read
+    latest: Option<MagSample>,
+}
+
+impl<I2C: I2c> Qmc5883l<I2C> {
+    /// The QMC5883L starts out in standby. Call `configure` before reading.
+    pub fn new(i2c: I2C) -> Self {
+        Qmc5883l {
+            i2c,
+            config: Qmc5883lConfig::new(),
+            latest: None,
+        }
+    }
+
+    /// Access the underlying bus, e.g. to recover it after an error
+    pub fn bus(&mut self) -> &mut I2C {
+        &mut self.i2c
+    }
+
+    /// Whether something that identifies as a QMC5883L answers on the bus
+    pub async fn probe(&mut self) -> bool {
+        let mut identity = [0u8];
+        let result = self
+            .i2c
+            .write_read(QMC5883L, &[CHIP_ID], &mut identity)
+            .await;
+        result.is_ok() && identity[0] == IDENTITY
+    }
+
+    /// Whether the QMC5883L has a measurement that we haven't read yet
+    async fn data_ready(&mut self) -> Result<bool, I2C::Error> {
+        let mut status = [0u8];
+        self.i2c
+            .write_read(QMC5883L, &[STATUS], &mut status)
+            .await?;
+        Ok(status[0] & DRDY != 0)
+    }
+
+    /// Read the output registers, whether they hold a new measurement or not
+    async fn read_output(&mut self) -> Result<MagSample, I2C::Error> {
+        let mut buffer = [0u8; 6];
+        self.i2c
+            .write_read(QMC5883L, &[DATA_X_LSB], &mut buffer)
+            .await?;
+
+        let x = i16::from_le_bytes([buffer[0], buffer[1]]);
+        let y = i16::from_le_bytes([buffer[2], buffer[3]]);
+        let z = i16::from_le_bytes([buffer[4], buffer[5]]);
+
+        let lsb_per_gauss = self.config.range.lsb_per_gauss();
+        Ok(MagSample {
+            raw: (x, y, z),
+            lsb_per_gauss: (lsb_per_gauss, lsb_per_gauss),
+        })
+    }
+
+    /// Wait for a measurement that we haven't read yet, then return it
+    pub async fn read_fresh(&mut self) -> Result<MagSample, I2C::Error> {
+        while !self.data_ready().await? {}
+
+        let sample = self.read_output().await?;
+        self.latest = Some(sample);
+        Ok(sample)
+    }
+
+    /// Read the temperature in °C. Like the LSM303DLHC's, this is off by a constant that differs
+    /// from chip to chip, and only changes in temperature are meaningful.
+    pub async fn get_temperature(&mut self) -> Result<f32, I2C::Error> {
+        let mut buffer = [0u8; 2];
+        self.i2c
+            .write_read(QMC5883L, &[TOUT_LSB], &mut buffer)
+            .await?;
+
+        Ok(f32::from(i16::from_le_bytes(buffer)) / TEMP_LSB_PER_C)
+    }
+}
+
+impl<I2C: I2c> Magnetometer for Qmc5883l<I2C> {
+    type Config = Qmc5883lConfig;
+    type Error = I2C::Error;
+
+    /// Reset the QMC5883L, then write the data rate, range, oversampling and mode to it
+    async fn configure(&mut self, config: Qmc5883lConfig) -> Result<(), I2C::Error> {
+        self.i2c.write(QMC5883L, &[CONTROL_2, SOFT_RST]).await?;
+        self.i2c
+            .write(QMC5883L, &[SET_RESET_PERIOD, SET_RESET_PERIOD_RECOMMENDED])
+            .await?;
+        let control_1 = (config.oversampling as u8) << 6
+            | (config.range as u8) << 4
+            | (config.data_rate as u8) << 2
+            | config.mode as u8;
+        self.i2c.write(QMC5883L, &[CONTROL_1, control_1]).await?;
+        self.config = config;
+        self.latest = None;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<MagSample, I2C::Error> {
+        match self.config.mode {
+            Mode::Standby => self.read_output().await,
+            Mode::Continuous => {
+                let latest = self.latest;
+                match latest {
+                    Some(latest) if !self.data_ready().await? => Ok(latest),
+                    _ => self.read_fresh().await,
+                }
+            }
+        }
+    }
+
+    async fn self_test(&mut self) -> Result<(), SelfTestError<I2C::Error>> {
+        let mut identity = [0u8];
+        self.i2c
+            .write_read(QMC5883L, &[CHIP_ID], &mut identity)
+            .await
+            .map_err(SelfTestError::Bus)?;
+        if identity[0] != IDENTITY {
+            // The QMC5883L only has the one identification register
+            return Err(SelfTestError::Identity([identity[0], 0, 0]));
+        }
+
+        let sample = self.read_fresh().await.map_err(SelfTestError::Bus)?;
+        magnetometer::check_field(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{now, Registers};
+
+    fn qmc5883l() -> Qmc5883l<Registers> {
+        let mut registers = Registers::new(QMC5883L);
+        registers.set(CHIP_ID, &[IDENTITY]);
+        registers.set(STATUS, &[DRDY]);
+        Qmc5883l::new(registers)
+    }
+
+    #[test]
+    fn configure_packs_control_1() {
+        let mut qmc = qmc5883l();
+        assert!(now(qmc.probe()));
+        let config = Qmc5883lConfig::new()
+            .data_rate(DataRate::Hz50)
+            .range(Range::Gauss8)
+            .oversampling(Oversampling::X128);
+        now(qmc.configure(config)).unwrap();
+        assert_eq!(qmc.bus().get(CONTROL_1, 1), [0b1001_0101]);
+        assert_eq!(
+            qmc.bus().get(SET_RESET_PERIOD, 1),
+            [SET_RESET_PERIOD_RECOMMENDED]
+        );
+    }
+
+    #[test]
+    fn outputs_are_little_endian_x_y_z() {
+        let mut qmc = qmc5883l();
+        qmc.bus()
+            .set(DATA_X_LSB, &[0x02, 0x01, 0x10, 0x00, 0xfe, 0xff]);
+        assert_eq!(now(qmc.read()).map(|s| s.raw), Ok((0x0102, 0x0010, -2)));
+    }
+}
@@ -0,0 +1,71 @@
+//! How fast the heading is changing, for helmsmen and robots that steer by it
+//!
+//! The rate of turn is the difference between consecutive headings divided by the time between
+//! them. The difference is wrapped, so that turning from 359° to 1° is 2° clockwise rather than
+//! 358° counterclockwise. Differentiating amplifies noise, so the rate goes through a low-pass
+//! filter.
+
+use crate::fusion::wrap_degrees;
+
+pub struct RateOfTurn {
+    /// How long it takes, in seconds, for the rate to absorb most of a change
+    time_constant_s: f32,
+    previous: Option<f32>,
+    rate: Option<f32>,
+}
+
+impl RateOfTurn {
+    pub fn new(time_constant_s: f32) -> Self {
+        RateOfTurn {
+            time_constant_s,
+            previous: None,
+            rate: None,
+        }
+    }
+
+    /// Add a bearing in degrees that came `dt_s` seconds after the previous one, and return the
+    /// rate of turn in degrees per second, positive clockwise. This is `None` until there are two
+    /// bearings.
+    pub fn update(&mut self, bearing: f32, dt_s: f32) -> Option<f32> {
+        if let Some(previous) = self.previous.replace(bearing) {
+            if dt_s > 0.0 {
+                let rate = wrap_degrees(bearing - previous) / dt_s;
+                self.rate = Some(match self.rate {
+                    None => rate,
+                    Some(average) => {
+                        let gain = dt_s / (self.time_constant_s + dt_s);
+                        average + gain * (rate - average)
+                    }
+                });
+            }
+        }
+        self.rate
+    }
+
+    /// The latest rate of turn in degrees per second, positive clockwise
+    pub fn rate(&self) -> Option<f32> {
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turning_through_north_is_a_small_turn() {
+        let mut rate_of_turn = RateOfTurn::new(0.0);
+        assert_eq!(rate_of_turn.update(358.0, 0.1), None);
+        assert_eq!(rate_of_turn.update(0.5, 0.1), Some(25.0));
+        assert_eq!(rate_of_turn.update(359.5, 0.5), Some(-2.0));
+    }
+
+    #[test]
+    fn rate_is_smoothed() {
+        let mut rate_of_turn = RateOfTurn::new(0.9);
+        rate_of_turn.update(10.0, 0.1);
+        rate_of_turn.update(11.0, 0.1);
+        assert_eq!(rate_of_turn.update(11.0, 0.1), Some(9.0));
+        assert_eq!(rate_of_turn.rate(), Some(9.0));
+    }
+}
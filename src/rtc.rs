@@ -0,0 +1,87 @@
+//! Periodic ticks from the RTC wakeup timer
+//!
+//! TIM2 stops along with every other clock in STOP mode, so in low-power builds the main loop is
+//! paced by the RTC instead. The RTC runs from LSI, which keeps running in STOP, and its wakeup
+//! timer is connected to EXTI line 20, which wakes the MCU up.
+//!
+//! LSI is an RC oscillator somewhere between 30 and 50 kHz, so the period is only accurate to
+//! about ±25%.
+
+use crate::wakers;
+use core::future::Future;
+use f3::hal::stm32f30x::{exti, pwr, rcc, rtc, EXTI, PWR, RCC, RTC};
+
+// Nominal LSI frequency
+const LSI_HZ: u32 = 40_000;
+
+// WUCKSEL = 0b000: the wakeup timer counts RTC / 16
+const WAKEUP_DIVIDER: u32 = 16;
+
+// RTCSEL value that selects LSI
+const RTCSEL_LSI: u8 = 0b10;
+
+// Writing these to RTC_WPR unlocks the RTC registers
+const KEY1: u8 = 0xca;
+const KEY2: u8 = 0x53;
+
+pub struct Wakeup {
+    // There's only one wakeup timer, so nothing else may reconfigure it
+    rtc: &'static rtc::RegisterBlock,
+}
+
+impl Wakeup {
+    /// Start the RTC and let its wakeup timer fire every `ms` milliseconds, which must be at most
+    /// about 26 seconds. `power::init` must have been called before.
+    pub fn new(ms: u32) -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let pwr: &'static pwr::RegisterBlock = unsafe { &*PWR::ptr() };
+        let exti: &'static exti::RegisterBlock = unsafe { &*EXTI::ptr() };
+        let rtc: &'static rtc::RegisterBlock = unsafe { &*RTC::ptr() };
+
+        rcc.csr.modify(|_, w| w.lsion().set_bit());
+        while rcc.csr.read().lsirdy().bit_is_clear() {}
+
+        // The RTC is in the backup domain, which is write protected and survives a reset. The
+        // clock source can only be changed by resetting the whole domain.
+        pwr.cr.modify(|_, w| w.dbp().set_bit());
+        if rcc.bdcr.read().rtcsel().bits() != RTCSEL_LSI {
+            rcc.bdcr.modify(|_, w| w.bdrst().set_bit());
+            rcc.bdcr.modify(|_, w| w.bdrst().clear_bit());
+        }
+        rcc.bdcr
+            .modify(|_, w| unsafe { w.rtcsel().bits(RTCSEL_LSI).rtcen().set_bit() });
+
+        exti.rtsr1.modify(|_, w| w.tr20().set_bit());
+        exti.imr1.modify(|_, w| w.mr20().set_bit());
+
+        let mut wakeup = Wakeup { rtc };
+        wakeup.set_period(ms);
+        wakeup
+    }
+
+    /// Change the period to `ms` milliseconds, starting from now
+    pub fn set_period(&mut self, ms: u32) {
+        let rtc = self.rtc;
+        rtc.wpr.write(|w| unsafe { w.key().bits(KEY1) });
+        rtc.wpr.write(|w| unsafe { w.key().bits(KEY2) });
+
+        // The reload value can only be written while the timer is stopped
+        rtc.cr.modify(|_, w| w.wute().clear_bit());
+        while rtc.isr.read().wutwf().bit_is_clear() {}
+        let reload = ms * LSI_HZ / WAKEUP_DIVIDER / 1000 - 1;
+        rtc.wutr.write(|w| unsafe { w.wut().bits(reload as u16) });
+        rtc.cr.modify(|_, w| unsafe {
+            w.wcksel().bits(0);
+            w.wutie().set_bit();
+            w.wute().set_bit()
+        });
+
+        rtc.wpr.write(|w| unsafe { w.key().bits(0xff) });
+    }
+
+    /// Wait until the next period has elapsed. If more than one period has elapsed since the
+    /// previous call, this returns immediately, but only once.
+    pub fn tick(&mut self) -> impl Future<Output = ()> + '_ {
+        wakers::wait_for(&wakers::RTC_WAKEUP, wakers::rtc_elapsed, || ())
+    }
+}
@@ -0,0 +1,97 @@
+//! How often the main loop runs
+//!
+//! Every timer tick runs the sensor fusion once, so the tick period is also the time step that the
+//! filters and dead reckoning integrate over. Keeping both in one type means that they can't get
+//! out of sync when the rate changes at runtime.
+
+/// Rate of the main loop's timer ticks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SampleRate {
+    Hz5,
+    Hz10,
+    Hz20,
+    Hz50,
+}
+
+impl SampleRate {
+    /// The rate with exactly this frequency, if there is one
+    pub fn from_hz(hz: f32) -> Option<Self> {
+        [
+            SampleRate::Hz5,
+            SampleRate::Hz10,
+            SampleRate::Hz20,
+            SampleRate::Hz50,
+        ]
+        .iter()
+        .copied()
+        .find(|rate| rate.hz() == hz)
+    }
+
+    pub fn hz(self) -> f32 {
+        match self {
+            SampleRate::Hz5 => 5.0,
+            SampleRate::Hz10 => 10.0,
+            SampleRate::Hz20 => 20.0,
+            SampleRate::Hz50 => 50.0,
+        }
+    }
+
+    /// Time between ticks, in milliseconds, which is what the timers count
+    pub fn period_ms(self) -> u16 {
+        match self {
+            SampleRate::Hz5 => 200,
+            SampleRate::Hz10 => 100,
+            SampleRate::Hz20 => 50,
+            SampleRate::Hz50 => 20,
+        }
+    }
+
+    /// Time between ticks, in seconds, which is what the filters integrate over
+    pub fn period_s(self) -> f32 {
+        f32::from(self.period_ms()) / 1000.0
+    }
+
+    /// How many ticks to wait between sending the heading, to send it at about `hz`. We can't send
+    /// more often than once per tick.
+    pub fn ticks_per_output(self, hz: f32) -> usize {
+        (libm::roundf(self.hz() / hz) as usize).max(1)
+    }
+
+    /// The next faster rate, wrapping around to the slowest one
+    pub fn next(self) -> Self {
+        match self {
+            SampleRate::Hz5 => SampleRate::Hz10,
+            SampleRate::Hz10 => SampleRate::Hz20,
+            SampleRate::Hz20 => SampleRate::Hz50,
+            SampleRate::Hz50 => SampleRate::Hz5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_rate_has_its_frequency() {
+        for &rate in &[
+            SampleRate::Hz5,
+            SampleRate::Hz10,
+            SampleRate::Hz20,
+            SampleRate::Hz50,
+        ] {
+            assert_eq!(SampleRate::from_hz(rate.hz()), Some(rate));
+            assert_eq!(rate.period_s(), 1.0 / rate.hz());
+        }
+        assert_eq!(SampleRate::from_hz(7.0), None);
+    }
+
+    #[test]
+    fn outputs_are_rounded_to_whole_ticks() {
+        assert_eq!(SampleRate::Hz50.ticks_per_output(10.0), 5);
+        assert_eq!(SampleRate::Hz20.ticks_per_output(3.0), 7);
+        // Never more than once per tick
+        assert_eq!(SampleRate::Hz5.ticks_per_output(10.0), 1);
+    }
+}
@@ -0,0 +1,222 @@
+//! An append-only log of telemetry frames on an SD card
+//!
+//! There's no file system: this overwrites whatever was on the card. Block 0 is a superblock with a
+//! magic number, the current session number and the next free block. Every block after it starts
+//! with a header (magic number and session) followed by `compass-schema` frames, padded with
+//! zeros. A frame never spans two blocks, so every block can be decoded on its own, and since COBS
+//! frames end in a zero, the padding just reads as empty frames. On a host, something like
+//! `dd if=/dev/sdX bs=512 skip=1` gets the data back.
+//!
+//! The superblock is only rewritten every `SUPERBLOCK_INTERVAL` blocks, so after a reset we scan
+//! forward from the block that it points to until we find one that's not from its session.
+//!
+//! Writing a block can take hundreds of milliseconds, so the main loop never waits for the card.
+//! `Recorder::push` copies a frame into a queue of blocks in RAM, and `Recorder::run` writes full
+//! blocks out whenever the card is ready. If the card falls too far behind, frames are dropped.
+
+use super::{SdCard, SdError, BLOCK_SIZE};
+use core::cell::{Cell, RefCell};
+use core::task::Poll;
+use futures::future::poll_fn;
+use futures::task::AtomicWaker;
+
+const SUPERBLOCK: u32 = 0;
+const SUPERBLOCK_MAGIC: u32 = 0x434c_4f47; // "CLOG"
+const BLOCK_MAGIC: u32 = 0x4342_4c4b; // "CBLK"
+
+// Magic number and session
+const HEADER_SIZE: usize = 8;
+
+// How many data blocks to write between updates of the superblock
+const SUPERBLOCK_INTERVAL: u32 = 16;
+
+// How many blocks the queue holds, including the one that's being filled. A block holds about 20
+// telemetry frames, so this is several seconds of data at the magnetometer's data rate.
+const QUEUE_BLOCKS: usize = 4;
+
+fn u32_at(block: &[u8; BLOCK_SIZE], index: usize) -> u32 {
+    u32::from_le_bytes([
+        block[index],
+        block[index + 1],
+        block[index + 2],
+        block[index + 3],
+    ])
+}
+
+/// Blocks waiting to be written, as a ring buffer
+struct Queue {
+    blocks: [[u8; BLOCK_SIZE]; QUEUE_BLOCKS],
+    /// The block that `push` is filling
+    filling: usize,
+    /// How many bytes of `filling` are used, or 0 if it hasn't been started
+    used: usize,
+    /// How many full blocks come before `filling`
+    full: usize,
+}
+
+pub struct Recorder {
+    queue: RefCell<Queue>,
+    /// The session is only known once the card has been opened
+    session: Cell<Option<u32>>,
+    /// Set once the card has failed, after which we stop queueing frames
+    stopped: Cell<bool>,
+    error: Cell<Option<SdError>>,
+    dropped: Cell<u32>,
+    waker: AtomicWaker,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder {
+            queue: RefCell::new(Queue {
+                blocks: [[0; BLOCK_SIZE]; QUEUE_BLOCKS],
+                filling: 0,
+                used: 0,
+                full: 0,
+            }),
+            session: Cell::new(None),
+            stopped: Cell::new(false),
+            error: Cell::new(None),
+            dropped: Cell::new(0),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Queue an encoded frame. This never waits: if the queue is full, the frame is dropped.
+    pub fn push(&self, frame: &[u8]) {
+        if self.stopped.get() {
+            return;
+        }
+        // Frames that arrive before the card is open go in the first block, which gets its header
+        // once we know the session
+        let session = self.session.get().unwrap_or(0);
+        let mut queue = self.queue.borrow_mut();
+
+        if queue.used + frame.len() > BLOCK_SIZE {
+            if queue.full + 1 == QUEUE_BLOCKS {
+                self.dropped.set(self.dropped.get().wrapping_add(1));
+                return;
+            }
+            queue.full += 1;
+            queue.filling = (queue.filling + 1) % QUEUE_BLOCKS;
+            queue.used = 0;
+            self.waker.wake();
+        }
+
+        let Queue {
+            blocks,
+            filling,
+            used,
+            ..
+        } = &mut *queue;
+        let block = &mut blocks[*filling];
+        if *used == 0 {
+            *block = [0; BLOCK_SIZE];
+            block[0..4].copy_from_slice(&BLOCK_MAGIC.to_le_bytes());
+            block[4..8].copy_from_slice(&session.to_le_bytes());
+            *used = HEADER_SIZE;
+        }
+        block[*used..*used + frame.len()].copy_from_slice(frame);
+        *used += frame.len();
+    }
+
+    /// How many frames have been dropped because the card couldn't keep up
+    pub fn dropped(&self) -> u32 {
+        self.dropped.get()
+    }
+
+    /// The error that stopped the recorder, if there is one that nobody has asked about yet
+    pub fn take_error(&self) -> Option<SdError> {
+        self.error.take()
+    }
+
+    /// Wait for a full block and copy it into `data`
+    async fn pop(&self, data: &mut [u8; BLOCK_SIZE]) {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            let mut queue = self.queue.borrow_mut();
+            if queue.full == 0 {
+                return Poll::Pending;
+            }
+            let oldest = (queue.filling + QUEUE_BLOCKS - queue.full) % QUEUE_BLOCKS;
+            *data = queue.blocks[oldest];
+            queue.full -= 1;
+            Poll::Ready(())
+        })
+        .await
+    }
+
+    /// Find where the previous session ended, and start a new one after it. Returns the next free
+    /// block.
+    async fn open(&self, card: &mut SdCard) -> Result<u32, SdError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        card.read_block(SUPERBLOCK, &mut block).await?;
+        let (session, mut next) = if u32_at(&block, 0) == SUPERBLOCK_MAGIC {
+            (u32_at(&block, 4), u32_at(&block, 8))
+        } else {
+            // A card that we haven't written to before
+            (0, SUPERBLOCK + 1)
+        };
+
+        loop {
+            card.read_block(next, &mut block).await?;
+            if u32_at(&block, 0) != BLOCK_MAGIC || u32_at(&block, 4) != session {
+                break;
+            }
+            next += 1;
+        }
+
+        let session = session.wrapping_add(1);
+        self.write_superblock(card, session, next).await?;
+        self.session.set(Some(session));
+
+        // Give the frames that arrived in the meantime the right session
+        let mut queue = self.queue.borrow_mut();
+        for block in queue.blocks.iter_mut() {
+            block[4..8].copy_from_slice(&session.to_le_bytes());
+        }
+        Ok(next)
+    }
+
+    async fn write_superblock(
+        &self,
+        card: &mut SdCard,
+        session: u32,
+        next: u32,
+    ) -> Result<(), SdError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[0..4].copy_from_slice(&SUPERBLOCK_MAGIC.to_le_bytes());
+        block[4..8].copy_from_slice(&session.to_le_bytes());
+        block[8..12].copy_from_slice(&next.to_le_bytes());
+        card.write_block(SUPERBLOCK, &block).await
+    }
+
+    async fn write_forever(&self, card: &mut SdCard) -> Result<(), SdError> {
+        card.init().await?;
+        let mut next = self.open(card).await?;
+        let session = self.session.get().unwrap_or(0);
+
+        let mut data = [0u8; BLOCK_SIZE];
+        let mut since_superblock = 0;
+        loop {
+            self.pop(&mut data).await;
+            card.write_block(next, &data).await?;
+            next += 1;
+
+            since_superblock += 1;
+            if since_superblock == SUPERBLOCK_INTERVAL {
+                self.write_superblock(card, session, next).await?;
+                since_superblock = 0;
+            }
+        }
+    }
+
+    /// Initialize the card and write queued blocks to it. This only returns if something goes
+    /// wrong, and then the error is available from `take_error`.
+    pub async fn run(&self, mut card: SdCard) {
+        if let Err(error) = self.write_forever(&mut card).await {
+            self.stopped.set(true);
+            self.error.set(Some(error));
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Driver for SD cards in SPI mode, on SPI2
+//!
+//! The card's chip select is PB12. SPI mode only needs the four SPI pins, and every SD card
+//! supports it, including SDHC and SDXC cards. The card starts out at a clock of at most 400 kHz,
+//! so we initialize it at 281 kHz and only switch to 4.5 MHz once it's ready.
+//!
+//! There's no timer involved: timeouts are counted in bytes, which take a known time to clock out
+//! at a given SPI speed.
+
+use crate::spi::{Divider, Mode, Spi, SpiError};
+use embedded_hal_async::spi::SpiBus;
+use f3::hal::stm32f30x::{gpiob, GPIOB};
+
+pub mod log;
+
+pub const BLOCK_SIZE: usize = 512;
+
+// Chip select is PB12
+const CS: u32 = 12;
+
+// Commands
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const WRITE_BLOCK: u8 = 24;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+// Application command, which has to follow APP_CMD
+const SD_SEND_OP_COND: u8 = 41;
+
+// R1 response flags. Bit 7 is always clear, which is how we tell a response from an idle bus.
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_ERRORS: u8 = 0x7e;
+
+// SEND_IF_COND argument: 2.7-3.6 V and a check pattern that the card echoes back
+const IF_COND: u32 = 0x1aa;
+
+// SD_SEND_OP_COND argument: we support high capacity cards
+const HCS: u32 = 1 << 30;
+
+// OCR: the card is high capacity and addresses blocks instead of bytes
+const CCS: u8 = 0x40;
+
+// Sent before a block of data
+const START_BLOCK: u8 = 0xfe;
+
+// Data response token after writing a block: accepted
+const DATA_ACCEPTED: u8 = 0x05;
+
+// A card answers a command within 8 bytes
+const RESPONSE_BYTES: usize = 8;
+
+// SD_SEND_OP_COND takes about 20 bytes at 281 kHz, so this is more than the 1 s that a card may
+// take to initialize
+const INIT_ATTEMPTS: usize = 2_000;
+
+// Reads take up to 100 ms and writes up to 250 ms. At 4.5 MHz a byte takes 1.8 µs,
+// so these are a little generous.
+const READ_BYTES: usize = 50_000;
+const BUSY_BYTES: usize = 125_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SdError {
+    Spi(SpiError),
+    /// The card didn't answer a command, probably because there is no card
+    NoResponse,
+    /// The card answered a command with these R1 error flags
+    Command(u8),
+    /// The card doesn't work at 3.3 V, or isn't an SD card
+    Unsupported,
+    /// The card took too long to initialize, to send data or to finish writing
+    Timeout,
+    /// The card sent this error token instead of a block
+    Read(u8),
+    /// The card answered a block with this data response token instead of accepting it
+    Write(u8),
+}
+
+impl From<SpiError> for SdError {
+    fn from(error: SpiError) -> Self {
+        SdError::Spi(error)
+    }
+}
+
+/// CRC-7 of a command. Cards only check it for GO_IDLE_STATE and SEND_IF_COND, but it's cheap
+/// enough to always send the right one.
+fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        for bit in (0..8).rev() {
+            let feedback = ((crc >> 6) ^ (byte >> bit)) & 1;
+            crc = (crc << 1) & 0x7f;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+pub struct SdCard {
+    spi: Spi,
+    gpiob: &'static gpiob::RegisterBlock,
+    /// Whether the card addresses blocks (SDHC and SDXC) or bytes (SDSC)
+    block_addressed: bool,
+}
+
+impl SdCard {
+    /// Set up SPI2 and the chip select. This doesn't talk to the card yet, so it works without one.
+    pub fn new() -> Self {
+        // PCLK1 = 36 MHz
+        // 36 MHz / 128 = 281 kHz
+        let spi = Spi::spi2(Mode::Mode0, Divider::Div128);
+        let gpiob: &'static gpiob::RegisterBlock = unsafe { &*GPIOB::ptr() };
+
+        // Chip select as a push-pull output, deselected
+        gpiob.bsrr.write(|w| unsafe { w.bits(1 << CS) });
+        gpiob.moder.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << (2 * CS))) | (0b01 << (2 * CS)))
+        });
+
+        SdCard {
+            spi,
+            gpiob,
+            block_addressed: false,
+        }
+    }
+
+    fn select(&self, selected: bool) {
+        if selected {
+            self.gpiob.bsrr.write(|w| unsafe { w.bits(1 << (CS + 16)) });
+        } else {
+            self.gpiob.bsrr.write(|w| unsafe { w.bits(1 << CS) });
+        }
+    }
+
+    /// Deselect the card. It only lets go of MISO on the next clock edge, so send one more byte.
+    async fn release(&mut self) -> Result<(), SdError> {
+        self.select(false);
+        self.spi.write(&[0xff]).await?;
+        Ok(())
+    }
+
+    async fn read_byte(&mut self) -> Result<u8, SdError> {
+        let mut byte = [0];
+        self.spi.read(&mut byte).await?;
+        Ok(byte[0])
+    }
+
+    /// Read until the card sends something other than 0xff, and return that
+    async fn wait_while_idle(&mut self, max_bytes: usize) -> Result<u8, SdError> {
+        for _ in 0..max_bytes {
+            let byte = self.read_byte().await?;
+            if byte != 0xff {
+                return Ok(byte);
+            }
+        }
+        Err(SdError::Timeout)
+    }
+
+    /// Read until the card stops holding MISO low, which it does while it's busy
+    async fn wait_while_busy(&mut self, max_bytes: usize) -> Result<(), SdError> {
+        for _ in 0..max_bytes {
+            if self.read_byte().await? == 0xff {
+                return Ok(());
+            }
+        }
+        Err(SdError::Timeout)
+    }
+
+    /// Send a command to a selected card and return its R1 response
+    async fn send_command(&mut self, index: u8, argument: u32) -> Result<u8, SdError> {
+        let mut command = [0u8; 6];
+        command[0] = 0x40 | index;
+        command[1..5].copy_from_slice(&argument.to_be_bytes());
+        command[5] = (crc7(&command[..5]) << 1) | 1;
+        self.spi.write(&command).await?;
+
+        for _ in 0..RESPONSE_BYTES {
+            let r1 = self.read_byte().await?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(SdError::NoResponse)
+    }
+
+    /// Send a command and read the `response` bytes that follow R1, if the card didn't reject it
+    async fn command(
+        &mut self,
+        index: u8,
+        argument: u32,
+        response: &mut [u8],
+    ) -> Result<u8, SdError> {
+        self.select(true);
+        let mut result = self.send_command(index, argument).await;
+        if let Ok(r1) = result {
+            if r1 & R1_ILLEGAL_COMMAND == 0 {
+                if let Err(error) = self.spi.read(response).await {
+                    result = Err(error.into());
+                }
+            }
+        }
+        let released = self.release().await;
+        let r1 = result?;
+        released?;
+        Ok(r1)
+    }
+
+    /// Wake the card up and find out how it's addressed. This needs to be called once before
+    /// reading or writing.
+    pub async fn init(&mut self) -> Result<(), SdError> {
+        self.spi.set_divider(Divider::Div32);
+
+        // At least 74 clocks with chip select high put the card into its native mode, and
+        // GO_IDLE_STATE with chip select low then switches it to SPI mode
+        self.select(false);
+        self.spi.write(&[0xff; 10]).await?;
+        let r1 = self.command(GO_IDLE_STATE, 0, &mut []).await?;
+        if r1 != R1_IDLE {
+            return Err(SdError::Command(r1));
+        }
+
+        // Version 1 cards don't know SEND_IF_COND, and are never high capacity
+        let mut r7 = [0u8; 4];
+        let r1 = self.command(SEND_IF_COND, IF_COND, &mut r7).await?;
+        let version_2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if version_2 && u32::from_be_bytes(r7) & 0xfff != IF_COND {
+            return Err(SdError::Unsupported);
+        }
+
+        let argument = if version_2 { HCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_ATTEMPTS {
+            self.command(APP_CMD, 0, &mut []).await?;
+            let r1 = self.command(SD_SEND_OP_COND, argument, &mut []).await?;
+            if r1 & R1_ERRORS != 0 {
+                return Err(SdError::Command(r1));
+            }
+            if r1 & R1_IDLE == 0 {
+                ready = true;
+                break;
+            }
+        }
+        if !ready {
+            return Err(SdError::Timeout);
+        }
+
+        self.block_addressed = false;
+        if version_2 {
+            let mut ocr = [0u8; 4];
+            let r1 = self.command(READ_OCR, 0, &mut ocr).await?;
+            if r1 & R1_ERRORS != 0 {
+                return Err(SdError::Command(r1));
+            }
+            self.block_addressed = ocr[0] & CCS != 0;
+        }
+        if !self.block_addressed {
+            let r1 = self
+                .command(SET_BLOCKLEN, BLOCK_SIZE as u32, &mut [])
+                .await?;
+            if r1 & R1_ERRORS != 0 {
+                return Err(SdError::Command(r1));
+            }
+        }
+
+        // 36 MHz / 8 = 4.5 MHz
+        self.spi.set_divider(Divider::Div8);
+        Ok(())
+    }
+
+    fn address(&self, block: u32) -> u32 {
+        if self.block_addressed {
+            block
+        } else {
+            block * BLOCK_SIZE as u32
+        }
+    }
+
+    async fn read_selected(
+        &mut self,
+        block: u32,
+        data: &mut [u8; BLOCK_SIZE],
+    ) -> Result<(), SdError> {
+        let r1 = self
+            .send_command(READ_SINGLE_BLOCK, self.address(block))
+            .await?;
+        if r1 != 0 {
+            return Err(SdError::Command(r1));
+        }
+        let token = self.wait_while_idle(READ_BYTES).await?;
+        if token != START_BLOCK {
+            return Err(SdError::Read(token));
+        }
+        self.spi.read(data).await?;
+
+        // We don't check the CRC
+        self.spi.read(&mut [0; 2]).await?;
+        Ok(())
+    }
+
+    /// Read one block
+    pub async fn read_block(
+        &mut self,
+        block: u32,
+        data: &mut [u8; BLOCK_SIZE],
+    ) -> Result<(), SdError> {
+        self.select(true);
+        let result = self.read_selected(block, data).await;
+        let released = self.release().await;
+        result?;
+        released
+    }
+
+    async fn write_selected(&mut self, block: u32, data: &[u8; BLOCK_SIZE]) -> Result<(), SdError> {
+        let r1 = self.send_command(WRITE_BLOCK, self.address(block)).await?;
+        if r1 != 0 {
+            return Err(SdError::Command(r1));
+        }
+
+        // One byte of space before the token. The card doesn't check the CRC in SPI mode.
+        self.spi.write(&[0xff, START_BLOCK]).await?;
+        self.spi.write(data).await?;
+        self.spi.write(&[0xff; 2]).await?;
+
+        let response = self.read_byte().await? & 0x1f;
+        if response != DATA_ACCEPTED {
+            return Err(SdError::Write(response));
+        }
+        self.wait_while_busy(BUSY_BYTES).await
+    }
+
+    /// Write one block, and wait until the card has finished programming it
+    pub async fn write_block(
+        &mut self,
+        block: u32,
+        data: &[u8; BLOCK_SIZE],
+    ) -> Result<(), SdError> {
+        self.select(true);
+        let result = self.write_selected(block, data).await;
+        let released = self.release().await;
+        result?;
+        released
+    }
+}
@@ -0,0 +1,126 @@
+//! Recognizing a vigorous shake of the board, as a gesture that doesn't need the button
+//!
+//! Walking or turning the board barely changes the strength of the acceleration, which stays
+//! close to 1 g. Shaking it makes the strength jump far away from 1 g and back, over and over. We
+//! count those jolts, and a shake is `JOLTS` of them within `WINDOW_S`. After a shake, we ignore
+//! the board for `COOLDOWN_S` so that the rest of the same shake doesn't count as another one.
+
+use crate::trig;
+
+// How far the strength has to be from 1 g for a jolt
+const THRESHOLD_G: f32 = 0.8;
+
+const JOLTS: u8 = 6;
+const WINDOW_S: f32 = 1.5;
+const COOLDOWN_S: f32 = 2.0;
+
+pub struct ShakeDetector {
+    /// Jolts since the start of the current window
+    jolts: u8,
+    /// Time since the first jolt of the current window
+    elapsed_s: f32,
+    /// Whether the previous reading was a jolt, so that a long one only counts once
+    jolting: bool,
+    cooldown_s: f32,
+}
+
+impl ShakeDetector {
+    pub fn new() -> Self {
+        ShakeDetector {
+            jolts: 0,
+            elapsed_s: 0.0,
+            jolting: false,
+            cooldown_s: 0.0,
+        }
+    }
+
+    /// Add a reading in milli-g that came `dt_s` seconds after the previous one, and return
+    /// whether it completed a shake
+    pub fn update(&mut self, accel: (i16, i16, i16), dt_s: f32) -> bool {
+        let (x, y, z) = (f32::from(accel.0), f32::from(accel.1), f32::from(accel.2));
+        let strength = trig::sqrt(x * x + y * y + z * z) / 1000.0;
+        let jolt = (strength - 1.0).abs() > THRESHOLD_G;
+        let new_jolt = jolt && !self.jolting;
+        self.jolting = jolt;
+
+        if self.cooldown_s > 0.0 {
+            self.cooldown_s -= dt_s;
+            return false;
+        }
+
+        if self.jolts > 0 {
+            self.elapsed_s += dt_s;
+            if self.elapsed_s > WINDOW_S {
+                self.jolts = 0;
+            }
+        }
+        if new_jolt {
+            if self.jolts == 0 {
+                self.elapsed_s = 0.0;
+            }
+            self.jolts += 1;
+        }
+
+        if self.jolts >= JOLTS {
+            self.jolts = 0;
+            self.cooldown_s = COOLDOWN_S;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for ShakeDetector {
+    fn default() -> Self {
+        ShakeDetector::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT_S: f32 = 0.01;
+
+    /// Jolt the board `jolts` times, `period_s` apart, and count the shakes
+    fn jolt(detector: &mut ShakeDetector, jolts: usize, period_s: f32) -> usize {
+        let samples = (period_s / DT_S) as usize;
+        let mut shakes = 0;
+        for _ in 0..jolts {
+            for i in 0..samples {
+                let z = if i == 0 { 2500 } else { 1000 };
+                if detector.update((0, 0, z), DT_S) {
+                    shakes += 1;
+                }
+            }
+        }
+        shakes
+    }
+
+    #[test]
+    fn six_quick_jolts_are_a_shake() {
+        let mut detector = ShakeDetector::new();
+        assert_eq!(jolt(&mut detector, 5, 0.2), 0);
+        assert_eq!(jolt(&mut detector, 1, 0.2), 1);
+    }
+
+    #[test]
+    fn slow_jolts_are_not_a_shake() {
+        let mut detector = ShakeDetector::new();
+        assert_eq!(jolt(&mut detector, 12, 0.4), 0);
+    }
+
+    #[test]
+    fn the_rest_of_a_shake_does_not_count() {
+        let mut detector = ShakeDetector::new();
+        assert_eq!(jolt(&mut detector, 6, 0.2), 1);
+        // Shaking on for almost all of the cooldown
+        assert_eq!(jolt(&mut detector, 9, 0.2), 0);
+        // Once the cooldown is over, another shake counts
+        for _ in 0..50 {
+            assert!(!detector.update((0, 0, 1000), DT_S));
+        }
+        assert_eq!(jolt(&mut detector, 6, 0.2), 1);
+    }
+}
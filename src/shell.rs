@@ -0,0 +1,295 @@
+//! A line-based command shell, on USART1 or anything else that can receive bytes
+//!
+//! Commands are words separated by spaces and terminated by a carriage return or line feed:
+//!
+//! - `cal start` starts calibration, like a short press of the button
+//! - `cal stop` finishes it
+//! - `decl set 13.2` sets the magnetic declination in degrees, positive east
+//! - `decl at 40.0 -105.3` sets it to the declination at a latitude and longitude in degrees, from
+//!   `compass::declination::at`
+//! - `rate 2` sends the heading twice a second
+//! - `format text` sends the heading as plain numbers, and `format nmea`, `format telemetry` and
+//!   `format mavlink` switch to the other `compass::config::OutputFormat`s
+//! - `sample 20` runs the sensor fusion 20 times a second; 5, 10, 20 and 50 are supported
+//! - `target 270` makes the buzzer guide you toward a bearing of 270°, and `target off` stops it
+//! - `deadband 5` counts headings within 5° of the target as on course in hold mode
+//! - `pos` shows the dead-reckoning position, `pos reset` makes the current position the origin,
+//!   and `pos on` and `pos off` switch sending it along with the heading
+//! - `speed 1.4` makes dead reckoning assume a constant speed in meters per second, and
+//!   `stride 0.75` makes it move by that many meters with every step instead
+//! - `capture on` replaces the usual output with the raw sensor samples, in the format of
+//!   `compass::capture`, until `capture off`
+//! - `alarm 270 15 10` sets off the alarm when the heading has been more than 15° off a course of
+//!   270° for 10 seconds, and `alarm off` turns it off
+//! - `alarm action buzzer off` stops the alarm from sounding the buzzer. The other actions are
+//!   `flash`, which flashes the LEDs, and `event`, which tells the host.
+//! - `config reset` puts the sample rate, declination, display mode, filter and output format
+//!   settings back to their defaults, and keeps the calibration
+//! - `dump` shows the current settings
+//! - `flight` writes the flight recorder's records to the log, oldest first
+//! - `log` writes the heading log in flash to the log, oldest first, and `log erase` erases it
+//! - `scan` writes the addresses of the devices on the I2C bus to the log
+//!
+//! The shell doesn't echo, so that the replies aren't mixed up with what the terminal shows.
+
+use crate::uart::{UartError, Usart1};
+use crate::usb::UsbSerial;
+use compass::alarm::{AlarmAction, AlarmSettings};
+use compass::config::OutputFormat;
+use compass::sample_rate::SampleRate;
+use core::future::Future;
+use core::str;
+use futures::{stream, Stream};
+use usb_device::UsbError;
+
+// Long enough for any command
+const MAX_LINE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    StartCalibration,
+    FinishCalibration,
+    SetDeclination(f32),
+    /// Latitude and longitude in degrees, to work the declination out from
+    SetLocation(f32, f32),
+    /// How often to send the heading, in Hz
+    SetRate(f32),
+    /// What to send the heading as
+    SetOutputFormat(OutputFormat),
+    /// How often to run the sensor fusion
+    SetSampleRate(SampleRate),
+    /// The bearing that the buzzer guides toward, in degrees clockwise from north
+    SetTarget(Option<f32>),
+    /// How far off the target still counts as on course, in degrees
+    SetDeadband(f32),
+    ShowPosition,
+    ResetPosition,
+    /// Whether to send the position along with the heading
+    SetPositionOutput(bool),
+    /// Constant speed for dead reckoning, in meters per second
+    SetSpeed(f32),
+    /// Length of a step for dead reckoning, in meters
+    SetStride(f32),
+    /// Whether to send the raw sensor samples instead of the usual output
+    SetCapture(bool),
+    /// When the off-course alarm goes off, or `None` to turn it off
+    SetAlarm(Option<AlarmSettings>),
+    /// Enable or disable one of the things that the alarm does
+    SetAlarmAction(AlarmAction, bool),
+    /// Put the `Config` back to its defaults
+    ResetConfig,
+    Dump,
+    /// Write the flight recorder to the log
+    DumpFlightRecorder,
+    /// Write the heading log to the log
+    DumpHeadingLog,
+    EraseHeadingLog,
+    /// Write what's on the I2C bus to the log
+    ScanI2c,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShellError<E> {
+    /// Receiving failed
+    Read(E),
+    /// The line didn't fit into the buffer
+    LineTooLong,
+    UnknownCommand,
+    /// The argument is missing or isn't a number
+    InvalidNumber,
+    /// The number isn't in the range that makes sense for the command
+    OutOfRange,
+}
+
+/// Where commands come from
+pub trait ReadByte {
+    type Error;
+
+    /// Wait for the next byte
+    fn read_byte(&self) -> impl Future<Output = Result<u8, Self::Error>>;
+}
+
+impl ReadByte for Usart1 {
+    type Error = UartError;
+
+    fn read_byte(&self) -> impl Future<Output = Result<u8, UartError>> {
+        Usart1::read_byte(self)
+    }
+}
+
+impl ReadByte for &UsbSerial<'_> {
+    type Error = UsbError;
+
+    fn read_byte(&self) -> impl Future<Output = Result<u8, UsbError>> {
+        UsbSerial::read_byte(self)
+    }
+}
+
+fn number<E>(word: Option<&str>) -> Result<f32, ShellError<E>> {
+    let value: f32 = word
+        .and_then(|word| word.parse().ok())
+        .ok_or(ShellError::InvalidNumber)?;
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(ShellError::InvalidNumber)
+    }
+}
+
+/// Parse one line, without the line ending
+pub fn parse<E>(line: &str) -> Result<Command, ShellError<E>> {
+    let mut words = line.split_whitespace();
+    let command = match (words.next(), words.next()) {
+        (Some("cal"), Some("start")) => Command::StartCalibration,
+        (Some("cal"), Some("stop")) => Command::FinishCalibration,
+        (Some("decl"), Some("at")) => {
+            let latitude = number(words.next())?;
+            let longitude = number(words.next())?;
+            if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetLocation(latitude, longitude)
+        }
+        (Some("decl"), Some("set")) => {
+            let declination = number(words.next())?;
+            if !(-180.0..=180.0).contains(&declination) {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetDeclination(declination)
+        }
+        (Some("rate"), hz) => {
+            let hz = number(hz)?;
+            if hz <= 0.0 {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetRate(hz)
+        }
+        (Some("format"), Some("nmea")) => Command::SetOutputFormat(OutputFormat::Nmea),
+        (Some("format"), Some("text")) => Command::SetOutputFormat(OutputFormat::Text),
+        (Some("format"), Some("telemetry")) => Command::SetOutputFormat(OutputFormat::Telemetry),
+        (Some("format"), Some("mavlink")) => Command::SetOutputFormat(OutputFormat::Mavlink),
+        (Some("sample"), hz) => {
+            let rate = SampleRate::from_hz(number(hz)?).ok_or(ShellError::OutOfRange)?;
+            Command::SetSampleRate(rate)
+        }
+        (Some("target"), Some("off")) => Command::SetTarget(None),
+        (Some("target"), bearing) => {
+            let bearing = number(bearing)?;
+            if !(0.0..360.0).contains(&bearing) {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetTarget(Some(bearing))
+        }
+        (Some("deadband"), degrees) => {
+            let degrees = number(degrees)?;
+            if !(0.0..=180.0).contains(&degrees) {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetDeadband(degrees)
+        }
+        (Some("pos"), None) => Command::ShowPosition,
+        (Some("pos"), Some("reset")) => Command::ResetPosition,
+        (Some("pos"), Some("on")) => Command::SetPositionOutput(true),
+        (Some("pos"), Some("off")) => Command::SetPositionOutput(false),
+        (Some("speed"), speed) => {
+            let speed = number(speed)?;
+            if speed < 0.0 {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetSpeed(speed)
+        }
+        (Some("stride"), stride) => {
+            let stride = number(stride)?;
+            if stride < 0.0 {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetStride(stride)
+        }
+        (Some("capture"), Some("on")) => Command::SetCapture(true),
+        (Some("capture"), Some("off")) => Command::SetCapture(false),
+        (Some("alarm"), Some("off")) => Command::SetAlarm(None),
+        (Some("alarm"), Some("action")) => {
+            let action = match words.next() {
+                Some("flash") => AlarmAction::Flash,
+                Some("buzzer") => AlarmAction::Buzzer,
+                Some("event") => AlarmAction::Event,
+                _ => return Err(ShellError::UnknownCommand),
+            };
+            let enabled = match words.next() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(ShellError::UnknownCommand),
+            };
+            Command::SetAlarmAction(action, enabled)
+        }
+        (Some("alarm"), course) => {
+            let course = number(course)?;
+            let tolerance_deg = number(words.next())?;
+            let delay_s = number(words.next())?;
+            if !(0.0..360.0).contains(&course)
+                || !(0.0..=180.0).contains(&tolerance_deg)
+                || delay_s < 0.0
+            {
+                return Err(ShellError::OutOfRange);
+            }
+            Command::SetAlarm(Some(AlarmSettings {
+                course,
+                tolerance_deg,
+                delay_s,
+            }))
+        }
+        (Some("config"), Some("reset")) => Command::ResetConfig,
+        (Some("dump"), None) => Command::Dump,
+        (Some("flight"), None) => Command::DumpFlightRecorder,
+        (Some("log"), None) => Command::DumpHeadingLog,
+        (Some("log"), Some("erase")) => Command::EraseHeadingLog,
+        (Some("scan"), None) => Command::ScanI2c,
+        _ => return Err(ShellError::UnknownCommand),
+    };
+    match words.next() {
+        None => Ok(command),
+        Some(_) => Err(ShellError::UnknownCommand),
+    }
+}
+
+/// Read a line, skipping empty ones
+async fn read_line<R: ReadByte>(
+    source: &R,
+    buffer: &mut [u8; MAX_LINE],
+) -> Result<usize, ShellError<R::Error>> {
+    let mut len = 0;
+    // After an error, ignore the rest of the line
+    let mut error = None;
+    loop {
+        match source.read_byte().await {
+            Ok(b'\r') | Ok(b'\n') => match error {
+                Some(error) => return Err(error),
+                None if len > 0 => return Ok(len),
+                None => {}
+            },
+            Ok(_) if error.is_some() => {}
+            Ok(byte) => match buffer.get_mut(len) {
+                Some(slot) => {
+                    *slot = byte;
+                    len += 1;
+                }
+                None => error = Some(ShellError::LineTooLong),
+            },
+            Err(read_error) => error = Some(ShellError::Read(read_error)),
+        }
+    }
+}
+
+/// Commands received from `source`, as they arrive
+pub fn commands_forever<R: ReadByte>(
+    source: R,
+) -> impl Stream<Item = Result<Command, ShellError<R::Error>>> {
+    stream::unfold(source, |source| async move {
+        let mut buffer = [0; MAX_LINE];
+        let result = read_line(&source, &mut buffer).await.and_then(|len| {
+            let line = str::from_utf8(&buffer[..len]).map_err(|_| ShellError::UnknownCommand)?;
+            parse(line)
+        });
+        Some((result, source))
+    })
+}
@@ -0,0 +1,37 @@
+//! The ring of 8 LEDs, printed to the terminal
+
+use compass::heading::Direction;
+
+// What each LED looks like, from off to fully on
+const OFF: char = '.';
+const ON: char = 'O';
+
+/// The LEDs in the same order as `Direction`, starting with North and going clockwise
+pub struct Leds {
+    lit: [bool; 8],
+}
+
+impl Leds {
+    pub fn new() -> Self {
+        Leds { lit: [false; 8] }
+    }
+
+    /// Light only the LED that points in `direction`. Returns whether that changed anything.
+    pub fn show(&mut self, direction: Direction) -> bool {
+        let mut lit = [false; 8];
+        lit[direction as usize] = true;
+        let changed = lit != self.lit;
+        self.lit = lit;
+        changed
+    }
+
+    /// One character per LED, starting with North
+    pub fn render(&self) -> String {
+        self.lit
+            .iter()
+            .map(|&lit| if lit { ON } else { OFF })
+            .map(String::from)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
@@ -0,0 +1,144 @@
+//! A mock of the magnetometer half of the LSM303DLHC on an I2C bus
+//!
+//! It answers the same registers as the real chip, so the real driver in `compass::magnetometer`
+//! runs against it unchanged. The measurements come from a simulated board that turns on the spot,
+//! with a bit of noise and the occasional spike.
+
+use crate::timer::Timer;
+use core::f32::consts::PI;
+use embedded_hal_async::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+
+// Slave address
+const MAGNETOMETER: u8 = 0b001_1110;
+
+// Registers that don't just hold what was last written to them
+const OUT_X_H_M: u8 = 0x03;
+const SR_REG_M: u8 = 0x09;
+const IRA_REG_M: u8 = 0x0a;
+const TEMP_OUT_H_M: u8 = 0x31;
+
+// The status register's data-ready bit
+const DRDY: u8 = 1 << 0;
+
+// The earth's field, horizontal and vertical, in gauss. The vertical part points down, like in the
+// northern hemisphere.
+const HORIZONTAL_GAUSS: f32 = 0.2;
+const VERTICAL_GAUSS: f32 = -0.45;
+
+// The sensitivity at the reset gain of ±1.3 gauss, which is the gain that the firmware uses
+const XY_LSB_PER_GAUSS: f32 = 1100.0;
+const Z_LSB_PER_GAUSS: f32 = 980.0;
+
+// Noise on each axis, in LSB
+const NOISE_LSB: i32 = 8;
+
+// Every this many measurements, one axis is way off, for the median filter to catch
+const SPIKE_EVERY: u32 = 23;
+const SPIKE_LSB: i16 = 1500;
+
+// A constant temperature of 25 °C, 12 bits left-justified at 8 LSB per °C
+const TEMPERATURE: i16 = (25 * 8) << 4;
+
+/// Which way the simulated board points at `seconds` into the simulation, as a compass bearing
+pub type Scenario = fn(f32) -> f32;
+
+pub struct Lsm303 {
+    timer: Timer,
+    scenario: Scenario,
+    registers: [u8; 0x40],
+    // Where the next read starts, like the chip's register address pointer
+    pointer: u8,
+    measurements: u32,
+    // State of the noise generator
+    seed: u32,
+}
+
+impl Lsm303 {
+    pub fn new(timer: Timer, scenario: Scenario) -> Self {
+        let mut registers = [0; 0x40];
+        registers[usize::from(IRA_REG_M)..][..3].copy_from_slice(b"H43");
+        registers[usize::from(SR_REG_M)] = DRDY;
+        registers[usize::from(TEMP_OUT_H_M)..][..2].copy_from_slice(&TEMPERATURE.to_be_bytes());
+        Lsm303 {
+            timer,
+            scenario,
+            registers,
+            pointer: 0,
+            measurements: 0,
+            seed: 1,
+        }
+    }
+
+    /// A small random number in [-NOISE_LSB, NOISE_LSB], from a linear congruential generator
+    fn noise(&mut self) -> i16 {
+        self.seed = self.seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let noise = (self.seed >> 16) as i32 % (2 * NOISE_LSB + 1) - NOISE_LSB;
+        noise as i16
+    }
+
+    /// Put a new measurement into the output registers. The chip measures continuously, so we
+    /// pretend that there's always a new one when the driver reads the output.
+    fn measure(&mut self) {
+        let bearing = (self.scenario)(self.timer.elapsed_s());
+        // The angle that `mag_to_angle` turns into this bearing
+        let angle = (bearing - 180.0) * PI / 180.0;
+        let mut x = (HORIZONTAL_GAUSS * angle.cos() * XY_LSB_PER_GAUSS) as i16 + self.noise();
+        let y = (HORIZONTAL_GAUSS * angle.sin() * XY_LSB_PER_GAUSS) as i16 + self.noise();
+        let z = (VERTICAL_GAUSS * Z_LSB_PER_GAUSS) as i16 + self.noise();
+
+        self.measurements += 1;
+        if self.measurements.is_multiple_of(SPIKE_EVERY) {
+            x += SPIKE_LSB;
+        }
+
+        // The registers are ordered X, Z, Y, each high byte first
+        let output = &mut self.registers[usize::from(OUT_X_H_M)..][..6];
+        output[0..2].copy_from_slice(&x.to_be_bytes());
+        output[2..4].copy_from_slice(&z.to_be_bytes());
+        output[4..6].copy_from_slice(&y.to_be_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some((&register, data)) = bytes.split_first() {
+            self.pointer = register;
+            for &byte in data {
+                self.registers[usize::from(self.pointer) % self.registers.len()] = byte;
+                self.pointer = self.pointer.wrapping_add(1);
+            }
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        if self.pointer == OUT_X_H_M {
+            self.measure();
+        }
+        for byte in buffer {
+            *byte = self.registers[usize::from(self.pointer) % self.registers.len()];
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+    }
+}
+
+impl ErrorType for Lsm303 {
+    type Error = ErrorKind;
+}
+
+impl I2c for Lsm303 {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), ErrorKind> {
+        // Nothing else is on the bus
+        if address != MAGNETOMETER {
+            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+        }
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => self.write(bytes),
+                Operation::Read(buffer) => self.read(buffer),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! The compass on the host
+//!
+//! This runs the firmware's async pipeline on a desktop, with mocks in place of the peripherals:
+//! the magnetometer is simulated on a mock I2C bus, TIM2 is replaced by the host's clock, and the
+//! LED ring is printed to the terminal whenever it changes. That makes it possible to step through
+//! the pipeline in a normal debugger, without flashing the board.
+//!
+//! Instead of the simulated magnetometer, the pipeline can also be fed a recording made on the
+//! board with the shell's `capture on`. The recording replays as fast as possible, so the output
+//! can be compared against an earlier run to check that a change to the filters didn't change the
+//! headings.
+//!
+//! ```text
+//! cargo sim [seconds]
+//! cargo sim -- --replay recording.txt
+//! ```
+
+use compass::capture::{self, Sample};
+use compass::filters::low_pass::LowPass;
+use compass::filters::median::Median;
+use compass::heading::{angle_to_bearing, angle_to_direction, mag_to_angle};
+use compass::lsm303dlhc::{DataRate, Gain, Lsm303dlhc, Lsm303dlhcConfig, Mode};
+use compass::magnetometer::Magnetometer;
+use compass::smoothing::HeadingSmoother;
+use embedded_hal_async::i2c::ErrorKind;
+use futures::stream::{self, Stream, StreamExt};
+use leds::Leds;
+use lsm303::{Lsm303, Scenario};
+use timer::Timer;
+
+mod executor;
+mod leds;
+mod lsm303;
+mod timer;
+
+// The same settings as the firmware
+const MEDIAN_WINDOW: usize = 5;
+const SMOOTHING_WINDOW: usize = 5;
+const MAG_DATA_RATE: DataRate = DataRate::Hz15;
+const MAG_CUTOFF_HZ: f32 = 1.0;
+const SAMPLE_PERIOD_MS: u32 = 100;
+
+// How long to run for, unless given on the command line
+const DEFAULT_SECONDS: f32 = 30.0;
+
+/// The simulated board turns once clockwise over 20 s, holds still for 5 s, then swings back and
+/// forth around northwest
+fn scenario(seconds: f32) -> f32 {
+    if seconds < 20.0 {
+        seconds * 18.0
+    } else if seconds < 25.0 {
+        0.0
+    } else {
+        315.0 + 30.0 * (seconds - 25.0).sin()
+    }
+}
+
+enum Event {
+    Mag(Result<(i16, i16, i16), ErrorKind>),
+    Tick,
+}
+
+/// The part of the firmware's main loop that the simulator runs: the magnetometer filters, the
+/// heading and the LEDs
+struct Pipeline {
+    median: Median<MEDIAN_WINDOW>,
+    low_pass: LowPass,
+    smoother: HeadingSmoother<SMOOTHING_WINDOW>,
+    leds: Leds,
+    ticks: u32,
+}
+
+impl Pipeline {
+    fn new() -> Self {
+        Pipeline {
+            median: Median::new(),
+            low_pass: LowPass::new(MAG_CUTOFF_HZ, MAG_DATA_RATE.period_s()),
+            smoother: HeadingSmoother::new(),
+            leds: Leds::new(),
+            ticks: 0,
+        }
+    }
+
+    fn mag(&mut self, sample: (i16, i16, i16)) {
+        let (x, y, z) = self.low_pass.update(self.median.update(sample));
+        let mag = (f32::from(x), f32::from(y), f32::from(z));
+        self.smoother.add(mag_to_angle(mag, 0.0));
+    }
+
+    /// Update the LEDs, and print them if they changed. With a `scenario`, also print where the
+    /// simulated board actually points.
+    fn tick(&mut self, scenario: Option<Scenario>) {
+        self.ticks += 1;
+        let seconds = (self.ticks * SAMPLE_PERIOD_MS) as f32 / 1000.0;
+        if let Some(heading) = self.smoother.heading() {
+            let angle = (heading + 360.0) % 360.0;
+            if self.leds.show(angle_to_direction(angle)) {
+                let actual = match scenario {
+                    Some(scenario) => format!("actual {:5.1}°  ", scenario(seconds)),
+                    None => String::new(),
+                };
+                println!(
+                    "{:6.1} s  {}shown {:5.1}°  {}",
+                    seconds,
+                    actual,
+                    angle_to_bearing(heading),
+                    self.leds.render(),
+                );
+            }
+        }
+    }
+}
+
+/// Read the compass at its data rate
+fn get_compass_forever(
+    mag: impl Magnetometer<Error = ErrorKind>,
+    timer: Timer,
+) -> impl Stream<Item = Result<(i16, i16, i16), ErrorKind>> {
+    let period_ms = (MAG_DATA_RATE.period_s() * 1000.0) as u32;
+    stream::unfold(mag, move |mut mag| async move {
+        timer.delay(period_ms).await;
+        let result = mag.read().await.map(|sample| sample.raw);
+        Some((result, mag))
+    })
+}
+
+/// The magnetometer samples and ticks in `recording`, skipping the samples that the simulator
+/// doesn't use
+fn replay_forever(recording: &str) -> impl Stream<Item = Event> + '_ {
+    let events = capture::replay(recording).filter_map(|sample| match sample {
+        Ok(Sample::Mag(mag)) => Some(Event::Mag(Ok(mag))),
+        Ok(Sample::Tick) => Some(Event::Tick),
+        Ok(_) => None,
+        Err((line, error)) => {
+            println!("Skipping line {}: {:?}", line, error);
+            None
+        }
+    });
+    stream::iter(events)
+}
+
+async fn run(events: impl Stream<Item = Event>, scenario: Option<Scenario>) {
+    let mut pipeline = Pipeline::new();
+    events
+        .for_each(|event| {
+            match event {
+                Event::Mag(Ok(mag)) => pipeline.mag(mag),
+                Event::Mag(Err(error)) => println!("Couldn't read the magnetometer: {:?}", error),
+                Event::Tick => pipeline.tick(scenario),
+            }
+            async {}
+        })
+        .await;
+}
+
+async fn simulate(seconds: f32) -> Result<(), ErrorKind> {
+    let timer = Timer::new();
+    let mut mag = Lsm303dlhc::new(Lsm303::new(timer, scenario));
+    let mag_config = Lsm303dlhcConfig::new()
+        .data_rate(MAG_DATA_RATE)
+        .gain(Gain::Gauss1_3)
+        .mode(Mode::Continuous);
+    mag.configure(mag_config).await?;
+    if let Err(error) = mag.self_test().await {
+        println!("Magnetometer self-test failed: {:?}", error);
+    }
+
+    let ticks = stream::repeat(()).then(move |()| timer.delay(SAMPLE_PERIOD_MS));
+    let events = stream::select(
+        get_compass_forever(mag, timer).map(Event::Mag),
+        ticks.map(|()| Event::Tick),
+    )
+    .take_while(move |_| futures::future::ready(timer.elapsed_s() < seconds));
+    run(events, Some(scenario)).await;
+    Ok(())
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    match (args.next(), args.next()) {
+        (Some(flag), Some(path)) if flag == "--replay" => {
+            let recording = std::fs::read_to_string(&path).expect("Couldn't read the recording");
+            executor::block_on(run(replay_forever(&recording), None));
+        }
+        (seconds, None) => {
+            let seconds = match seconds {
+                Some(seconds) => seconds.parse().expect("Usage: sim [seconds]"),
+                None => DEFAULT_SECONDS,
+            };
+            if let Err(error) = executor::block_on(simulate(seconds)) {
+                println!("Couldn't configure the magnetometer: {:?}", error);
+            }
+        }
+        _ => panic!("Usage: sim [seconds] | sim --replay <recording>"),
+    }
+}
@@ -0,0 +1,64 @@
+//! A timer that runs on the host's clock, in place of TIM2
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Counts from when the simulation started
+#[derive(Clone, Copy)]
+pub struct Timer {
+    start: Instant,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            start: Instant::now(),
+        }
+    }
+
+    /// Seconds since the timer was created
+    pub fn elapsed_s(&self) -> f32 {
+        self.start.elapsed().as_secs_f32()
+    }
+
+    /// Wait for `ms` milliseconds
+    pub fn delay(&self, ms: u32) -> Delay {
+        Delay {
+            deadline: Instant::now() + Duration::from_millis(u64::from(ms)),
+            sleeping: false,
+        }
+    }
+}
+
+/// Returned by `Timer::delay`
+pub struct Delay {
+    deadline: Instant,
+    // Whether a thread is already sleeping until the deadline to wake us up
+    sleeping: bool,
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        // This stands in for the timer interrupt
+        if !self.sleeping {
+            let waker = cx.waker().clone();
+            let remaining = self.deadline - now;
+            thread::spawn(move || {
+                thread::sleep(remaining);
+                waker.wake();
+            });
+            self.sleeping = true;
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,93 @@
+//! Smoothing for the displayed heading
+//!
+//! Averaging angles directly goes wrong at the wrap: the average of 179° and -179° should be 180°,
+//! not 0°. Instead, we turn each heading into a unit vector, average the vectors and take the angle
+//! of the result.
+
+use crate::trig;
+
+/// Circular moving average over the last `N` headings
+pub struct HeadingSmoother<const N: usize> {
+    // Unit vectors (cos, sin) of the most recent headings
+    vectors: [(f32, f32); N],
+    // Where the next heading goes
+    next: usize,
+    // How many of `vectors` have been filled so far
+    len: usize,
+}
+
+impl<const N: usize> HeadingSmoother<N> {
+    pub fn new() -> Self {
+        HeadingSmoother {
+            vectors: [(0.0, 0.0); N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Add a heading in degrees, replacing the oldest one once the window is full
+    pub fn add(&mut self, heading: f32) {
+        let (sin, cos) = trig::sin_cos(heading.to_radians());
+        self.vectors[self.next] = (cos, sin);
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    /// The average heading in degrees, in the range (-180, 180]. This is `None` before the first
+    /// heading, and if the headings in the window cancel each other out.
+    pub fn heading(&self) -> Option<f32> {
+        let (x, y) = self.vectors[..self.len]
+            .iter()
+            .fold((0.0, 0.0), |(x, y), (cos, sin)| (x + cos, y + sin));
+
+        if trig::sqrt(x * x + y * y) < f32::EPSILON {
+            None
+        } else {
+            Some(trig::atan2(y, x).to_degrees())
+        }
+    }
+}
+
+impl<const N: usize> Default for HeadingSmoother<N> {
+    fn default() -> Self {
+        HeadingSmoother::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_across_the_wrap() {
+        let mut smoother = HeadingSmoother::<2>::new();
+        assert_eq!(smoother.heading(), None);
+        smoother.add(179.0);
+        smoother.add(-179.0);
+        let heading = smoother.heading().unwrap();
+        assert!((heading - 180.0).abs() < 0.1, "{}", heading);
+    }
+
+    #[test]
+    fn opposite_headings_have_no_average() {
+        let mut smoother = HeadingSmoother::<2>::new();
+        smoother.add(0.0);
+        smoother.add(180.0);
+        assert_eq!(smoother.heading(), None);
+
+        smoother.add(90.0);
+        smoother.add(-90.0);
+        assert_eq!(smoother.heading(), None);
+    }
+
+    #[test]
+    fn forgets_the_oldest_heading() {
+        let mut smoother = HeadingSmoother::<3>::new();
+        smoother.add(-120.0);
+        for _ in 0..3 {
+            smoother.add(30.0);
+        }
+        let heading = smoother.heading().unwrap();
+        assert!((heading - 30.0).abs() < 0.1, "{}", heading);
+    }
+}
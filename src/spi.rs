@@ -0,0 +1,343 @@
+//! Interrupt-driven async drivers for SPI1 and SPI2
+//!
+//! `Spi` is a bus without chip select, which implements the `embedded-hal-async` `SpiBus` trait.
+//!
+//! On the F3 Discovery, SPI1 is wired to the L3GD20 gyroscope, with PE3 as its chip select. `Spi1`
+//! implements the `SpiDevice` trait for that combination, so the gyro driver doesn't have to handle
+//! chip select itself. SPI2 is free, with SCK, MISO and MOSI on PB13, PB14 and PB15.
+
+use crate::clocks;
+use crate::power::Awake;
+use crate::wakers;
+use core::ptr;
+use cortex_m::asm;
+use embedded_hal_async::spi::{self, ErrorKind, Operation};
+use f3::hal::stm32f30x::{gpioa, gpiob, gpioc, rcc, spi1, GPIOA, GPIOB, GPIOE, RCC, SPI1, SPI2};
+use futures::task::AtomicWaker;
+
+// SPI1's SCK, MISO and MOSI are PA5, PA6 and PA7
+const SPI1_SCK: u32 = 5;
+const SPI1_MISO: u32 = 6;
+const SPI1_MOSI: u32 = 7;
+
+// SPI2's SCK, MISO and MOSI are PB13, PB14 and PB15
+const SPI2_SCK: u32 = 13;
+const SPI2_MISO: u32 = 14;
+const SPI2_MOSI: u32 = 15;
+
+// The gyro's chip select is PE3
+const CS: u32 = 3;
+
+// Nanoseconds per core clock cycle, rounded down so that delays run long rather than short
+// 1 / 72 MHz = 13.9 ns
+const NS_PER_CYCLE: u32 = 1_000_000_000 / clocks::SYSCLK_HZ;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SpiError {
+    /// A received byte was overwritten before we read it
+    Overrun,
+    /// Another device pulled NSS low while we were master
+    ModeFault,
+}
+
+impl SpiError {
+    fn from_sr(sr: &spi1::sr::R) -> Option<Self> {
+        if sr.ovr().bit_is_set() {
+            Some(SpiError::Overrun)
+        } else if sr.modf().bit_is_set() {
+            Some(SpiError::ModeFault)
+        } else {
+            None
+        }
+    }
+}
+
+impl spi::Error for SpiError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            SpiError::Overrun => ErrorKind::Overrun,
+            SpiError::ModeFault => ErrorKind::ModeFault,
+        }
+    }
+}
+
+/// The SPI clock as a fraction of the peripheral clock: PCLK2 for SPI1, PCLK1 for SPI2
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Divider {
+    Div2 = 0b000,
+    Div4 = 0b001,
+    Div8 = 0b010,
+    Div16 = 0b011,
+    Div32 = 0b100,
+    Div64 = 0b101,
+    Div128 = 0b110,
+    Div256 = 0b111,
+}
+
+/// Clock polarity and phase
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// CPOL = 0, CPHA = 0
+    Mode0,
+    /// CPOL = 1, CPHA = 1
+    Mode3,
+}
+
+/// An SPI peripheral in master mode, with software chip select
+pub struct Spi {
+    regs: &'static spi1::RegisterBlock,
+    waker: &'static AtomicWaker,
+}
+
+impl Spi {
+    fn new(
+        regs: &'static spi1::RegisterBlock,
+        waker: &'static AtomicWaker,
+        mode: Mode,
+        divider: Divider,
+    ) -> Self {
+        // FRXTH: RXNE event is generated if the FIFO level is greater than or equal to 8-bit
+        // DS: 8-bit data size
+        regs.cr2
+            .write(|w| unsafe { w.frxth().set_bit().ds().bits(0b111) });
+
+        // SSM, SSI: software slave management, so that the NSS pin doesn't matter
+        regs.cr1.write(|w| unsafe {
+            w.cpha().bit(mode == Mode::Mode3);
+            w.cpol().bit(mode == Mode::Mode3);
+            w.mstr().set_bit();
+            w.br().bits(divider as u8);
+            w.ssm().set_bit();
+            w.ssi().set_bit();
+            w.spe().set_bit()
+        });
+
+        Spi { regs, waker }
+    }
+
+    /// Power on SPI2 and set it up on PB13, PB14 and PB15
+    pub fn spi2(mode: Mode, divider: Divider) -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let gpiob: &'static gpiob::RegisterBlock = unsafe { &*GPIOB::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopben().set_bit());
+        rcc.apb1enr.modify(|_, w| w.spi2en().set_bit());
+
+        // SCK, MISO and MOSI in alternate function 5
+        gpiob.moder.modify(|r, w| unsafe {
+            let mask =
+                (0b11 << (2 * SPI2_SCK)) | (0b11 << (2 * SPI2_MISO)) | (0b11 << (2 * SPI2_MOSI));
+            let af =
+                (0b10 << (2 * SPI2_SCK)) | (0b10 << (2 * SPI2_MISO)) | (0b10 << (2 * SPI2_MOSI));
+            w.bits((r.bits() & !mask) | af)
+        });
+        gpiob.afrh.modify(|r, w| unsafe {
+            let mask = (0b1111 << (4 * (SPI2_SCK - 8)))
+                | (0b1111 << (4 * (SPI2_MISO - 8)))
+                | (0b1111 << (4 * (SPI2_MOSI - 8)));
+            let af5 = (5 << (4 * (SPI2_SCK - 8)))
+                | (5 << (4 * (SPI2_MISO - 8)))
+                | (5 << (4 * (SPI2_MOSI - 8)));
+            w.bits((r.bits() & !mask) | af5)
+        });
+
+        Spi::new(unsafe { &*SPI2::ptr() }, &wakers::SPI2_EV, mode, divider)
+    }
+
+    /// Change the clock speed. This must not be called in the middle of a transfer.
+    pub fn set_divider(&mut self, divider: Divider) {
+        // BR can't be changed while the peripheral is enabled
+        self.regs.cr1.modify(|_, w| w.spe().clear_bit());
+        self.regs
+            .cr1
+            .modify(|_, w| unsafe { w.br().bits(divider as u8).spe().set_bit() });
+    }
+
+    /// DR must be accessed as a byte, otherwise the FIFO treats each access as two frames
+    fn dr(&self) -> *mut u8 {
+        ptr::addr_of!(self.regs.dr) as *mut u8
+    }
+
+    /// Wait until `flag` is set in the SR, or until one of the error flags is set. `listen` must
+    /// enable the interrupt that corresponds to `flag`.
+    async fn wait_for_flag(
+        &self,
+        flag: impl Fn(&spi1::sr::R) -> bool,
+        listen: impl Fn(&mut spi1::cr2::W) -> &mut spi1::cr2::W,
+    ) -> Result<(), SpiError> {
+        let regs = self.regs;
+        wakers::wait_for(
+            self.waker,
+            || {
+                let sr = regs.sr.read();
+                flag(&sr) || SpiError::from_sr(&sr).is_some()
+            },
+            || regs.cr2.modify(|_, w| listen(w).errie().set_bit()),
+        )
+        .await;
+
+        match SpiError::from_sr(&regs.sr.read()) {
+            None => Ok(()),
+            Some(error) => {
+                // OVR is cleared by reading DR and then SR, MODF by reading SR and then writing CR1
+                unsafe { ptr::read_volatile(self.dr()) };
+                regs.sr.read();
+                regs.cr1.modify(|_, w| w.spe().set_bit());
+                Err(error)
+            }
+        }
+    }
+
+    /// Send one byte and return the byte that was received at the same time
+    async fn exchange(&self, byte: u8) -> Result<u8, SpiError> {
+        // The peripheral needs its clock until the byte has gone both ways
+        let _awake = Awake::new();
+        self.wait_for_flag(|sr| sr.txe().bit_is_set(), |w| w.txeie().set_bit())
+            .await?;
+        unsafe { ptr::write_volatile(self.dr(), byte) }
+
+        self.wait_for_flag(|sr| sr.rxne().bit_is_set(), |w| w.rxneie().set_bit())
+            .await?;
+        Ok(unsafe { ptr::read_volatile(self.dr()) })
+    }
+}
+
+impl spi::ErrorType for Spi {
+    type Error = SpiError;
+}
+
+impl spi::SpiBus<u8> for Spi {
+    /// Sends 0xff while reading, which is what SD cards expect
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+        for word in words.iter_mut() {
+            *word = self.exchange(0xff).await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        for word in words.iter() {
+            self.exchange(*word).await?;
+        }
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), SpiError> {
+        let length = read.len().max(write.len());
+        for index in 0..length {
+            let received = self
+                .exchange(write.get(index).copied().unwrap_or(0))
+                .await?;
+            if let Some(word) = read.get_mut(index) {
+                *word = received;
+            }
+        }
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+        for word in words.iter_mut() {
+            *word = self.exchange(*word).await?;
+        }
+        Ok(())
+    }
+
+    /// Every exchange waits for the received byte, so there's never anything left to flush
+    async fn flush(&mut self) -> Result<(), SpiError> {
+        Ok(())
+    }
+}
+
+/// SPI1 with the gyro's chip select
+pub struct Spi1 {
+    spi: Spi,
+    gpioe: &'static gpioc::RegisterBlock,
+}
+
+impl Spi1 {
+    /// Power on SPI1 and configure it in mode 3 (CPOL = 1, CPHA = 1) at about 1 MHz, which is what
+    /// the L3GD20 expects
+    pub fn new() -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let gpioa: &'static gpioa::RegisterBlock = unsafe { &*GPIOA::ptr() };
+        let gpioe: &'static gpioc::RegisterBlock = unsafe { &*GPIOE::ptr() };
+
+        rcc.ahbenr
+            .modify(|_, w| w.iopaen().set_bit().iopeen().set_bit());
+        rcc.apb2enr.modify(|_, w| w.spi1en().set_bit());
+
+        // SCK, MISO and MOSI in alternate function 5
+        gpioa.moder.modify(|r, w| unsafe {
+            let mask =
+                (0b11 << (2 * SPI1_SCK)) | (0b11 << (2 * SPI1_MISO)) | (0b11 << (2 * SPI1_MOSI));
+            let af =
+                (0b10 << (2 * SPI1_SCK)) | (0b10 << (2 * SPI1_MISO)) | (0b10 << (2 * SPI1_MOSI));
+            w.bits((r.bits() & !mask) | af)
+        });
+        gpioa.afrl.modify(|r, w| unsafe {
+            let mask = (0b1111 << (4 * SPI1_SCK))
+                | (0b1111 << (4 * SPI1_MISO))
+                | (0b1111 << (4 * SPI1_MOSI));
+            let af5 = (5 << (4 * SPI1_SCK)) | (5 << (4 * SPI1_MISO)) | (5 << (4 * SPI1_MOSI));
+            w.bits((r.bits() & !mask) | af5)
+        });
+
+        // Chip select as a push-pull output, deselected
+        gpioe.bsrr.write(|w| unsafe { w.bits(1 << CS) });
+        gpioe.moder.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << (2 * CS))) | (0b01 << (2 * CS)))
+        });
+
+        // PCLK2 = 72 MHz
+        // 72 MHz / 64 = 1.125 MHz
+        let spi = Spi::new(
+            unsafe { &*SPI1::ptr() },
+            &wakers::SPI1_EV,
+            Mode::Mode3,
+            Divider::Div64,
+        );
+        Spi1 { spi, gpioe }
+    }
+
+    fn select(&self, selected: bool) {
+        if selected {
+            self.gpioe.bsrr.write(|w| unsafe { w.bits(1 << (CS + 16)) });
+        } else {
+            self.gpioe.bsrr.write(|w| unsafe { w.bits(1 << CS) });
+        }
+    }
+
+    async fn run(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), SpiError> {
+        use spi::SpiBus;
+
+        for operation in operations {
+            match operation {
+                Operation::Read(words) => self.spi.read(words).await?,
+                Operation::Write(words) => self.spi.write(words).await?,
+                Operation::Transfer(read, write) => self.spi.transfer(read, write).await?,
+                Operation::TransferInPlace(words) => self.spi.transfer_in_place(words).await?,
+                Operation::DelayNs(ns) => asm::delay(*ns / NS_PER_CYCLE + 1),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl spi::ErrorType for Spi1 {
+    type Error = SpiError;
+}
+
+impl spi::SpiDevice<u8> for Spi1 {
+    /// In order to leave chip select deasserted, the returned future must run to completion.
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        self.select(true);
+        let result = self.run(operations).await;
+        self.select(false);
+        result
+    }
+}
@@ -0,0 +1,121 @@
+//! Persistent storage in the last page of flash
+//!
+//! See `compass::stored` for what's in it and how it's encoded.
+//!
+//! The program can't grow into the last page, or into the pages of the heading log below it, see
+//! `heading_log`: our memory.x ends FLASH at `DATA_ADDRESS`, so the linker fails instead.
+//!
+//! Erasing and programming is synchronous: the CPU stalls on instruction fetches while the flash
+//! is busy anyway, so there is nothing to gain from making it async.
+
+use compass::stored::{Stored, RECORD_SIZE};
+use core::ptr;
+use f3::hal::stm32f30x::{flash, FLASH};
+
+// The STM32F303VC has 256 KB of flash in 2 KB pages. Everything from `DATA_ADDRESS` on is for
+// data, which has to match where memory.x ends FLASH.
+pub const DATA_ADDRESS: usize = 0x0803_D000;
+pub const PAGE_ADDRESS: usize = 0x0803_F800;
+pub const PAGE_SIZE: usize = 2048;
+
+// Unlock sequence for FLASH_CR
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xcdef_89ab;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StorageError {
+    /// The page is write protected
+    WriteProtected,
+    /// We tried to program a location that wasn't erased
+    Programming,
+}
+
+fn flash() -> &'static flash::RegisterBlock {
+    unsafe { &*FLASH::ptr() }
+}
+
+fn wait_until_done() -> Result<(), StorageError> {
+    let flash = flash();
+    while flash.sr.read().bsy().bit_is_set() {}
+
+    let sr = flash.sr.read();
+    // The flags are cleared by writing 1 to them
+    flash.sr.write(|w| {
+        w.eop().set_bit();
+        w.wrprt().set_bit();
+        w.pgerr().set_bit()
+    });
+
+    if sr.wrprt().bit_is_set() {
+        Err(StorageError::WriteProtected)
+    } else if sr.pgerr().bit_is_set() {
+        Err(StorageError::Programming)
+    } else {
+        Ok(())
+    }
+}
+
+/// Erase the page at `address`. The flash has to be unlocked.
+pub fn erase_page(address: usize) -> Result<(), StorageError> {
+    let flash = flash();
+
+    flash.cr.modify(|_, w| w.per().set_bit());
+    flash.ar.write(|w| unsafe { w.bits(address as u32) });
+    flash.cr.modify(|_, w| w.strt().set_bit());
+    let erased = wait_until_done();
+    flash.cr.modify(|_, w| w.per().clear_bit());
+    erased
+}
+
+/// Program `bytes` into erased flash at `address`, which is half-word aligned. The flash has to be
+/// unlocked.
+pub fn program(address: usize, bytes: &[u8]) -> Result<(), StorageError> {
+    let flash = flash();
+
+    // Flash is programmed one half-word at a time
+    flash.cr.modify(|_, w| w.pg().set_bit());
+    for (index, chunk) in bytes.chunks(2).enumerate() {
+        let half_word = u16::from_le_bytes([chunk[0], *chunk.get(1).unwrap_or(&0xff)]);
+        unsafe { ptr::write_volatile((address + 2 * index) as *mut u16, half_word) };
+        if let Err(error) = wait_until_done() {
+            flash.cr.modify(|_, w| w.pg().clear_bit());
+            return Err(error);
+        }
+    }
+    flash.cr.modify(|_, w| w.pg().clear_bit());
+
+    Ok(())
+}
+
+/// Unlock the flash for erasing and programming, run `f`, and lock it again
+pub fn unlocked<T>(f: impl FnOnce() -> T) -> T {
+    let flash = flash();
+    flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+    flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+
+    let result = f();
+
+    flash.cr.modify(|_, w| w.lock().set_bit());
+    result
+}
+
+/// Read the stored data, or `None` if there isn't any valid data
+pub fn load() -> Option<Stored> {
+    let mut record = [0u8; RECORD_SIZE];
+    for (index, byte) in record.iter_mut().enumerate() {
+        *byte = unsafe { ptr::read_volatile((PAGE_ADDRESS + index) as *const u8) };
+    }
+    Stored::decode(&record)
+}
+
+/// Erase the storage page and write `stored` to it
+pub fn save(stored: &Stored) -> Result<(), StorageError> {
+    let record = stored.encode();
+    assert!(record.len() <= PAGE_SIZE);
+
+    unlocked(|| {
+        erase_page(PAGE_ADDRESS)?;
+        program(PAGE_ADDRESS, &record)
+    })
+}
@@ -0,0 +1,219 @@
+//! What the settings page of flash holds, and how it's encoded
+//!
+//! The record starts with a header (magic number, format version and payload length) and ends
+//! with a CRC-32 of everything after the magic number. If anything doesn't match, e.g. because the
+//! page is erased, was written by an incompatible version or the write was interrupted by a reset,
+//! `decode` returns `None` and the firmware falls back to defaults.
+//!
+//! Version 3 records, from before the user's settings moved into `Config`, are still read. They
+//! keep their calibration and declination, and the rest of the settings start at the defaults.
+
+use crate::calibration::{MagCalibration, TemperatureDrift};
+use crate::config::{Config, CONFIG_SIZE};
+use crate::crc::crc32;
+
+const MAGIC: u32 = 0x434d_5053; // "CMPS"
+const VERSION: u16 = 4;
+
+const HEADER_SIZE: usize = 8;
+// The calibration and its temperature drift, then the `Config` blob
+const PAYLOAD_SIZE: usize = 16 * 4 + CONFIG_SIZE;
+pub const RECORD_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE + 4;
+
+// Version 3 had the declination where the `Config` blob is now
+const V3: u16 = 3;
+const V3_PAYLOAD_SIZE: usize = 17 * 4;
+
+/// Everything that survives a power cycle
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stored {
+    pub calibration: MagCalibration,
+    /// Temperature drift of `calibration`
+    pub drift: TemperatureDrift,
+    pub config: Config,
+}
+
+impl Stored {
+    /// The record, header and CRC included
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        record[6..8].copy_from_slice(&(PAYLOAD_SIZE as u16).to_le_bytes());
+
+        let MagCalibration { offset, matrix } = self.calibration;
+        let offset = [offset.0, offset.1, offset.2];
+        let TemperatureDrift {
+            reference_c,
+            offset_per_c,
+        } = self.drift;
+        // An unknown reference temperature is stored as NaN
+        let drift = [
+            reference_c.unwrap_or(f32::NAN),
+            offset_per_c.0,
+            offset_per_c.1,
+            offset_per_c.2,
+        ];
+        let values = offset
+            .iter()
+            .chain(matrix.iter().flatten())
+            .chain(drift.iter());
+        for (chunk, value) in record[HEADER_SIZE..HEADER_SIZE + 16 * 4]
+            .chunks_exact_mut(4)
+            .zip(values)
+        {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        record[HEADER_SIZE + 16 * 4..HEADER_SIZE + PAYLOAD_SIZE]
+            .copy_from_slice(&self.config.encode());
+
+        let crc = crc32(&record[4..HEADER_SIZE + PAYLOAD_SIZE]);
+        record[HEADER_SIZE + PAYLOAD_SIZE..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    /// The `Stored` in `record`, or `None` if it isn't a valid record of this version or version 3
+    pub fn decode(record: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([record[i], record[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_le_bytes([record[i], record[i + 1], record[i + 2], record[i + 3]]);
+
+        let version = u16_at(4);
+        let payload_size = match version {
+            VERSION => PAYLOAD_SIZE,
+            V3 => V3_PAYLOAD_SIZE,
+            _ => return None,
+        };
+        if u32_at(0) != MAGIC || usize::from(u16_at(6)) != payload_size {
+            return None;
+        }
+        if u32_at(HEADER_SIZE + payload_size) != crc32(&record[4..HEADER_SIZE + payload_size]) {
+            return None;
+        }
+
+        let f32_at = |index: usize| f32::from_bits(u32_at(HEADER_SIZE + 4 * index));
+        let mut matrix = [[0.0; 3]; 3];
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = f32_at(3 + 3 * i + j);
+            }
+        }
+        let calibration = MagCalibration {
+            offset: (f32_at(0), f32_at(1), f32_at(2)),
+            matrix,
+        };
+        // The drift comes after the declination in version 3
+        let drift_at = if version == V3 { 13 } else { 12 };
+        let drift = TemperatureDrift {
+            reference_c: Some(f32_at(drift_at)).filter(|reference_c| !reference_c.is_nan()),
+            offset_per_c: (
+                f32_at(drift_at + 1),
+                f32_at(drift_at + 2),
+                f32_at(drift_at + 3),
+            ),
+        };
+
+        let config = if version == V3 {
+            Config {
+                declination_deg: f32_at(12),
+                ..Config::default()
+            }
+        } else {
+            let mut blob = [0u8; CONFIG_SIZE];
+            blob.copy_from_slice(&record[HEADER_SIZE + 16 * 4..HEADER_SIZE + PAYLOAD_SIZE]);
+            Config::decode(&blob)?
+        };
+
+        Some(Stored {
+            calibration,
+            drift,
+            config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored() -> Stored {
+        Stored {
+            calibration: MagCalibration {
+                offset: (12.0, -34.0, 56.0),
+                matrix: [[1.1, 0.0, 0.1], [0.0, 0.9, 0.0], [0.1, 0.0, 1.0]],
+            },
+            drift: TemperatureDrift {
+                reference_c: Some(21.5),
+                offset_per_c: (0.5, -0.25, 0.0),
+            },
+            config: Config {
+                declination_deg: 3.5,
+                ..Config::default()
+            },
+        }
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        assert_eq!(Stored::decode(&stored().encode()), Some(stored()));
+
+        // Without a reference temperature
+        let unreferenced = Stored {
+            drift: TemperatureDrift {
+                reference_c: None,
+                ..stored().drift
+            },
+            ..stored()
+        };
+        assert_eq!(Stored::decode(&unreferenced.encode()), Some(unreferenced));
+    }
+
+    #[test]
+    fn rejects_damaged_records() {
+        assert_eq!(Stored::decode(&[0xff; RECORD_SIZE]), None);
+
+        let mut record = stored().encode();
+        record[HEADER_SIZE] ^= 1;
+        assert_eq!(Stored::decode(&record), None);
+
+        let mut record = stored().encode();
+        record[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(Stored::decode(&record), None);
+    }
+
+    #[test]
+    fn reads_version_3() {
+        let stored = stored();
+        let MagCalibration { offset, matrix } = stored.calibration;
+        let TemperatureDrift {
+            reference_c,
+            offset_per_c,
+        } = stored.drift;
+        // The declination came before the drift
+        let drift = [
+            stored.config.declination_deg,
+            reference_c.unwrap(),
+            offset_per_c.0,
+            offset_per_c.1,
+            offset_per_c.2,
+        ];
+        let offset = [offset.0, offset.1, offset.2];
+        let values = offset
+            .iter()
+            .chain(matrix.iter().flatten())
+            .chain(drift.iter());
+
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4..6].copy_from_slice(&V3.to_le_bytes());
+        record[6..8].copy_from_slice(&(V3_PAYLOAD_SIZE as u16).to_le_bytes());
+        for (chunk, value) in record[HEADER_SIZE..].chunks_exact_mut(4).zip(values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        let crc = crc32(&record[4..HEADER_SIZE + V3_PAYLOAD_SIZE]);
+        record[HEADER_SIZE + V3_PAYLOAD_SIZE..][..4].copy_from_slice(&crc.to_le_bytes());
+
+        assert_eq!(Stored::decode(&record), Some(stored));
+    }
+}
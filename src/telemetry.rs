@@ -0,0 +1,39 @@
+//! Binary telemetry frames
+//!
+//! The messages and their framing are defined in the `compass-schema` crate, so that host tools can
+//! decode them with the same code. A telemetry frame is at most 38 bytes. At 115200 baud (11520
+//! bytes per second), 100 frames per second use about a third of the bandwidth, where the same
+//! data as text would hardly fit.
+
+use compass::stored::Stored;
+use compass_schema::{Config, Message, MAX_FRAME_SIZE};
+
+/// An encoded frame, ready to send
+pub struct Frame {
+    buffer: [u8; MAX_FRAME_SIZE],
+    len: usize,
+}
+
+impl Frame {
+    pub fn new(message: &Message) -> Self {
+        let mut buffer = [0; MAX_FRAME_SIZE];
+        let len = compass_schema::encode(message, &mut buffer)
+            .expect("MAX_FRAME_SIZE is big enough for any message");
+        Frame { buffer, len }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+/// The settings as host tools see them
+pub fn config(stored: &Stored) -> Config {
+    Config {
+        offset: stored.calibration.offset,
+        matrix: stored.calibration.matrix,
+        declination_deg: stored.config.declination_deg,
+        drift_reference_c: stored.drift.reference_c,
+        drift_offset_per_c: stored.drift.offset_per_c,
+    }
+}
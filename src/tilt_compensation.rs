@@ -0,0 +1,116 @@
+//! Tilt compensation for the magnetometer
+//!
+//! The heading is the direction of the horizontal part of the magnetic field. When the board isn't
+//! level, the magnetometer's X and Y axes aren't horizontal, so we use gravity (as measured by the
+//! accelerometer) to find out how the board is tilted and rotate the magnetic vector back into the
+//! horizontal plane. The math follows ST's application note AN3192.
+
+use crate::trig;
+
+/// The orientation of the board relative to level. We store sines and cosines rather than the
+/// angles themselves because that's what the rotation needs, and because they fall straight out of
+/// the gravity vector without any trigonometry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tilt {
+    sin_pitch: f32,
+    cos_pitch: f32,
+    sin_roll: f32,
+    cos_roll: f32,
+}
+
+impl Tilt {
+    /// Estimate the tilt from an accelerometer reading. This is only valid while the board isn't
+    /// accelerating, and returns `None` if there's no usable gravity vector (e.g. in free fall).
+    pub fn from_accel(accel: (i16, i16, i16)) -> Option<Tilt> {
+        let (x, y, z) = accel;
+        let x = f32::from(x);
+        let y = f32::from(y);
+        let z = f32::from(z);
+
+        // roll = atan2(y, z)
+        let yz_norm = trig::sqrt(y * y + z * z);
+        if yz_norm < f32::EPSILON {
+            return None;
+        }
+        let sin_roll = y / yz_norm;
+        let cos_roll = z / yz_norm;
+
+        // pitch = atan2(-x, y * sin(roll) + z * cos(roll)), and that second term is just yz_norm
+        let norm = trig::sqrt(x * x + yz_norm * yz_norm);
+        let sin_pitch = -x / norm;
+        let cos_pitch = yz_norm / norm;
+
+        Some(Tilt {
+            sin_pitch,
+            cos_pitch,
+            sin_roll,
+            cos_roll,
+        })
+    }
+
+    /// Rotation about the Y axis in degrees, in the range [-90, 90], positive when the X axis points
+    /// down
+    pub fn pitch_deg(&self) -> f32 {
+        trig::atan2(self.sin_pitch, self.cos_pitch).to_degrees()
+    }
+
+    /// Rotation about the X axis in degrees, in the range (-180, 180], positive when the Y axis
+    /// points up
+    pub fn roll_deg(&self) -> f32 {
+        trig::atan2(self.sin_roll, self.cos_roll).to_degrees()
+    }
+
+    /// Rotate a magnetometer reading into the horizontal plane. The returned Z component is the
+    /// vertical part of the field.
+    pub fn level(&self, mag: (i16, i16, i16)) -> (f32, f32, f32) {
+        let (x, y, z) = mag;
+        let x = f32::from(x);
+        let y = f32::from(y);
+        let z = f32::from(z);
+
+        let Tilt {
+            sin_pitch,
+            cos_pitch,
+            sin_roll,
+            cos_roll,
+        } = *self;
+
+        let x_h = x * cos_pitch + y * sin_pitch * sin_roll + z * sin_pitch * cos_roll;
+        let y_h = y * cos_roll - z * sin_roll;
+        let z_h = -x * sin_pitch + y * cos_pitch * sin_roll + z * cos_pitch * cos_roll;
+
+        (x_h, y_h, z_h)
+    }
+}
+
+/// Rotate `mag` into the horizontal plane using the tilt measured by `accel`. If the accelerometer
+/// reading is unusable, the magnetometer reading is returned unchanged.
+pub fn compensate(mag: (i16, i16, i16), accel: (i16, i16, i16)) -> (f32, f32, f32) {
+    match Tilt::from_accel(accel) {
+        Some(tilt) => tilt.level(mag),
+        None => (f32::from(mag.0), f32::from(mag.1), f32::from(mag.2)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pitch_and_roll_come_from_gravity() {
+        let level = Tilt::from_accel((0, 0, 1000)).unwrap();
+        assert_eq!((level.pitch_deg(), level.roll_deg()), (0.0, 0.0));
+
+        // The accelerometer measures the opposite of gravity, so this is the X axis pointing 30°
+        // down
+        let tilt = Tilt::from_accel((-500, 0, 866)).unwrap();
+        assert!((tilt.pitch_deg() - 30.0).abs() < 0.01);
+        assert_eq!(tilt.roll_deg(), 0.0);
+
+        let tilt = Tilt::from_accel((0, 707, 707)).unwrap();
+        assert_eq!(tilt.pitch_deg(), 0.0);
+        assert!((tilt.roll_deg() - 45.0).abs() < 0.01);
+
+        assert_eq!(Tilt::from_accel((1000, 0, 0)), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Deadlines that share one hardware timer
+//!
+//! Every sleeping future takes a slot with its deadline and its waker. The hardware timer only has
+//! to interrupt at the earliest deadline in the queue. When it does, `expire` wakes every future
+//! whose deadline has passed, and the timer is set up for the next one.
+//!
+//! A slot stays taken until its future is done or dropped, even after it has been woken, so that
+//! the slot can't be handed to another future while the first one still refers to it.
+
+use core::task::Waker;
+
+struct Entry {
+    deadline: u64,
+    // `None` once the deadline has passed and the waker was woken
+    waker: Option<Waker>,
+}
+
+/// Up to `N` deadlines, in whatever unit the hardware timer counts in
+pub struct TimerQueue<const N: usize> {
+    entries: [Option<Entry>; N],
+}
+
+impl<const N: usize> TimerQueue<N> {
+    const FREE: Option<Entry> = None;
+
+    pub const fn new() -> Self {
+        TimerQueue {
+            entries: [Self::FREE; N],
+        }
+    }
+
+    /// Take a slot that wakes `waker` at `deadline`, or return `None` if every slot is taken
+    pub fn insert(&mut self, deadline: u64, waker: &Waker) -> Option<usize> {
+        let slot = self.entries.iter().position(Option::is_none)?;
+        self.entries[slot] = Some(Entry {
+            deadline,
+            waker: Some(waker.clone()),
+        });
+        Some(slot)
+    }
+
+    /// Wake `waker` instead of the one that `slot` had before, for a future that was polled again
+    pub fn register(&mut self, slot: usize, waker: &Waker) {
+        if let Some(entry) = &mut self.entries[slot] {
+            match &entry.waker {
+                Some(current) if current.will_wake(waker) => {}
+                _ => entry.waker = Some(waker.clone()),
+            }
+        }
+    }
+
+    /// Give up `slot`, whether or not its deadline has passed
+    pub fn remove(&mut self, slot: usize) {
+        self.entries[slot] = None;
+    }
+
+    /// The earliest deadline that hasn't been woken yet
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.entries
+            .iter()
+            .flatten()
+            .filter(|entry| entry.waker.is_some())
+            .map(|entry| entry.deadline)
+            .min()
+    }
+
+    /// Wake every future whose deadline is at or before `now`
+    pub fn expire(&mut self, now: u64) {
+        for entry in self.entries.iter_mut().flatten() {
+            if entry.deadline <= now {
+                if let Some(waker) = entry.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for TimerQueue<N> {
+    fn default() -> Self {
+        TimerQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    /// Counts how often it was woken
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn counter() -> (Arc<Counter>, Waker) {
+        let counter = Arc::new(Counter::default());
+        (counter.clone(), Waker::from(counter))
+    }
+
+    fn wakes(counter: &Counter) -> usize {
+        counter.0.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn wakes_each_future_at_its_own_deadline() {
+        let mut queue = TimerQueue::<4>::new();
+        let (early, early_waker) = counter();
+        let (late, late_waker) = counter();
+        queue.insert(300, &late_waker).unwrap();
+        queue.insert(100, &early_waker).unwrap();
+        assert_eq!(queue.next_deadline(), Some(100));
+
+        queue.expire(99);
+        assert_eq!((wakes(&early), wakes(&late)), (0, 0));
+        queue.expire(100);
+        assert_eq!((wakes(&early), wakes(&late)), (1, 0));
+        assert_eq!(queue.next_deadline(), Some(300));
+        queue.expire(1000);
+        assert_eq!((wakes(&early), wakes(&late)), (1, 1));
+        assert_eq!(queue.next_deadline(), None);
+    }
+
+    #[test]
+    fn slots_are_kept_until_removed() {
+        let mut queue = TimerQueue::<2>::new();
+        let (_, waker) = counter();
+        let first = queue.insert(10, &waker).unwrap();
+        queue.insert(20, &waker).unwrap();
+        assert_eq!(queue.insert(30, &waker), None);
+
+        // Waking doesn't free the slot, removing does
+        queue.expire(10);
+        assert_eq!(queue.insert(30, &waker), None);
+        queue.remove(first);
+        assert_eq!(queue.insert(30, &waker), Some(first));
+        assert_eq!(queue.next_deadline(), Some(20));
+    }
+
+    #[test]
+    fn register_replaces_the_waker() {
+        let mut queue = TimerQueue::<1>::new();
+        let (old, old_waker) = counter();
+        let (new, new_waker) = counter();
+        let slot = queue.insert(50, &old_waker).unwrap();
+        queue.register(slot, &new_waker);
+        queue.expire(50);
+        assert_eq!((wakes(&old), wakes(&new)), (0, 1));
+    }
+}
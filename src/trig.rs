@@ -0,0 +1,172 @@
+//! The floating-point math that the rest of the library needs, from a choice of backends
+//!
+//! - By default, `sqrt` and `atan2` come from the `m` crate, and `sin_cos` and `ln` from `libm`.
+//! - With the `trig-libm` feature, everything comes from `libm`, which is the most accurate and the
+//!   biggest.
+//! - With the `trig-micromath` feature, everything comes from `micromath`, whose approximations are
+//!   the smallest and fastest, but put headings off by up to about 0.3°.
+//!
+//! The `backends_are_accurate_enough` test compares the heading error of all three. The ellipsoid fit
+//! in `calibration` works in `f64` and only needs `sqrt_f64`, which micromath doesn't have, so that
+//! comes from `libm` with either feature.
+
+#[cfg(all(feature = "trig-libm", feature = "trig-micromath"))]
+compile_error!("The trig-libm and trig-micromath features can't be enabled together");
+
+#[cfg(any(test, not(any(feature = "trig-libm", feature = "trig-micromath"))))]
+mod m_backend {
+    use m::Float;
+
+    pub fn sqrt(x: f32) -> f32 {
+        x.sqrt()
+    }
+
+    pub fn atan2(y: f32, x: f32) -> f32 {
+        y.atan2(x)
+    }
+
+    pub fn sin_cos(x: f32) -> (f32, f32) {
+        libm::sincosf(x)
+    }
+
+    pub fn ln(x: f32) -> f32 {
+        libm::logf(x)
+    }
+
+    pub fn sqrt_f64(x: f64) -> f64 {
+        x.sqrt()
+    }
+}
+
+#[cfg(any(test, feature = "trig-libm"))]
+mod libm_backend {
+    pub fn sqrt(x: f32) -> f32 {
+        libm::sqrtf(x)
+    }
+
+    pub fn atan2(y: f32, x: f32) -> f32 {
+        libm::atan2f(y, x)
+    }
+
+    pub fn sin_cos(x: f32) -> (f32, f32) {
+        libm::sincosf(x)
+    }
+
+    pub fn ln(x: f32) -> f32 {
+        libm::logf(x)
+    }
+
+    pub fn sqrt_f64(x: f64) -> f64 {
+        libm::sqrt(x)
+    }
+}
+
+#[cfg(any(test, feature = "trig-micromath"))]
+mod micromath_backend {
+    use micromath::F32Ext;
+
+    pub fn sqrt(x: f32) -> f32 {
+        x.sqrt()
+    }
+
+    pub fn atan2(y: f32, x: f32) -> f32 {
+        y.atan2(x)
+    }
+
+    pub fn sin_cos(x: f32) -> (f32, f32) {
+        x.sin_cos()
+    }
+
+    pub fn ln(x: f32) -> f32 {
+        x.ln()
+    }
+
+    pub fn sqrt_f64(x: f64) -> f64 {
+        libm::sqrt(x)
+    }
+}
+
+#[cfg(not(any(feature = "trig-libm", feature = "trig-micromath")))]
+use m_backend as backend;
+
+#[cfg(feature = "trig-libm")]
+use libm_backend as backend;
+
+#[cfg(all(feature = "trig-micromath", not(feature = "trig-libm")))]
+use micromath_backend as backend;
+
+/// The square root of `x`
+pub fn sqrt(x: f32) -> f32 {
+    backend::sqrt(x)
+}
+
+/// The angle of (x, y) in radians, in the range [-π, π]
+pub fn atan2(y: f32, x: f32) -> f32 {
+    backend::atan2(y, x)
+}
+
+/// The sine and cosine of `x` in radians
+pub fn sin_cos(x: f32) -> (f32, f32) {
+    backend::sin_cos(x)
+}
+
+/// The natural logarithm of `x`
+pub fn ln(x: f32) -> f32 {
+    backend::ln(x)
+}
+
+/// The square root of `x`, in double precision
+pub fn sqrt_f64(x: f64) -> f64 {
+    backend::sqrt_f64(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The biggest error in degrees of the heading from `atan2` for a field of `strength`, over a
+    /// full turn in steps of 0.1°
+    fn worst_heading_error(atan2: fn(f32, f32) -> f32, strength: f64) -> f64 {
+        (-1799..=1800)
+            .map(|tenths| {
+                let angle = (f64::from(tenths) / 10.0).to_radians();
+                let (sin, cos) = libm::sincos(angle);
+                let (y, x) = ((strength * sin) as f32, (strength * cos) as f32);
+                let exact = libm::atan2(f64::from(y), f64::from(x)).to_degrees();
+                let error = f64::from(atan2(y, x).to_degrees()) - exact;
+                (error + 540.0).rem_euclid(360.0) - 180.0
+            })
+            .fold(0.0, |worst: f64, error| worst.max(error.abs()))
+    }
+
+    #[test]
+    fn backends_are_accurate_enough() {
+        for &strength in &[1.0, 500.0, 30000.0] {
+            assert!(worst_heading_error(libm_backend::atan2, strength) < 1e-4);
+            assert!(worst_heading_error(m_backend::atan2, strength) < 0.01);
+            assert!(worst_heading_error(micromath_backend::atan2, strength) < 0.3);
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_sqrt_sin_cos_and_ln() {
+        for &x in &[0.0, 0.25, 2.0, 1e6] {
+            assert!((m_backend::sqrt(x) - libm_backend::sqrt(x)).abs() <= 1e-6 * x.max(1.0));
+            assert!(
+                (micromath_backend::sqrt(x) - libm_backend::sqrt(x)).abs() <= 1e-2 * x.max(1.0)
+            );
+        }
+        for tenths in -31..=31 {
+            let x = tenths as f32 / 10.0;
+            let (sin, cos) = libm_backend::sin_cos(x);
+            let (approx_sin, approx_cos) = micromath_backend::sin_cos(x);
+            assert!((approx_sin - sin).abs() < 2e-3 && (approx_cos - cos).abs() < 2e-3);
+            assert_eq!(m_backend::sin_cos(x), (sin, cos));
+        }
+        for &x in &[0.01, 0.5, 1.0, 3.0, 100.0] {
+            let ln = libm_backend::ln(x);
+            assert!((micromath_backend::ln(x) - ln).abs() < 1e-3 * ln.abs().max(1.0));
+            assert_eq!(m_backend::ln(x), ln);
+        }
+    }
+}
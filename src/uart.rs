@@ -0,0 +1,183 @@
+//! Interrupt-driven async driver for USART1
+//!
+//! TX is on PA9 and RX on PA10, 8N1. Sending and receiving are independent, so one task can wait
+//! for a byte while another one sends.
+//!
+//! The baud rate depends on what's on the other end: 115200 for a USB serial cable, or whatever a
+//! Bluetooth serial module passes data through at with the `hc05` and `hm10` features, see
+//! `bluetooth_uart`.
+
+use crate::clocks;
+use crate::power::Awake;
+use crate::wakers;
+use core::fmt;
+use f3::hal::stm32f30x::{gpioa, rcc, usart1, GPIOA, RCC, USART1};
+
+// TX and RX pins, which are both alternate function 7
+const TX: u32 = 9;
+const RX: u32 = 10;
+
+#[cfg(not(any(feature = "hc05", feature = "hm10")))]
+pub const BAUD: u32 = 115_200;
+#[cfg(any(feature = "hc05", feature = "hm10"))]
+pub const BAUD: u32 = crate::bluetooth_uart::MODULE.data_baud();
+
+// PCLK2 = 72 MHz
+// e.g. 72 MHz / 115200 baud = 625
+fn brr(baud: u32) -> u32 {
+    clocks::PCLK2_HZ / baud
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UartError {
+    /// A byte arrived before we read the previous one, which is lost
+    Overrun,
+    /// The stop bit was missing, e.g. because of a baud rate mismatch
+    Framing,
+    /// The line was noisy while receiving the byte
+    Noise,
+}
+
+impl UartError {
+    /// The error flagged in ISR, if any. USART2 and USART3 have the same registers as USART1.
+    pub fn from_isr(isr: &usart1::isr::R) -> Option<Self> {
+        if isr.ore().bit_is_set() {
+            Some(UartError::Overrun)
+        } else if isr.fe().bit_is_set() {
+            Some(UartError::Framing)
+        } else if isr.nf().bit_is_set() {
+            Some(UartError::Noise)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Usart1 {
+    regs: &'static usart1::RegisterBlock,
+}
+
+impl Usart1 {
+    pub fn new() -> Self {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        let gpioa: &'static gpioa::RegisterBlock = unsafe { &*GPIOA::ptr() };
+        let regs: &'static usart1::RegisterBlock = unsafe { &*USART1::ptr() };
+
+        rcc.ahbenr.modify(|_, w| w.iopaen().set_bit());
+        rcc.apb2enr.modify(|_, w| w.usart1en().set_bit());
+
+        gpioa.moder.modify(|r, w| unsafe {
+            let mask = (0b11 << (2 * TX)) | (0b11 << (2 * RX));
+            let af = (0b10 << (2 * TX)) | (0b10 << (2 * RX));
+            w.bits((r.bits() & !mask) | af)
+        });
+        gpioa.afrh.modify(|r, w| unsafe {
+            let mask = (0b1111 << (4 * (TX - 8))) | (0b1111 << (4 * (RX - 8)));
+            let af7 = (7 << (4 * (TX - 8))) | (7 << (4 * (RX - 8)));
+            w.bits((r.bits() & !mask) | af7)
+        });
+
+        regs.brr.write(|w| unsafe { w.bits(brr(BAUD)) });
+        regs.cr1
+            .write(|w| w.ue().set_bit().te().set_bit().re().set_bit());
+
+        Usart1 { regs }
+    }
+
+    /// Switch to `baud`. Anything that's still being sent or received is lost.
+    #[cfg(any(feature = "hc05", feature = "hm10"))]
+    pub fn set_baud(&self, baud: u32) {
+        // BRR can only be written while the USART is disabled
+        self.regs.cr1.modify(|_, w| w.ue().clear_bit());
+        self.regs.brr.write(|w| unsafe { w.bits(brr(baud)) });
+        self.regs.cr1.modify(|_, w| w.ue().set_bit());
+    }
+
+    /// Send all of `bytes`
+    pub async fn write_all(&self, bytes: &[u8]) {
+        let usart1 = self.regs;
+        let _awake = Awake::new();
+        for &byte in bytes {
+            wakers::wait_for(
+                &wakers::USART1_TX,
+                || usart1.isr.read().txe().bit_is_set(),
+                || usart1.cr1.modify(|_, w| w.txeie().set_bit()),
+            )
+            .await;
+            usart1.tdr.write(|w| w.tdr().bits(u16::from(byte)));
+        }
+        // STOP mode would cut off the last byte, which takes less than 100 µs to shift out
+        if cfg!(feature = "low-power") {
+            while usart1.isr.read().tc().bit_is_clear() {}
+        }
+    }
+
+    /// Wait for the next byte
+    pub async fn read_byte(&self) -> Result<u8, UartError> {
+        let usart1 = self.regs;
+        wakers::wait_for(
+            &wakers::USART1_RX,
+            || {
+                let isr = usart1.isr.read();
+                isr.rxne().bit_is_set() || isr.ore().bit_is_set()
+            },
+            || usart1.cr1.modify(|_, w| w.rxneie().set_bit()),
+        )
+        .await;
+
+        // Reading RDR clears RXNE, so read it even if there's an error to get rid of the byte
+        let isr = usart1.isr.read();
+        let byte = usart1.rdr.read().rdr().bits() as u8;
+        match UartError::from_isr(&isr) {
+            None => Ok(byte),
+            Some(error) => {
+                usart1
+                    .icr
+                    .write(|w| w.orecf().set_bit().fecf().set_bit().ncf().set_bit());
+                Err(error)
+            }
+        }
+    }
+}
+
+// Long enough for anything we send at once, including the shell's `dump`
+const LINE_CAPACITY: usize = 384;
+
+/// A line of text, formatted with `write!` without allocating
+pub struct Line {
+    buffer: [u8; LINE_CAPACITY],
+    len: usize,
+}
+
+impl Line {
+    pub fn new() -> Self {
+        Line {
+            buffer: [0; LINE_CAPACITY],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only `write_str` adds to the buffer, so it's always valid UTF-8
+        core::str::from_utf8(self.as_bytes()).unwrap()
+    }
+}
+
+impl fmt::Write for Line {
+    /// Fails if the line is full
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > LINE_CAPACITY {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! USB CDC-ACM virtual serial port on the user USB connector
+//!
+//! The host sees a serial port that gets the same output as USART1 and accepts the same shell
+//! commands, so no UART adapter is needed.
+//!
+//! The USB peripheral needs a 48 MHz clock, which `clocks` derives from the 72 MHz PLL.
+//!
+//! The PLL stops in STOP mode, so while the host has configured the device, it keeps the MCU awake.
+//!
+//! usb-device has to be polled whenever the USB interrupt fires. Nothing else would get around to
+//! it, so `read_byte` does that while it waits, which means that something must always be waiting
+//! for a byte.
+
+use crate::clocks;
+use crate::power::Awake;
+use crate::wakers;
+use core::cell::{Cell, RefCell};
+use cortex_m::asm;
+use cortex_m::peripheral::NVIC;
+use f3::hal::stm32f30x::{gpioa, rcc, Interrupt, GPIOA, RCC};
+use stm32_usbd::{UsbBus, UsbPeripheral};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
+use usb_device::UsbError;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+// D- and D+ are PA11 and PA12
+const DM: u32 = 11;
+const DP: u32 = 12;
+
+// pid.codes test VID/PID, which is fine for a device that's never sold
+const VID_PID: UsbVidPid = UsbVidPid(0x1209, 0x0001);
+
+pub struct Peripheral;
+
+unsafe impl UsbPeripheral for Peripheral {
+    // USB_FS::ptr() isn't a const fn
+    const REGISTERS: *const () = 0x4000_5c00 as *const ();
+
+    // The Discovery board has a fixed 1.5 kΩ pull-up on D+
+    const DP_PULL_UP_FEATURE: bool = false;
+
+    // The STM32F303VC has 512 bytes of packet memory
+    const EP_MEMORY: *const () = 0x4000_6000 as *const ();
+    const EP_MEMORY_SIZE: usize = 512;
+
+    fn enable() {
+        let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+        rcc.apb1enr.modify(|_, w| w.usben().set_bit());
+        rcc.apb1rstr.modify(|_, w| w.usbrst().set_bit());
+        rcc.apb1rstr.modify(|_, w| w.usbrst().clear_bit());
+    }
+
+    fn startup_delay() {
+        // tSTARTUP is 1 µs
+        asm::delay(clocks::SYSCLK_HZ / 1_000_000);
+    }
+}
+
+pub type Bus = UsbBus<Peripheral>;
+
+/// Set up the pins. The returned allocator must outlive the `UsbSerial`.
+pub fn init() -> UsbBusAllocator<Bus> {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let gpioa: &'static gpioa::RegisterBlock = unsafe { &*GPIOA::ptr() };
+
+    rcc.ahbenr.modify(|_, w| w.iopaen().set_bit());
+
+    // Because of the fixed pull-up, the host doesn't notice a reset of the board. Pulling D+ low
+    // for a moment makes it enumerate the device again.
+    gpioa.bsrr.write(|w| unsafe { w.bits(1 << (DP + 16)) });
+    gpioa
+        .moder
+        .modify(|r, w| unsafe { w.bits((r.bits() & !(0b11 << (2 * DP))) | (0b01 << (2 * DP))) });
+    // 10 ms
+    asm::delay(clocks::SYSCLK_HZ / 100);
+
+    // D- and D+ in alternate function 14
+    gpioa.moder.modify(|r, w| unsafe {
+        let mask = (0b11 << (2 * DM)) | (0b11 << (2 * DP));
+        let af = (0b10 << (2 * DM)) | (0b10 << (2 * DP));
+        w.bits((r.bits() & !mask) | af)
+    });
+    gpioa.afrh.modify(|r, w| unsafe {
+        let mask = (0b1111 << (4 * (DM - 8))) | (0b1111 << (4 * (DP - 8)));
+        let af14 = (14 << (4 * (DM - 8))) | (14 << (4 * (DP - 8)));
+        w.bits((r.bits() & !mask) | af14)
+    });
+
+    UsbBus::new(Peripheral)
+}
+
+struct State<'a> {
+    device: UsbDevice<'a, Bus>,
+    serial: SerialPort<'a, Bus>,
+    awake: Option<Awake>,
+}
+
+pub struct UsbSerial<'a> {
+    state: RefCell<State<'a>>,
+}
+
+impl<'a> UsbSerial<'a> {
+    pub fn new(bus: &'a UsbBusAllocator<Bus>) -> Self {
+        let serial = SerialPort::new(bus);
+        let device = UsbDeviceBuilder::new(bus, VID_PID)
+            .manufacturer("paulkernfeld")
+            .product("compass")
+            .serial_number("1")
+            .device_class(USB_CLASS_CDC)
+            .build();
+        UsbSerial {
+            state: RefCell::new(State {
+                device,
+                serial,
+                awake: None,
+            }),
+        }
+    }
+
+    /// Wait for the next byte from the host
+    pub async fn read_byte(&self) -> Result<u8, UsbError> {
+        let result = Cell::new(None);
+        wakers::wait_for(
+            &wakers::USB_LP,
+            || {
+                let State {
+                    device,
+                    serial,
+                    awake,
+                } = &mut *self.state.borrow_mut();
+                device.poll(&mut [serial]);
+                if device.state() == UsbDeviceState::Configured {
+                    awake.get_or_insert_with(Awake::new);
+                } else {
+                    *awake = None;
+                }
+                let mut byte = [0];
+                match serial.read(&mut byte) {
+                    Ok(0) | Err(UsbError::WouldBlock) => false,
+                    Ok(_) => {
+                        result.set(Some(Ok(byte[0])));
+                        true
+                    }
+                    Err(error) => {
+                        result.set(Some(Err(error)));
+                        true
+                    }
+                }
+            },
+            || unsafe { NVIC::unmask(Interrupt::USB_LP_CAN_RX0) },
+        )
+        .await;
+        result.take().unwrap()
+    }
+
+    /// Send as much of `bytes` as fits into the buffer. The rest is dropped, because if nobody is
+    /// listening on the host, waiting for the buffer to empty would stall everything else.
+    pub fn write(&self, bytes: &[u8]) {
+        let serial = &mut self.state.borrow_mut().serial;
+        let mut written = 0;
+        while written < bytes.len() {
+            match serial.write(&bytes[written..]) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => written += len,
+            }
+        }
+    }
+}
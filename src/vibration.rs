@@ -0,0 +1,100 @@
+//! Finding the dominant frequency of a vibration with the accelerometer
+//!
+//! A machine that hums, a washing machine that spins or a motor that's out of balance shakes
+//! whatever it's standing on at a few characteristic frequencies. We collect a window of `N`
+//! readings of the strength of the acceleration, take out its mean, which is mostly gravity, and
+//! look for the strongest bin of its spectrum.
+//!
+//! With the accelerometer at 100 Hz, frequencies up to 50 Hz can be told apart, and a window of 64
+//! readings resolves them to about 1.6 Hz. The windows don't overlap, so there's a new result every
+//! 0.64 s. The window isn't tapered, so a frequency between two bins leaks into its neighbors, but
+//! the strongest bin is still the right one.
+
+use crate::fft::fft;
+use crate::trig;
+
+/// The strongest frequency in a window of readings
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Vibration {
+    pub frequency_hz: f32,
+    /// Amplitude of the vibration at that frequency, in the unit of the readings
+    pub amplitude: f32,
+}
+
+/// Collects windows of `N` accelerometer readings, where `N` is a power of two
+pub struct VibrationAnalyzer<const N: usize> {
+    samples: [i32; N],
+    len: usize,
+    sample_rate_hz: f32,
+}
+
+impl<const N: usize> VibrationAnalyzer<N> {
+    pub fn new(sample_rate_hz: f32) -> Self {
+        VibrationAnalyzer {
+            samples: [0; N],
+            len: 0,
+            sample_rate_hz,
+        }
+    }
+
+    /// Add a reading, and return the strongest frequency once the window is full. Returns `None`
+    /// for a window without any vibration at all.
+    pub fn update(&mut self, accel: (i16, i16, i16)) -> Option<Vibration> {
+        let (x, y, z) = (f32::from(accel.0), f32::from(accel.1), f32::from(accel.2));
+        self.samples[self.len] = trig::sqrt(x * x + y * y + z * z) as i32;
+        self.len += 1;
+        if self.len < N {
+            return None;
+        }
+        self.len = 0;
+
+        let mean = self.samples.iter().sum::<i32>() / N as i32;
+        let mut re = self.samples;
+        for value in re.iter_mut() {
+            *value -= mean;
+        }
+        let mut im = [0; N];
+        fft(&mut re, &mut im);
+
+        // Only the bins up to the Nyquist frequency are distinct, and the first is what's left of
+        // the mean
+        let power = |k: usize| i64::from(re[k]).pow(2) + i64::from(im[k]).pow(2);
+        let strongest = (1..=N / 2).max_by_key(|&k| power(k))?;
+        if power(strongest) == 0 {
+            return None;
+        }
+        Some(Vibration {
+            frequency_hz: strongest as f32 * self.sample_rate_hz / N as f32,
+            // `fft` already divides by N, and the mirror bin holds the other half of the amplitude
+            amplitude: 2.0 * trig::sqrt(power(strongest) as f32),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    #[test]
+    fn finds_the_frequency_of_a_vibration() {
+        let mut analyzer = VibrationAnalyzer::<64>::new(100.0);
+        let mut result = None;
+        for i in 0..64 {
+            // 1 g of gravity on Z, and 200 mg of vibration at 12.5 Hz
+            let (sin, _) = trig::sin_cos(2.0 * PI * 12.5 * i as f32 / 100.0);
+            let z = 1000.0 + 200.0 * sin;
+            assert_eq!(result, None);
+            result = analyzer.update((0, 0, z as i16));
+        }
+        let vibration = result.unwrap();
+        assert_eq!(vibration.frequency_hz, 12.5);
+        assert!((vibration.amplitude - 200.0).abs() < 5.0);
+
+        // A board that lies still doesn't vibrate at any frequency
+        for _ in 0..64 {
+            assert_eq!(analyzer.update((0, 0, 1000)), None);
+        }
+    }
+}
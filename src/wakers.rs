@@ -0,0 +1,254 @@
+//! Interrupt-driven wakers
+//!
+//! Every interrupt that a future can wait on has a static `AtomicWaker`. A waiting future
+//! registers its waker, enables the interrupt source in the peripheral and returns `Pending`. When
+//! the interrupt fires, the handler disables the interrupt source again (otherwise it would keep
+//! firing until the future gets around to clearing the flag) and wakes the registered waker.
+//!
+//! SysTick and the RTC wakeup timer are the exception: they keep running, so their handlers record
+//! that they fired in a flag instead.
+//!
+//! USB is different too: usb-device clears the interrupt flags when it's polled, so the handler
+//! masks the interrupt in the NVIC and a waiting future unmasks it.
+//!
+//! The handlers are bound to their interrupts in `interrupts`, except in the RTIC build, which binds
+//! them to its hardware tasks instead.
+
+use crate::board;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use cortex_m::peripheral::NVIC;
+use f3::hal::stm32f30x::{Interrupt, EXTI, RTC, SPI1, SPI2, TIM7, USART1, USART2};
+use futures::future::poll_fn;
+use futures::task::AtomicWaker;
+
+/// Woken by the sensor I2C peripheral's event interrupt (TXIS, RXNE, TC, TCR, STOPF, NACKF) and
+/// error interrupt (BERR, ARLO, OVR)
+pub static I2C_EV: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the SPI1 interrupt (TXE, RXNE, OVR, MODF)
+pub static SPI1_EV: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the SPI2 interrupt (TXE, RXNE, OVR, MODF)
+pub static SPI2_EV: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the TIM7 update interrupt
+pub static TIM7_UP: AtomicWaker = AtomicWaker::new();
+
+/// Woken by EXTI line 0, the user button
+pub static EXTI0_EV: AtomicWaker = AtomicWaker::new();
+
+/// Woken by EXTI line 1, the gyro's INT2 pin
+pub static EXTI1_EV: AtomicWaker = AtomicWaker::new();
+
+/// Woken by EXTI line 2, the magnetometer's DRDY pin
+pub static EXTI2: AtomicWaker = AtomicWaker::new();
+
+/// Woken by EXTI line 4, the accelerometer's INT1 pin
+pub static EXTI4_EV: AtomicWaker = AtomicWaker::new();
+
+/// Woken by EXTI line 5, the accelerometer's INT2 pin
+pub static EXTI5_EV: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the USART1 interrupt when it's ready to send (TXE)
+pub static USART1_TX: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the USART1 interrupt when it has received something (RXNE, ORE)
+pub static USART1_RX: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the USART2 interrupt when it has received something (RXNE, ORE)
+pub static USART2_RX: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the USB low priority interrupt
+pub static USB_LP: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the SysTick exception
+pub static SYSTICK: AtomicWaker = AtomicWaker::new();
+
+/// Woken by the RTC wakeup timer
+pub static RTC_WAKEUP: AtomicWaker = AtomicWaker::new();
+
+// Set by the SysTick exception
+static SYSTICK_ELAPSED: AtomicBool = AtomicBool::new(false);
+
+// Set by the RTC wakeup interrupt
+static RTC_ELAPSED: AtomicBool = AtomicBool::new(false);
+
+/// Whether SysTick has fired since the last call
+#[cfg(not(feature = "hmc5883l"))]
+pub fn systick_elapsed() -> bool {
+    SYSTICK_ELAPSED.swap(false, Ordering::Relaxed)
+}
+
+/// Whether the RTC wakeup timer has fired since the last call
+#[cfg(feature = "low-power")]
+pub fn rtc_elapsed() -> bool {
+    RTC_ELAPSED.swap(false, Ordering::Relaxed)
+}
+
+/// Unmask the interrupts that have wakers in this module. The interrupts still won't fire until a
+/// future enables the corresponding source in the peripheral.
+pub fn init() {
+    unsafe {
+        NVIC::unmask(board::I2C_EV_INTERRUPT);
+        NVIC::unmask(board::I2C_ER_INTERRUPT);
+        NVIC::unmask(Interrupt::SPI1);
+        NVIC::unmask(Interrupt::SPI2);
+        NVIC::unmask(Interrupt::TIM7);
+        NVIC::unmask(Interrupt::EXTI0);
+        NVIC::unmask(Interrupt::EXTI1);
+        NVIC::unmask(Interrupt::EXTI2_TSC);
+        NVIC::unmask(Interrupt::EXTI4);
+        NVIC::unmask(Interrupt::EXTI9_5);
+        NVIC::unmask(Interrupt::RTC_WKUP);
+        NVIC::unmask(Interrupt::USART1_EXTI25);
+        NVIC::unmask(Interrupt::USART2_EXTI26);
+    }
+}
+
+/// Wait until `ready` returns true.
+///
+/// If it isn't ready yet, the future registers itself with `waker` and calls `listen`, which must
+/// enable an interrupt source whose handler will wake `waker`. We check `ready` a second time
+/// after registering because the event may have happened in between, in which case nobody would
+/// ever wake us.
+pub fn wait_for<'a>(
+    waker: &'a AtomicWaker,
+    ready: impl Fn() -> bool + 'a,
+    listen: impl Fn() + 'a,
+) -> impl Future<Output = ()> + 'a {
+    poll_fn(move |cx| {
+        if ready() {
+            return Poll::Ready(());
+        }
+
+        waker.register(cx.waker());
+        listen();
+
+        if ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+}
+
+pub fn i2c_ev() {
+    board::i2c().cr1.modify(|_, w| {
+        w.txie().clear_bit();
+        w.rxie().clear_bit();
+        w.tcie().clear_bit();
+        w.stopie().clear_bit();
+        w.nackie().clear_bit()
+    });
+    I2C_EV.wake();
+}
+
+pub fn i2c_er() {
+    board::i2c().cr1.modify(|_, w| w.errie().clear_bit());
+    I2C_EV.wake();
+}
+
+pub fn spi1() {
+    let spi1 = unsafe { &*SPI1::ptr() };
+    spi1.cr2.modify(|_, w| {
+        w.txeie().clear_bit();
+        w.rxneie().clear_bit();
+        w.errie().clear_bit()
+    });
+    SPI1_EV.wake();
+}
+
+pub fn spi2() {
+    let spi2 = unsafe { &*SPI2::ptr() };
+    spi2.cr2.modify(|_, w| {
+        w.txeie().clear_bit();
+        w.rxneie().clear_bit();
+        w.errie().clear_bit()
+    });
+    SPI2_EV.wake();
+}
+
+pub fn tim7() {
+    let tim7 = unsafe { &*TIM7::ptr() };
+    tim7.dier.modify(|_, w| w.uie().clear_bit());
+    TIM7_UP.wake();
+}
+
+// The pending flag stays set, because the edge is what the waiting future is looking for
+pub fn exti0() {
+    let exti = unsafe { &*EXTI::ptr() };
+    exti.imr1.modify(|_, w| w.mr0().clear_bit());
+    EXTI0_EV.wake();
+}
+
+pub fn exti1() {
+    let exti = unsafe { &*EXTI::ptr() };
+    exti.imr1.modify(|_, w| w.mr1().clear_bit());
+    exti.pr1.write(|w| w.pr1().set_bit());
+    EXTI1_EV.wake();
+}
+
+pub fn exti2_tsc() {
+    let exti = unsafe { &*EXTI::ptr() };
+    exti.imr1.modify(|_, w| w.mr2().clear_bit());
+    exti.pr1.write(|w| w.pr2().set_bit());
+    EXTI2.wake();
+}
+
+pub fn exti4() {
+    let exti = unsafe { &*EXTI::ptr() };
+    exti.imr1.modify(|_, w| w.mr4().clear_bit());
+    exti.pr1.write(|w| w.pr4().set_bit());
+    EXTI4_EV.wake();
+}
+
+// The pending flag stays set, because the edge is what the waiting future is looking for
+pub fn exti9_5() {
+    let exti = unsafe { &*EXTI::ptr() };
+    exti.imr1.modify(|_, w| w.mr5().clear_bit());
+    EXTI5_EV.wake();
+}
+
+// The timer keeps running, so clear the flags rather than disabling the source
+pub fn rtc_wkup() {
+    let rtc = unsafe { &*RTC::ptr() };
+    let exti = unsafe { &*EXTI::ptr() };
+    rtc.isr.modify(|_, w| w.wutf().clear_bit());
+    exti.pr1.write(|w| w.pr20().set_bit());
+    RTC_ELAPSED.store(true, Ordering::Relaxed);
+    RTC_WAKEUP.wake();
+}
+
+// Sending and receiving can be waited on at the same time, so only disable the source that fired
+pub fn usart1_exti25() {
+    let usart1 = unsafe { &*USART1::ptr() };
+    let isr = usart1.isr.read();
+    let cr1 = usart1.cr1.read();
+    if cr1.txeie().bit_is_set() && isr.txe().bit_is_set() {
+        usart1.cr1.modify(|_, w| w.txeie().clear_bit());
+        USART1_TX.wake();
+    }
+    if cr1.rxneie().bit_is_set() && (isr.rxne().bit_is_set() || isr.ore().bit_is_set()) {
+        usart1.cr1.modify(|_, w| w.rxneie().clear_bit());
+        USART1_RX.wake();
+    }
+}
+
+// We only ever receive from USART2
+pub fn usart2_exti26() {
+    let usart2 = unsafe { &*USART2::ptr() };
+    usart2.cr1.modify(|_, w| w.rxneie().clear_bit());
+    USART2_RX.wake();
+}
+
+pub fn usb_lp_can_rx0() {
+    NVIC::mask(Interrupt::USB_LP_CAN_RX0);
+    USB_LP.wake();
+}
+
+pub fn systick() {
+    SYSTICK_ELAPSED.store(true, Ordering::Relaxed);
+    SYSTICK.wake();
+}
@@ -0,0 +1,102 @@
+//! Independent watchdog supervision
+//!
+//! The IWDG runs from LSI and resets the MCU unless it's reloaded often enough. Once started, it
+//! can't be stopped, not even by STOP mode. Reloading it from a timer interrupt would only prove
+//! that interrupts still fire, so instead a `Supervisor` reloads it only after every supervised
+//! part of the program has checked in. If the I2C bus locks up, the magnetometer stops delivering
+//! samples, and if restarting it (see `health`) doesn't help either, the watchdog runs out and the
+//! compass starts over instead of freezing.
+
+use core::cell::Cell;
+use f3::hal::stm32f30x::{dbgmcu, iwdg, rcc, DBGMCU, IWDG, RCC};
+
+// Nominal LSI frequency
+const LSI_HZ: u32 = 40_000;
+
+// PR = 0b011: the counter runs at LSI / 32
+const PRESCALER: u8 = 0b011;
+const DIVIDER: u32 = 32;
+
+// The reload value is 12 bits
+const MAX_RELOAD: u32 = 0x0fff;
+
+// Values for IWDG_KR
+const KEY_RELOAD: u16 = 0xaaaa;
+const KEY_UNLOCK: u16 = 0x5555;
+const KEY_START: u16 = 0xcccc;
+
+/// Parts of the program that have to keep making progress
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    /// Magnetometer samples keep arriving
+    Sensors,
+    /// The main loop keeps updating the LEDs
+    Display,
+}
+
+/// Shared by the tasks that check in
+pub struct Supervisor {
+    /// A bit for each `Task` that has checked in since the last reload
+    checked_in: Cell<u8>,
+}
+
+impl Supervisor {
+    /// Start the watchdog with a timeout of `ms` milliseconds, which must be at most about 3
+    /// seconds. LSI may be up to 25% fast, so the actual timeout can be that much shorter.
+    pub fn start(ms: u32) -> Self {
+        let iwdg: &'static iwdg::RegisterBlock = unsafe { &*IWDG::ptr() };
+        let dbgmcu: &'static dbgmcu::RegisterBlock = unsafe { &*DBGMCU::ptr() };
+
+        // Don't reset while the debugger has halted the core
+        dbgmcu.apb1fz.modify(|_, w| w.dbg_iwdg_stop().set_bit());
+
+        let reload_value = (ms * (LSI_HZ / 1000) / DIVIDER).min(MAX_RELOAD);
+
+        iwdg.kr.write(|w| unsafe { w.key().bits(KEY_START) });
+        iwdg.kr.write(|w| unsafe { w.key().bits(KEY_UNLOCK) });
+        iwdg.pr.write(|w| unsafe { w.pr().bits(PRESCALER) });
+        iwdg.rlr
+            .write(|w| unsafe { w.rl().bits(reload_value as u16) });
+        // The new values only take effect once they have made it into the LSI clock domain
+        while {
+            let sr = iwdg.sr.read();
+            sr.pvu().bit_is_set() || sr.rvu().bit_is_set()
+        } {}
+        reload();
+
+        Supervisor {
+            checked_in: Cell::new(0),
+        }
+    }
+
+    /// Record that `task` has made progress
+    pub fn check_in(&self, task: Task) {
+        self.checked_in.set(self.checked_in.get() | 1 << task as u8);
+    }
+
+    /// Reload the watchdog if every task has checked in since the last reload. This should be
+    /// called periodically, much more often than the timeout.
+    pub fn service(&self) {
+        let all = (1 << Task::Sensors as u8) | (1 << Task::Display as u8);
+        if self.checked_in.get() == all {
+            reload();
+            self.checked_in.set(0);
+        }
+    }
+}
+
+/// Reload the watchdog no matter what. This is for when the program has given up anyway, and does
+/// nothing if the watchdog hasn't been started.
+pub fn reload() {
+    let iwdg: &'static iwdg::RegisterBlock = unsafe { &*IWDG::ptr() };
+    iwdg.kr.write(|w| unsafe { w.key().bits(KEY_RELOAD) });
+}
+
+/// Whether the last reset was caused by the watchdog. This clears the reset flags, so it only
+/// returns the right answer the first time.
+pub fn caused_reset() -> bool {
+    let rcc: &'static rcc::RegisterBlock = unsafe { &*RCC::ptr() };
+    let caused = rcc.csr.read().iwdgrstf().bit_is_set();
+    rcc.csr.modify(|_, w| w.rmvf().set_bit());
+    caused
+}